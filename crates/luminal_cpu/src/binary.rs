@@ -1,15 +1,16 @@
 use luminal::{
     op::*,
-    prelude::{petgraph::visit::EdgeRef, *},
+    prelude::{other, petgraph::visit::EdgeRef, *},
 };
 
-use super::other::ARange;
+use super::{matmul::MatMul2D, other::ARange};
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct Sub;
 
 impl Operator for Sub {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&tensors, 2);
         let (a_data, b_data) = (get_vec(&tensors[0].0), get_vec(&tensors[1].0));
         let (a_ind, a_val, b_ind, b_val) = (
             tensors[0].1.index_expression(),
@@ -40,7 +41,7 @@ pub struct SubtractionCompiler;
 
 impl Compiler for SubtractionCompiler {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let (lhs, rhs) = (node(), node());
         let mul = binary::<Mul>(rhs.clone(), super::constant(-1.));
         let add = binary::<Add>(lhs.clone(), mul.clone());
@@ -82,6 +83,7 @@ impl Compiler for SubtractionCompiler {
                 .input(b, b_edge.1, b_edge.2)
                 .finish();
             move_outgoing_edge(add, sub, &mut graph.graph);
+            remap(add, sub, &mut ids, graph);
 
             graph.graph.remove_node(add);
             s.try_delete();
@@ -94,6 +96,7 @@ pub struct Equal;
 
 impl Operator for Equal {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&tensors, 2);
         let (a_data, b_data) = (get_vec(&tensors[0].0), get_vec(&tensors[1].0));
         let mut data = vec![0.; tensors[0].1.n_elements().to_usize().unwrap()];
         let (a_ind, a_val, b_ind, b_val) = (
@@ -113,7 +116,7 @@ impl Operator for Equal {
             } else {
                 0.0
             };
-            data[i] = if a < b { 1. } else { 0. };
+            data[i] = if a == b { 1. } else { 0. };
         }
         vec![Tensor::new(data)]
     }
@@ -124,16 +127,21 @@ pub struct EqualCompiler;
 
 impl Compiler for EqualCompiler {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let one = super::constant(1.);
         let (lhs, rhs) = (node(), node());
         let lt1 = binary::<LessThan>(lhs.clone(), rhs.clone());
         let ne = binary::<Add>(lt1.clone(), binary::<LessThan>(rhs.clone(), lhs.clone()));
-        let eq = binary::<Sub>(one, ne);
+        let eq = binary::<Sub>(one.clone(), ne);
 
         let mut s = eq.clone().search(graph);
         while s.next_match() {
-            if s.check_no_delete(&[eq.id]) {
+            // The compared tensors are kept as inputs, so they can be retrieved
+            if s.check_no_delete(&[eq.id, lhs.id, rhs.id]) {
+                continue;
+            }
+            // The selector is order-agnostic, so make sure this is 1 - ne rather than ne - 1
+            if graph.get_sources(s.get(&eq))[0].0 != s.get(&one) {
                 continue;
             }
             let (lhs, rhs) = (s.get(&lhs), s.get(&rhs));
@@ -160,6 +168,7 @@ impl Compiler for EqualCompiler {
                 .input(rhs, b_edge.1, b_edge.2)
                 .finish();
             move_outgoing_edge(eq, equals, &mut graph.graph);
+            remap(eq, equals, &mut ids, graph);
 
             graph.graph.remove_node(eq);
             s.try_delete();
@@ -174,6 +183,7 @@ pub struct Gather {
 
 impl Operator for Gather {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&tensors, 2);
         // Inp 1 should be Vec<f32> and inp 2 should be a CudaSlice<T>
         let indexes = tensors[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
         let weights = tensors[1].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
@@ -195,7 +205,7 @@ pub struct GatherCompiler;
 
 impl Compiler for GatherCompiler {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let indexes = node();
         let eq = binary::<Equal>(indexes.clone(), op::<ARange>());
         let embedding = node();
@@ -203,7 +213,7 @@ impl Compiler for GatherCompiler {
         let sum_reduce = unary::<SumReduce>(mul.clone());
         let mut s = sum_reduce.clone().search(graph);
         while s.next_match() {
-            if s.check_no_delete(&[embedding.id]) {
+            if s.check_no_delete(&[sum_reduce.id, embedding.id, indexes.id]) {
                 continue;
             }
             let emb_shape = graph
@@ -214,6 +224,12 @@ impl Compiler for GatherCompiler {
                 .as_data()
                 .unwrap()
                 .2;
+            // Gather reads the buffers directly, so the embedding must be a plain (vocab, dim) matrix and the indexes a plain vector
+            let mut emb_matrix = emb_shape;
+            emb_matrix.remove_dim(0);
+            if emb_matrix.is_reshaped() {
+                continue;
+            }
             let index_shape = graph
                 .edges_connecting(s.get(&indexes), s.get(&eq))
                 .next()
@@ -222,6 +238,11 @@ impl Compiler for GatherCompiler {
                 .as_data()
                 .unwrap()
                 .2;
+            let mut index_vec = index_shape;
+            index_vec.remove_dim(1);
+            if index_vec.is_reshaped() {
+                continue;
+            }
             let embed_dim = graph
                 .graph
                 .edges_connecting(s.get(&embedding), s.get(&mul))
@@ -241,6 +262,168 @@ impl Compiler for GatherCompiler {
                 .input(s.get(&embedding), 0, emb_shape)
                 .finish();
             move_outgoing_edge(s.get(&sum_reduce), gather, &mut graph.graph);
+            remap(s.get(&sum_reduce), gather, &mut ids, graph);
+            graph.remove_node(s.get(&sum_reduce));
+            s.try_delete();
+        }
+    }
+}
+
+/// Pool gathered embedding rows over bags of indexes, without materializing the gathered rows or the bag segments
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmbeddingBag {
+    pub embed_dim: usize,
+    pub mode: other::EmbeddingBagMode,
+}
+
+impl Operator for EmbeddingBag {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&tensors, 3);
+        let indexes = get_vec(&tensors[0].0);
+        let weights = get_vec(&tensors[1].0);
+        let ranges =
+            other::embedding_bag_ranges(tensors[2].0.borrowed(), tensors[2].1, indexes.len());
+
+        let mut out = vec![0.; ranges.len() * self.embed_dim];
+        for (bag, (start, end)) in out.chunks_exact_mut(self.embed_dim).zip(ranges) {
+            for &e in &indexes[start..end] {
+                let row = &weights[e as usize * self.embed_dim..(e as usize + 1) * self.embed_dim];
+                for (o, w) in bag.iter_mut().zip(row) {
+                    *o += w;
+                }
+            }
+            if self.mode == other::EmbeddingBagMode::Mean && end > start {
+                let scale = 1. / (end - start) as f32;
+                bag.iter_mut().for_each(|o| *o *= scale);
+            }
+        }
+
+        vec![Tensor::new(out)]
+    }
+}
+
+/// Fuse an embedding bag's segment matrix times its gathered rows into one op. Runs after the matmul and gather compilers.
+#[derive(Debug, Default)]
+pub struct EmbeddingBagCompiler;
+
+impl Compiler for EmbeddingBagCompiler {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let mut segments = op::<Function>();
+        segments
+            .attr(|f: &Function| other::EmbeddingBagMode::from_segments_op_name(&f.0).is_some());
+        let gather = op::<Gather>();
+        let matmul = binary::<MatMul2D>(gather.clone(), segments.clone());
+        let mut s = matmul.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[matmul.id]) {
+                continue;
+            }
+            let (segments, gather, matmul) = (s.get(&segments), s.get(&gather), s.get(&matmul));
+            let srcs = graph.get_sources(matmul);
+            // The segments must be the lhs, and the gathered rows the untouched rhs
+            if srcs[0].0 != segments || srcs[1].2.is_reshaped() {
+                continue;
+            }
+            let mode = other::EmbeddingBagMode::from_segments_op_name(
+                &graph
+                    .node_weight(segments)
+                    .unwrap()
+                    .as_any()
+                    .downcast_ref::<Function>()
+                    .unwrap()
+                    .0,
+            )
+            .unwrap();
+            let embed_dim = graph
+                .node_weight(gather)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<Gather>()
+                .unwrap()
+                .embed_dim;
+            let gather_srcs = graph.get_sources(gather);
+            let offsets = graph.get_sources(segments)[0];
+            let bag = graph
+                .add_op(EmbeddingBag { embed_dim, mode })
+                .input(gather_srcs[0].0, gather_srcs[0].1, gather_srcs[0].2)
+                .input(gather_srcs[1].0, gather_srcs[1].1, gather_srcs[1].2)
+                .input(offsets.0, offsets.1, offsets.2)
+                .finish();
+            move_outgoing_edge(matmul, bag, &mut graph.graph);
+            remap(matmul, bag, &mut ids, graph);
+            graph.remove_node(matmul);
+            s.try_delete();
+        }
+    }
+}
+
+/// Count the occurrences of each bin index, skipping indexes that don't land exactly on a bin
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bincount {
+    pub num_bins: usize,
+}
+
+impl Operator for Bincount {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&tensors, 1);
+        let indexes = get_vec(&tensors[0].0);
+        let (ind, val) = (
+            tensors[0].1.index_expression(),
+            tensors[0].1.valid_expression(),
+        );
+        let mut out = vec![0.; self.num_bins];
+        for i in 0..tensors[0].1.n_elements().to_usize().unwrap() {
+            let bin = if val.exec_single_var(i) != 0 {
+                indexes[ind.exec_single_var(i)]
+            } else {
+                0.0
+            };
+            if bin >= 0. && bin.fract() == 0. && (bin as usize) < self.num_bins {
+                out[bin as usize] += 1.;
+            }
+        }
+        vec![Tensor::new(out)]
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BincountCompiler;
+
+impl Compiler for BincountCompiler {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let indexes = node();
+        let eq = binary::<Equal>(indexes.clone(), op::<ARange>());
+        let mut sum_reduce = unary::<SumReduce>(eq.clone());
+        sum_reduce.attr(|o: &SumReduce| o.0 == 0);
+        let mut s = sum_reduce.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[sum_reduce.id, indexes.id]) {
+                continue;
+            }
+            // The indexes are broadcast across the bins in the one-hot matrix
+            let mut index_shape = graph
+                .edges_connecting(s.get(&indexes), s.get(&eq))
+                .next()
+                .unwrap()
+                .weight()
+                .as_data()
+                .unwrap()
+                .2;
+            if index_shape.len() != 2 || !index_shape.fake[index_shape.indexes[1]] {
+                continue;
+            }
+            let Some(num_bins) = index_shape.dims()[1].to_usize() else {
+                continue;
+            };
+            index_shape.remove_dim(1);
+            let bincount = graph
+                .add_op(Bincount { num_bins })
+                .input(s.get(&indexes), 0, index_shape)
+                .finish();
+            move_outgoing_edge(s.get(&sum_reduce), bincount, &mut graph.graph);
+            remap(s.get(&sum_reduce), bincount, &mut ids, graph);
             graph.remove_node(s.get(&sum_reduce));
             s.try_delete();
         }
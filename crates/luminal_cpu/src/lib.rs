@@ -17,10 +17,15 @@ use luminal::{
 pub type CPUCompiler = (
     matmul::MatMulCompiler,
     binary::SubtractionCompiler,
+    other::MaskedSoftmaxCompiler,
     binary::EqualCompiler,
+    other::MaxWithIndexCompiler,
     other::ARangeCompiler,
     binary::GatherCompiler,
+    binary::BincountCompiler,
+    binary::EmbeddingBagCompiler,
     UnaryFusionCompiler,
+    RemoveBarriers,
 );
 
 pub(crate) fn constant(num: f32) -> SelectGraph {
@@ -122,6 +127,7 @@ pub struct FusedUnary(Vec<fn(f32) -> f32>);
 
 impl Operator for FusedUnary {
     fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 1);
         let mut t = inp.pop().unwrap().0.cloned();
         for a in t.downcast_mut::<Vec<f32>>().unwrap().iter_mut() {
             for f in &self.0 {
@@ -190,4 +196,287 @@ mod tests {
         cx.execute();
         assert_close(&c.data(), &unoptimized_c);
     }
+
+    #[test]
+    fn test_vector_matmul() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(3).set(random_vec(3));
+        let b = cx.tensor((3, 4)).set(random_vec(12));
+        let mut c = a.matmul(b).retrieve();
+        cx.execute();
+        let unoptimized_c = c.data();
+        c.drop();
+
+        cx.compile(CPUCompiler::default(), &mut c);
+        assert_eq!(cx.op_counts().get("MatMul2D"), Some(&1));
+        assert_eq!(c.dims1().to_usize(), Some(4));
+        cx.execute();
+        assert_close(&c.data(), &unoptimized_c);
+    }
+
+    #[test]
+    fn test_flipped_matmul() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((3, 4)).set(random_vec(12));
+        let b = cx.tensor((4, 5)).set(random_vec(20));
+        let c = cx.tensor((2, 3, 4)).set(random_vec(24));
+        let mut d = a.flip(1).matmul(b.flip(0)).retrieve();
+        let mut e = c.flip(1).matmul(b).retrieve();
+        cx.execute();
+        let (unoptimized_d, unoptimized_e) = (d.data(), e.data());
+        d.drop();
+        e.drop();
+
+        cx.compile(CPUCompiler::default(), (&mut d, &mut e));
+        cx.execute();
+        assert_close(&d.data(), &unoptimized_d);
+        assert_close(&e.data(), &unoptimized_e);
+    }
+
+    #[test]
+    fn test_einsum_three_way() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 3, 4)).set(random_vec(24));
+        let b = cx.tensor((3, 4, 5)).set(random_vec(60));
+        let mut c = a.einsum(b, "ijk,jkl->il").retrieve();
+        cx.execute();
+        let unoptimized_c = c.data();
+        c.drop();
+
+        cx.compile(CPUCompiler::default(), &mut c);
+        cx.execute();
+        assert_close(&c.data(), &unoptimized_c);
+    }
+
+    #[test]
+    fn test_matmul_flop_count() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((5, 3));
+        let b = cx.tensor((3, 4));
+        let mut c = a.matmul(b).retrieve();
+        let unoptimized_flops = cx.flop_count();
+
+        cx.compile(CPUCompiler::default(), &mut c);
+        // Counted as the mul and sum reduce the fused matmul replaced
+        assert_eq!(cx.op_counts().get("MatMul2D"), Some(&1));
+        assert_eq!(cx.flop_count(), unoptimized_flops);
+        assert_eq!(unoptimized_flops, 2 * 5 * 4 * 3);
+    }
+
+    #[test]
+    fn test_masked_softmax() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 3, 4)).set(random_vec(24));
+        // Causal mask along the last axis
+        let mut mask_data = vec![0.; 24];
+        for (i, m) in mask_data.iter_mut().enumerate() {
+            if i % 4 > (i / 4) % 3 {
+                *m = -1e9;
+            }
+        }
+        let mask = cx.tensor((2, 3, 4)).set(mask_data);
+        let mut b = a.masked_softmax(mask, 2).retrieve();
+        let mut c = a.masked_softmax(mask, 1).retrieve();
+
+        cx.execute();
+        let (unoptimized_b, unoptimized_c) = (b.data(), c.data());
+        b.drop();
+        c.drop();
+
+        cx.compile(CPUCompiler::default(), (&mut b, &mut c));
+        let counts = cx.op_counts();
+        assert_eq!(counts.get("MaskedSoftmax"), Some(&2));
+        assert_eq!(counts.get("Add"), None);
+        assert_eq!(counts.get("MaxReduce"), None);
+        cx.execute();
+        assert_close(&b.data(), &unoptimized_b);
+        assert_close(&c.data(), &unoptimized_c);
+
+        // Rows masked to -inf everywhere come out as zeros rather than NaN
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 2)).set(vec![1., 2., 3., 4.]);
+        let mask = cx.tensor((2, 2)).set(vec![
+            0.,
+            f32::NEG_INFINITY,
+            f32::NEG_INFINITY,
+            f32::NEG_INFINITY,
+        ]);
+        let mut b = a.masked_softmax(mask, 1).retrieve();
+        cx.compile(CPUCompiler::default(), &mut b);
+        cx.execute();
+        assert_exact(&b.data(), &[1., 0., 0., 0.]);
+    }
+
+    #[test]
+    fn test_gather_nd() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((4, 3, 5)).set(random_vec(60));
+        let indices = cx.tensor((3, 2)).set(vec![3., 1., 0., 2., 2., 0.]);
+        let mut b = a.gather_nd(indices).retrieve();
+        cx.execute();
+        let unoptimized_b = b.data();
+        b.drop();
+
+        cx.compile(CPUCompiler::default(), &mut b);
+        assert_eq!(cx.op_counts().get("Gather"), Some(&1));
+        cx.execute();
+        assert_exact(&b.data(), &unoptimized_b);
+    }
+
+    #[test]
+    fn test_max_with_index() {
+        let mut cx = Graph::new();
+        let mut data = random_vec(3 * 5 * 4);
+        // Ties go to the lowest index
+        data[7] = 2.;
+        data[15] = 2.;
+        let a = cx.tensor((3, 5, 4)).set(data);
+        let (max, argmax) = a.max_with_index(1);
+        let (min, argmin) = a.min_with_index(2);
+        let mut outs = (
+            max.retrieve(),
+            argmax.retrieve(),
+            min.retrieve(),
+            argmin.retrieve(),
+        );
+        cx.execute();
+        let unoptimized = (outs.0.data(), outs.1.data(), outs.2.data(), outs.3.data());
+        assert_eq!(unoptimized.1[3], 1.);
+        outs.0.drop();
+        outs.1.drop();
+        outs.2.drop();
+        outs.3.drop();
+
+        cx.compile(
+            CPUCompiler::default(),
+            (&mut outs.0, &mut outs.1, &mut outs.2, &mut outs.3),
+        );
+        let counts = cx.op_counts();
+        assert_eq!(counts.get("MaxWithIndex"), Some(&2));
+        assert_eq!(counts.get("MaxReduce"), None);
+        cx.execute();
+        assert_exact(&outs.0.data(), &unoptimized.0);
+        assert_exact(&outs.1.data(), &unoptimized.1);
+        assert_exact(&outs.2.data(), &unoptimized.2);
+        assert_exact(&outs.3.data(), &unoptimized.3);
+    }
+
+    #[test]
+    fn test_equals() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(6).set(vec![1., 2., 3., 4., 5., 6.]);
+        let b = cx.tensor(6).set(vec![1., 0., 3., 5., 5., 2.]);
+        let mut c = a.equals(b).retrieve();
+        let mut d = (a - b).retrieve();
+        cx.execute();
+        let (unoptimized_c, unoptimized_d) = (c.data(), d.data());
+        c.drop();
+        d.drop();
+
+        cx.compile(CPUCompiler::default(), (&mut c, &mut d));
+        let counts = cx.op_counts();
+        assert_eq!(counts.get("Equal"), Some(&1));
+        assert_eq!(counts.get("Sub"), Some(&1));
+        cx.execute();
+        assert_exact(&c.data(), &unoptimized_c);
+        assert_exact(&c.data(), &[1., 0., 1., 0., 1., 0.]);
+        assert_exact(&d.data(), &unoptimized_d);
+    }
+
+    #[test]
+    fn test_barrier() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(5).set(random_vec(5));
+        let mut fused = a.exp2().sin().retrieve();
+        let mut split = a.exp2().barrier().sin().retrieve();
+        cx.execute();
+        let unoptimized = (fused.data(), split.data());
+        fused.drop();
+        split.drop();
+
+        cx.compile(CPUCompiler::default(), (&mut fused, &mut split));
+        let counts = cx.op_counts();
+        assert_eq!(counts.get("FusedUnary"), Some(&1));
+        // The barrier keeps the split chain unfused, then is spliced out
+        assert_eq!(counts.get("Barrier"), None);
+        assert_eq!(counts.get("Exp2"), Some(&1));
+        assert_eq!(counts.get("Sin"), Some(&1));
+        cx.execute();
+        assert_exact(&fused.data(), &unoptimized.0);
+        assert_exact(&split.data(), &unoptimized.1);
+    }
+
+    #[test]
+    fn test_interpolate_gathers() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 3, 4)).set(random_vec(24));
+        let mut nearest = a
+            .interpolate(1.5, other::InterpolateMode::Nearest)
+            .retrieve();
+        let mut bilinear = a
+            .interpolate(
+                2.,
+                other::InterpolateMode::Bilinear {
+                    align_corners: false,
+                },
+            )
+            .retrieve();
+        cx.execute();
+        let unoptimized = (nearest.data(), bilinear.data());
+        nearest.drop();
+        bilinear.drop();
+
+        cx.compile(CPUCompiler::default(), (&mut nearest, &mut bilinear));
+        // One gather per tap on each axis, rather than dense weight matmuls
+        assert_eq!(cx.op_counts().get("Gather"), Some(&6));
+        cx.execute();
+        assert_exact(&nearest.data(), &unoptimized.0);
+        assert_close(&bilinear.data(), &unoptimized.1);
+    }
+
+    #[test]
+    fn test_embedding_bag() {
+        let mut cx = Graph::new();
+        let weights = cx.tensor((6, 3)).set(random_vec(18));
+        let indices = cx.tensor(7).set(vec![1, 3, 3, 0, 5, 2, 4]);
+        // Includes an empty bag
+        let offsets = cx.tensor(4).set(vec![0, 3, 3, 5]);
+        let mut sum = weights
+            .embedding_bag(indices, offsets, other::EmbeddingBagMode::Sum)
+            .retrieve();
+        let mut mean = weights
+            .embedding_bag(indices, offsets, other::EmbeddingBagMode::Mean)
+            .retrieve();
+        cx.execute();
+        let unoptimized = (sum.data(), mean.data());
+        sum.drop();
+        mean.drop();
+
+        cx.compile(CPUCompiler::default(), (&mut sum, &mut mean));
+        let counts = cx.op_counts();
+        assert_eq!(counts.get("EmbeddingBag"), Some(&2));
+        assert_eq!(counts.get("Gather"), None);
+        assert_eq!(counts.get("MatMul2D"), None);
+        cx.execute();
+        assert_close(&sum.data(), &unoptimized.0);
+        assert_close(&mean.data(), &unoptimized.1);
+    }
+
+    #[test]
+    fn test_bincount() {
+        let mut cx = Graph::new();
+        let a = cx.tensor('n').set_dyn(vec![1., 3., 1., 0., 5., 1., 3.], 7);
+        let mut b = a.bincount(4).retrieve();
+        cx.execute();
+        let unoptimized_b = b.data();
+        b.drop();
+
+        cx.compile(CPUCompiler::default(), &mut b);
+        let counts = cx.op_counts();
+        assert_eq!(counts.get("Bincount"), Some(&1));
+        assert_eq!(counts.get("SumReduce"), None);
+        cx.execute();
+        assert_exact(&b.data(), &unoptimized_b);
+        assert_exact(&b.data(), &[1., 3., 0., 2.]);
+    }
 }
@@ -1,3 +1,5 @@
+use std::any::Any;
+
 use luminal::{
     op::{InputTensor, Mul, Operator, SumReduce},
     prelude::*,
@@ -21,12 +23,7 @@ impl Compiler for MatMul2DCompiler {
             [Some(true), Some(false), Some(false)],
         ]);
         let mut sum_reduce = unary::<SumReduce>(mul.clone());
-        sum_reduce.check(|o, _| {
-            o.as_any()
-                .downcast_ref::<SumReduce>()
-                .map(|o| o.0 == 2)
-                .unwrap_or_default()
-        });
+        sum_reduce.attr(|o: &SumReduce| o.0 == 2);
         let mut s = sum_reduce.clone().search(graph);
         while s.next_match() {
             if s.check_no_delete(&[sum_reduce.id]) {
@@ -36,6 +33,10 @@ impl Compiler for MatMul2DCompiler {
             let (mul, sum_reduce) = (s.get(&mul), s.get(&sum_reduce));
             // Insert MatMul2D op
             let mut srcs = graph.get_sources(mul);
+            if srcs.iter().any(|(_, _, sh)| sh.is_flipped()) {
+                // sgemm takes plain strides, which can't walk an axis backwards
+                continue;
+            }
             // Undo expansions and permute
             srcs[0].2.remove_dim(1);
             srcs[1].2.remove_dim(0);
@@ -63,6 +64,7 @@ pub struct MatMul2D;
 
 impl Operator for MatMul2D {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 2);
         let (a_shape, b_shape) = (inp[0].1.dims(), inp[1].1.dims());
         let (a_strides, b_strides) = (inp[0].1.strides(), inp[1].1.strides());
         let a_data = inp[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
@@ -89,6 +91,16 @@ impl Operator for MatMul2D {
 
         vec![Tensor::new(c)]
     }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "flops" {
+            let shapes = input.downcast::<Vec<ShapeTracker>>().unwrap();
+            return Some(Box::new(
+                shapes[0].n_elements() * *shapes[1].dims().last().unwrap() * 2,
+            ));
+        }
+        None
+    }
 }
 
 #[derive(Debug, Default)]
@@ -107,12 +119,7 @@ impl Compiler for BatchMatMul2DCompiler {
             [Some(true), Some(true), Some(false), Some(false)],
         ]);
         let mut sum_reduce = unary::<SumReduce>(mul.clone());
-        sum_reduce.check(|o, _| {
-            o.as_any()
-                .downcast_ref::<SumReduce>()
-                .map(|o| o.0 == 3)
-                .unwrap_or_default()
-        });
+        sum_reduce.attr(|o: &SumReduce| o.0 == 3);
         let mut s = sum_reduce.clone().search(graph);
         while s.next_match() {
             if s.check_no_delete(&[sum_reduce.id]) {
@@ -122,6 +129,10 @@ impl Compiler for BatchMatMul2DCompiler {
             let (mul, sum_reduce) = (s.get(&mul), s.get(&sum_reduce));
             // Insert MatMul2D op
             let mut srcs = graph.get_sources(mul);
+            if srcs.iter().any(|(_, _, sh)| sh.is_flipped()) {
+                // sgemm takes plain strides, which can't walk an axis backwards
+                continue;
+            }
             // Undo expansions and permute
             srcs[0].2.remove_dim(2);
             srcs[1].2.remove_dim(1);
@@ -151,6 +162,7 @@ pub struct BatchedMatMul2D;
 // ABCxCD -> ABD
 impl Operator for BatchedMatMul2D {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 2);
         let (a_shape, b_shape) = (inp[0].1.dims(), inp[1].1.dims());
         let (a_strides, b_strides) = (inp[0].1.strides(), inp[1].1.strides());
         let a_data = inp[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
@@ -186,4 +198,14 @@ impl Operator for BatchedMatMul2D {
 
         vec![Tensor::new(c)]
     }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "flops" {
+            let shapes = input.downcast::<Vec<ShapeTracker>>().unwrap();
+            return Some(Box::new(
+                shapes[0].n_elements() * *shapes[1].dims().last().unwrap() * 2,
+            ));
+        }
+        None
+    }
 }
@@ -4,7 +4,7 @@ use luminal::{
 };
 use rustc_hash::FxHashMap;
 
-use super::binary::Sub;
+use super::binary::{Equal, Sub};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ARange {
@@ -71,3 +71,292 @@ impl Compiler for ARangeCompiler {
         }
     }
 }
+
+/// Softmax over `scores + mask` along a single axis, computed row by row without materializing the masked scores
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaskedSoftmax(pub usize);
+
+impl Operator for MaskedSoftmax {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&tensors, 2);
+        let (a_data, b_data) = (
+            tensors[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap(),
+            tensors[1].0.borrowed().downcast_ref::<Vec<f32>>().unwrap(),
+        );
+        let (a_ind, a_val, b_ind, b_val) = (
+            tensors[0].1.index_expression(),
+            tensors[0].1.valid_expression(),
+            tensors[1].1.index_expression(),
+            tensors[1].1.valid_expression(),
+        );
+        let dims = tensors[0].1.shape_usize();
+        let front_size: usize = dims[..self.0].iter().product();
+        let back_size: usize = dims[self.0 + 1..].iter().product();
+        let dim_size = dims[self.0];
+
+        let mut out = vec![0.; front_size * dim_size * back_size];
+        let mut row = vec![0.; dim_size];
+        for i in 0..front_size {
+            for j in 0..back_size {
+                let idx = |k| i * dim_size * back_size + k * back_size + j;
+                for (k, r) in row.iter_mut().enumerate() {
+                    let a = if a_val.exec_single_var(idx(k)) != 0 {
+                        a_data[a_ind.exec_single_var(idx(k))]
+                    } else {
+                        0.0
+                    };
+                    let b = if b_val.exec_single_var(idx(k)) != 0 {
+                        b_data[b_ind.exec_single_var(idx(k))]
+                    } else {
+                        0.0
+                    };
+                    *r = a + b;
+                }
+                let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                // Fully masked rows stay zero instead of going NaN
+                if max == f32::NEG_INFINITY {
+                    continue;
+                }
+                row.iter_mut().for_each(|r| *r = (*r - max).exp());
+                let sum = row.iter().sum::<f32>();
+                for (k, r) in row.iter().enumerate() {
+                    out[idx(k)] = r / sum;
+                }
+            }
+        }
+        vec![Tensor::new(out)]
+    }
+}
+
+/// Replace softmax(add(scores, mask)) with a single masked softmax op. This is meant to be ran **after** the SubtractionCompiler.
+#[derive(Debug, Default)]
+pub struct MaskedSoftmaxCompiler;
+
+impl Compiler for MaskedSoftmaxCompiler {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        // mul(exp, recip(sum_reduce(exp))) where exp = exp2(mul(sub(x, max_reduce(x)), 1 / ln 2)) and x = add(scores, mask)
+        // The selector only matches trees, so the reuses of x and exp are checked below
+        let add = binary::<Add>(node(), node());
+        let max = op::<MaxReduce>();
+        let sub = binary::<Sub>(add.clone(), max.clone());
+        let scale = binary::<Mul>(sub.clone(), super::constant(1.0 / f32::ln(2.)));
+        let exp = unary::<Exp2>(scale.clone());
+        let sum = unary::<SumReduce>(exp.clone());
+        let recip = unary::<Recip>(sum.clone());
+        let out = unary::<Mul>(recip.clone());
+
+        let mut s = out.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[out.id]) {
+                // An intermediate node can't be deleted
+                continue;
+            }
+            if graph.get_sources(s.get(&max))[0].0 != s.get(&add)
+                || !graph
+                    .get_sources(s.get(&out))
+                    .iter()
+                    .any(|(i, _, _)| *i == s.get(&exp))
+            {
+                continue;
+            }
+            let dim = graph.get_op::<MaxReduce>(s.get(&max)).0;
+            if graph.get_op::<SumReduce>(s.get(&sum)).0 != dim {
+                continue;
+            }
+            let shape = |a, b| {
+                graph
+                    .edges_connecting(a, b)
+                    .next()
+                    .unwrap()
+                    .weight()
+                    .as_data()
+                    .unwrap()
+                    .2
+            };
+            // Elementwise links must be plain reads, and the reductions must be broadcast back along the reduced axis
+            if [
+                (&add, &max),
+                (&add, &sub),
+                (&sub, &scale),
+                (&scale, &exp),
+                (&exp, &sum),
+                (&exp, &out),
+                (&recip, &out),
+            ]
+            .iter()
+            .any(|(a, b)| shape(s.get(*a), s.get(*b)).is_reshaped())
+            {
+                continue;
+            }
+            if [(&max, &sub), (&sum, &recip)].iter().any(|(a, b)| {
+                let mut sh = shape(s.get(*a), s.get(*b));
+                if !sh.fake[sh.indexes[dim]] {
+                    return true;
+                }
+                sh.remove_dim(dim);
+                sh.is_reshaped()
+            }) {
+                continue;
+            }
+
+            let add = s.get(&add);
+            let srcs = graph.get_sources(add);
+            let masked_softmax = graph
+                .add_op(MaskedSoftmax(dim))
+                .input(srcs[0].0, srcs[0].1, srcs[0].2)
+                .input(srcs[1].0, srcs[1].1, srcs[1].2)
+                .finish();
+
+            // Create edges to dests
+            let out = s.get(&out);
+            move_outgoing_edge(out, masked_softmax, graph);
+            remap(out, masked_softmax, &mut ids, graph);
+
+            // Remove the old ops
+            graph.remove_node(out);
+            s.try_delete();
+            // The max reduce isn't linked to x in the selector, so x may have outlived it
+            graph.safe_remove_node(add, 0);
+        }
+    }
+}
+
+/// Max reduce along an axis, fused with `max_reduce(equal(x, max) * w)` along the same axis, in one pass over the input.
+///
+/// Outputs the max values and the largest weight held at a max, which `max_with_index` turns into the index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaxWithIndex(pub usize);
+
+impl Operator for MaxWithIndex {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&tensors, 2);
+        let (x_data, w_data) = (
+            tensors[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap(),
+            tensors[1].0.borrowed().downcast_ref::<Vec<f32>>().unwrap(),
+        );
+        let (x_ind, x_val, w_ind, w_val) = (
+            tensors[0].1.index_expression(),
+            tensors[0].1.valid_expression(),
+            tensors[1].1.index_expression(),
+            tensors[1].1.valid_expression(),
+        );
+        let dims = tensors[0].1.shape_usize();
+        let front_size: usize = dims[..self.0].iter().product();
+        let back_size: usize = dims[self.0 + 1..].iter().product();
+        let dim_size = dims[self.0];
+
+        let mut values = vec![0.; front_size * back_size];
+        let mut weights = vec![0.; front_size * back_size];
+        for i in 0..front_size {
+            for j in 0..back_size {
+                // Positions that don't hold the max contribute a weight of 0
+                let (mut max, mut weight, mut others) =
+                    (f32::NEG_INFINITY, f32::NEG_INFINITY, false);
+                for k in 0..dim_size {
+                    let idx = i * dim_size * back_size + k * back_size + j;
+                    let x = if x_val.exec_single_var(idx) != 0 {
+                        x_data[x_ind.exec_single_var(idx)]
+                    } else {
+                        0.0
+                    };
+                    let w = if w_val.exec_single_var(idx) != 0 {
+                        w_data[w_ind.exec_single_var(idx)]
+                    } else {
+                        0.0
+                    };
+                    if x > max {
+                        others |= k > 0;
+                        max = x;
+                        weight = w;
+                    } else if x == max {
+                        weight = weight.max(w);
+                    } else {
+                        others = true;
+                    }
+                }
+                values[i * back_size + j] = max;
+                weights[i * back_size + j] = if others { weight.max(0.) } else { weight };
+            }
+        }
+        vec![Tensor::new(values), Tensor::new(weights)]
+    }
+}
+
+/// Fuse the max reduce and weighted one-hot max reduce built by `max_with_index` into a single op.
+/// This is meant to be ran **after** the EqualCompiler.
+#[derive(Debug, Default)]
+pub struct MaxWithIndexCompiler;
+
+impl Compiler for MaxWithIndexCompiler {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        // max_reduce(mul(equal(x, max_reduce(x)), w))
+        // The selector only matches trees, so the reuse of x is checked below. Parents are matched greedily from the
+        // last one given, so the wildcards go first
+        let (x, w) = (node(), node());
+        let max = op::<MaxReduce>();
+        let eq = binary::<Equal>(x.clone(), max.clone());
+        let mul = binary::<Mul>(w.clone(), eq.clone());
+        let out = unary::<MaxReduce>(mul.clone());
+
+        let mut s = out.clone().search(graph);
+        while s.next_match() {
+            // Only the first output of a node can be retrieved, so the weighted max must stay internal
+            if s.check_no_delete(&[x.id, w.id, max.id]) {
+                continue;
+            }
+            let (max, out) = (s.get(&max), s.get(&out));
+            let dim = graph.get_op::<MaxReduce>(max).0;
+            if graph.get_op::<MaxReduce>(out).0 != dim {
+                continue;
+            }
+            let shape = |a, b| {
+                graph
+                    .edges_connecting(a, b)
+                    .next()
+                    .unwrap()
+                    .weight()
+                    .as_data()
+                    .unwrap()
+            };
+            // The max must be taken over the same view of x that's compared against it
+            let x_edge = shape(s.get(&x), s.get(&eq));
+            if graph.get_sources(max)[0] != (s.get(&x), x_edge.1, x_edge.2) {
+                continue;
+            }
+            if [(s.get(&eq), s.get(&mul)), (s.get(&mul), out)]
+                .iter()
+                .any(|(a, b)| shape(*a, *b).2.is_reshaped())
+            {
+                continue;
+            }
+            // The max must be broadcast back along the reduced axis
+            let mut sh = shape(max, s.get(&eq)).2;
+            if !sh.fake[sh.indexes[dim]] {
+                continue;
+            }
+            sh.remove_dim(dim);
+            if sh.is_reshaped() {
+                continue;
+            }
+
+            let w_edge = shape(s.get(&w), s.get(&mul));
+            let fused = graph
+                .add_op(MaxWithIndex(dim))
+                .input(s.get(&x), x_edge.1, x_edge.2)
+                .input(s.get(&w), w_edge.1, w_edge.2)
+                .finish();
+
+            // Create edges to dests
+            move_outgoing_edge_to_output(max, fused, 0, graph);
+            remap(max, fused, &mut ids, graph);
+            move_outgoing_edge_to_output(out, fused, 1, graph);
+
+            // Remove the old ops
+            graph.remove_node(out);
+            graph.remove_node(max);
+            s.try_delete();
+        }
+    }
+}
@@ -35,6 +35,7 @@ pub type CudaCompiler<T> = (
     SpecialOpsCompiler<T>,
     other::CopyCompiler<T>,
     elementwise_fusion::ElementwiseFusionCompiler<T>,
+    RemoveBarriers,
 );
 
 /// Compiler to replace cuda primops with specialized variants
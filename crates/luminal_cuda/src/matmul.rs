@@ -125,13 +125,7 @@ where
             [Some(true), Some(false), Some(false)],
         ]);
         let mut sr2d = op::<CudaSumReduce<T>>();
-        sr2d.check(|o, _| {
-            if let Some(o) = o.as_any().downcast_ref::<CudaSumReduce<T>>() {
-                o.dim == 2
-            } else {
-                false
-            }
-        });
+        sr2d.attr(|o: &CudaSumReduce<T>| o.dim == 2);
         let mut s2d = mul2d.clone().connect(sr2d.clone()).search(graph);
         let mut mul3d = op::<CudaMul<T>>();
         mul3d.shapes([['D', 'A', 'C', 'B'], ['D', 'A', 'C', 'B']]);
@@ -140,13 +134,7 @@ where
             [None, Some(true), Some(false), Some(false)],
         ]);
         let mut sr3d = op::<CudaSumReduce<T>>();
-        sr3d.check(|o, _| {
-            if let Some(o) = o.as_any().downcast_ref::<CudaSumReduce<T>>() {
-                o.dim == 3
-            } else {
-                false
-            }
-        });
+        sr3d.attr(|o: &CudaSumReduce<T>| o.dim == 3);
         let mut s3d = mul3d.clone().connect(sr3d.clone()).search(graph);
         let mut mul4d = op::<CudaMul<T>>();
         mul4d.shapes([['E', 'D', 'A', 'C', 'B'], ['E', 'D', 'A', 'C', 'B']]);
@@ -161,13 +149,7 @@ where
             [None, None, Some(true), Some(false), Some(false)],
         ]);
         let mut sr4d = op::<CudaSumReduce<T>>();
-        sr4d.check(|o, _| {
-            if let Some(o) = o.as_any().downcast_ref::<CudaSumReduce<T>>() {
-                o.dim == 4
-            } else {
-                false
-            }
-        });
+        sr4d.attr(|o: &CudaSumReduce<T>| o.dim == 4);
         let mut s4d = mul4d.clone().connect(sr4d.clone()).search(graph);
         let mut mul5d = op::<CudaMul<T>>();
         mul5d.shapes([
@@ -186,13 +168,7 @@ where
             [None, None, None, Some(true), Some(false), Some(false)],
         ]);
         let mut sr5d = op::<CudaSumReduce<T>>();
-        sr5d.check(|o, _| {
-            if let Some(o) = o.as_any().downcast_ref::<CudaSumReduce<T>>() {
-                o.dim == 5
-            } else {
-                false
-            }
-        });
+        sr5d.attr(|o: &CudaSumReduce<T>| o.dim == 5);
         let mut s5d = mul5d.clone().connect(sr5d.clone()).search(graph);
         while s2d.next_match() || s3d.next_match() || s4d.next_match() || s5d.next_match() {
             let (mul, sum_reduce) = if s2d.matched {
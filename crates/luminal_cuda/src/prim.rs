@@ -205,6 +205,82 @@ cuda_unary_op!(if T::is_f32() { "sqrt" } else { "hsqrt" }, CudaSqrt);
 cuda_unary_op!("sin", CudaSin);
 cuda_unary_op!(if T::is_f32() { "__frcp_rn" } else { "hrcp" }, CudaRecip);
 
+#[derive(Clone)]
+pub struct CudaNanToNum<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(CudaNanToNum);
+
+impl<T: CudaFloat> CudaNanToNum<T> {
+    pub fn new(
+        op: &NanToNum,
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (idx_exp, valid_exp) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
+        let type_name = T::type_name();
+        // Infinities default to the largest finite value of the dtype the kernel runs in
+        let max = if T::is_f32() {
+            f32::MAX
+        } else {
+            f16::MAX.to_f32()
+        };
+        let (nan, posinf, neginf) = (op.nan, op.posinf.unwrap_or(max), op.neginf.unwrap_or(-max));
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int numel{rendered}) {{
+    int idx = blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel && {valid_exp} != 0) {{
+        float x = (float)inp[{idx_exp}];
+        if (isnan(x)) {{
+            out[idx] = ({type_name})({nan:?}f);
+        }} else if (isinf(x)) {{
+            out[idx] = ({type_name})(x > 0 ? {posinf:?}f : {neginf:?}f);
+        }} else {{
+            out[idx] = inp[{idx_exp}];
+        }}
+    }}
+}}"
+        );
+        Self {
+            function: compile_and_load_kernel(code, &device),
+            device,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaNanToNum<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        let out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            inp.as_kernel_param(),
+            inp_size.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData(out))]
+    }
+}
+
 #[derive(Clone)]
 pub struct CudaAdd<T> {
     function: CudaFunction,
@@ -800,6 +876,13 @@ impl<T: CudaFloat> Compiler for PrimitiveCompiler<T> {
                     c.0.clone(),
                     &graph.dyn_map,
                 ));
+            } else if let Some(n) = op_ref.as_any().downcast_ref::<NanToNum>() {
+                *op_ref = Box::new(CudaNanToNum::<T>::new(
+                    n,
+                    shapes[0],
+                    dev.clone(),
+                    &graph.dyn_map,
+                ));
             } else if is::<Recip>(op) {
                 *op_ref = Box::new(CudaRecip::<T>::new(shapes[0], dev.clone(), &graph.dyn_map));
             } else if is::<Sqrt>(op) {
@@ -586,3 +586,18 @@ fn test_conv1d_pad_stride() {
         &output.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
     );
 }
+
+#[test]
+fn test_nan_to_num() {
+    let mut cx = Graph::new();
+    let a = cx
+        .tensor(5)
+        .set(vec![1., f32::NAN, f32::INFINITY, f32::NEG_INFINITY, -2.]);
+    let mut b = a.nan_to_num(0., None, None).retrieve();
+    let mut c = a.nan_to_num(-1., Some(100.), Some(-100.)).retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), (&mut b, &mut c));
+    cx.execute();
+
+    assert_exact(&b.data(), &[1., 0., f32::MAX, f32::MIN, -2.]);
+    assert_exact(&c.data(), &[1., -1., 100., -100., -2.]);
+}
@@ -319,13 +319,9 @@ impl<T: CudaFloat> Compiler for StdNormCompiler<T> {
         // mul(recip(sqrt(add(mean_reduce(mul(x, x)), 1e-6))), x)
 
         let mut eps = op::<CudaConstant<T>>();
-        eps.check(|op, _| {
-            if let Some(c) = op.as_any().downcast_ref::<CudaConstant<T>>() {
-                if let ConstantValue::Float(v) = c.value {
-                    v <= 1e-2 && v > 0.0
-                } else {
-                    false
-                }
+        eps.attr(|c: &CudaConstant<T>| {
+            if let ConstantValue::Float(v) = c.value {
+                v <= 1e-2 && v > 0.0
             } else {
                 false
             }
@@ -496,7 +492,7 @@ impl<T: CudaFloat> Compiler for CudaCosCompiler<T> {
     }
 }
 
-/// Special kernel for efficient softmax. Currently only works on the last dim
+/// Special kernel for efficient softmax, accumulated in f32. Currently only works on the last dim
 #[derive(Clone)]
 pub struct CudaSoftmax<T> {
     function: CudaFunction,
@@ -518,11 +514,12 @@ extern \"C\" __global__ void kernel(const {type_name} * x, {type_name} * dst, co
     const int block_size = blockDim.y;
     const int tid = threadIdx.y;
 
-    {type_name} max_val = -__int_as_float(0x7f800000);
+    // Accumulate in float whatever the storage type, so long rows don't lose the sum of exponentials
+    float max_val = -__int_as_float(0x7f800000);
 
     for (int col = tid; col < ncols; col += block_size) {{
         const int i = row*ncols + col;
-        max_val = fmaxf(max_val, x[i]);
+        max_val = fmaxf(max_val, (float)x[i]);
     }}
 
     // find the max value in the block
@@ -531,13 +528,11 @@ extern \"C\" __global__ void kernel(const {type_name} * x, {type_name} * dst, co
         max_val = fmaxf(max_val, __shfl_xor_sync(0xffffffff, max_val, mask, 32));
     }}
 
-    {type_name} tmp = 0.;
+    float tmp = 0.;
 
     for (int col = tid; col < ncols; col += block_size) {{
         const int i = row*ncols + col;
-        const {type_name} val = exp(x[i] - max_val);
-        tmp += static_cast<{type_name}>(val);
-        dst[i] = val;
+        tmp += expf((float)x[i] - max_val);
     }}
 
     // sum up partial sums
@@ -546,11 +541,11 @@ extern \"C\" __global__ void kernel(const {type_name} * x, {type_name} * dst, co
         tmp += __shfl_xor_sync(0xffffffff, tmp, mask, 32);
     }}
 
-    const {type_name} inv_tmp = ({type_name})1. / tmp;
+    const float inv_tmp = 1.f / tmp;
 
     for (int col = tid; col < ncols; col += block_size) {{
         const int i = row*ncols + col;
-        dst[i] *= inv_tmp;
+        dst[i] = static_cast<{type_name}>(expf((float)x[i] - max_val) * inv_tmp);
     }}
 }}
 ",
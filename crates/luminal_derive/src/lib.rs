@@ -0,0 +1,80 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, spanned::Spanned, Data, DeriveInput, Error, Fields, LitStr};
+
+/// Derive `SerializeModule` by serializing every field under its name, in declaration order.
+///
+/// Fields can be tensors or anything else that implements `SerializeModule`. Field attributes:
+/// - `#[serialize(rename = "attn_q/weight")]` stores the field under a different name. An empty name serializes the
+///   field's contents directly into the parent.
+/// - `#[serialize(skip)]` leaves the field out, for config values and other non-weight state.
+#[proc_macro_derive(SerializeModule, attributes(serialize))]
+pub fn derive_serialize_module(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match serialize_module(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn serialize_module(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new(
+            input.span(),
+            "SerializeModule can only be derived for structs",
+        ));
+    };
+    let mut calls = vec![];
+    let fields = match &data.fields {
+        Fields::Named(f) => f.named.iter().collect::<Vec<_>>(),
+        Fields::Unnamed(f) => f.unnamed.iter().collect(),
+        Fields::Unit => vec![],
+    };
+    for (i, field) in fields.into_iter().enumerate() {
+        let mut name = field
+            .ident
+            .as_ref()
+            .map(|ident| ident.to_string())
+            .unwrap_or_else(|| i.to_string());
+        let mut skip = false;
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|a| a.path().is_ident("serialize"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("skip") {
+                    skip = true;
+                    Ok(())
+                } else if meta.path.is_ident("rename") {
+                    name = meta.value()?.parse::<LitStr>()?.value();
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `skip` or `rename = \"...\"`"))
+                }
+            })?;
+        }
+        if skip {
+            continue;
+        }
+        let member = match &field.ident {
+            Some(ident) => quote!(#ident),
+            None => {
+                let index = syn::Index::from(i);
+                quote!(#index)
+            }
+        };
+        calls.push(quote!(s.module(#name, &self.#member);));
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::luminal::module::SerializeModule for #ident #ty_generics #where_clause {
+            fn serialize(&self, s: &mut ::luminal::module::Serializer) {
+                #(#calls)*
+            }
+        }
+    })
+}
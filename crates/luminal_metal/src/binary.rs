@@ -7,18 +7,22 @@ use metal_rs::{
 use rustc_hash::FxHashMap;
 
 use crate::{
-    compile_function, constant, get_buffer_from_tensor, get_idx_valid_exps, input_dyn_dims,
-    render_dyn_dim_inputs, DispatchNElements, MetalBuffer, MetalFloat, MetalKernel,
-    MetalKernelWrapper, SetInt,
+    compile_function, compile_lib, constant, get_buffer_from_tensor, get_idx_valid_exps,
+    input_dyn_dims, render_dyn_dim_inputs, select_function_from_lib, DispatchNElements,
+    MetalBuffer, MetalFloat, MetalKernel, MetalKernelWrapper, SetInt,
 };
 
 use super::prim::*;
 use luminal::{
     op::{InputTensor, Operator},
-    prelude::{petgraph::visit::EdgeRef, *},
+    prelude::{
+        other::{embedding_bag_ranges, EmbeddingBagMode, NONZERO_OP_NAME},
+        petgraph::visit::EdgeRef,
+        *,
+    },
 };
 
-use super::other::MetalARange;
+use super::{matmul::Matmul, other::MetalARange};
 
 #[derive(Clone)]
 pub struct MetalSub<T> {
@@ -341,11 +345,16 @@ impl<T: MetalFloat> Compiler for MetalEqualCompiler<T> {
             lt1.clone(),
             binary::<MetalLessThan<T>>(rhs.clone(), lhs.clone()),
         );
-        let eq = binary::<MetalSub<T>>(one, ne);
+        let eq = binary::<MetalSub<T>>(one.clone(), ne);
 
         let mut s = eq.clone().search(graph);
         while s.next_match() {
-            if s.check_no_delete(&[eq.id]) {
+            // The compared tensors are kept as inputs, so they can be retrieved
+            if s.check_no_delete(&[eq.id, lhs.id, rhs.id]) {
+                continue;
+            }
+            // The selector is order-agnostic, so make sure this is 1 - ne rather than ne - 1
+            if graph.get_sources(s.get(&eq))[0].0 != s.get(&one) {
                 continue;
             }
             let (lhs, rhs) = (s.get(&lhs), s.get(&rhs));
@@ -521,6 +530,592 @@ impl<T: MetalFloat> Compiler for MetalGatherCompiler<T> {
     }
 }
 
+/// Pool gathered embedding rows over bags of indexes in one kernel, without materializing the gathered rows or the bag segments
+#[derive(Clone)]
+pub struct MetalEmbeddingBag<T> {
+    pipeline: ComputePipelineState,
+    device: Device,
+    queue: CommandQueue,
+    pub embed_dim: usize,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(MetalEmbeddingBag);
+
+impl<T: MetalFloat> MetalEmbeddingBag<T> {
+    pub fn new(
+        device: Device,
+        queue: CommandQueue,
+        embed_dim: usize,
+        mode: EmbeddingBagMode,
+    ) -> Self {
+        let type_name = T::type_name();
+        let mean = if mode == EmbeddingBagMode::Mean {
+            "if (end > start) sum /= (float)(end - start);"
+        } else {
+            ""
+        };
+        Self {pipeline: compile_function("metal_embedding_bag", &format!(
+            "
+#include <metal_stdlib>
+using namespace metal;
+kernel void metal_embedding_bag(device float *inp [[buffer(0)]], device {type_name} *weights [[buffer(1)]], device {type_name} *out [[buffer(2)]], device int *bounds [[buffer(3)]], device int& n_bags [[buffer(4)]], device int& embedding_dim [[buffer(5)]], uint2 i_ [[thread_position_in_grid]]) {{
+    if (i_.x < n_bags && i_.y < embedding_dim) {{
+        int start = bounds[i_.x];
+        int end = bounds[i_.x + 1];
+        float sum = 0.0;
+        for (int i = start; i < end; i++) {{
+            sum += (float)weights[(int)inp[i] * embedding_dim + i_.y];
+        }}
+        {mean}
+        out[i_.x * embedding_dim + i_.y] = ({type_name})sum;
+    }}
+}}"), &device), device, embed_dim, queue, _phantom: Default::default()}
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalEmbeddingBag<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        autoreleasepool(|| {
+            // Setup buffers
+            let indexes = tensors[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+            let index_buffer = self.device.new_buffer_with_data(
+                indexes.as_ptr() as *const _,
+                (indexes.len() * std::mem::size_of::<f32>()) as u64,
+                MTLResourceOptions::StorageModeShared,
+            );
+            let b_inp = tensors[1]
+                .0
+                .borrowed()
+                .downcast_ref::<MetalBuffer>()
+                .unwrap();
+            // Bag boundaries, so bag b covers bounds[b]..bounds[b + 1]
+            let ranges = embedding_bag_ranges(tensors[2].0.borrowed(), tensors[2].1, indexes.len());
+            let n_bags = ranges.len();
+            let bounds = ranges
+                .iter()
+                .map(|(start, _)| *start as i32)
+                .chain(ranges.last().map(|(_, end)| *end as i32))
+                .collect::<Vec<_>>();
+            let bounds_buffer = self.device.new_buffer_with_data(
+                bounds.as_ptr() as *const _,
+                (bounds.len() * std::mem::size_of::<i32>()) as u64,
+                MTLResourceOptions::StorageModeShared,
+            );
+
+            // Setup command queue / command buffer / encoder
+            let command_buffer = self.queue.new_command_buffer();
+
+            let out = self.device.new_buffer(
+                (n_bags * self.embed_dim * std::mem::size_of::<T>()) as u64,
+                MTLResourceOptions::StorageModeShared,
+            );
+
+            let encoder = command_buffer
+                .compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+            encoder.set_compute_pipeline_state(&self.pipeline);
+
+            // Set inputs
+            encoder.set_buffer(0, Some(&index_buffer), 0);
+            encoder.set_buffer(1, Some(b_inp), 0);
+            encoder.set_buffer(2, Some(&out), 0);
+            encoder.set_buffer(3, Some(&bounds_buffer), 0);
+            encoder.set_u32(4, n_bags as u32);
+            encoder.set_u32(5, self.embed_dim as u32);
+
+            // Execute
+            encoder.dispatch_threads(
+                MTLSize {
+                    width: n_bags as u64,
+                    height: self.embed_dim as u64,
+                    depth: 1,
+                },
+                MTLSize {
+                    width: 16,
+                    height: 16,
+                    depth: 1,
+                },
+            );
+            encoder.end_encoding();
+
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+
+            vec![Tensor::new(MetalBuffer(out))]
+        })
+    }
+}
+
+/// Fuse an embedding bag's segment matrix times its gathered rows into one op. Runs after the gather and matmul compilers.
+#[derive(Debug, Default)]
+pub struct MetalEmbeddingBagCompiler<T: MetalFloat>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for MetalEmbeddingBagCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = Device::system_default().unwrap();
+        let queue = dev.new_command_queue();
+        let mut segments = op::<Function>();
+        segments.attr(|f: &Function| EmbeddingBagMode::from_segments_op_name(&f.0).is_some());
+        let segments_copy = unary::<MetalCopyToDevice<T>>(segments.clone());
+        let gather = op::<MetalGather<T>>();
+        let matmul = binary::<Matmul<T>>(gather.clone(), segments_copy.clone());
+        let mut s = matmul.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[matmul.id]) {
+                continue;
+            }
+            let (segments, segments_copy, gather, matmul) = (
+                s.get(&segments),
+                s.get(&segments_copy),
+                s.get(&gather),
+                s.get(&matmul),
+            );
+            let srcs = graph.get_sources(matmul);
+            // The segments must be the lhs, and the gathered rows the untouched rhs
+            if srcs[0].0 != segments_copy || srcs[1].2.is_reshaped() {
+                continue;
+            }
+            let mode = EmbeddingBagMode::from_segments_op_name(
+                &graph
+                    .node_weight(segments)
+                    .unwrap()
+                    .as_any()
+                    .downcast_ref::<Function>()
+                    .unwrap()
+                    .0,
+            )
+            .unwrap();
+            let embed_dim = graph
+                .node_weight(gather)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<MetalGather<T>>()
+                .unwrap()
+                .embed_dim;
+            let gather_srcs = graph.get_sources(gather);
+            let offsets = graph.get_sources(segments)[0];
+            let bag = graph
+                .add_op(MetalEmbeddingBag::<T>::new(
+                    dev.clone(),
+                    queue.clone(),
+                    embed_dim,
+                    mode,
+                ))
+                .input(gather_srcs[0].0, gather_srcs[0].1, gather_srcs[0].2)
+                .input(gather_srcs[1].0, gather_srcs[1].1, gather_srcs[1].2)
+                .input(offsets.0, offsets.1, offsets.2)
+                .finish();
+            move_outgoing_edge(matmul, bag, graph);
+            remap(matmul, bag, &mut ids, graph);
+            graph.remove_node(matmul);
+            s.try_delete();
+        }
+    }
+}
+
+/// Count the occurrences of each bin index, skipping indexes that don't land exactly on a bin.
+/// Counts are accumulated with atomic adds into an intermediate buffer, then cast to the output type.
+#[derive(Clone)]
+pub struct MetalBincount<T> {
+    zero_pipeline: ComputePipelineState,
+    count_pipeline: ComputePipelineState,
+    cast_pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    pub num_bins: usize,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(MetalBincount);
+
+impl<T: MetalFloat> MetalBincount<T> {
+    pub fn new(
+        num_bins: usize,
+        shape: ShapeTracker,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (idx_exp, valid_exp) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape], 4);
+        let type_name = T::type_name();
+        let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+kernel void bincount_zero(device atomic_uint *counts [[buffer(0)]], device int& n_bins [[buffer(1)]], uint i_ [[thread_position_in_grid]]) {{
+    if (i_ < n_bins) {{
+        atomic_store_explicit(&counts[i_], 0u, memory_order_relaxed);
+    }}
+}}
+kernel void bincount_count(device {type_name} *inp [[buffer(0)]], device atomic_uint *counts [[buffer(1)]], device int& n_elements [[buffer(2)]], device int& n_bins [[buffer(3)]], uint idx [[thread_position_in_grid]]{rendered}) {{
+    if (idx < n_elements) {{
+        float bin = ({valid_exp}) == 0 ? 0.0 : (float)inp[{idx_exp}];
+        if (bin >= 0 && bin == floor(bin) && bin < n_bins) {{
+            atomic_fetch_add_explicit(&counts[(int)bin], 1u, memory_order_relaxed);
+        }}
+    }}
+}}
+kernel void bincount_cast(device atomic_uint *counts [[buffer(0)]], device {type_name} *out [[buffer(1)]], device int& n_bins [[buffer(2)]], uint i_ [[thread_position_in_grid]]) {{
+    if (i_ < n_bins) {{
+        out[i_] = ({type_name})atomic_load_explicit(&counts[i_], memory_order_relaxed);
+    }}
+}}");
+        let lib = compile_lib(&device, &code);
+        Self {
+            zero_pipeline: select_function_from_lib(&lib, "bincount_zero", &device),
+            count_pipeline: select_function_from_lib(&lib, "bincount_count", &device),
+            cast_pipeline: select_function_from_lib(&lib, "bincount_cast", &device),
+            queue,
+            device,
+            num_bins,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T> MetalKernel for MetalBincount<T> {
+    fn intermediate_buffer_sizes(&self, _: &[ShapeTracker]) -> Vec<Expression> {
+        vec![(self.num_bins * size_of::<u32>()).into()]
+    }
+    fn output_buffer_sizes(&self, _: &[ShapeTracker]) -> Vec<Expression> {
+        vec![(self.num_bins * size_of::<T>()).into()]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        intermediate_buffers: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let n_elements = inputs[0].1.n_elements().to_usize().unwrap();
+
+        // Clear the counts
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.zero_pipeline);
+        encoder.set_buffer(0, Some(intermediate_buffers[0]), 0);
+        encoder.set_u32(1, self.num_bins as u32);
+        encoder.dispatch_1d(self.num_bins);
+        encoder.end_encoding();
+
+        // Accumulate
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.count_pipeline);
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(intermediate_buffers[0]), 0);
+        encoder.set_u32(2, n_elements as u32);
+        encoder.set_u32(3, self.num_bins as u32);
+        input_dyn_dims(
+            &self.dyn_symbols,
+            unsafe { self.dyn_map.as_ref().unwrap() },
+            encoder,
+            4,
+        );
+        encoder.dispatch_1d(n_elements);
+        encoder.end_encoding();
+
+        // Cast to the output type
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.cast_pipeline);
+        encoder.set_buffer(0, Some(intermediate_buffers[0]), 0);
+        encoder.set_buffer(1, Some(output_buffers[0]), 0);
+        encoder.set_u32(2, self.num_bins as u32);
+        encoder.dispatch_1d(self.num_bins);
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalBincount<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&tensors, 1);
+        autoreleasepool(|| {
+            let command_buffer = self.queue.new_command_buffer();
+            let counts = self.device.new_buffer(
+                (self.num_bins * size_of::<u32>()) as u64,
+                MTLResourceOptions::StorageModeShared,
+            );
+            let out = self.device.new_buffer(
+                (self.num_bins * size_of::<T>()) as u64,
+                MTLResourceOptions::StorageModeShared,
+            );
+
+            self.metal_forward(
+                &[(get_buffer_from_tensor(&tensors[0].0), tensors[0].1)],
+                command_buffer,
+                &[&counts],
+                &[&out],
+            );
+
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+
+            vec![Tensor::new(MetalBuffer(out))]
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        None
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MetalBincountCompiler<T: MetalFloat>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for MetalBincountCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = Device::system_default().unwrap();
+        let queue = dev.new_command_queue();
+        let indexes = node();
+        let equal = binary::<MetalEqual<T>>(op::<MetalARange<T>>(), indexes.clone());
+        let mut sum_reduce = unary::<MetalSumReduce<T>>(equal.clone());
+        sum_reduce.attr(|o: &MetalSumReduce<T>| o.dim == 0);
+        let mut s = sum_reduce.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[sum_reduce.id, indexes.id]) {
+                continue;
+            }
+            // The indexes are broadcast across the bins in the one-hot matrix
+            let mut index_shape = graph
+                .edges_connecting(s.get(&indexes), s.get(&equal))
+                .next()
+                .unwrap()
+                .weight()
+                .as_data()
+                .unwrap()
+                .2;
+            if index_shape.len() != 2 || !index_shape.fake[index_shape.indexes[1]] {
+                continue;
+            }
+            let Some(num_bins) = index_shape.dims()[1].to_usize() else {
+                continue;
+            };
+            index_shape.remove_dim(1);
+            let bincount = graph
+                .add_op(MetalBincount::<T>::new(
+                    num_bins,
+                    index_shape,
+                    dev.clone(),
+                    queue.clone(),
+                    &graph.dyn_map,
+                ))
+                .input(s.get(&indexes), 0, index_shape)
+                .finish();
+            move_outgoing_edge(s.get(&sum_reduce), bincount, graph);
+            remap(s.get(&sum_reduce), bincount, &mut ids, graph);
+
+            graph.remove_node(s.get(&sum_reduce));
+            s.try_delete();
+        }
+    }
+}
+
+/// Compact the indexes of a 1D tensor's nonzero elements. A single threadgroup does an exclusive prefix sum over the
+/// nonzero flags to find where each index lands, then the indexes are scattered into an output the length of the
+/// input, with the slots past the count filled with -1.
+#[derive(Clone)]
+pub struct MetalNonzero<T> {
+    scan_pipeline: ComputePipelineState,
+    scatter_pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(MetalNonzero);
+
+/// Threadgroup scratch size of the scan kernel
+const NONZERO_SCAN_THREADS: usize = 1024;
+
+impl<T: MetalFloat> MetalNonzero<T> {
+    pub fn new(
+        shape: ShapeTracker,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (idx_exp, valid_exp) = get_idx_valid_exps(shape);
+        let (dyn_symbols, scan_dims) = render_dyn_dim_inputs(&[shape], 4);
+        let (_, scatter_dims) = render_dyn_dim_inputs(&[shape], 5);
+        let type_name = T::type_name();
+        let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+kernel void nonzero_scan(device {type_name} *inp [[buffer(0)]], device int *positions [[buffer(1)]], device int *count [[buffer(2)]], device int& n_elements [[buffer(3)]], uint t [[thread_position_in_threadgroup]], uint n_threads [[threads_per_threadgroup]]{scan_dims}) {{
+    threadgroup int scratch[{NONZERO_SCAN_THREADS}];
+    int carry = 0;
+    for (int base = 0; base < n_elements; base += n_threads) {{
+        int idx = base + t;
+        int flag = idx < n_elements && ({valid_exp}) != 0 && (float)inp[{idx_exp}] != 0.0 ? 1 : 0;
+        scratch[t] = flag;
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+        // Inclusive Hillis-Steele scan of this chunk
+        for (uint offset = 1; offset < n_threads; offset <<= 1) {{
+            int prev = t >= offset ? scratch[t - offset] : 0;
+            threadgroup_barrier(mem_flags::mem_threadgroup);
+            scratch[t] += prev;
+            threadgroup_barrier(mem_flags::mem_threadgroup);
+        }}
+        if (idx < n_elements) {{
+            positions[idx] = carry + scratch[t] - flag;
+        }}
+        carry += scratch[n_threads - 1];
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }}
+    if (t == 0) {{
+        count[0] = carry;
+    }}
+}}
+kernel void nonzero_scatter(device {type_name} *inp [[buffer(0)]], device int *positions [[buffer(1)]], device {type_name} *out [[buffer(2)]], device int& n_elements [[buffer(3)]], device int *count [[buffer(4)]], uint idx [[thread_position_in_grid]]{scatter_dims}) {{
+    if (idx < n_elements && ({valid_exp}) != 0 && (float)inp[{idx_exp}] != 0.0) {{
+        out[positions[idx]] = ({type_name})idx;
+    }}
+    // Every slot past the count is padding, and no nonzero lands there
+    if (idx < n_elements && (int)idx >= count[0]) {{
+        out[idx] = -1.0;
+    }}
+}}");
+        let lib = compile_lib(&device, &code);
+        Self {
+            scan_pipeline: select_function_from_lib(&lib, "nonzero_scan", &device),
+            scatter_pipeline: select_function_from_lib(&lib, "nonzero_scatter", &device),
+            queue,
+            device,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalNonzero<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&tensors, 1);
+        autoreleasepool(|| {
+            let inp = get_buffer_from_tensor(&tensors[0].0);
+            let n_elements = tensors[0].1.n_elements().to_usize().unwrap();
+            let positions = self.device.new_buffer(
+                (n_elements.max(1) * size_of::<i32>()) as u64,
+                MTLResourceOptions::StorageModeShared,
+            );
+            let count_buffer = self.device.new_buffer(
+                size_of::<i32>() as u64,
+                MTLResourceOptions::StorageModeShared,
+            );
+
+            // Scan the nonzero flags
+            let command_buffer = self.queue.new_command_buffer();
+            let encoder = command_buffer
+                .compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+            encoder.set_compute_pipeline_state(&self.scan_pipeline);
+            encoder.set_buffer(0, Some(inp), 0);
+            encoder.set_buffer(1, Some(&positions), 0);
+            encoder.set_buffer(2, Some(&count_buffer), 0);
+            encoder.set_u32(3, n_elements as u32);
+            input_dyn_dims(
+                &self.dyn_symbols,
+                unsafe { self.dyn_map.as_ref().unwrap() },
+                encoder,
+                4,
+            );
+            let threads = n_elements
+                .clamp(1, NONZERO_SCAN_THREADS)
+                .min(self.scan_pipeline.max_total_threads_per_threadgroup() as usize)
+                .next_power_of_two();
+            encoder.dispatch_thread_groups(
+                MTLSize {
+                    width: 1,
+                    height: 1,
+                    depth: 1,
+                },
+                MTLSize {
+                    width: threads as u64,
+                    height: 1,
+                    depth: 1,
+                },
+            );
+            encoder.end_encoding();
+
+            // Scatter the indexes to their positions, padding the rest
+            let out = self.device.new_buffer(
+                (n_elements.max(1) * size_of::<T>()) as u64,
+                MTLResourceOptions::StorageModeShared,
+            );
+            let encoder = command_buffer
+                .compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+            encoder.set_compute_pipeline_state(&self.scatter_pipeline);
+            encoder.set_buffer(0, Some(inp), 0);
+            encoder.set_buffer(1, Some(&positions), 0);
+            encoder.set_buffer(2, Some(&out), 0);
+            encoder.set_u32(3, n_elements as u32);
+            encoder.set_buffer(4, Some(&count_buffer), 0);
+            input_dyn_dims(
+                &self.dyn_symbols,
+                unsafe { self.dyn_map.as_ref().unwrap() },
+                encoder,
+                5,
+            );
+            encoder.dispatch_1d(n_elements);
+            encoder.end_encoding();
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+
+            vec![Tensor::new(MetalBuffer(out))]
+        })
+    }
+}
+
+/// Replace the host nonzero function (and the copies around it) with the Metal compaction
+#[derive(Debug, Default)]
+pub struct MetalNonzeroCompiler<T: MetalFloat>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for MetalNonzeroCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = Device::system_default().unwrap();
+        let queue = dev.new_command_queue();
+        let copy_from = op::<MetalCopyFromDevice<T>>();
+        let mut function = unary::<Function>(copy_from.clone());
+        function.attr(|f: &Function| f.0 == NONZERO_OP_NAME);
+        let copy_to = unary::<MetalCopyToDevice<T>>(function.clone());
+        let mut s = copy_to.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[copy_to.id]) {
+                continue;
+            }
+            let (copy_from, function, copy_to) =
+                (s.get(&copy_from), s.get(&function), s.get(&copy_to));
+            let src = graph.get_sources(copy_from)[0];
+            let inp_shape = graph.get_sources(function)[0].2;
+            let nonzero = graph
+                .add_op(MetalNonzero::<T>::new(
+                    inp_shape,
+                    dev.clone(),
+                    queue.clone(),
+                    &graph.dyn_map,
+                ))
+                .input(src.0, src.1, inp_shape)
+                .finish();
+            move_outgoing_edge(copy_to, nonzero, graph);
+            remap(copy_to, nonzero, &mut ids, graph);
+            graph.remove_node(copy_to);
+            s.try_delete();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use luminal::{prelude::*, tests::assert_close};
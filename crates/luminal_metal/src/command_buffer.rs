@@ -1,7 +1,7 @@
-use std::{any::Any, cell::UnsafeCell, fmt::Debug, ops::Deref, sync::Arc};
+use std::{any::Any, cell::UnsafeCell, fmt::Debug, marker::PhantomData, ops::Deref, sync::Arc};
 
 use itertools::Itertools;
-use metal_rs::{Buffer, CommandBuffer, CommandQueue, Device};
+use metal_rs::{Buffer, CommandBuffer, CommandQueue, Device, MTLCommandBufferStatus};
 use petgraph::{
     stable_graph::NodeIndex,
     visit::EdgeRef,
@@ -14,7 +14,7 @@ use luminal::{
     prelude::*,
 };
 
-use crate::{MetalBuffer, MetalKernel, MetalKernelWrapper};
+use crate::{prim::MetalCopyFromDevice, MetalBuffer, MetalFloat, MetalKernel, MetalKernelWrapper};
 
 use super::get_buffer_from_tensor;
 
@@ -150,25 +150,53 @@ impl Compiler for CommandBufferCompiler {
                     set
                 });
         }
+        if node_sets.is_empty() {
+            return;
+        }
+        // Sets whose results are only retrieved don't wait on their own, they all share one wait before the retrievals
+        #[allow(clippy::arc_with_non_send_sync)]
+        let pending = Arc::new(UnsafeCell::new(vec![]));
+        let wait = graph.add_op(WaitForMetalKernels(pending.clone())).finish();
         // Add sets to graph
         let dev = Device::system_default().unwrap();
         let mut queue = dev.new_command_queue();
-        let mut num_buffers_on_queue = 0;
+        let (mut queue_index, mut num_buffers_on_queue) = (0, 0);
         for set in node_sets.values() {
             if num_buffers_on_queue >= 63 {
                 num_buffers_on_queue = 0;
                 queue = dev.new_command_queue();
+                queue_index += 1;
             } else {
                 num_buffers_on_queue += 1;
             }
+            let only_retrieved = set.iter().all(|node| {
+                graph
+                    .graph
+                    .edges_directed(*node, Direction::Outgoing)
+                    .filter(|e| !e.weight().is_schedule())
+                    .map(|e| e.target())
+                    .filter(|n| !set.contains(n))
+                    .all(|n| {
+                        graph.to_retrieve.contains_key(&n)
+                            && !is_metal.contains(&n)
+                            && graph
+                                .graph
+                                .edges_directed(n, Direction::Outgoing)
+                                .all(|e| e.weight().is_schedule())
+                    })
+            });
             #[allow(clippy::arc_with_non_send_sync)]
             let buffer = Arc::new(UnsafeCell::new(queue.new_command_buffer().to_owned()));
             let exec = graph
                 .add_op(ExecuteMetalKernels {
                     queue: queue.clone(),
+                    queue_index,
                     buffer: buffer.clone(),
+                    wait: !only_retrieved,
+                    pending: pending.clone(),
                 })
                 .finish();
+            graph.add_schedule_dependency(exec, wait);
             for node in set {
                 // Create schedule dependency
                 graph.add_schedule_dependency(*node, exec);
@@ -195,16 +223,26 @@ impl Compiler for CommandBufferCompiler {
                     .filter(|n| !set.contains(n))
                     .collect::<Vec<_>>()
                 {
-                    graph.add_schedule_dependency(exec, outside_node);
+                    graph.add_schedule_dependency(
+                        if only_retrieved { wait } else { exec },
+                        outside_node,
+                    );
                 }
             }
         }
     }
 }
 
+/// Command buffers committed without waiting, with the index of the queue they were committed to
+type PendingBuffers = Arc<UnsafeCell<Vec<(usize, CommandBuffer)>>>;
+
 struct ExecuteMetalKernels {
     queue: CommandQueue,
+    queue_index: usize,
     buffer: Arc<UnsafeCell<CommandBuffer>>,
+    /// Wait for the kernels to finish, rather than leaving them pending until `WaitForMetalKernels`
+    wait: bool,
+    pending: PendingBuffers,
 }
 impl Debug for ExecuteMetalKernels {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -214,14 +252,150 @@ impl Debug for ExecuteMetalKernels {
 
 impl Operator for ExecuteMetalKernels {
     fn process(&mut self, _: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let pending = unsafe { &mut *self.pending.get() };
+        // Buffers only run in order within a queue, so pending work on other queues must finish before storage
+        // buffers it used can be reused here
+        pending.retain(|(queue, buffer)| {
+            if *queue != self.queue_index {
+                buffer.wait_until_completed();
+            }
+            *queue == self.queue_index && buffer.status() != MTLCommandBufferStatus::Completed
+        });
         let buffer = unsafe { &mut *self.buffer.get() };
         buffer.commit();
-        buffer.wait_until_completed();
+        if self.wait {
+            buffer.wait_until_completed();
+        } else {
+            pending.push((self.queue_index, buffer.clone()));
+        }
         *buffer = self.queue.new_command_buffer().to_owned();
         vec![]
     }
 }
 
+/// Waits once for every command buffer left pending, before the retrieved outputs are copied off the device
+struct WaitForMetalKernels(PendingBuffers);
+impl Debug for WaitForMetalKernels {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WaitForMetalKernels")
+    }
+}
+
+impl Operator for WaitForMetalKernels {
+    fn process(&mut self, _: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        for (_, buffer) in unsafe { &mut *self.0.get() }.drain(..) {
+            buffer.wait_until_completed();
+        }
+        vec![]
+    }
+}
+
+/// Lets `execute` return while the command buffers whose results are only retrieved are still running. Retrieved
+/// outputs hold a handle instead of host data, which waits for the device the first time the data is read, so the CPU
+/// can prepare the next inputs in the meantime. Poll with `GraphTensor::is_ready`.
+///
+/// Run after `MetalCompiler`. Retrieved data should be read before the next execution, since the device buffers it's
+/// copied from can be reused.
+#[derive(Debug, Default)]
+pub struct AsyncRetrieveCompiler<T>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for AsyncRetrieveCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+        let Some(wait) = graph
+            .node_indices()
+            .find(|n| graph.check_node_type::<WaitForMetalKernels>(*n))
+        else {
+            return;
+        };
+        let retrievals = graph
+            .graph
+            .neighbors_directed(wait, Direction::Outgoing)
+            .collect::<Vec<_>>();
+        // Anything other than a copy off the device reads the buffers directly, so it needs the wait
+        if !retrievals
+            .iter()
+            .all(|n| graph.check_node_type::<MetalCopyFromDevice<T>>(*n))
+        {
+            return;
+        }
+        let pending = graph.get_op::<WaitForMetalKernels>(wait).0.clone();
+        for exec in graph
+            .graph
+            .neighbors_directed(wait, Direction::Incoming)
+            .collect::<Vec<_>>()
+        {
+            for retrieval in &retrievals {
+                graph.add_schedule_dependency(exec, *retrieval);
+            }
+        }
+        for retrieval in retrievals {
+            *graph.graph.node_weight_mut(retrieval).unwrap() =
+                Box::new(MetalCopyFromDeviceAsync::<T> {
+                    pending: pending.clone(),
+                    _phantom: Default::default(),
+                });
+        }
+        graph.graph.remove_node(wait);
+    }
+}
+
+/// Hands back a retrieved output without waiting for the command buffers computing it
+struct MetalCopyFromDeviceAsync<T> {
+    pending: PendingBuffers,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(MetalCopyFromDeviceAsync);
+
+impl<T: MetalFloat> Operator for MetalCopyFromDeviceAsync<T> {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        if inp[0].0.borrowed().is::<Vec<f32>>() {
+            // Already off device
+            return vec![inp.pop().unwrap().0.cloned()];
+        }
+        vec![Tensor::new(MetalReadback::<T> {
+            buffer: get_buffer_from_tensor(&inp[0].0).0.clone(),
+            pending: self.pending.clone(),
+            _phantom: Default::default(),
+        })]
+    }
+}
+
+/// A retrieved output that may still be computing on the device. Its data is copied off the device once the pending
+/// command buffers finish.
+#[derive(Clone)]
+pub struct MetalReadback<T> {
+    buffer: Buffer,
+    pending: PendingBuffers,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(MetalReadback);
+
+impl<T: MetalFloat> Data for MetalReadback<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn is_ready(&self) -> bool {
+        unsafe { &*self.pending.get() }
+            .iter()
+            .all(|(_, buffer)| buffer.status() == MTLCommandBufferStatus::Completed)
+    }
+    fn wait_for_data(&self) -> Option<Box<dyn Data>> {
+        for (_, buffer) in unsafe { &mut *self.pending.get() }.drain(..) {
+            buffer.wait_until_completed();
+        }
+        let ptr = self.buffer.contents() as *const T;
+        Some(Box::new(
+            (0..self.buffer.length() as usize / std::mem::size_of::<T>())
+                .map(|i| unsafe { *ptr.add(i) }.to_f32())
+                .collect::<Vec<_>>(),
+        ))
+    }
+}
+
 #[derive(Clone)]
 struct CommandBufferWrapper {
     wrapper: Box<MetalKernelWrapper>,
@@ -320,3 +494,80 @@ fn test_common_buffer() {
 
     assert_close(&d.data(), &d_unopt);
 }
+
+#[cfg(test)]
+#[test]
+fn test_single_wait_for_retrievals() {
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
+
+    use crate::MetalCompiler;
+    let mut cx = Graph::new();
+    let a = cx.tensor(5).set(random_vec(5)).keep();
+    let b = cx.tensor(5).set(random_vec(5)).keep();
+    // Several retrieved outputs, like logits and per-layer KV caches
+    let c = (a + b).retrieve();
+    let d = (c * a).retrieve();
+    let e = (d.exp() + b).retrieve();
+    let mut outputs = [c, d, e];
+    cx.execute();
+    let unopt = outputs.map(|o| o.data());
+    outputs.iter().for_each(|o| o.drop());
+
+    cx.compile(MetalCompiler::<f16>::default(), &mut outputs[..]);
+    // No command buffer waits on its own, they all share the wait before the retrievals
+    assert!(cx
+        .node_indices()
+        .filter_map(|n| cx.try_get_op::<ExecuteMetalKernels>(n))
+        .all(|e| !e.wait));
+    cx.execute();
+
+    for (o, unopt) in outputs.iter().zip(unopt) {
+        assert_close(&o.data(), &unopt);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_async_retrieve() {
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
+
+    use crate::MetalCompiler;
+    let mut cx = Graph::new();
+    let a = cx.tensor(5).set(random_vec(5)).keep();
+    let b = cx.tensor(5).set(random_vec(5)).keep();
+    let c = (a + b).retrieve();
+    let d = (c.exp() * a).retrieve();
+    let mut outputs = [c, d];
+    cx.execute();
+    let unopt = outputs.map(|o| o.data());
+    outputs.iter().for_each(|o| o.drop());
+
+    cx.compile(
+        (
+            MetalCompiler::<f16>::default(),
+            AsyncRetrieveCompiler::<f16>::default(),
+        ),
+        &mut outputs[..],
+    );
+    assert!(!cx
+        .node_indices()
+        .any(|n| cx.check_node_type::<WaitForMetalKernels>(n)));
+    for _ in 0..2 {
+        cx.execute();
+        // Outputs come back as handles, which wait for the device when read
+        for (o, unopt) in outputs.iter().zip(&unopt) {
+            assert!(cx
+                .get_tensor_ref(o.id, 0)
+                .unwrap()
+                .is::<MetalReadback<f16>>());
+            assert_close(&o.data(), unopt);
+            assert!(o.is_ready());
+        }
+    }
+}
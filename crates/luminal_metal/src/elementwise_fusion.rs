@@ -692,6 +692,33 @@ mod tests {
         assert_close(&out.data(), &unopt_out);
     }
 
+    #[test]
+    fn test_fusion_activations() {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        let a = cx.tensor(32).set(random_vec_rng(32, &mut rng));
+        let b = cx.tensor(32).set(random_vec_rng(32, &mut rng));
+        // The gelu decomposition and the gated swish from the MLP
+        let mut gelu = a.gelu().retrieve();
+        let mut gated = (a.swish() * b + a).retrieve();
+
+        cx.execute();
+        let (unopt_gelu, unopt_gated) = (gelu.data(), gated.data());
+        gelu.drop();
+        gated.drop();
+
+        cx.compile(
+            <(GenericCompiler, crate::MetalCompilerPreBuffer<f16>)>::default(),
+            (&mut gelu, &mut gated),
+        );
+        // Each chain becomes a single kernel
+        assert_eq!(cx.op_counts().get("FusedElementwiseOp"), Some(&2));
+        cx.execute();
+
+        assert_close_precision(&gelu.data(), &unopt_gelu, 1e-2);
+        assert_close_precision(&gated.data(), &unopt_gated, 1e-2);
+    }
+
     #[test]
     fn test_fusion_subexpression_complex() {
         let mut cx = Graph::new();
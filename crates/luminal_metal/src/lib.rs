@@ -37,6 +37,7 @@ pub type MetalCompilerPreBuffer<T> = (
     Timed<SpecialOpsCompiler<T>>,
     Timed<other::CopyCompiler<T>>,
     Timed<elementwise_fusion::ElementwiseFusionCompiler<T>>,
+    Timed<RemoveBarriers>,
 );
 
 /// Compilers to share command and storage buffers
@@ -49,13 +50,23 @@ pub type BufferCompilers = (
 pub type SpecialOpsCompiler<T> = (
     binary::MetalSubtractionCompiler<T>,
     binary::MetalEqualCompiler<T>,
+    unary::MaxWithIndexCompiler<T>,
     other::ARangeCompiler<T>,
     binary::MetalGatherCompiler<T>,
+    binary::MetalBincountCompiler<T>,
+    binary::MetalNonzeroCompiler<T>,
     unary::MetalExpCompiler<T>,
     unary::MetalCosCompiler<T>,
+    unary::MaskedSoftmaxCompiler<T>,
+    unary::SoftmaxCompiler<T>,
     unary::MeanReduceCompiler<T>,
+    unary::VarMeanCompiler<T>,
     unary::StdNormCompiler<T>,
+    unary::LayerNormCompiler<T>,
+    unary::RMSNormCompiler<T>,
+    unary::AddRMSNormCompiler<T>,
     matmul::MetalMatMulCompiler<T>,
+    binary::MetalEmbeddingBagCompiler<T>,
 );
 
 #[derive(Debug, Clone)]
@@ -76,6 +87,15 @@ impl Data for MetalBuffer {
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
         self
     }
+
+    fn copy_data(&self) -> Option<Box<dyn Data>> {
+        // Cloning only bumps the buffer's reference count
+        Some(Box::new(MetalBuffer(self.device().new_buffer_with_data(
+            self.contents(),
+            self.length(),
+            MTLResourceOptions::StorageModeShared,
+        ))))
+    }
 }
 
 pub trait MetalFloat: Copy + Debug + PartialEq + 'static + Default {
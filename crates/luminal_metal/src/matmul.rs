@@ -1,7 +1,7 @@
 use std::{any::Any, fmt::Debug, marker::PhantomData, mem::size_of, sync::Arc};
 
 use luminal::{
-    op::{InputTensor, Operator},
+    op::{check_arity, InputTensor, Operator},
     prelude::*,
 };
 
@@ -16,9 +16,13 @@ use crate::{
 /// Multiplies a BxMxK matrix with a KxN matrix, resulting in a BxMxN matrix
 #[derive(Clone)]
 pub struct Matmul<T> {
+    /// Fully general gemm, bounds checking M, N and K
     pub matmul_pipeline: ComputePipelineState,
+    /// Unchecked gemm, only used when M and N are multiples of 32 and K a multiple of 16
+    pub aligned_matmul_pipeline: ComputePipelineState,
     pub matvec_pipeline: ComputePipelineState,
     pub matmul_kernel: String,
+    pub aligned_matmul_kernel: String,
     pub matvec_kernel: String,
     pub queue: CommandQueue,
     pub device: Device,
@@ -30,10 +34,11 @@ impl<T> Debug for Matmul<T> {
     }
 }
 
-const BM: u64 = 8;
-const BN: u64 = 32;
-impl<T> MetalKernel for Matmul<T> {
-    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+impl<T> Matmul<T> {
+    /// The (batch, m, n) output dimensions, with all leading batch dimensions of A folded together.
+    ///
+    /// Both the declared buffer size and the buffer allocated when running standalone come from this.
+    pub fn output_shape(input_shapes: &[ShapeTracker]) -> (Expression, Expression, Expression) {
         let m = input_shapes[0].dims()[input_shapes[0].len() - 2];
         let n = input_shapes[1].dims()[input_shapes[1].len() - 1];
         let batch_size = input_shapes[0]
@@ -42,6 +47,18 @@ impl<T> MetalKernel for Matmul<T> {
             .take(input_shapes[0].len() - 2)
             .product::<Expression>()
             .max(1);
+        (batch_size, m, n)
+    }
+}
+
+const BM: u64 = 8;
+const BN: u64 = 32;
+const GEMM_BM: usize = 32;
+const GEMM_BN: usize = 32;
+const GEMM_BK: usize = 16;
+impl<T> MetalKernel for Matmul<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+        let (batch_size, m, n) = Self::output_shape(input_shapes);
         vec![batch_size * m * n * size_of::<T>()]
     }
     fn metal_forward(
@@ -108,8 +125,14 @@ impl<T> MetalKernel for Matmul<T> {
                 MTLSize::new(BN, BM, 1),
             );
         } else {
-            // Matmul
-            encoder.set_compute_pipeline_state(&self.matmul_pipeline);
+            // Matmul, falling back to the bounds checked kernel when any dim is off the tile grid
+            encoder.set_compute_pipeline_state(
+                if m % GEMM_BM == 0 && n % GEMM_BN == 0 && k % GEMM_BK == 0 {
+                    &self.aligned_matmul_pipeline
+                } else {
+                    &self.matmul_pipeline
+                },
+            );
 
             // Set inputs
             encoder.set_buffer(0, Some(inputs[0].0), 0);
@@ -154,21 +177,15 @@ impl<T> MetalKernel for Matmul<T> {
 
 impl<T: MetalFloat> Operator for Matmul<T> {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 2);
         autoreleasepool(|| {
             // Setup command queue / command buffer / encoder
             let command_buffer = self.queue.new_command_buffer();
 
-            let (a_shape, b_shape) = (inp[0].1.dims(), inp[1].1.dims());
-            let n = b_shape.last().unwrap().to_usize().unwrap();
-            let batch_size = a_shape
-                .iter()
-                .map(|i| i.to_usize().unwrap())
-                .take(a_shape.len() - 2)
-                .product::<usize>();
-            let m = a_shape[a_shape.len() - 2].to_usize().unwrap();
-
             let out = self.device.new_buffer(
-                (batch_size * m * n * std::mem::size_of::<T>()) as u64,
+                self.output_buffer_sizes(&[inp[0].1, inp[1].1])[0]
+                    .to_usize()
+                    .unwrap() as u64,
                 MTLResourceOptions::StorageModeShared,
             );
 
@@ -189,12 +206,19 @@ impl<T: MetalFloat> Operator for Matmul<T> {
         })
     }
 
-    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
         if key == "metal" {
             return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
                 self.clone(),
             )))));
         }
+        if key == "flops" {
+            // A multiply and an add for every (batch, m, k) element of A against each of the n columns of B
+            let shapes = input.downcast::<Vec<ShapeTracker>>().unwrap();
+            return Some(Box::new(
+                shapes[0].n_elements() * *shapes[1].dims().last().unwrap() * 2,
+            ));
+        }
         None
     }
 }
@@ -218,13 +242,7 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
             [Some(true), Some(false), Some(false)],
         ]);
         let mut sr2d = op::<MetalSumReduce<T>>();
-        sr2d.check(|o, _| {
-            if let Some(o) = o.as_any().downcast_ref::<MetalSumReduce<T>>() {
-                o.dim == 2
-            } else {
-                false
-            }
-        });
+        sr2d.attr(|o: &MetalSumReduce<T>| o.dim == 2);
         let mut s2d = mul2d.clone().connect(sr2d.clone()).search(graph);
         let mut mul3d = op::<MetalMul<T>>();
         mul3d.shapes([['D', 'A', 'C', 'B'], ['D', 'A', 'C', 'B']]);
@@ -233,13 +251,7 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
             [None, Some(true), Some(false), Some(false)],
         ]);
         let mut sr3d = op::<MetalSumReduce<T>>();
-        sr3d.check(|o, _| {
-            if let Some(o) = o.as_any().downcast_ref::<MetalSumReduce<T>>() {
-                o.dim == 3
-            } else {
-                false
-            }
-        });
+        sr3d.attr(|o: &MetalSumReduce<T>| o.dim == 3);
         let mut s3d = mul3d.clone().connect(sr3d.clone()).search(graph);
         let mut mul4d = op::<MetalMul<T>>();
         mul4d.shapes([['E', 'D', 'A', 'C', 'B'], ['E', 'D', 'A', 'C', 'B']]);
@@ -254,13 +266,7 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
             [None, None, Some(true), Some(false), Some(false)],
         ]);
         let mut sr4d = op::<MetalSumReduce<T>>();
-        sr4d.check(|o, _| {
-            if let Some(o) = o.as_any().downcast_ref::<MetalSumReduce<T>>() {
-                o.dim == 4
-            } else {
-                false
-            }
-        });
+        sr4d.attr(|o: &MetalSumReduce<T>| o.dim == 4);
         let mut s4d = mul4d.clone().connect(sr4d.clone()).search(graph);
         let mut mul5d = op::<MetalMul<T>>();
         mul5d.shapes([
@@ -279,13 +285,7 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
             [None, None, None, Some(true), Some(false), Some(false)],
         ]);
         let mut sr5d = op::<MetalSumReduce<T>>();
-        sr5d.check(|o, _| {
-            if let Some(o) = o.as_any().downcast_ref::<MetalSumReduce<T>>() {
-                o.dim == 5
-            } else {
-                false
-            }
-        });
+        sr5d.attr(|o: &MetalSumReduce<T>| o.dim == 5);
         let mut s5d = mul5d.clone().connect(sr5d.clone()).search(graph);
         let matmul_library = compile_lib(&dev, include_str!("kernels/gemm.metal"));
         let matvec_library = compile_lib(&dev, include_str!("kernels/gemv.metal"));
@@ -313,7 +313,7 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
             let mut dims = (0..src2_shape.len()).collect::<Vec<_>>();
             dims.swap(src2_shape.len() - 2, src2_shape.len() - 1);
             src2_shape.permute(&dims);
-            // If src1 is padded or sliced, or batch dim isn't first, we need to make it contiguous
+            // If src1 is padded, sliced or flipped, or batch dim isn't first, we need to make it contiguous
             if src1_shape
                 .indexes
                 .iter()
@@ -322,6 +322,7 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
                 .any(|(a, b)| a != *b)
                 || src1_shape.is_sliced()
                 || src1_shape.is_padded()
+                || src1_shape.is_flipped()
             {
                 src1 = graph
                     .add_op(MetalContiguous::<T>::new(
@@ -334,7 +335,7 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
                     .finish();
                 src1_shape = src1_shape.contiguous();
             }
-            // If src2 is padded or sliced, or batch dim isn't first, we need to make it contiguous
+            // If src2 is padded, sliced or flipped, or batch dim isn't first, we need to make it contiguous
             if src2_shape
                 .indexes
                 .iter()
@@ -344,6 +345,7 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
                 .any(|(a, b)| a != *b)
                 || src2_shape.is_sliced()
                 || src2_shape.is_padded()
+                || src2_shape.is_flipped()
             {
                 src2 = graph
                     .add_op(MetalContiguous::<T>::new(
@@ -357,56 +359,52 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
                 src2_shape = src2_shape.contiguous();
             }
             let type_name = if T::is_f32() { "float32" } else { "float16" };
-            let matmul_kernel = format!(
-                "gemm_{}{}_{type_name}_{type_name}_bm32_bn32_bk16_wm2_wn2_MN_naligned_K_taligned",
-                if src1_shape.is_contiguous() { "n" } else { "t" },
-                if src2_shape.indexes[src2_shape.len() - 1]
-                    > src2_shape.indexes[src2_shape.len() - 2]
-                {
-                    "n"
-                } else {
-                    "t"
-                }
-            );
+            // B is stored as (N, K) when its last two axes are swapped, as `matmul_transposed_b` builds it
+            let transpose_b =
+                src2_shape.indexes[src2_shape.len() - 1] < src2_shape.indexes[src2_shape.len() - 2];
+            let gemm_kernel = |alignment: &str| {
+                format!(
+                    "gemm_{}{}_{type_name}_{type_name}_bm{GEMM_BM}_bn{GEMM_BN}_bk{GEMM_BK}_wm2_wn2_{alignment}",
+                    if src1_shape.is_contiguous() { "n" } else { "t" },
+                    if transpose_b { "t" } else { "n" }
+                )
+            };
+            let matmul_kernel = gemm_kernel("MN_naligned_K_naligned");
+            let aligned_matmul_kernel = gemm_kernel("MN_taligned_K_taligned");
             let matvec_kernel = format!(
                 "gemv_{}{type_name}_bm{BM}_bn{BN}_tm4_tn4",
-                if src2_shape.indexes[src2_shape.len() - 1]
-                    > src2_shape.indexes[src2_shape.len() - 2]
-                {
-                    "t_"
-                } else {
-                    ""
-                }
+                if transpose_b { "" } else { "t_" }
             );
-            let matmul_op = graph
-                .add_op(Matmul::<T> {
+            // Swap the sum reduce for the matmul, then drop the now unused mul
+            graph.replace_op(
+                sum_reduce,
+                Matmul::<T> {
                     matmul_pipeline: select_function_from_lib(
                         &matmul_library,
                         &matmul_kernel,
                         &dev,
                     ),
+                    aligned_matmul_pipeline: select_function_from_lib(
+                        &matmul_library,
+                        &aligned_matmul_kernel,
+                        &dev,
+                    ),
                     matvec_pipeline: select_function_from_lib(
                         &matvec_library,
                         &matvec_kernel,
                         &dev,
                     ),
                     matmul_kernel,
+                    aligned_matmul_kernel,
                     matvec_kernel,
                     queue: queue.clone(),
                     device: dev.clone(),
                     _phantom: Default::default(),
-                })
-                .input(src1, 0, src1_shape)
-                .input(src2, 0, src2_shape)
-                .finish();
-
-            // Create edges to dests
-            move_outgoing_edge(sum_reduce, matmul_op, graph);
-            remap(sum_reduce, matmul_op, &mut ids, graph);
-
-            // Remove the old ops
+                },
+                &[(src1, 0, src1_shape), (src2, 0, src2_shape)],
+                &mut ids,
+            );
             graph.remove_node(mul);
-            graph.remove_node(sum_reduce);
         }
     }
 }
@@ -422,7 +420,67 @@ mod tests {
         tests::{assert_close_precision, random_vec},
     };
 
-    use crate::MetalCompiler;
+    use crate::{MetalCompiler, MetalKernelWrapper};
+
+    use super::Matmul;
+
+    #[test]
+    fn test_matmul_output_shape() {
+        for (a, b, (batch, m, n)) in [
+            (vec![3, 4], vec![4, 5], (1, 3, 5)),
+            (vec![2, 3, 4], vec![4, 5], (2, 3, 5)),
+            (vec![2, 3, 4], vec![2, 4, 5], (2, 3, 5)),
+        ] {
+            let mut cx = Graph::new();
+            let x = cx.tensor(a.clone()).set(random_vec(a.iter().product()));
+            let y = cx.tensor(b.clone()).set(random_vec(b.iter().product()));
+            let mut out = x.matmul(y).retrieve();
+            cx.compile(
+                <(GenericCompiler, crate::MetalCompilerPreBuffer<f16>)>::default(),
+                &mut out,
+            );
+
+            let node = cx
+                .graph
+                .node_indices()
+                .find(|n| {
+                    cx.graph
+                        .node_weight(*n)
+                        .unwrap()
+                        .as_any()
+                        .is::<Matmul<f16>>()
+                })
+                .unwrap();
+            let shapes = cx
+                .get_sources(node)
+                .into_iter()
+                .map(|(_, _, sh)| sh)
+                .collect::<Vec<_>>();
+            let (e_batch, e_m, e_n) = Matmul::<f16>::output_shape(&shapes);
+            assert_eq!(
+                (e_batch.to_usize(), e_m.to_usize(), e_n.to_usize()),
+                (Some(batch), Some(m), Some(n))
+            );
+            let declared = cx
+                .graph
+                .node_weight_mut(node)
+                .unwrap()
+                .custom("metal", Box::new(()))
+                .unwrap()
+                .downcast::<MetalKernelWrapper>()
+                .unwrap()
+                .output_buffer_sizes(&shapes);
+            assert_eq!(
+                declared[0].to_usize(),
+                Some(batch * m * n * std::mem::size_of::<f16>())
+            );
+
+            // The buffer allocated when running holds exactly the output
+            cx.execute();
+            assert_eq!(out.data().len(), batch * m * n);
+        }
+    }
+
     #[test]
     fn test_matrix_vector() {
         const M: usize = 53;
@@ -448,6 +506,35 @@ mod tests {
         assert_close_precision(&c.data(), &d_c.as_vec(), 1e-2);
     }
 
+    #[test]
+    fn test_1d_matrix_vector() {
+        const M: usize = 64;
+        const N: usize = 256;
+        let mut cx = Graph::new();
+        let (a_vec, b_mat) = (random_vec(M), random_vec(M * N));
+        let mut a = cx.named_tensor("Vec", M).set(a_vec.clone());
+        let mut b = cx.named_tensor("Mat", (M, N)).set(b_mat.clone());
+        let mut c = a.matmul(b).retrieve();
+
+        // Skip the buffer compilers so the matmul keeps its name
+        cx.compile(
+            <(GenericCompiler, crate::MetalCompilerPreBuffer<f16>)>::default(),
+            (&mut a, &mut b, &mut c),
+        );
+        let counts = cx.op_counts();
+        assert_eq!(counts.get("Matmul"), Some(&1));
+        assert_eq!(counts.get("MetalSumReduce"), None);
+        cx.execute();
+
+        let d_dev = dfdx::tensor::Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_vec, (dfdx::shapes::Const::<M>,));
+        let d_b =
+            d_dev.tensor_from_vec(b_mat, (dfdx::shapes::Const::<M>, dfdx::shapes::Const::<N>));
+        let d_c = d_a.matmul(d_b);
+
+        assert_close_precision(&c.data(), &d_c.as_vec(), 1e-2);
+    }
+
     #[test]
     fn test_batch_matrix_vector() {
         const M: usize = 256;
@@ -479,4 +566,32 @@ mod tests {
 
         assert_close_precision(&c.data(), &d_c.to_dtype::<f32>().as_vec(), 1e-2);
     }
+
+    #[test]
+    fn test_unaligned_matmul() {
+        // Off-tile dims take the bounds checked kernel, the last case takes the aligned one
+        for (m, k, n) in [
+            (7, 53, 37),
+            (33, 53, 64),
+            (64, 17, 32),
+            (5, 3, 2),
+            (64, 32, 64),
+        ] {
+            let mut cx = Graph::new();
+            let (a_data, b_data) = (random_vec(m * k), random_vec(k * n));
+            let a = cx.tensor((m, k)).set(a_data.clone());
+            let b = cx.tensor((k, n)).set(b_data.clone());
+            let mut c = a.matmul(b).retrieve();
+
+            cx.compile(<(GenericCompiler, MetalCompiler<f32>)>::default(), &mut c);
+            cx.execute();
+
+            let d_dev = dfdx::tensor::Cpu::default();
+            let d_a = d_dev.tensor_from_vec(a_data, (m, k));
+            let d_b = d_dev.tensor_from_vec(b_data, (k, n));
+            let d_c = d_a.matmul(d_b);
+
+            assert_close_precision(&c.data(), &d_c.as_vec(), 1e-3);
+        }
+    }
 }
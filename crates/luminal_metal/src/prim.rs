@@ -28,15 +28,21 @@ impl<T: MetalFloat> Operator for MetalCopyToDevice<T> {
             // Already on device
             return vec![inp.pop().unwrap().0.cloned()];
         }
-        let mut data = inp[0]
-            .0
-            .borrowed()
-            .downcast_ref::<Vec<f32>>()
-            .unwrap()
-            .iter()
-            .copied()
-            .map(MetalFloat::from_f32)
-            .collect::<Vec<T>>();
+        let tensor = inp[0].0.borrowed();
+        let mut data = if let Some(ints) = tensor.downcast_ref::<Vec<i32>>() {
+            // Kernels only read floats, so host i32 tensors are converted on the way up
+            ints.iter()
+                .map(|i| T::from_f32(*i as f32))
+                .collect::<Vec<T>>()
+        } else {
+            tensor
+                .downcast_ref::<Vec<f32>>()
+                .unwrap()
+                .iter()
+                .copied()
+                .map(MetalFloat::from_f32)
+                .collect::<Vec<T>>()
+        };
         if data.is_empty() {
             data.push(T::from_f32(0.0));
         }
@@ -47,6 +53,15 @@ impl<T: MetalFloat> Operator for MetalCopyToDevice<T> {
         );
         vec![Tensor::new(MetalBuffer(buffer))]
     }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "device_buffer_size" {
+            // The size of the buffer an input of this shape gets copied into
+            let shape = input.downcast::<ShapeTracker>().ok()?;
+            return Some(Box::new(shape.n_physical_elements() * size_of::<T>()));
+        }
+        None
+    }
 }
 
 /// Copy a tensor from the GPU
@@ -242,6 +257,133 @@ metal_unary_op!("sin", MetalSin);
 metal_unary_op!("sqrt", MetalSqrt);
 metal_unary_op!("1.0 / ", MetalRecip);
 
+#[derive(Clone)]
+pub struct MetalNanToNum<T> {
+    pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(MetalNanToNum);
+
+impl<T: MetalFloat> MetalNanToNum<T> {
+    pub fn new(
+        op: &NanToNum,
+        shape: ShapeTracker,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (idx_exp, valid_exp) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape], 3);
+        let type_name = T::type_name();
+        // Infinities default to the largest finite value of the dtype the kernel runs in
+        let max = if T::is_f32() {
+            f32::MAX
+        } else {
+            f16::MAX.to_f32()
+        };
+        let (nan, posinf, neginf) = (op.nan, op.posinf.unwrap_or(max), op.neginf.unwrap_or(-max));
+        // Fast math assumes no NaNs or infinities, so check the exponent and mantissa bits directly
+        let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+kernel void mkernel(device {type_name} *inp [[buffer(0)]], device {type_name} *out [[buffer(1)]], device int& n_elements [[buffer(2)]], uint idx [[thread_position_in_grid]]{rendered}) {{
+    if (idx < n_elements && {valid_exp} != 0) {{
+        {type_name} x = inp[{idx_exp}];
+        uint bits = as_type<uint>((float)x);
+        if ((bits & 0x7f800000) != 0x7f800000) {{
+            out[idx] = x;
+        }} else if ((bits & 0x007fffff) != 0) {{
+            out[idx] = ({type_name})({nan:?});
+        }} else if ((bits >> 31) == 0) {{
+            out[idx] = ({type_name})({posinf:?});
+        }} else {{
+            out[idx] = ({type_name})({neginf:?});
+        }}
+    }}
+}}");
+        Self {
+            pipeline: compile_function("mkernel", &code, &device),
+            queue,
+            device,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T> MetalKernel for MetalNanToNum<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+        vec![input_shapes[0].contiguous().n_elements() * size_of::<T>()]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let inp_size = inputs[0].1.n_elements().to_usize().unwrap();
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(output_buffers[0]), 0);
+        encoder.set_u32(2, inp_size as u32);
+        input_dyn_dims(
+            &self.dyn_symbols,
+            unsafe { self.dyn_map.as_ref().unwrap() },
+            encoder,
+            3,
+        );
+
+        // Execute
+        encoder.dispatch_1d(inp_size);
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalNanToNum<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        autoreleasepool(|| {
+            let command_buffer = self.queue.new_command_buffer();
+            let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+            let out = self.device.new_buffer(
+                (inp_size * size_of::<T>()) as u64,
+                MTLResourceOptions::StorageModeShared,
+            );
+
+            self.metal_forward(
+                &[(get_buffer_from_tensor(&tensors[0].0), tensors[0].1)],
+                command_buffer,
+                &[],
+                &[&out],
+            );
+
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+
+            vec![Tensor::new(MetalBuffer(out))]
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        None
+    }
+}
+
 #[derive(Clone)]
 pub struct MetalAdd<T> {
     pipeline: ComputePipelineState,
@@ -1184,6 +1326,14 @@ impl<T: MetalFloat + 'static> Compiler for PrimitiveCompiler<T> {
                     queue.clone(),
                     &graph.dyn_map,
                 ));
+            } else if let Some(n) = op_ref.as_any().downcast_ref::<NanToNum>() {
+                *op_ref = Box::new(MetalNanToNum::<T>::new(
+                    n,
+                    src_shapes[0],
+                    dev.clone(),
+                    queue.clone(),
+                    &graph.dyn_map,
+                ));
             } else if is::<Recip>(op) {
                 *op_ref = Box::new(MetalRecip::<T>::new(
                     src_shapes[0],
@@ -416,6 +416,257 @@ impl<T: MetalFloat> Operator for QuantizedMatmul<T> {
     }
 }
 
+/// Multiplies a BxMxK matrix with a quantized KxN matrix, quantizing the activations to int8 as well (W8A8).
+///
+/// Each row (token) of the input is dynamically quantized with its own absmax scale, then multiplied against the
+/// Q8_0 weight blocks with int32 accumulation. The result is dequantized with activation scale x weight scale.
+/// Threadgroups work on tiles of 8 rows x 4 columns, so each weight block is read once per tile rather than once per
+/// row during prefill. The activations must be contiguous.
+#[derive(Clone)]
+pub struct W8A8Matmul<T> {
+    quantize_pipeline: ComputePipelineState,
+    matmul_pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(W8A8Matmul);
+
+impl<T: MetalFloat> W8A8Matmul<T> {
+    fn new(device: Device, queue: CommandQueue) -> Self {
+        let type_name = T::type_name();
+        Self {
+            quantize_pipeline: compile_function("quantize_rows", &format!("
+#include <metal_stdlib>
+using namespace metal;
+
+kernel void quantize_rows(
+    device const {type_name}* x [[buffer(0)]], // Float activations
+    device char* xq [[buffer(1)]], // Quantized activations
+    device float* x_scale [[buffer(2)]], // Per-row scales
+    constant uint & k [[buffer(3)]], // Row size
+    uint row [[threadgroup_position_in_grid]],
+    uint lane [[thread_index_in_simdgroup]]
+) {{
+    x += row * k;
+    xq += row * k;
+
+    float amax = 0.0f;
+    for (uint i = lane; i < k; i += 32) {{
+        amax = max(amax, fabs((float)x[i]));
+    }}
+    amax = simd_max(amax);
+
+    const float d = amax / 127.0f;
+    const float id = d > 0.0f ? 1.0f / d : 0.0f;
+    for (uint i = lane; i < k; i += 32) {{
+        xq[i] = (char)round((float)x[i] * id);
+    }}
+    if (lane == 0) {{
+        x_scale[row] = d;
+    }}
+}}"), &device),
+            matmul_pipeline: compile_function("w8a8_matmul", &format!("
+#include <metal_stdlib>
+using namespace metal;
+typedef struct {{
+    half    d;         // delta
+    int8_t  qs[32]; // quants
+}} block_q8_0;
+
+kernel void w8a8_matmul(
+    device const char* xq [[buffer(0)]], // Quantized activations (rows x k)
+    device const float* x_scale [[buffer(1)]], // Per-row activation scales
+    device const block_q8_0* w [[buffer(2)]], // Quantized weights (n x k)
+    device {type_name}* dst [[buffer(3)]], // Dest matrix (rows x n)
+    constant uint & k [[buffer(4)]],
+    constant uint & n [[buffer(5)]],
+    constant uint & rows [[buffer(6)]],
+    uint2 tgpig [[threadgroup_position_in_grid]],
+    uint lane [[thread_index_in_simdgroup]]
+) {{
+    const uint num_cols = 4;
+    const uint num_rows = 8;
+    const uint first_row = tgpig.y * num_rows;
+    const uint first_col = tgpig.x * num_cols;
+    const uint num_blocks = k / 32;
+
+    // Each lane walks every 32nd block. A weight block is loaded once and reused for every row of the tile, and each
+    // block is accumulated in int32 before applying the weight scale
+    float sumf[num_rows][num_cols] = {{{{0.f}}}};
+    for (uint ib = lane; ib < num_blocks; ib += 32) {{
+        for (uint c = 0; c < num_cols && first_col + c < n; ++c) {{
+            device const block_q8_0* block = w + (first_col + c) * num_blocks + ib;
+            char wq[32];
+            for (int i = 0; i < 32; ++i) {{
+                wq[i] = block->qs[i];
+            }}
+            const float wd = (float)block->d;
+            for (uint r = 0; r < num_rows && first_row + r < rows; ++r) {{
+                device const char* xb = xq + (first_row + r) * k + ib * 32;
+                int acc = 0;
+                for (int i = 0; i < 32; ++i) {{
+                    acc += (int)xb[i] * (int)wq[i];
+                }}
+                sumf[r][c] += (float)acc * wd;
+            }}
+        }}
+    }}
+
+    for (uint r = 0; r < num_rows && first_row + r < rows; ++r) {{
+        const float scale = x_scale[first_row + r];
+        for (uint c = 0; c < num_cols; ++c) {{
+            const float tot = simd_sum(sumf[r][c]);
+            if (lane == 0 && first_col + c < n) {{
+                dst[(first_row + r) * n + first_col + c] = ({type_name})(tot * scale);
+            }}
+        }}
+    }}
+}}"), &device),
+            queue,
+            device,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T> MetalKernel for W8A8Matmul<T> {
+    fn intermediate_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+        let rows = input_shapes[0]
+            .dims()
+            .into_iter()
+            .take(input_shapes[0].len() - 1)
+            .product::<Expression>()
+            .max(1);
+        let k = input_shapes[1].dims()[input_shapes[1].len() - 2];
+        vec![rows * k, rows * size_of::<f32>()]
+    }
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+        let rows = input_shapes[0]
+            .dims()
+            .into_iter()
+            .take(input_shapes[0].len() - 1)
+            .product::<Expression>()
+            .max(1);
+        let n = input_shapes[1].dims()[input_shapes[1].len() - 1];
+        vec![rows * n * size_of::<T>()]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        intermediate_buffers: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        assert!(
+            !inputs[1].1.is_contiguous(),
+            "Weight matrix must be column-major"
+        );
+        assert!(
+            !inputs[0].1.is_reshaped(),
+            "W8A8 matmul needs contiguous activations"
+        );
+        let a_shape = inputs[0]
+            .1
+            .dims()
+            .into_iter()
+            .map(|i| i.to_usize().unwrap())
+            .collect::<Vec<_>>();
+        let b_shape = inputs[1]
+            .1
+            .dims()
+            .into_iter()
+            .map(|i| i.to_usize().unwrap())
+            .collect::<Vec<_>>();
+        let rows = a_shape
+            .iter()
+            .take(a_shape.len() - 1)
+            .product::<usize>()
+            .max(1);
+        let k = b_shape[b_shape.len() - 2];
+        let n = b_shape[b_shape.len() - 1];
+        assert!(
+            k % 32 == 0,
+            "W8A8 matmul needs the inner dimension to be a multiple of 32"
+        );
+
+        // Quantize activations per row
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.quantize_pipeline);
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(intermediate_buffers[0]), 0);
+        encoder.set_buffer(2, Some(intermediate_buffers[1]), 0);
+        encoder.set_u32(3, k as u32);
+        encoder.dispatch_thread_groups(MTLSize::new(rows as u64, 1, 1), MTLSize::new(32, 1, 1));
+        encoder.end_encoding();
+
+        // Int8 x int8 matmul
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.matmul_pipeline);
+        encoder.set_buffer(0, Some(intermediate_buffers[0]), 0);
+        encoder.set_buffer(1, Some(intermediate_buffers[1]), 0);
+        encoder.set_buffer(2, Some(inputs[1].0), 0);
+        encoder.set_buffer(3, Some(output_buffers[0]), 0);
+        encoder.set_u32(4, k as u32);
+        encoder.set_u32(5, n as u32);
+        encoder.set_u32(6, rows as u32);
+        encoder.dispatch_thread_groups(
+            MTLSize::new(n.div_ceil(4) as u64, rows.div_ceil(8) as u64, 1),
+            MTLSize::new(32, 1, 1),
+        );
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for W8A8Matmul<T> {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        autoreleasepool(|| {
+            let command_buffer = self.queue.new_command_buffer();
+            let shapes = [inp[0].1, inp[1].1];
+            let intermediates = self
+                .intermediate_buffer_sizes(&shapes)
+                .into_iter()
+                .map(|s| {
+                    self.device.new_buffer(
+                        s.to_usize().unwrap() as u64,
+                        MTLResourceOptions::StorageModeShared,
+                    )
+                })
+                .collect::<Vec<_>>();
+            let out = self.device.new_buffer(
+                self.output_buffer_sizes(&shapes)[0].to_usize().unwrap() as u64,
+                MTLResourceOptions::StorageModeShared,
+            );
+
+            self.metal_forward(
+                &[
+                    (get_buffer_from_tensor(&inp[0].0), inp[0].1),
+                    (get_buffer_from_tensor(&inp[1].0), inp[1].1),
+                ],
+                command_buffer,
+                &intermediates.iter().collect::<Vec<_>>(),
+                &[&out],
+            );
+
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+
+            vec![Tensor::new(MetalBuffer(out))]
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        None
+    }
+}
+
 #[derive(Clone)]
 pub struct QuantizedGather<T> {
     pipeline: ComputePipelineState,
@@ -504,6 +755,7 @@ impl<T: MetalFloat> Operator for QuantizedGather<T> {
 #[derive(Default)]
 pub struct MetalQuantizedCompiler<T> {
     quantized_weights: Vec<NodeIndex>,
+    quantize_activations: bool,
     _phantom: PhantomData<T>,
 }
 
@@ -511,6 +763,16 @@ impl<T: MetalFloat> MetalQuantizedCompiler<T> {
     pub fn new(weights: impl ToIds) -> Self {
         Self {
             quantized_weights: weights.to_ids(),
+            quantize_activations: false,
+            _phantom: Default::default(),
+        }
+    }
+
+    /// Quantize the activations going into quantized matmuls to int8 as well (W8A8)
+    pub fn new_w8a8(weights: impl ToIds) -> Self {
+        Self {
+            quantized_weights: weights.to_ids(),
+            quantize_activations: true,
             _phantom: Default::default(),
         }
     }
@@ -533,6 +795,8 @@ impl<T: MetalFloat + Default> Compiler for MetalQuantizedCompiler<T> {
                     inp_ind, 1,
                     "Quantized weight {target:?} is the wrong input!",
                 );
+                // W8A8 quantizes the activations row by row straight from their buffer
+                let contiguous_activations = !graph.get_sources(target)[0].2.is_reshaped();
                 let op_node = graph.node_weight_mut(target).unwrap();
                 if let Some(gather) = op_node.as_any().downcast_ref::<MetalGather<T>>() {
                     *op_node = Box::new(QuantizedGather::<T>::new(
@@ -540,6 +804,11 @@ impl<T: MetalFloat + Default> Compiler for MetalQuantizedCompiler<T> {
                         queue.clone(),
                         gather.embed_dim,
                     ));
+                } else if op_node.as_any().is::<super::matmul::Matmul<T>>()
+                    && self.quantize_activations
+                    && contiguous_activations
+                {
+                    *op_node = Box::new(W8A8Matmul::<T>::new(device.clone(), queue.clone()));
                 } else if op_node.as_any().is::<super::matmul::Matmul<T>>() {
                     *op_node = Box::new(QuantizedMatmul::<T>::new(device.clone(), queue.clone()));
                 } else {
@@ -853,10 +1122,11 @@ mod tests {
         tests::{assert_close, assert_close_precision, random_vec_rng},
     };
     use metal_rs::{Device, MTLResourceOptions};
-    use rand::{thread_rng, Rng};
+    use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
 
     use crate::{
-        quantized::MetalQuantizedCompiler, BufferCompilers, MetalBuffer, MetalCompilerPreBuffer,
+        quantized::MetalQuantizedCompiler, BufferCompilers, MetalBuffer, MetalCompiler,
+        MetalCompilerPreBuffer, MetalFloat,
     };
 
     #[repr(C, packed)]
@@ -1025,4 +1295,128 @@ mod tests {
         assert_close_precision(&out.data(), &d_c.as_vec(), 1e-0);
         // This is imprecise currently because we accumulate in fp16 in the matmul. TODO: accumulate in fp32 and convert before saving to dest
     }
+
+    fn quantize_q8_0(data: &[f32]) -> Vec<BlockQ8_0> {
+        data.chunks_exact(32)
+            .map(|chunk| {
+                let d = chunk.iter().fold(0.0_f32, |a, b| a.max(b.abs())) / 127.0;
+                let mut array = [0; 32];
+                for (i, n) in chunk.iter().enumerate() {
+                    array[i] = if d == 0.0 { 0 } else { (n / d).round() as i8 };
+                }
+                BlockQ8_0 {
+                    _d: f16::from_f32(d),
+                    _qs: array,
+                }
+            })
+            .collect()
+    }
+
+    fn dequantize_q8_0(blocks: &[BlockQ8_0]) -> Vec<f32> {
+        blocks
+            .iter()
+            .flat_map(|b| {
+                let (d, qs) = ({ b._d }, { b._qs });
+                qs.map(|q| q as f32 * d.to_f32())
+            })
+            .collect()
+    }
+
+    /// A Llama MLP block, returning the output and the gate, up and down weights
+    fn llama_mlp_graph(cx: &mut Graph, input: &[f32]) -> (GraphTensor, [GraphTensor; 3]) {
+        let gate_w = cx.tensor((1024, 512)).keep();
+        let up_w = cx.tensor((1024, 512)).keep();
+        let down_w = cx.tensor((512, 1024)).keep();
+        let inp = cx.tensor((16, 512)).set(input.to_vec());
+        let hidden = inp.matmul(gate_w.permute((1, 0))).swish() * inp.matmul(up_w.permute((1, 0)));
+        let out = hidden.matmul(down_w.permute((1, 0))).retrieve();
+        (out, [gate_w, up_w, down_w])
+    }
+
+    fn llama_mlp<T: MetalFloat>(
+        quantize_activations: bool,
+        input: &[f32],
+        gate: &[BlockQ8_0],
+        up: &[BlockQ8_0],
+        down: &[BlockQ8_0],
+    ) -> Vec<f32> {
+        let mut cx = Graph::new();
+        let (mut out, [gate_w, up_w, down_w]) = llama_mlp_graph(&mut cx, input);
+
+        let weights = vec![gate_w.id, up_w.id, down_w.id];
+        cx.compile(
+            (
+                MetalCompilerPreBuffer::<T>::default(),
+                if quantize_activations {
+                    MetalQuantizedCompiler::<T>::new_w8a8(weights)
+                } else {
+                    MetalQuantizedCompiler::<T>::new(weights)
+                },
+                BufferCompilers::default(),
+            ),
+            &mut out,
+        );
+        let dev = Device::system_default().unwrap();
+        cx.tensors
+            .insert((gate_w.id, 0), quantized_buffer(gate, &dev));
+        cx.tensors.insert((up_w.id, 0), quantized_buffer(up, &dev));
+        cx.tensors
+            .insert((down_w.id, 0), quantized_buffer(down, &dev));
+        cx.execute();
+        out.data()
+    }
+
+    /// The same block in plain fp16, with the weights dequantized
+    fn llama_mlp_fp16(
+        input: &[f32],
+        gate: &[BlockQ8_0],
+        up: &[BlockQ8_0],
+        down: &[BlockQ8_0],
+    ) -> Vec<f32> {
+        let mut cx = Graph::new();
+        let (mut out, [gate_w, up_w, down_w]) = llama_mlp_graph(&mut cx, input);
+        gate_w.set(dequantize_q8_0(gate));
+        up_w.set(dequantize_q8_0(up));
+        down_w.set(dequantize_q8_0(down));
+        cx.compile(MetalCompiler::<f16>::default(), &mut out);
+        cx.execute();
+        out.data()
+    }
+
+    fn mean_relative_error(a: &[f32], reference: &[f32]) -> f32 {
+        a.iter()
+            .zip(reference)
+            .map(|(a, b)| (a - b).abs())
+            .sum::<f32>()
+            / reference.iter().map(|b| b.abs()).sum::<f32>()
+    }
+
+    /// W8A8 should stay within 2% mean relative error of W8A16 and of plain fp16 on a Llama MLP block
+    #[test]
+    fn test_w8a8_llama_mlp() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let input = random_vec_rng(16 * 512, &mut rng);
+        let mut weight = |n| {
+            quantize_q8_0(
+                &random_vec_rng(n, &mut rng)
+                    .into_iter()
+                    .map(|i| i * 0.1)
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let (gate, up, down) = (weight(1024 * 512), weight(1024 * 512), weight(512 * 1024));
+
+        let w8a8 = llama_mlp::<f32>(true, &input, &gate, &up, &down);
+        let w8a16 = llama_mlp::<f32>(false, &input, &gate, &up, &down);
+        let err = mean_relative_error(&w8a8, &w8a16);
+        assert!(err < 0.02, "W8A8 mean relative error too high: {err}");
+
+        let fp16 = llama_mlp_fp16(&input, &gate, &up, &down);
+        let err = mean_relative_error(&w8a8, &fp16);
+        assert!(err < 0.02, "W8A8 error against fp16 too high: {err}");
+        // Writing fp16 out of the kernel shouldn't add much on top
+        let w8a8_f16 = llama_mlp::<f16>(true, &input, &gate, &up, &down);
+        let err = mean_relative_error(&w8a8_f16, &fp16);
+        assert!(err < 0.02, "fp16 W8A8 error against fp16 too high: {err}");
+    }
 }
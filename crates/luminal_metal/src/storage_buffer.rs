@@ -296,6 +296,86 @@ fn btreeset_intersection<T: Ord>(mut a: BTreeSet<T>, b: &BTreeSet<T>) -> BTreeSe
     a
 }
 
+/// Estimate the peak memory used by Metal buffers when running a graph
+pub trait EstimateMemory {
+    /// Walk the execution order, allocating each input's device copy and each Metal kernel's output and intermediate
+    /// buffers when they run, and freeing them once their last consumer has ran. Kept tensors (like weights) and
+    /// retrieved outputs persist for the whole run, so they're never freed. Returns the peak in bytes.
+    ///
+    /// Run this after the pre-buffer compilers and before the buffer compilers, since those hide the kernels.
+    /// All dynamic dimensions must be set.
+    fn estimated_memory(&mut self) -> usize;
+}
+
+impl EstimateMemory for Graph {
+    fn estimated_memory(&mut self) -> usize {
+        let order = self.execution_order();
+        let position = order
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (*n, i))
+            .collect::<FxHashMap<_, _>>();
+        // Buffers to free after each step
+        let mut frees: FxHashMap<usize, usize> = FxHashMap::default();
+        let (mut current, mut peak) = (0, 0);
+        for (step, node) in order.iter().enumerate() {
+            let input_shapes = self
+                .get_sources(*node)
+                .into_iter()
+                .map(|(_, _, i)| i)
+                .collect::<Vec<_>>();
+            let size = |e: Expression| {
+                e.exec(&self.dyn_map)
+                    .expect("All dynamic dimensions must be set to estimate memory")
+            };
+            let op = self.graph.node_weight_mut(*node).unwrap();
+            let (outputs, intermediates) = if let Some(Ok(wrapper)) = op
+                .custom("metal", Box::new(()))
+                .map(|e| e.downcast::<MetalKernelWrapper>())
+            {
+                (
+                    wrapper
+                        .output_buffer_sizes(&input_shapes)
+                        .into_iter()
+                        .map(size)
+                        .sum::<usize>(),
+                    wrapper
+                        .intermediate_buffer_sizes(&input_shapes)
+                        .into_iter()
+                        .map(size)
+                        .sum::<usize>(),
+                )
+            } else if let Some(Ok(copy)) = input_shapes.first().and_then(|shape| {
+                op.custom("device_buffer_size", Box::new(*shape))
+                    .map(|e| e.downcast::<Expression>())
+            }) {
+                // Inputs copied onto the device
+                (size(*copy), 0)
+            } else {
+                continue;
+            };
+            current += outputs + intermediates;
+            peak = peak.max(current);
+            current -= intermediates;
+            let consumers = self
+                .graph
+                .edges_directed(*node, Direction::Outgoing)
+                .filter(|e| !e.weight().is_schedule())
+                .map(|e| e.target())
+                .collect::<Vec<_>>();
+            let persistent = self.no_delete.contains(node)
+                || self.to_retrieve.contains_key(node)
+                || consumers.iter().any(|n| self.to_retrieve.contains_key(n));
+            if !persistent {
+                let last_use = consumers.iter().map(|n| position[n]).max().unwrap_or(step);
+                *frees.entry(last_use).or_default() += outputs;
+            }
+            current -= frees.remove(&step).unwrap_or_default();
+        }
+        peak
+    }
+}
+
 struct AllocateMetalBuffers {
     dev: Device,
     dyn_map: *const FxHashMap<char, usize>,
@@ -405,3 +485,37 @@ fn test_shared_buffers() {
 
     assert_close_precision(&e.data(), &e_unopt, 1e-2);
 }
+
+#[test]
+fn test_estimated_memory() {
+    use luminal::prelude::*;
+    use luminal::tests::random_vec;
+    let n = 64;
+    // (n, n) f16 buffer
+    let buffer = n * n * std::mem::size_of::<f16>();
+    let estimate = |keep: bool, retrieve: bool| {
+        let mut cx = Graph::new();
+        let a = cx.tensor((n, n)).set(random_vec(n * n));
+        let w = cx.tensor((n, n)).set(random_vec(n * n));
+        let mut b = a.matmul(w);
+        if keep {
+            b.keep();
+        }
+        if retrieve {
+            b.retrieve();
+        }
+        let mut d = b.matmul(w).matmul(w).retrieve();
+        cx.compile(
+            crate::MetalCompilerPreBuffer::<f16>::default(),
+            (&mut b, &mut d),
+        );
+        cx.estimated_memory()
+    };
+
+    // Both inputs are copied onto the device. The first input can be freed once the first matmul has ran, and each
+    // matmul output once the next one has, so only three buffers are ever live
+    assert_eq!(estimate(false, false), 3 * buffer);
+    // Keeping or retrieving the first output holds it for the whole run
+    assert_eq!(estimate(true, false), 4 * buffer);
+    assert_eq!(estimate(false, true), 4 * buffer);
+}
@@ -530,6 +530,127 @@ fn test_rms_norm() {
     assert_close(&b.data(), &out.to_dtype::<f32>().as_vec());
 }
 
+#[test]
+fn test_fused_rms_norm() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let inp_data = random_vec_rng(15 * 64, &mut rng);
+    let weight_data = random_vec_rng(64, &mut rng);
+    let mut cx = Graph::new();
+    let a = cx.tensor((15, 64)).set(inp_data.clone());
+
+    let model = luminal_nn::RMSNorm::new(64, 1e-5, &mut cx);
+    model.weight.set(weight_data.clone());
+    let mut b = model.forward(a).retrieve();
+    cx.execute();
+    let unoptimized_b = b.data();
+    b.drop();
+
+    // Skip the buffer compilers so ops aren't wrapped into command buffers and keep their names
+    cx.compile(
+        <(GenericCompiler, crate::MetalCompilerPreBuffer<f16>)>::default(),
+        &mut b,
+    );
+    // Square, mean, rsqrt and the weight multiply all collapse into one kernel
+    let counts = cx.op_counts();
+    assert_eq!(counts.get("MetalRMSNorm"), Some(&1));
+    assert_eq!(counts.get("MetalStdNorm"), None);
+    cx.execute();
+
+    assert_close_precision(&b.data(), &unoptimized_b, 1e-2);
+}
+
+#[test]
+fn test_fused_rms_norm_hl_op() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let inp_data = random_vec_rng(15 * 64, &mut rng);
+    let weight_data = random_vec_rng(64, &mut rng);
+    let mut cx = Graph::new();
+    let a = cx.tensor((15, 64)).set(inp_data.clone());
+    let w = cx.tensor(64).set(weight_data.clone());
+    let mut b = a.rms_norm(w, 1e-5).retrieve();
+    cx.execute();
+    let unoptimized_b = b.data();
+    b.drop();
+
+    cx.compile(
+        <(GenericCompiler, crate::MetalCompilerPreBuffer<f16>)>::default(),
+        &mut b,
+    );
+    // The inline method lowers to the same kernel as the RMSNorm module
+    assert_eq!(cx.op_counts().get("MetalRMSNorm"), Some(&1));
+    cx.execute();
+
+    assert_close_precision(&b.data(), &unoptimized_b, 1e-2);
+}
+
+#[test]
+fn test_fused_add_rms_norm() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut cx = Graph::new();
+    let x = cx.tensor((15, 64)).set(random_vec_rng(15 * 64, &mut rng));
+    let sublayer = cx.tensor((15, 64)).set(random_vec_rng(15 * 64, &mut rng));
+
+    let model = luminal_nn::RMSNorm::new(64, 1e-5, &mut cx);
+    model.weight.set(random_vec_rng(64, &mut rng));
+    // x = x + sublayer(norm(x)), with the next block's norm consuming the residual
+    let residual = x + sublayer;
+    let normed = model.forward(residual);
+    let mut outs = (residual.retrieve(), (residual + normed).retrieve());
+    cx.execute();
+    let unoptimized = (outs.0.data(), outs.1.data());
+    outs.0.drop();
+    outs.1.drop();
+
+    // Skip the buffer compilers so ops aren't wrapped into command buffers and keep their names
+    cx.compile(
+        <(GenericCompiler, crate::MetalCompilerPreBuffer<f16>)>::default(),
+        (&mut outs.0, &mut outs.1),
+    );
+    let counts = cx.op_counts();
+    assert_eq!(counts.get("MetalAddRMSNorm"), Some(&1));
+    assert_eq!(counts.get("MetalRMSNorm"), None);
+    cx.execute();
+
+    assert_close_precision(&outs.0.data(), &unoptimized.0, 1e-2);
+    assert_close_precision(&outs.1.data(), &unoptimized.1, 1e-2);
+}
+
+#[test]
+fn test_fused_grouped_rms_norm() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut cx = Graph::new();
+    let x = cx.tensor((15, 256)).set(random_vec_rng(15 * 256, &mut rng));
+    let sublayer = cx.tensor((15, 256)).set(random_vec_rng(15 * 256, &mut rng));
+
+    let model = luminal_nn::RMSNorm::new_grouped(256, 4, 1e-5, &mut cx);
+    model.weight.set(random_vec_rng(256, &mut rng));
+    let residual = x + sublayer;
+    let mut outs = (
+        model.forward(x).retrieve(),
+        residual.retrieve(),
+        (residual + model.forward(residual)).retrieve(),
+    );
+    cx.execute();
+    let unoptimized = (outs.0.data(), outs.1.data(), outs.2.data());
+    outs.0.drop();
+    outs.1.drop();
+    outs.2.drop();
+
+    cx.compile(
+        <(GenericCompiler, crate::MetalCompilerPreBuffer<f16>)>::default(),
+        (&mut outs.0, &mut outs.1, &mut outs.2),
+    );
+    // Each group of 64 is a row of the fused kernels, with the weight offset by the group
+    let counts = cx.op_counts();
+    assert_eq!(counts.get("MetalRMSNorm"), Some(&1));
+    assert_eq!(counts.get("MetalAddRMSNorm"), Some(&1));
+    cx.execute();
+
+    assert_close_precision(&outs.0.data(), &unoptimized.0, 1e-2);
+    assert_close_precision(&outs.1.data(), &unoptimized.1, 1e-2);
+    assert_close_precision(&outs.2.data(), &unoptimized.2, 1e-2);
+}
+
 #[test]
 fn test_layer_norm() {
     let mut cx = Graph::new();
@@ -552,6 +673,98 @@ fn test_layer_norm() {
     assert_close_precision(&c.data(), &d_c.as_vec(), 1e-2);
 }
 
+#[test]
+fn test_layer_norm_f32_stats() {
+    let mut rng = StdRng::seed_from_u64(0);
+    // Offset with a small spread: a mean rounded to f16 before centering throws the variance off
+    let inp_data = random_vec_rng(2 * 4096, &mut rng)
+        .into_iter()
+        .map(|v| 30. + v)
+        .collect::<Vec<_>>();
+    let mut cx = Graph::new();
+    let a = cx.tensor((2, 4096)).set(inp_data.clone());
+    let model = LayerNorm::new(4096, false, false, true, 1e-5, &mut cx);
+    let mut out = model.forward(a).retrieve();
+
+    cx.compile(
+        <(GenericCompiler, crate::MetalCompilerPreBuffer<f16>)>::default(),
+        &mut out,
+    );
+    // The var_mean statistics are taken inside the norm kernel, in f32, rather than stored as f16 tensors
+    let counts = cx.op_counts();
+    assert_eq!(counts.get("MetalStdNorm"), Some(&1));
+    assert_eq!(counts.get("MetalMeanReduce"), None);
+    assert_eq!(counts.get("MetalVarMean"), None);
+    cx.execute();
+
+    // Reference on the f16-rounded inputs, in f64
+    let expected = inp_data
+        .iter()
+        .map(|v| f16::from_f32(*v).to_f64())
+        .collect::<Vec<_>>()
+        .chunks(4096)
+        .flat_map(|row| {
+            let mean = row.iter().sum::<f64>() / 4096.;
+            let var = row.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / 4096.;
+            row.iter()
+                .map(move |v| ((v - mean) / (var + 1e-5).sqrt()) as f32)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    assert_close_precision(&out.data(), &expected, 1e-2);
+}
+
+#[test]
+fn test_softmax_attention_f32_sum() {
+    let (dim, seq) = (4, 8192);
+    let identity = (0..16)
+        .map(|i| if i % 5 == 0 { 1. } else { 0. })
+        .collect::<Vec<_>>();
+    let mut rng = StdRng::seed_from_u64(0);
+    let kv_data = random_vec_rng(seq * dim, &mut rng);
+    let q_data = random_vec_rng(dim, &mut rng);
+    let mut cx = Graph::new();
+    let kv = cx.tensor((seq, dim)).set(kv_data.clone());
+    let q = cx.tensor((1, dim)).set(q_data.clone());
+    let model = luminal_nn::MultiHeadSelfAttention::new(dim, dim, dim, 1, &mut cx);
+    for w in [&model.w_q, &model.w_k, &model.w_v, &model.w_o] {
+        w.weight.set(identity.clone());
+    }
+    let mut out = model.forward((kv, q, kv)).retrieve();
+
+    cx.compile(
+        <(GenericCompiler, crate::MetalCompilerPreBuffer<f16>)>::default(),
+        &mut out,
+    );
+    // The softmax runs in one kernel accumulating in f32, rather than summing the exponentials in f16
+    let counts = cx.op_counts();
+    assert_eq!(counts.get("MetalMaskedSoftmax"), Some(&1));
+    assert_eq!(counts.get("MetalMaxReduce"), None);
+    cx.execute();
+
+    // Single query attention on the f16-rounded inputs, in f64: softmax(q k^T / sqrt(d)) v
+    let round = |x: &[f32]| {
+        x.iter()
+            .map(|v| f16::from_f32(*v).to_f64())
+            .collect::<Vec<_>>()
+    };
+    let (kv_data, q_data) = (round(&kv_data), round(&q_data));
+    let exps = kv_data
+        .chunks(dim)
+        .map(|k| (k.iter().zip(&q_data).map(|(a, b)| a * b).sum::<f64>() / 2.).exp())
+        .collect::<Vec<_>>();
+    let sum = exps.iter().sum::<f64>();
+    let expected = (0..dim)
+        .map(|d| {
+            exps.iter()
+                .zip(kv_data.chunks(dim))
+                .map(|(e, v)| e / sum * v[d])
+                .sum::<f64>() as f32
+        })
+        .collect::<Vec<_>>();
+    assert_close_precision(&out.data(), &expected, 1e-2);
+}
+
 #[test]
 fn test_transformer_encoder_block() {
     let mut cx = Graph::new();
@@ -930,3 +1143,27 @@ fn test_conv1d_pad_stride() {
         1e-2,
     );
 }
+
+#[test]
+fn test_verify_against_cpu() {
+    // Run a small transformer-ish block through the fp16 compiler across a few shapes
+    for (m, k, n) in [(1, 32, 16), (7, 64, 33), (32, 128, 64)] {
+        let mut rng = StdRng::seed_from_u64(m as u64);
+        let (a_data, b_data) = (
+            random_vec_rng(m * k, &mut rng),
+            random_vec_rng(k * n, &mut rng),
+        );
+        let errors = Graph::verify_against_cpu(MetalCompiler::<f16>::default(), |cx| {
+            let a = cx.tensor((m, k)).set(a_data.clone());
+            let b = cx.tensor((k, n)).set(b_data.clone());
+            let c = a.matmul(b);
+            vec![c, c.softmax(1), a.layer_norm(1, 1e-5).swish()]
+        });
+        for (i, err) in errors.into_iter().enumerate() {
+            assert!(
+                err.max_abs < 1e-2,
+                "Output {i} of ({m}, {k}) x ({k}, {n}) is off by {err:?}"
+            );
+        }
+    }
+}
@@ -40,6 +40,20 @@ binary_test!(
 binary_test!(|a, b| a.min(b), |a, b| a.minimum(b), test_min, f32);
 binary_test!(|a, b| a.max(b), |a, b| a.maximum(b), test_max, f32);
 
+#[test]
+fn test_fmod_remainder() {
+    let mut cx = Graph::new();
+    let a = cx.tensor(6).set(vec![5.5, -5.5, 5.5, -5.5, 6., -0.5]);
+    let b = cx.tensor(6).set(vec![2., 2., -2., -2., 3., 4.]);
+    let mut fmod = a.fmod(b).retrieve();
+    let mut remainder = a.remainder(b).retrieve();
+    cx.compile(MetalCompiler::<f32>::default(), (&mut fmod, &mut remainder));
+    cx.execute();
+
+    assert_close(&fmod.data(), &[1.5, -1.5, 1.5, -1.5, 0., -0.5]);
+    assert_close(&remainder.data(), &[1.5, 0.5, -0.5, -1.5, 0., 3.5]);
+}
+
 #[test]
 fn test_contiguous() {
     let mut cx = Graph::new();
@@ -162,6 +176,98 @@ fn test_matmul() {
     assert_close(&c.data(), &d_c.as_vec());
 }
 
+#[test]
+fn test_flipped_matmul() {
+    let mut cx = Graph::new();
+    let a = cx.tensor((3, 4)).set(random_vec(12));
+    let b = cx.tensor((4, 5)).set(random_vec(20));
+    let c = cx.tensor((2, 3, 4)).set(random_vec(24));
+    let mut d = a.flip(1).matmul(b.flip(0)).retrieve();
+    let mut e = c.flip(1).matmul(b.flip(1)).retrieve();
+    cx.execute();
+    let (unoptimized_d, unoptimized_e) = (d.data(), e.data());
+    d.drop();
+    e.drop();
+
+    cx.compile(
+        crate::MetalCompilerPreBuffer::<f32>::default(),
+        (&mut d, &mut e),
+    );
+    assert_eq!(cx.op_counts().get("Matmul"), Some(&2));
+    cx.execute();
+
+    assert_close(&d.data(), &unoptimized_d);
+    assert_close(&e.data(), &unoptimized_e);
+}
+
+#[test]
+fn test_matmul_transposed_b() {
+    let mut cx = Graph::new();
+    let a = cx.tensor((33, 48)).set(random_vec(33 * 48));
+    let b = cx.tensor((40, 48)).set(random_vec(40 * 48));
+    let mut c = a.matmul_transposed_b(b).retrieve();
+    cx.execute();
+    let unoptimized_c = c.data();
+    c.drop();
+
+    cx.compile(crate::MetalCompilerPreBuffer::<f32>::default(), &mut c);
+    // The (out, in) weight is read in place by the transposed kernels
+    let matmul = cx
+        .graph
+        .node_indices()
+        .find_map(|n| cx.try_get_op::<crate::matmul::Matmul<f32>>(n))
+        .unwrap();
+    assert!(matmul.matmul_kernel.starts_with("gemm_nt_"));
+    assert!(matmul.matvec_kernel.starts_with("gemv_float32"));
+    cx.execute();
+
+    assert_close(&c.data(), &unoptimized_c);
+}
+
+#[test]
+fn test_barrier() {
+    let mut cx = Graph::new();
+    let a = cx.tensor(5).set(random_vec(5));
+    let mut fused = a.exp2().sin().retrieve();
+    let mut split = a.exp2().barrier().sin().retrieve();
+    cx.execute();
+    let unoptimized = (fused.data(), split.data());
+    fused.drop();
+    split.drop();
+
+    cx.compile(
+        crate::MetalCompilerPreBuffer::<f32>::default(),
+        (&mut fused, &mut split),
+    );
+    // Only the chain without a barrier is fused, and the barrier is gone without a round trip through the host
+    let counts = cx.op_counts();
+    assert_eq!(counts.get("FusedElementwiseOp"), Some(&1));
+    assert_eq!(counts.get("Barrier"), None);
+    assert_eq!(counts.get("MetalCopyToDevice"), Some(&1));
+    assert_eq!(counts.get("MetalCopyFromDevice"), Some(&2));
+    cx.execute();
+
+    assert_close(&fused.data(), &unoptimized.0);
+    assert_close(&split.data(), &unoptimized.1);
+}
+
+#[test]
+fn test_flop_count() {
+    let mut cx = Graph::new();
+    let a = cx.tensor(('s', 3));
+    let b = cx.tensor((3, 4));
+    let mut c = (a.matmul(b) + 1.).exp().retrieve();
+    cx.set_dyn_dim('s', 5);
+    let unoptimized_flops = cx.flop_count();
+
+    cx.compile(MetalCompiler::<f32>::default(), &mut c);
+    // Fused and command buffer wrapped ops are counted as the primitives they replaced
+    assert_eq!(cx.flop_count(), unoptimized_flops);
+    assert_eq!(unoptimized_flops, 2 * 5 * 4 * 3 + 3 * 5 * 4);
+    cx.set_dyn_dim('s', 10);
+    assert_eq!(cx.flop_count(), 2 * 10 * 4 * 3 + 3 * 10 * 4);
+}
+
 #[test]
 fn test_batch_matmul() {
     let mut cx = Graph::new();
@@ -588,3 +694,273 @@ fn test_conv1d_pad_stride() {
         &output.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
     );
 }
+
+#[test]
+fn test_masked_softmax() {
+    let mut cx = Graph::new();
+    let a = cx.tensor((2, 3, 4)).set(random_vec(24));
+    // Causal mask along the last axis
+    let mut mask_data = vec![0.; 24];
+    for (i, m) in mask_data.iter_mut().enumerate() {
+        if i % 4 > (i / 4) % 3 {
+            *m = -1e9;
+        }
+    }
+    let mask = cx.tensor((2, 3, 4)).set(mask_data);
+    let mut b = a.masked_softmax(mask, 2).retrieve();
+    let mut c = a.masked_softmax(mask, 1).retrieve();
+    cx.execute();
+    let (unoptimized_b, unoptimized_c) = (b.data(), c.data());
+    b.drop();
+    c.drop();
+
+    // Skip the buffer compilers so ops aren't wrapped into command buffers and keep their names
+    cx.compile(
+        <(GenericCompiler, crate::MetalCompilerPreBuffer<f32>)>::default(),
+        (&mut b, &mut c),
+    );
+    let counts = cx.op_counts();
+    assert_eq!(counts.get("MetalMaskedSoftmax"), Some(&2));
+    assert_eq!(counts.get("MetalMaxReduce"), None);
+    cx.execute();
+
+    assert_close(&b.data(), &unoptimized_b);
+    assert_close(&c.data(), &unoptimized_c);
+}
+
+#[test]
+fn test_causal_attention_mask_by_index() {
+    let mut cx = Graph::new();
+    let q = cx.tensor((2, 3, 4)).set(random_vec(24));
+    let k = cx.tensor((2, 5, 4)).set(random_vec(40));
+    let v = cx.tensor((2, 5, 3)).set(random_vec(30));
+    let mut out = q.scaled_dot_product_attention(k, v, None, true).retrieve();
+    cx.execute();
+    let unoptimized_out = out.data();
+    out.drop();
+
+    cx.compile(
+        <(GenericCompiler, crate::MetalCompilerPreBuffer<f32>)>::default(),
+        &mut out,
+    );
+    // The causal mask is applied inside the softmax kernel, so none of its ops are left
+    let counts = cx.op_counts();
+    assert_eq!(counts.get("MetalMaskedSoftmax"), Some(&1));
+    assert_eq!(counts.get("MetalLessThan"), None);
+    assert_eq!(counts.get("MetalARange"), None);
+    cx.execute();
+
+    assert_close(&out.data(), &unoptimized_out);
+}
+
+#[test]
+fn test_gather_nd() {
+    let mut cx = Graph::new();
+    let a = cx.tensor((4, 3, 5)).set(random_vec(60));
+    let indices = cx.tensor((3, 2)).set(vec![3., 1., 0., 2., 2., 0.]);
+    let mut b = a.gather_nd(indices).retrieve();
+    cx.execute();
+    let unoptimized_b = b.data();
+    b.drop();
+
+    cx.compile(
+        <(GenericCompiler, crate::MetalCompilerPreBuffer<f32>)>::default(),
+        &mut b,
+    );
+    assert_eq!(cx.op_counts().get("MetalGather"), Some(&1));
+    cx.execute();
+
+    assert_exact(&b.data(), &unoptimized_b);
+}
+
+#[test]
+fn test_i32_indexes() {
+    let mut cx = Graph::new();
+    let matrix = cx.tensor((3, 2)).set(vec![1., 2., 3., 4., 5., 6.]);
+    let indexes = cx.tensor(3).set(vec![2, 0, 1]);
+    let a = cx.tensor((2, 3)).set(vec![1., 5., 2., 7., -1., 3.]);
+    let mut outs = (
+        matrix.gather(indexes.int_to_float()).retrieve(),
+        a.argmax_i32().retrieve(),
+    );
+
+    cx.compile(
+        <(GenericCompiler, MetalCompiler<f32>)>::default(),
+        (&mut outs.0, &mut outs.1),
+    );
+    cx.execute();
+
+    // The casts run on the host, around the device gather and argmax
+    assert_exact(&outs.0.data(), &[5., 6., 1., 2., 3., 4.]);
+    assert_eq!(outs.1.data_i32(), vec![1, 0]);
+}
+
+#[test]
+fn test_max_with_index() {
+    let mut cx = Graph::new();
+    let mut data = random_vec(3 * 5 * 4);
+    // Ties go to the lowest index
+    data[7] = 2.;
+    data[15] = 2.;
+    let a = cx.tensor((3, 5, 4)).set(data);
+    let (max, argmax) = a.max_with_index(1);
+    let (min, argmin) = a.min_with_index(2);
+    let mut outs = (
+        max.retrieve(),
+        argmax.retrieve(),
+        min.retrieve(),
+        argmin.retrieve(),
+    );
+    cx.execute();
+    let unoptimized = (outs.0.data(), outs.1.data(), outs.2.data(), outs.3.data());
+    outs.0.drop();
+    outs.1.drop();
+    outs.2.drop();
+    outs.3.drop();
+
+    cx.compile(
+        <(GenericCompiler, crate::MetalCompilerPreBuffer<f32>)>::default(),
+        (&mut outs.0, &mut outs.1, &mut outs.2, &mut outs.3),
+    );
+    let counts = cx.op_counts();
+    assert_eq!(counts.get("MetalMaxWithIndex"), Some(&2));
+    assert_eq!(counts.get("MetalMaxReduce"), None);
+    cx.execute();
+
+    assert_exact(&outs.0.data(), &unoptimized.0);
+    assert_exact(&outs.1.data(), &unoptimized.1);
+    assert_exact(&outs.2.data(), &unoptimized.2);
+    assert_exact(&outs.3.data(), &unoptimized.3);
+}
+
+#[test]
+fn test_embedding_bag() {
+    let mut cx = Graph::new();
+    let weights = cx.tensor((6, 3)).set(random_vec(18));
+    let indices = cx.tensor(7).set(vec![1., 3., 3., 0., 5., 2., 4.]);
+    // Includes an empty bag
+    let offsets = cx.tensor(4).set(vec![0., 3., 3., 5.]);
+    let mut sum = weights
+        .embedding_bag(indices, offsets, other::EmbeddingBagMode::Sum)
+        .retrieve();
+    let mut mean = weights
+        .embedding_bag(indices, offsets, other::EmbeddingBagMode::Mean)
+        .retrieve();
+    cx.execute();
+    let unoptimized = (sum.data(), mean.data());
+    sum.drop();
+    mean.drop();
+
+    cx.compile(
+        <(GenericCompiler, crate::MetalCompilerPreBuffer<f32>)>::default(),
+        (&mut sum, &mut mean),
+    );
+    let counts = cx.op_counts();
+    assert_eq!(counts.get("MetalEmbeddingBag"), Some(&2));
+    assert_eq!(counts.get("MetalGather"), None);
+    assert_eq!(counts.get("Matmul"), None);
+    cx.execute();
+
+    assert_close(&sum.data(), &unoptimized.0);
+    assert_close(&mean.data(), &unoptimized.1);
+}
+
+#[test]
+fn test_bincount() {
+    let mut cx = Graph::new();
+    let a = cx.tensor('n').set_dyn(vec![1., 3., 1., 0., 5., 1., 3.], 7);
+    let mut b = a.bincount(4).retrieve();
+    cx.execute();
+    let unoptimized_b = b.data();
+    b.drop();
+
+    cx.compile(
+        <(GenericCompiler, crate::MetalCompilerPreBuffer<f32>)>::default(),
+        &mut b,
+    );
+    assert_eq!(cx.op_counts().get("MetalBincount"), Some(&1));
+    cx.execute();
+
+    assert_exact(&b.data(), &unoptimized_b);
+    assert_exact(&b.data(), &[1., 3., 0., 2.]);
+}
+
+#[test]
+fn test_einsum_three_way() {
+    let mut cx = Graph::new();
+    let a = cx.tensor((2, 3, 4)).set(random_vec(24));
+    let b = cx.tensor((3, 4, 5)).set(random_vec(60));
+    // Not a single gemm, so this runs as the plain multiply and sum reduce
+    let mut c = a.einsum(b, "ijk,jkl->il").retrieve();
+    cx.execute();
+    let unoptimized_c = c.data();
+    c.drop();
+
+    cx.compile(MetalCompiler::<f32>::default(), &mut c);
+    cx.execute();
+
+    assert_close(&c.data(), &unoptimized_c);
+}
+
+#[test]
+fn test_std_mean() {
+    let mut cx = Graph::new();
+    let a = cx.tensor((4, 48)).set(random_vec(4 * 48)) + 2.;
+    let (std, mean) = a.std_mean(1);
+    let (mut std, mut mean) = (std.retrieve(), mean.retrieve());
+    cx.execute();
+    let (unoptimized_std, unoptimized_mean) = (std.data(), mean.data());
+    std.drop();
+    mean.drop();
+
+    cx.compile(
+        <(GenericCompiler, crate::MetalCompilerPreBuffer<f32>)>::default(),
+        (&mut std, &mut mean),
+    );
+    assert_eq!(cx.op_counts().get("MetalVarMean"), Some(&1));
+    cx.execute();
+
+    assert_close(&std.data(), &unoptimized_std);
+    assert_close(&mean.data(), &unoptimized_mean);
+}
+
+#[test]
+fn test_nonzero() {
+    let mut cx = Graph::new();
+    // Longer than one scan chunk so the carry between chunks is exercised
+    let data = (0..2500)
+        .map(|i| if i % 7 == 3 { i as f32 } else { 0. })
+        .collect::<Vec<_>>();
+    let a = cx.tensor('n').set_dyn(data, 2500);
+    let (idx, count) = a.nonzero();
+    let (mut b, mut count) = ((idx * 2.).retrieve(), count.retrieve());
+    cx.execute();
+    let unoptimized_b = b.data();
+    b.drop();
+    count.drop();
+
+    cx.compile(
+        <(GenericCompiler, crate::MetalCompilerPreBuffer<f32>)>::default(),
+        (&mut b, &mut count),
+    );
+    assert_eq!(cx.op_counts().get("MetalNonzero"), Some(&1));
+    cx.execute();
+
+    assert_exact(&count.data(), &[357.]);
+    assert_exact(&b.data(), &unoptimized_b);
+}
+
+#[test]
+fn test_nan_to_num() {
+    let mut cx = Graph::new();
+    let a = cx
+        .tensor(5)
+        .set(vec![1., f32::NAN, f32::INFINITY, f32::NEG_INFINITY, -2.]);
+    let mut b = a.nan_to_num(0., None, None).retrieve();
+    let mut c = a.nan_to_num(-1., Some(100.), Some(-100.)).retrieve();
+    cx.compile(MetalCompiler::<f32>::default(), (&mut b, &mut c));
+    cx.execute();
+
+    assert_exact(&b.data(), &[1., 0., f32::MAX, f32::MIN, -2.]);
+    assert_exact(&c.data(), &[1., -1., 100., -100., -2.]);
+}
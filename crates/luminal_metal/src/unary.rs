@@ -17,7 +17,10 @@ use crate::{
     MetalKernelWrapper, SetInt,
 };
 
-use super::binary::MetalSub;
+use super::{
+    binary::{MetalEqual, MetalSub},
+    other::MetalARange,
+};
 
 /// Special kernel for efficient mean reduction
 #[derive(Clone)]
@@ -223,30 +226,66 @@ impl<T: MetalFloat> Compiler for MeanReduceCompiler<T> {
     }
 }
 
-/// Special kernel for efficient std norming
+/// Special kernel for efficient std norming, centering each row on its mean first if `mean_norm` is set.
+/// The statistics are accumulated in f32 whatever the storage type.
 #[derive(Clone)]
 pub struct MetalStdNorm<T> {
     pipeline: ComputePipelineState,
     device: Device,
     queue: CommandQueue,
     pub epsilon: f32, // Epsilon
+    pub mean_norm: bool,
     _phantom: PhantomData<T>,
 }
 crate::debug_type!(MetalStdNorm);
 
 impl<T> PartialEq for MetalStdNorm<T> {
     fn eq(&self, other: &Self) -> bool {
-        self.epsilon == other.epsilon
+        self.epsilon == other.epsilon && self.mean_norm == other.mean_norm
     }
 }
 
 impl<T: MetalFloat> MetalStdNorm<T> {
-    pub fn new(epsilon: f32, device: Device, queue: CommandQueue) -> Self {
+    pub fn new(epsilon: f32, mean_norm: bool, device: Device, queue: CommandQueue) -> Self {
         let type_name = T::type_name();
+        let mean_pass = if mean_norm {
+            "
+    float4 sumx = 0;
+    for (int i = thread_position_in_threadgroup; i < row_size/4; i += threads_per_threadgroup) {
+        sumx += (float4)x[i];
+    }
+    mean = threadgroup_sum(sumx[0] + sumx[1] + sumx[2] + sumx[3], buf, simdgroup_index_in_threadgroup, thread_index_in_simdgroup, threads_per_threadgroup) / row_size;
+"
+        } else {
+            ""
+        };
         let kernel_code = format!("#include <metal_stdlib>
 #define SIMD_WIDTH 32
 
 using namespace metal;
+float threadgroup_sum(float value, threadgroup float * buf, uint simdgroup_index, uint simd_lane, uint n_threads) {{
+    value = simd_sum(value);
+    if (n_threads > SIMD_WIDTH) {{
+        // buf may still be read from a previous sum
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+        if (simdgroup_index == 0) {{
+            buf[simd_lane] = 0.0f;
+        }}
+
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+
+        if (simd_lane == 0) {{
+            buf[simdgroup_index] = value;
+        }}
+
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+
+        value = buf[simd_lane];
+        value = simd_sum(value);
+    }}
+    return value;
+}}
+
 kernel void kernel_std_norm(
         device const  {type_name} * src0 [[buffer(0)]],
         device       {type_name} * dst [[buffer(1)]],
@@ -260,38 +299,22 @@ kernel void kernel_std_norm(
         uint threads_per_threadgroup[[threads_per_threadgroup]]) {{
     device const {type_name}4 * x = (device const {type_name}4 *) (src0 + threadgroup_position_in_grid * row_size);
 
+    float mean = 0.0f;
+{mean_pass}
     float4 sumf = 0;
 
     // parallel sum
     for (int i = thread_position_in_threadgroup; i < row_size/4; i += threads_per_threadgroup) {{
-        sumf += (float4)x[i] * (float4)x[i];
-    }}
-    float all_sum = sumf[0] + sumf[1] + sumf[2] + sumf[3];
-    all_sum = simd_sum(all_sum);
-
-    if (threads_per_threadgroup > SIMD_WIDTH) {{
-        if (simdgroup_index_in_threadgroup == 0) {{
-            buf[thread_index_in_simdgroup] = 0.0f;
-        }}
-
-        threadgroup_barrier(mem_flags::mem_threadgroup);
-
-        if (thread_index_in_simdgroup == 0) {{
-            buf[simdgroup_index_in_threadgroup] = all_sum;
-        }}
-
-        threadgroup_barrier(mem_flags::mem_threadgroup);
-
-        all_sum = buf[thread_index_in_simdgroup];
-        all_sum = simd_sum(all_sum);
+        float4 centered = (float4)x[i] - mean;
+        sumf += centered * centered;
     }}
+    float all_sum = threadgroup_sum(sumf[0] + sumf[1] + sumf[2] + sumf[3], buf, simdgroup_index_in_threadgroup, thread_index_in_simdgroup, threads_per_threadgroup);
 
-    const float mean  = all_sum / row_size;
-    const float scale = rsqrt(mean + eps);
+    const float scale = rsqrt(all_sum / row_size + eps);
 
     device {type_name}4 * y = (device {type_name}4 *) (dst + threadgroup_position_in_grid * row_size);
     for (int i = thread_position_in_threadgroup; i < row_size/4; i += threads_per_threadgroup) {{
-        y[i] = ({type_name}4)(x[i] * scale);
+        y[i] = ({type_name}4)(((float4)x[i] - mean) * scale);
     }}
 }}");
 
@@ -300,6 +323,7 @@ kernel void kernel_std_norm(
             device,
             queue,
             epsilon,
+            mean_norm,
             _phantom: Default::default(),
         }
     }
@@ -398,13 +422,9 @@ impl<T: MetalFloat> Compiler for StdNormCompiler<T> {
         // mul(recip(sqrt(add(mean_reduce(mul(x, x)), 1e-6))), x)
 
         let mut eps = op::<MetalConstant<T>>();
-        eps.check(|op, _| {
-            if let Some(c) = op.as_any().downcast_ref::<MetalConstant<T>>() {
-                if let ConstantValue::Float(v) = c.0 {
-                    v <= 1e-2 && v > 0.0
-                } else {
-                    false
-                }
+        eps.attr(|c: &MetalConstant<T>| {
+            if let ConstantValue::Float(v) = c.0 {
+                v <= 1e-2 && v > 0.0
             } else {
                 false
             }
@@ -467,6 +487,7 @@ impl<T: MetalFloat> Compiler for StdNormCompiler<T> {
             let rms_norm = graph
                 .add_op(MetalStdNorm::<T>::new(
                     epsilon_num,
+                    false,
                     dev.clone(),
                     queue.clone(),
                 ))
@@ -485,6 +506,1568 @@ impl<T: MetalFloat> Compiler for StdNormCompiler<T> {
     }
 }
 
+/// Fold the mean subtraction of a layer norm into its std norm, so the mean is kept in f32 rather than rounded to the
+/// storage type before centering. This is meant to be ran **after** the StdNormCompiler and MeanReduceCompiler.
+///
+/// `layer_norm` takes its statistics from `var_mean`, whose centered input also feeds the output, so the
+/// VarMeanCompiler leaves it alone and the StdNormCompiler fuses the variance and scaling into a std norm of the
+/// centered input. That leaves `std_norm(sub(x, mean_reduce(x)))` for this pass.
+#[derive(Default, Debug)]
+pub struct LayerNormCompiler<T>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for LayerNormCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = Device::system_default().unwrap();
+        let queue = dev.new_command_queue();
+        // std_norm(sub(x, mean_reduce(x)))
+        let mean = op::<MetalMeanReduce<T>>();
+        let centered = unary::<MetalSub<T>>(mean.clone());
+        let mut norm = unary::<MetalStdNorm<T>>(centered.clone());
+        norm.attr(|n: &MetalStdNorm<T>| !n.mean_norm);
+
+        let mut s = norm.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[norm.id]) {
+                continue;
+            }
+            let (mean, centered, norm) = (s.get(&mean), s.get(&centered), s.get(&norm));
+            if graph
+                .edges_directed(centered, petgraph::Direction::Outgoing)
+                .any(|e| e.target() != norm)
+            {
+                continue;
+            }
+            // x must be centered by its own mean over the last axis, broadcast back along it
+            let mut x = graph.get_sources(mean)[0];
+            let dim = graph.get_op::<MetalMeanReduce<T>>(mean).3;
+            if dim != x.2.len() - 1 {
+                continue;
+            }
+            let mut expanded_mean = x.2.dims();
+            let row = expanded_mean.pop().unwrap();
+            let mut expanded_mean = ShapeTracker::new(expanded_mean);
+            expanded_mean.expand(dim, row);
+            let centered_srcs = graph.get_sources(centered);
+            if centered_srcs[0] != x || centered_srcs[1] != (mean, 0, expanded_mean) {
+                continue;
+            }
+
+            // Input must be contiguous
+            if x.2.is_reshaped() {
+                x.0 = graph
+                    .add_op(MetalContiguous::<T>::new(
+                        x.2,
+                        dev.clone(),
+                        queue.clone(),
+                        &graph.dyn_map,
+                    ))
+                    .input(x.0, x.1, x.2)
+                    .finish();
+                x = (x.0, 0, x.2.contiguous());
+            }
+            let epsilon = graph.get_op::<MetalStdNorm<T>>(norm).epsilon;
+            let layer_norm = graph
+                .add_op(MetalStdNorm::<T>::new(
+                    epsilon,
+                    true,
+                    dev.clone(),
+                    queue.clone(),
+                ))
+                .input(x.0, x.1, x.2)
+                .finish();
+
+            // Create edges to dests
+            move_outgoing_edge(norm, layer_norm, graph);
+            remap(norm, layer_norm, &mut ids, graph);
+
+            // Remove the old ops
+            graph.remove_node(norm);
+            graph.remove_node(centered);
+            graph.safe_remove_node(mean, 0);
+        }
+    }
+}
+
+/// Variance and mean along an axis in a single Welford pass over the input.
+///
+/// Outputs the population variance and the mean, or the mean and the variance if `mean_first` is set.
+#[derive(Clone)]
+pub struct MetalVarMean<T> {
+    pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    pub dim: usize,
+    pub mean_first: bool,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(MetalVarMean);
+
+impl<T> PartialEq for MetalVarMean<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.dim == other.dim && self.mean_first == other.mean_first
+    }
+}
+
+impl<T: MetalFloat> MetalVarMean<T> {
+    pub fn new(
+        dim: usize,
+        mean_first: bool,
+        shape: ShapeTracker,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (idx_exp, valid_exp) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape], 6);
+        let type_name = T::type_name();
+        let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+kernel void kernel_var_mean(device {type_name} *inp [[buffer(0)]], device {type_name} *out_var [[buffer(1)]], device {type_name} *out_mean [[buffer(2)]], device int& n_rows [[buffer(3)]], device int& back_size [[buffer(4)]], device int& dim_size [[buffer(5)]], uint i_ [[thread_position_in_grid]]{rendered}) {{
+    if (i_ < n_rows) {{
+        int a_ = i_ / back_size;
+        int b_ = i_ % back_size;
+        float mean = 0.0;
+        float m2 = 0.0;
+        for (int c_ = 0; c_ < dim_size; c_++) {{
+            int idx = a_ * dim_size * back_size + c_ * back_size + b_;
+            float x = (({valid_exp}) == 0 ? 0.0 : (float)inp[{idx_exp}]);
+            float delta = x - mean;
+            mean += delta / (float)(c_ + 1);
+            m2 += delta * (x - mean);
+        }}
+        out_var[i_] = ({type_name})(m2 / (float)dim_size);
+        out_mean[i_] = ({type_name})mean;
+    }}
+}}");
+
+        Self {
+            pipeline: compile_function("kernel_var_mean", &code, &device),
+            queue,
+            device,
+            dim,
+            mean_first,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T> MetalKernel for MetalVarMean<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+        let mut sh = input_shapes[0];
+        sh.remove_dim(self.dim);
+        vec![
+            sh.n_elements() * size_of::<T>(),
+            sh.n_elements() * size_of::<T>(),
+        ]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let dims = inputs[0]
+            .1
+            .dims()
+            .into_iter()
+            .map(|i| i.to_usize().unwrap())
+            .collect::<Vec<_>>();
+        let front_size: usize = dims[..self.dim].iter().product();
+        let back_size: usize = dims[self.dim + 1..].iter().product();
+        let n_rows = front_size * back_size;
+
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        let (var, mean) = if self.mean_first {
+            (output_buffers[1], output_buffers[0])
+        } else {
+            (output_buffers[0], output_buffers[1])
+        };
+        encoder.set_buffer(1, Some(var), 0);
+        encoder.set_buffer(2, Some(mean), 0);
+        encoder.set_u32(3, n_rows as u32);
+        encoder.set_u32(4, back_size as u32);
+        encoder.set_u32(5, dims[self.dim] as u32);
+        input_dyn_dims(
+            &self.dyn_symbols,
+            unsafe { self.dyn_map.as_ref().unwrap() },
+            encoder,
+            6,
+        );
+
+        // Execute
+        encoder.dispatch_1d(n_rows);
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalVarMean<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&tensors, 1);
+        autoreleasepool(|| {
+            let command_buffer = self.queue.new_command_buffer();
+            let mut sh = tensors[0].1;
+            sh.remove_dim(self.dim);
+            let size = (sh.n_elements().to_usize().unwrap() * size_of::<T>()) as u64;
+            let first = self
+                .device
+                .new_buffer(size, MTLResourceOptions::StorageModeShared);
+            let second = self
+                .device
+                .new_buffer(size, MTLResourceOptions::StorageModeShared);
+
+            self.metal_forward(
+                &[(get_buffer_from_tensor(&tensors[0].0), tensors[0].1)],
+                command_buffer,
+                &[],
+                &[&first, &second],
+            );
+
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+
+            vec![
+                Tensor::new(MetalBuffer(first)),
+                Tensor::new(MetalBuffer(second)),
+            ]
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        None
+    }
+}
+
+/// Fuse the mean and the mean of squared deviations built by `var_mean` into a single kernel.
+/// This is meant to be ran **after** the MeanReduceCompiler and MetalSubtractionCompiler.
+///
+/// Only the first output of a node can be retrieved, so the mean is put first when it's kept. Layer norms, whose
+/// centered input is used past the variance, are left to the StdNormCompiler.
+#[derive(Default, Debug)]
+pub struct VarMeanCompiler<T>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for VarMeanCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = Device::system_default().unwrap();
+        let queue = dev.new_command_queue();
+        // mean_reduce(mul(sub(x, mean_reduce(x)), sub(x, mean_reduce(x))))
+        // The selector only matches trees, so the reuse of x and the centered input is checked below
+        let mean = op::<MetalMeanReduce<T>>();
+        let centered = unary::<MetalSub<T>>(mean.clone());
+        let square = unary::<MetalMul<T>>(centered.clone());
+        let var = unary::<MetalMeanReduce<T>>(square.clone());
+
+        let mut s = var.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[var.id, mean.id]) {
+                continue;
+            }
+            let (mean, centered, square, var) =
+                (s.get(&mean), s.get(&centered), s.get(&square), s.get(&var));
+            let (var_kept, mean_kept) = (
+                graph.no_delete.contains(&var),
+                graph.no_delete.contains(&mean),
+            );
+            if var_kept && mean_kept {
+                continue;
+            }
+            let dim = graph.get_op::<MetalMeanReduce<T>>(mean).3;
+            if graph.get_op::<MetalMeanReduce<T>>(var).3 != dim {
+                continue;
+            }
+            // The centered input and its square must only feed the variance
+            if graph
+                .edges_directed(centered, petgraph::Direction::Outgoing)
+                .any(|e| e.target() != square)
+                || graph
+                    .edges_directed(square, petgraph::Direction::Outgoing)
+                    .any(|e| e.target() != var)
+            {
+                continue;
+            }
+            // x must be centered through the same view it's averaged through, by the mean expanded along the
+            // reduced axis
+            let x = graph.get_sources(mean)[0];
+            let mut expanded_mean = x.2.dims();
+            let reduced = expanded_mean.remove(dim);
+            let mut expanded_mean = ShapeTracker::new(expanded_mean);
+            expanded_mean.expand(dim, reduced);
+            let centered_srcs = graph.get_sources(centered);
+            if centered_srcs[0] != x || centered_srcs[1] != (mean, 0, expanded_mean) {
+                continue;
+            }
+            if graph
+                .get_sources(square)
+                .iter()
+                .any(|(n, _, sh)| *n != centered || sh.is_reshaped())
+                || graph.get_sources(var)[0].2.is_reshaped()
+            {
+                continue;
+            }
+
+            let fused = graph
+                .add_op(MetalVarMean::<T>::new(
+                    dim,
+                    mean_kept,
+                    x.2,
+                    dev.clone(),
+                    queue.clone(),
+                    &graph.dyn_map,
+                ))
+                .input(x.0, x.1, x.2)
+                .finish();
+
+            // Create edges to dests
+            let (var_output, mean_output) = if mean_kept { (1, 0) } else { (0, 1) };
+            move_outgoing_edge_to_output(var, fused, var_output, graph);
+            move_outgoing_edge_to_output(mean, fused, mean_output, graph);
+            if mean_kept {
+                remap(mean, fused, &mut ids, graph);
+            } else {
+                remap(var, fused, &mut ids, graph);
+            }
+
+            // Remove the old ops
+            graph.remove_node(var);
+            graph.remove_node(square);
+            graph.remove_node(centered);
+            graph.remove_node(mean);
+        }
+    }
+}
+
+/// Fused RMSNorm: std norm over the last dimension followed by an elementwise weight, in a single kernel.
+/// The weight may span several rows, for grouped norms where each group of a row is normed on its own.
+#[derive(Clone)]
+pub struct MetalRMSNorm<T> {
+    pipeline: ComputePipelineState,
+    device: Device,
+    queue: CommandQueue,
+    pub epsilon: f32,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(MetalRMSNorm);
+
+impl<T> PartialEq for MetalRMSNorm<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.epsilon == other.epsilon
+    }
+}
+
+impl<T: MetalFloat> MetalRMSNorm<T> {
+    pub fn new(epsilon: f32, device: Device, queue: CommandQueue) -> Self {
+        let type_name = T::type_name();
+        let kernel_code = format!("#include <metal_stdlib>
+#define SIMD_WIDTH 32
+
+using namespace metal;
+kernel void kernel_rms_norm(
+        device const  {type_name} * src0 [[buffer(0)]],
+        device const  {type_name} * weight [[buffer(1)]],
+        device       {type_name} * dst [[buffer(2)]],
+        constant   int64_t & row_size [[buffer(3)]],
+        constant     float & eps [[buffer(4)]],
+        constant   int64_t & weight_size [[buffer(5)]],
+        threadgroup float  * buf [[threadgroup(0)]],
+        uint threadgroup_position_in_grid[[threadgroup_position_in_grid]],
+        uint thread_position_in_threadgroup[[thread_position_in_threadgroup]],
+        uint simdgroup_index_in_threadgroup[[simdgroup_index_in_threadgroup]],
+        uint thread_index_in_simdgroup[[thread_index_in_simdgroup]],
+        uint threads_per_threadgroup[[threads_per_threadgroup]]) {{
+    device const {type_name}4 * x = (device const {type_name}4 *) (src0 + threadgroup_position_in_grid * row_size);
+    device const {type_name}4 * w = (device const {type_name}4 *) (weight + (threadgroup_position_in_grid * row_size) % weight_size);
+
+    float4 sumf = 0;
+
+    // parallel sum
+    for (int i = thread_position_in_threadgroup; i < row_size/4; i += threads_per_threadgroup) {{
+        sumf += (float4)x[i] * (float4)x[i];
+    }}
+    float all_sum = sumf[0] + sumf[1] + sumf[2] + sumf[3];
+    all_sum = simd_sum(all_sum);
+
+    if (threads_per_threadgroup > SIMD_WIDTH) {{
+        if (simdgroup_index_in_threadgroup == 0) {{
+            buf[thread_index_in_simdgroup] = 0.0f;
+        }}
+
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+
+        if (thread_index_in_simdgroup == 0) {{
+            buf[simdgroup_index_in_threadgroup] = all_sum;
+        }}
+
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+
+        all_sum = buf[thread_index_in_simdgroup];
+        all_sum = simd_sum(all_sum);
+    }}
+
+    const float mean  = all_sum / row_size;
+    const float scale = rsqrt(mean + eps);
+
+    device {type_name}4 * y = (device {type_name}4 *) (dst + threadgroup_position_in_grid * row_size);
+    for (int i = thread_position_in_threadgroup; i < row_size/4; i += threads_per_threadgroup) {{
+        y[i] = ({type_name}4)((float4)x[i] * scale * (float4)w[i]);
+    }}
+}}");
+
+        Self {
+            pipeline: compile_function("kernel_rms_norm", &kernel_code, &device),
+            device,
+            queue,
+            epsilon,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T> MetalKernel for MetalRMSNorm<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+        vec![input_shapes[0].n_elements() * size_of::<T>()]
+    }
+
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+        let row_size = inputs[0].1.dims().last().unwrap().to_usize().unwrap();
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(inputs[1].0), 0);
+        encoder.set_buffer(2, Some(output_buffers[0]), 0);
+        encoder.set_i64(3, row_size as i64);
+        encoder.set_f32(4, self.epsilon);
+        encoder.set_i64(5, inputs[1].1.n_elements().to_usize().unwrap() as i64);
+        let batch_size = inputs[0]
+            .1
+            .dims()
+            .into_iter()
+            .take(inputs[0].1.len() - 1)
+            .map(|i| i.to_usize().unwrap())
+            .product::<usize>();
+        let mut nth = 32; // SIMD width
+        while nth < row_size / 4 && nth < 1024 {
+            nth *= 2;
+        }
+        encoder.set_threadgroup_memory_length(0, 32 * size_of::<f32>() as u64);
+        encoder.dispatch_thread_groups(
+            MTLSize {
+                width: batch_size as u64,
+                height: 1,
+                depth: 1,
+            },
+            MTLSize {
+                width: nth as u64,
+                height: 1,
+                depth: 1,
+            },
+        );
+        encoder.end_encoding();
+    }
+}
+
+impl<T: 'static + Clone> Operator for MetalRMSNorm<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&tensors, 2);
+        autoreleasepool(|| {
+            let command_buffer = self.queue.new_command_buffer();
+            let a = get_buffer_from_tensor(&tensors[0].0);
+            let w = get_buffer_from_tensor(&tensors[1].0);
+            let out = self.device.new_buffer(
+                (tensors[0].1.n_elements().to_usize().unwrap() * size_of::<T>()) as u64,
+                MTLResourceOptions::StorageModeShared,
+            );
+
+            self.metal_forward(
+                &[(a, tensors[0].1), (w, tensors[1].1)],
+                command_buffer,
+                &[],
+                &[&out],
+            );
+
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+
+            vec![Tensor::new(MetalBuffer(out))]
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        None
+    }
+}
+
+/// Fuse a std norm followed by a multiply with a weight broadcast along the last dimension into a single RMSNorm kernel.
+/// This is meant to be ran **after** the StdNormCompiler.
+#[derive(Default, Debug)]
+pub struct RMSNormCompiler<T>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for RMSNormCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = Device::system_default().unwrap();
+        let queue = dev.new_command_queue();
+        // mul(std_norm(x), expand(weight))
+        let mut norm = op::<MetalStdNorm<T>>();
+        norm.attr(|n: &MetalStdNorm<T>| !n.mean_norm);
+        let mul = unary::<MetalMul<T>>(norm.clone());
+
+        let mut s = mul.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[mul.id]) {
+                continue;
+            }
+            let (norm_node, mul_node) = (s.get(&norm), s.get(&mul));
+            let srcs = graph.get_sources(mul_node);
+            let Some((weight, _, weight_sh)) =
+                srcs.iter().find(|(i, _, _)| *i != norm_node).copied()
+            else {
+                continue;
+            };
+            let Some((_, _, norm_sh)) = srcs.iter().find(|(i, _, _)| *i == norm_node).copied()
+            else {
+                continue;
+            };
+            // The norm output must be read directly, and the weight must be a 1D vector broadcast over the leading dims
+            let n = weight_sh.len();
+            if norm_sh.is_reshaped()
+                || n == 0
+                || weight_sh.is_sliced()
+                || weight_sh.is_padded()
+                || weight_sh.fake[weight_sh.indexes[n - 1]]
+                || (0..n - 1).any(|i| !weight_sh.fake[weight_sh.indexes[i]])
+            {
+                continue;
+            }
+            let (x, _, x_sh) = graph.get_sources(norm_node)[0];
+            let epsilon = graph.get_op::<MetalStdNorm<T>>(norm_node).epsilon;
+            let mut w_sh = weight_sh;
+            for _ in 0..n - 1 {
+                w_sh.remove_dim(0);
+            }
+            // The weight covers either one normed row, or a whole group of rows when the norm is grouped
+            let (x_dims, w_len) = (x_sh.dims(), w_sh.dims()[0]);
+            let same = |a: Expression, b: Expression| match (a.to_usize(), b.to_usize()) {
+                (Some(a), Some(b)) => a == b,
+                _ => a == b,
+            };
+            let row = x_dims[x_dims.len() - 1];
+            if !same(w_len, row)
+                && (x_dims.len() < 2 || !same(w_len, row * x_dims[x_dims.len() - 2]))
+            {
+                continue;
+            }
+
+            let rms_norm = graph
+                .add_op(MetalRMSNorm::<T>::new(epsilon, dev.clone(), queue.clone()))
+                .input(x, 0, x_sh)
+                .input(weight, 0, w_sh)
+                .finish();
+
+            move_outgoing_edge(mul_node, rms_norm, graph);
+            remap(mul_node, rms_norm, &mut ids, graph);
+
+            graph.remove_node(mul_node);
+            s.try_delete();
+        }
+    }
+}
+
+/// Residual add fused with the RMSNorm that consumes it. Outputs the sum, which carries on as the residual stream,
+/// and the normed sum.
+#[derive(Clone)]
+pub struct MetalAddRMSNorm<T> {
+    pipeline: ComputePipelineState,
+    device: Device,
+    queue: CommandQueue,
+    pub epsilon: f32,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(MetalAddRMSNorm);
+
+impl<T> PartialEq for MetalAddRMSNorm<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.epsilon == other.epsilon
+    }
+}
+
+impl<T: MetalFloat> MetalAddRMSNorm<T> {
+    pub fn new(epsilon: f32, device: Device, queue: CommandQueue) -> Self {
+        let type_name = T::type_name();
+        let kernel_code = format!("#include <metal_stdlib>
+#define SIMD_WIDTH 32
+
+using namespace metal;
+kernel void kernel_add_rms_norm(
+        device const  {type_name} * src0 [[buffer(0)]],
+        device const  {type_name} * src1 [[buffer(1)]],
+        device const  {type_name} * weight [[buffer(2)]],
+        device       {type_name} * sum_dst [[buffer(3)]],
+        device       {type_name} * dst [[buffer(4)]],
+        constant   int64_t & row_size [[buffer(5)]],
+        constant     float & eps [[buffer(6)]],
+        constant   int64_t & weight_size [[buffer(7)]],
+        threadgroup float  * buf [[threadgroup(0)]],
+        uint threadgroup_position_in_grid[[threadgroup_position_in_grid]],
+        uint thread_position_in_threadgroup[[thread_position_in_threadgroup]],
+        uint simdgroup_index_in_threadgroup[[simdgroup_index_in_threadgroup]],
+        uint thread_index_in_simdgroup[[thread_index_in_simdgroup]],
+        uint threads_per_threadgroup[[threads_per_threadgroup]]) {{
+    device const {type_name}4 * a = (device const {type_name}4 *) (src0 + threadgroup_position_in_grid * row_size);
+    device const {type_name}4 * b = (device const {type_name}4 *) (src1 + threadgroup_position_in_grid * row_size);
+    device {type_name}4 * x = (device {type_name}4 *) (sum_dst + threadgroup_position_in_grid * row_size);
+    device const {type_name}4 * w = (device const {type_name}4 *) (weight + (threadgroup_position_in_grid * row_size) % weight_size);
+
+    float4 sumf = 0;
+
+    // residual add, then parallel sum of the rounded sums so the stats match the unfused chain
+    for (int i = thread_position_in_threadgroup; i < row_size/4; i += threads_per_threadgroup) {{
+        x[i] = a[i] + b[i];
+        sumf += (float4)x[i] * (float4)x[i];
+    }}
+    float all_sum = sumf[0] + sumf[1] + sumf[2] + sumf[3];
+    all_sum = simd_sum(all_sum);
+
+    if (threads_per_threadgroup > SIMD_WIDTH) {{
+        if (simdgroup_index_in_threadgroup == 0) {{
+            buf[thread_index_in_simdgroup] = 0.0f;
+        }}
+
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+
+        if (thread_index_in_simdgroup == 0) {{
+            buf[simdgroup_index_in_threadgroup] = all_sum;
+        }}
+
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+
+        all_sum = buf[thread_index_in_simdgroup];
+        all_sum = simd_sum(all_sum);
+    }}
+
+    const float mean  = all_sum / row_size;
+    const float scale = rsqrt(mean + eps);
+
+    device {type_name}4 * y = (device {type_name}4 *) (dst + threadgroup_position_in_grid * row_size);
+    for (int i = thread_position_in_threadgroup; i < row_size/4; i += threads_per_threadgroup) {{
+        y[i] = ({type_name}4)((float4)x[i] * scale * (float4)w[i]);
+    }}
+}}");
+
+        Self {
+            pipeline: compile_function("kernel_add_rms_norm", &kernel_code, &device),
+            device,
+            queue,
+            epsilon,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T> MetalKernel for MetalAddRMSNorm<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+        vec![
+            input_shapes[0].n_elements() * size_of::<T>(),
+            input_shapes[0].n_elements() * size_of::<T>(),
+        ]
+    }
+
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+        let row_size = inputs[0].1.dims().last().unwrap().to_usize().unwrap();
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(inputs[1].0), 0);
+        encoder.set_buffer(2, Some(inputs[2].0), 0);
+        encoder.set_buffer(3, Some(output_buffers[0]), 0);
+        encoder.set_buffer(4, Some(output_buffers[1]), 0);
+        encoder.set_i64(5, row_size as i64);
+        encoder.set_f32(6, self.epsilon);
+        encoder.set_i64(7, inputs[2].1.n_elements().to_usize().unwrap() as i64);
+        let batch_size = inputs[0]
+            .1
+            .dims()
+            .into_iter()
+            .take(inputs[0].1.len() - 1)
+            .map(|i| i.to_usize().unwrap())
+            .product::<usize>();
+        let mut nth = 32; // SIMD width
+        while nth < row_size / 4 && nth < 1024 {
+            nth *= 2;
+        }
+        encoder.set_threadgroup_memory_length(0, 32 * size_of::<f32>() as u64);
+        encoder.dispatch_thread_groups(
+            MTLSize {
+                width: batch_size as u64,
+                height: 1,
+                depth: 1,
+            },
+            MTLSize {
+                width: nth as u64,
+                height: 1,
+                depth: 1,
+            },
+        );
+        encoder.end_encoding();
+    }
+}
+
+impl<T: 'static + Clone> Operator for MetalAddRMSNorm<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&tensors, 3);
+        autoreleasepool(|| {
+            let command_buffer = self.queue.new_command_buffer();
+            let size = (tensors[0].1.n_elements().to_usize().unwrap() * size_of::<T>()) as u64;
+            let sum = self
+                .device
+                .new_buffer(size, MTLResourceOptions::StorageModeShared);
+            let out = self
+                .device
+                .new_buffer(size, MTLResourceOptions::StorageModeShared);
+
+            self.metal_forward(
+                &[
+                    (get_buffer_from_tensor(&tensors[0].0), tensors[0].1),
+                    (get_buffer_from_tensor(&tensors[1].0), tensors[1].1),
+                    (get_buffer_from_tensor(&tensors[2].0), tensors[2].1),
+                ],
+                command_buffer,
+                &[],
+                &[&sum, &out],
+            );
+
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+
+            vec![Tensor::new(MetalBuffer(sum)), Tensor::new(MetalBuffer(out))]
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        None
+    }
+}
+
+/// Fuse a residual add into the RMSNorm consuming it, so the norm doesn't wait on a separate add kernel.
+/// This is meant to be ran **after** the RMSNormCompiler.
+#[derive(Default, Debug)]
+pub struct AddRMSNormCompiler<T>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for AddRMSNormCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = Device::system_default().unwrap();
+        let queue = dev.new_command_queue();
+        // rms_norm(add(a, b), weight)
+        let (a, b) = (node(), node());
+        let add = binary::<MetalAdd<T>>(a.clone(), b.clone());
+        let norm = unary::<MetalRMSNorm<T>>(add.clone());
+
+        let mut s = norm.clone().search(graph);
+        while s.next_match() {
+            // Only the first output of a node can be retrieved, so the norm output must stay internal
+            if s.check_no_delete(&[a.id, b.id, add.id]) {
+                continue;
+            }
+            let (add, norm) = (s.get(&add), s.get(&norm));
+            let norm_srcs = graph.get_sources(norm);
+            let add_srcs = graph.get_sources(add);
+            // The sum must be the norm input, and every row is read as contiguous memory
+            if norm_srcs[0].0 != add
+                || norm_srcs[0].2.is_reshaped()
+                || add_srcs.iter().any(|(_, _, sh)| sh.is_reshaped())
+            {
+                continue;
+            }
+            let epsilon = graph.get_op::<MetalRMSNorm<T>>(norm).epsilon;
+            // The add inputs are contiguous, so they can be read in the norm's rows, which are groups for grouped norms
+            let rows_sh = norm_srcs[0].2;
+
+            let fused = graph
+                .add_op(MetalAddRMSNorm::<T>::new(
+                    epsilon,
+                    dev.clone(),
+                    queue.clone(),
+                ))
+                .input(add_srcs[0].0, add_srcs[0].1, rows_sh)
+                .input(add_srcs[1].0, add_srcs[1].1, rows_sh)
+                .input(norm_srcs[1].0, norm_srcs[1].1, norm_srcs[1].2)
+                .finish();
+
+            // The sum keeps feeding the rest of the residual stream
+            move_outgoing_edge_to_output(add, fused, 0, graph);
+            remap(add, fused, &mut ids, graph);
+            move_outgoing_edge_to_output(norm, fused, 1, graph);
+
+            graph.remove_node(norm);
+            graph.remove_node(add);
+            s.try_delete();
+        }
+    }
+}
+
+/// Softmax over `scores + mask` along a single axis, one thread per row, without materializing the masked scores.
+/// The max and sum of exponentials are accumulated in f32 whatever the storage type.
+///
+/// When `causal`, there's no mask input: keys past each query position are masked out by comparing indices in the
+/// kernel, with the softmax along the last axis and the queries along the one before it. Without a mask input and not
+/// `causal`, it's a plain softmax.
+#[derive(Clone)]
+pub struct MetalMaskedSoftmax<T> {
+    pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    pub dim: usize,
+    pub causal: bool,
+    masked: bool,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(MetalMaskedSoftmax);
+
+impl<T> PartialEq for MetalMaskedSoftmax<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.dim == other.dim && self.causal == other.causal && self.masked == other.masked
+    }
+}
+
+impl<T: MetalFloat> MetalMaskedSoftmax<T> {
+    /// Without a mask shape the softmax is causal if `causal` is set, and unmasked otherwise
+    pub fn new(
+        dim: usize,
+        a_shape: ShapeTracker,
+        b_shape: Option<ShapeTracker>,
+        causal: bool,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (a_idx_exp, a_valid_exp) = get_idx_valid_exps(a_shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(
+            &[Some(a_shape), b_shape]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>(),
+            6,
+        );
+        let type_name = T::type_name();
+        let (b_input, b_term) = if let Some(b_shape) = b_shape {
+            let (b_idx_exp, b_valid_exp) = get_idx_valid_exps(b_shape);
+            (
+                format!("device {type_name} *inp_b [[buffer(1)]], "),
+                format!("(({b_valid_exp}) == 0 ? 0.0 : (float)inp_b[{b_idx_exp}])"),
+            )
+        } else if causal {
+            // Each row is one query, and its keys are along the softmax axis
+            (
+                "device int& n_queries [[buffer(1)]], ".to_string(),
+                "(c_ > a_ % n_queries ? -INFINITY : 0.0)".to_string(),
+            )
+        } else {
+            (String::new(), "0.0".to_string())
+        };
+        let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+kernel void kernel_masked_softmax(device {type_name} *inp_a [[buffer(0)]], {b_input}device {type_name} *out [[buffer(2)]], device int& n_rows [[buffer(3)]], device int& back_size [[buffer(4)]], device int& dim_size [[buffer(5)]], uint i_ [[thread_position_in_grid]]{rendered}) {{
+    if (i_ < n_rows) {{
+        int a_ = i_ / back_size;
+        int b_ = i_ % back_size;
+        float max_value = -INFINITY;
+        for (int c_ = 0; c_ < dim_size; c_++) {{
+            int idx = a_ * dim_size * back_size + c_ * back_size + b_;
+            float x = (({a_valid_exp}) == 0 ? 0.0 : (float)inp_a[{a_idx_exp}]) + {b_term};
+            max_value = max(max_value, x);
+        }}
+        // Fully masked rows stay zero instead of going NaN
+        if (max_value == -INFINITY) {{
+            for (int c_ = 0; c_ < dim_size; c_++) {{
+                out[a_ * dim_size * back_size + c_ * back_size + b_] = 0.0;
+            }}
+            return;
+        }}
+        float sum = 0.0;
+        for (int c_ = 0; c_ < dim_size; c_++) {{
+            int idx = a_ * dim_size * back_size + c_ * back_size + b_;
+            float x = (({a_valid_exp}) == 0 ? 0.0 : (float)inp_a[{a_idx_exp}]) + {b_term};
+            sum += exp(x - max_value);
+        }}
+        for (int c_ = 0; c_ < dim_size; c_++) {{
+            int idx = a_ * dim_size * back_size + c_ * back_size + b_;
+            float x = (({a_valid_exp}) == 0 ? 0.0 : (float)inp_a[{a_idx_exp}]) + {b_term};
+            out[idx] = ({type_name})(exp(x - max_value) / sum);
+        }}
+    }}
+}}");
+
+        Self {
+            pipeline: compile_function("kernel_masked_softmax", &code, &device),
+            queue,
+            device,
+            dim,
+            causal: b_shape.is_none() && causal,
+            masked: b_shape.is_some(),
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T> MetalKernel for MetalMaskedSoftmax<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+        vec![input_shapes[0].n_elements() * size_of::<T>()]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let dims = inputs[0]
+            .1
+            .dims()
+            .into_iter()
+            .map(|i| i.to_usize().unwrap())
+            .collect::<Vec<_>>();
+        let front_size: usize = dims[..self.dim].iter().product();
+        let back_size: usize = dims[self.dim + 1..].iter().product();
+        let n_rows = front_size * back_size;
+
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        if self.causal {
+            encoder.set_u32(1, dims[self.dim - 1] as u32);
+        } else if self.masked {
+            encoder.set_buffer(1, Some(inputs[1].0), 0);
+        }
+        encoder.set_buffer(2, Some(output_buffers[0]), 0);
+        encoder.set_u32(3, n_rows as u32);
+        encoder.set_u32(4, back_size as u32);
+        encoder.set_u32(5, dims[self.dim] as u32);
+        input_dyn_dims(
+            &self.dyn_symbols,
+            unsafe { self.dyn_map.as_ref().unwrap() },
+            encoder,
+            6,
+        );
+
+        // Execute
+        encoder.dispatch_1d(n_rows);
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalMaskedSoftmax<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&tensors, if self.masked { 2 } else { 1 });
+        autoreleasepool(|| {
+            let command_buffer = self.queue.new_command_buffer();
+            let out = self.device.new_buffer(
+                (tensors[0].1.n_elements().to_usize().unwrap() * size_of::<T>()) as u64,
+                MTLResourceOptions::StorageModeShared,
+            );
+
+            self.metal_forward(
+                &tensors
+                    .iter()
+                    .map(|(t, sh)| (get_buffer_from_tensor(t), *sh))
+                    .collect::<Vec<_>>(),
+                command_buffer,
+                &[],
+                &[&out],
+            );
+
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+
+            vec![Tensor::new(MetalBuffer(out))]
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        None
+    }
+}
+
+/// Replace softmax(add(scores, mask)) with a single masked softmax kernel.
+/// A causal mask built from aranges, like `scaled_dot_product_attention`'s, is dropped and applied by index instead.
+/// This is meant to be ran **after** the MetalSubtractionCompiler, MetalExpCompiler and ARangeCompiler.
+#[derive(Default, Debug)]
+pub struct MaskedSoftmaxCompiler<T>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for MaskedSoftmaxCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = Device::system_default().unwrap();
+        let queue = dev.new_command_queue();
+        // mul(exp, recip(sum_reduce(exp))) where exp = exp(sub(x, max_reduce(x))) and x = add(scores, mask)
+        // The selector only matches trees, so the reuses of x and exp are checked below
+        let add = binary::<MetalAdd<T>>(node(), node());
+        let max = op::<MetalMaxReduce<T>>();
+        let sub = binary::<MetalSub<T>>(add.clone(), max.clone());
+        let exp = unary::<MetalExp<T>>(sub.clone());
+        let sum = unary::<MetalSumReduce<T>>(exp.clone());
+        let recip = unary::<MetalRecip<T>>(sum.clone());
+        let out = unary::<MetalMul<T>>(recip.clone());
+
+        let mut s = out.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[out.id]) {
+                // An intermediate node can't be deleted
+                continue;
+            }
+            if graph.get_sources(s.get(&max))[0].0 != s.get(&add)
+                || !graph
+                    .get_sources(s.get(&out))
+                    .iter()
+                    .any(|(i, _, _)| *i == s.get(&exp))
+            {
+                continue;
+            }
+            let dim = graph.get_op::<MetalMaxReduce<T>>(s.get(&max)).dim;
+            if graph.get_op::<MetalSumReduce<T>>(s.get(&sum)).dim != dim {
+                continue;
+            }
+            let shape = |a, b| {
+                graph
+                    .edges_connecting(a, b)
+                    .next()
+                    .unwrap()
+                    .weight()
+                    .as_data()
+                    .unwrap()
+                    .2
+            };
+            // Elementwise links must be plain reads, and the reductions must be broadcast back along the reduced axis
+            if [
+                (&add, &max),
+                (&add, &sub),
+                (&sub, &exp),
+                (&exp, &sum),
+                (&exp, &out),
+                (&recip, &out),
+            ]
+            .iter()
+            .any(|(a, b)| shape(s.get(*a), s.get(*b)).is_reshaped())
+            {
+                continue;
+            }
+            if [(&max, &sub), (&sum, &recip)].iter().any(|(a, b)| {
+                let mut sh = shape(s.get(*a), s.get(*b));
+                if !sh.fake[sh.indexes[dim]] {
+                    return true;
+                }
+                sh.remove_dim(dim);
+                sh.is_reshaped()
+            }) {
+                continue;
+            }
+
+            let add = s.get(&add);
+            let srcs = graph.get_sources(add);
+            let causal_mask = causal_mask_nodes::<T>(graph, &srcs[1], dim);
+            let mut masked_softmax = graph
+                .add_op(MetalMaskedSoftmax::<T>::new(
+                    dim,
+                    srcs[0].2,
+                    causal_mask.is_none().then_some(srcs[1].2),
+                    true,
+                    dev.clone(),
+                    queue.clone(),
+                    &graph.dyn_map,
+                ))
+                .input(srcs[0].0, srcs[0].1, srcs[0].2);
+            if causal_mask.is_none() {
+                masked_softmax = masked_softmax.input(srcs[1].0, srcs[1].1, srcs[1].2);
+            }
+            let masked_softmax = masked_softmax.finish();
+
+            // Create edges to dests
+            let out = s.get(&out);
+            move_outgoing_edge(out, masked_softmax, graph);
+            remap(out, masked_softmax, &mut ids, graph);
+
+            // Remove the old ops
+            graph.remove_node(out);
+            s.try_delete();
+            // The max reduce isn't linked to x in the selector, so x may have outlived it
+            graph.safe_remove_node(add, 0);
+            // Drop the causal mask's ops if nothing else reads them
+            for node in causal_mask.into_iter().flatten() {
+                if graph.graph.contains_node(node) && !graph.no_delete.contains(&node) {
+                    graph.safe_remove_node(node, 0);
+                }
+            }
+        }
+    }
+}
+
+/// Replace an unmasked softmax(x) with the masked softmax kernel, so its statistics are accumulated in f32.
+/// This is meant to be ran **after** the MaskedSoftmaxCompiler.
+#[derive(Default, Debug)]
+pub struct SoftmaxCompiler<T>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for SoftmaxCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = Device::system_default().unwrap();
+        let queue = dev.new_command_queue();
+        // mul(exp, recip(sum_reduce(exp))) where exp = exp(sub(x, max_reduce(x)))
+        let max = op::<MetalMaxReduce<T>>();
+        let sub = unary::<MetalSub<T>>(max.clone());
+        let exp = unary::<MetalExp<T>>(sub.clone());
+        let sum = unary::<MetalSumReduce<T>>(exp.clone());
+        let recip = unary::<MetalRecip<T>>(sum.clone());
+        let out = unary::<MetalMul<T>>(recip.clone());
+
+        let mut s = out.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[out.id]) {
+                // An intermediate node can't be deleted
+                continue;
+            }
+            // x must be read the same way by the max reduce and the subtraction
+            let x = graph.get_sources(s.get(&max))[0];
+            let sub_srcs = graph.get_sources(s.get(&sub));
+            if sub_srcs[0] != x
+                || sub_srcs[1].0 != s.get(&max)
+                || !graph
+                    .get_sources(s.get(&out))
+                    .iter()
+                    .any(|(i, _, _)| *i == s.get(&exp))
+            {
+                continue;
+            }
+            let dim = graph.get_op::<MetalMaxReduce<T>>(s.get(&max)).dim;
+            if graph.get_op::<MetalSumReduce<T>>(s.get(&sum)).dim != dim {
+                continue;
+            }
+            let shape = |a, b| {
+                graph
+                    .edges_connecting(a, b)
+                    .next()
+                    .unwrap()
+                    .weight()
+                    .as_data()
+                    .unwrap()
+                    .2
+            };
+            // Elementwise links must be plain reads, and the reductions must be broadcast back along the reduced axis
+            if [(&sub, &exp), (&exp, &sum), (&exp, &out), (&recip, &out)]
+                .iter()
+                .any(|(a, b)| shape(s.get(*a), s.get(*b)).is_reshaped())
+            {
+                continue;
+            }
+            if [(&max, &sub), (&sum, &recip)].iter().any(|(a, b)| {
+                let mut sh = shape(s.get(*a), s.get(*b));
+                if !sh.fake[sh.indexes[dim]] {
+                    return true;
+                }
+                sh.remove_dim(dim);
+                sh.is_reshaped()
+            }) {
+                continue;
+            }
+
+            let softmax = graph
+                .add_op(MetalMaskedSoftmax::<T>::new(
+                    dim,
+                    x.2,
+                    None,
+                    false,
+                    dev.clone(),
+                    queue.clone(),
+                    &graph.dyn_map,
+                ))
+                .input(x.0, x.1, x.2)
+                .finish();
+
+            // Create edges to dests
+            let out = s.get(&out);
+            move_outgoing_edge(out, softmax, graph);
+            remap(out, softmax, &mut ids, graph);
+
+            // Remove the old ops
+            graph.remove_node(out);
+            s.try_delete();
+        }
+    }
+}
+
+/// If `mask` (a source edge of the scores add) is `less_than(rows, cols) * f16::MIN` over aranges, broadcast over the
+/// leading dims of scores softmaxed along their last axis, returns its nodes in removal order
+fn causal_mask_nodes<T: MetalFloat>(
+    graph: &Graph,
+    (mask, _, mask_sh): &(NodeIndex, u8, ShapeTracker),
+    dim: usize,
+) -> Option<Vec<NodeIndex>> {
+    let n = mask_sh.len();
+    if n < 2 || dim != n - 1 || !graph.check_node_type::<MetalMul<T>>(*mask) {
+        return None;
+    }
+    // Only broadcast along the leading dims
+    let mut sh = *mask_sh;
+    for _ in 0..n - 2 {
+        if !sh.fake[sh.indexes[0]] {
+            return None;
+        }
+        sh.remove_dim(0);
+    }
+    if sh.is_reshaped() {
+        return None;
+    }
+    let mul_srcs = graph.get_sources(*mask);
+    let (lt, constant) = if graph.check_node_type::<MetalLessThan<T>>(mul_srcs[0].0) {
+        (&mul_srcs[0], &mul_srcs[1])
+    } else {
+        (&mul_srcs[1], &mul_srcs[0])
+    };
+    if !graph.check_node_type::<MetalLessThan<T>>(lt.0) || lt.2.is_reshaped() {
+        return None;
+    }
+    match graph.try_get_op::<MetalConstant<T>>(constant.0) {
+        Some(MetalConstant(ConstantValue::Float(f), ..)) if *f <= f16::MIN.to_f32() => {}
+        _ => return None,
+    }
+    // Rows are an arange broadcast along the keys (axis 1), columns one broadcast along the queries (axis 0)
+    let lt_srcs = graph.get_sources(lt.0);
+    for ((src, _, sh), broadcast) in lt_srcs.iter().zip([1, 0]) {
+        let mut sh = *sh;
+        if !graph.check_node_type::<MetalARange<T>>(*src) || sh.len() != 2 {
+            return None;
+        }
+        if !sh.fake[sh.indexes[broadcast]] {
+            return None;
+        }
+        sh.remove_dim(broadcast);
+        if sh.is_reshaped() {
+            return None;
+        }
+    }
+    Some(vec![*mask, lt.0, constant.0, lt_srcs[0].0, lt_srcs[1].0])
+}
+
+/// Max reduce along an axis, fused with `max_reduce(equal(x, max) * w)` along the same axis, in one pass over the input.
+///
+/// Outputs the max values and the largest weight held at a max, which `max_with_index` turns into the index.
+#[derive(Clone)]
+pub struct MetalMaxWithIndex<T> {
+    pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    pub dim: usize,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(MetalMaxWithIndex);
+
+impl<T> PartialEq for MetalMaxWithIndex<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.dim == other.dim
+    }
+}
+
+impl<T: MetalFloat> MetalMaxWithIndex<T> {
+    pub fn new(
+        dim: usize,
+        x_shape: ShapeTracker,
+        w_shape: ShapeTracker,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (x_idx_exp, x_valid_exp) = get_idx_valid_exps(x_shape);
+        let (w_idx_exp, w_valid_exp) = get_idx_valid_exps(w_shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[x_shape, w_shape], 7);
+        let type_name = T::type_name();
+        let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+kernel void kernel_max_with_index(device {type_name} *inp_x [[buffer(0)]], device {type_name} *inp_w [[buffer(1)]], device {type_name} *out_values [[buffer(2)]], device {type_name} *out_weights [[buffer(3)]], device int& n_rows [[buffer(4)]], device int& back_size [[buffer(5)]], device int& dim_size [[buffer(6)]], uint i_ [[thread_position_in_grid]]{rendered}) {{
+    if (i_ < n_rows) {{
+        int a_ = i_ / back_size;
+        int b_ = i_ % back_size;
+        float max_value = -INFINITY;
+        float weight = -INFINITY;
+        // Positions that don't hold the max contribute a weight of 0
+        bool others = false;
+        for (int c_ = 0; c_ < dim_size; c_++) {{
+            int idx = a_ * dim_size * back_size + c_ * back_size + b_;
+            float x = (({x_valid_exp}) == 0 ? 0.0 : (float)inp_x[{x_idx_exp}]);
+            float w = (({w_valid_exp}) == 0 ? 0.0 : (float)inp_w[{w_idx_exp}]);
+            if (x > max_value) {{
+                others = others || c_ > 0;
+                max_value = x;
+                weight = w;
+            }} else if (x == max_value) {{
+                weight = max(weight, w);
+            }} else {{
+                others = true;
+            }}
+        }}
+        out_values[i_] = ({type_name})max_value;
+        out_weights[i_] = ({type_name})(others ? max(weight, 0.0) : weight);
+    }}
+}}");
+
+        Self {
+            pipeline: compile_function("kernel_max_with_index", &code, &device),
+            queue,
+            device,
+            dim,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T> MetalKernel for MetalMaxWithIndex<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<Expression> {
+        let mut sh = input_shapes[0];
+        sh.remove_dim(self.dim);
+        vec![
+            sh.n_elements() * size_of::<T>(),
+            sh.n_elements() * size_of::<T>(),
+        ]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let dims = inputs[0]
+            .1
+            .dims()
+            .into_iter()
+            .map(|i| i.to_usize().unwrap())
+            .collect::<Vec<_>>();
+        let front_size: usize = dims[..self.dim].iter().product();
+        let back_size: usize = dims[self.dim + 1..].iter().product();
+        let n_rows = front_size * back_size;
+
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(inputs[1].0), 0);
+        encoder.set_buffer(2, Some(output_buffers[0]), 0);
+        encoder.set_buffer(3, Some(output_buffers[1]), 0);
+        encoder.set_u32(4, n_rows as u32);
+        encoder.set_u32(5, back_size as u32);
+        encoder.set_u32(6, dims[self.dim] as u32);
+        input_dyn_dims(
+            &self.dyn_symbols,
+            unsafe { self.dyn_map.as_ref().unwrap() },
+            encoder,
+            7,
+        );
+
+        // Execute
+        encoder.dispatch_1d(n_rows);
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalMaxWithIndex<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&tensors, 2);
+        autoreleasepool(|| {
+            let command_buffer = self.queue.new_command_buffer();
+            let mut sh = tensors[0].1;
+            sh.remove_dim(self.dim);
+            let size = (sh.n_elements().to_usize().unwrap() * size_of::<T>()) as u64;
+            let values = self
+                .device
+                .new_buffer(size, MTLResourceOptions::StorageModeShared);
+            let weights = self
+                .device
+                .new_buffer(size, MTLResourceOptions::StorageModeShared);
+
+            self.metal_forward(
+                &[
+                    (get_buffer_from_tensor(&tensors[0].0), tensors[0].1),
+                    (get_buffer_from_tensor(&tensors[1].0), tensors[1].1),
+                ],
+                command_buffer,
+                &[],
+                &[&values, &weights],
+            );
+
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+
+            vec![
+                Tensor::new(MetalBuffer(values)),
+                Tensor::new(MetalBuffer(weights)),
+            ]
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            #[allow(clippy::arc_with_non_send_sync)]
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        None
+    }
+}
+
+/// Fuse the max reduce and weighted one-hot max reduce built by `max_with_index` into a single kernel.
+/// This is meant to be ran **after** the MetalEqualCompiler.
+#[derive(Default, Debug)]
+pub struct MaxWithIndexCompiler<T>(PhantomData<T>);
+
+impl<T: MetalFloat> Compiler for MaxWithIndexCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let dev = Device::system_default().unwrap();
+        let queue = dev.new_command_queue();
+        // max_reduce(mul(equal(x, max_reduce(x)), w))
+        // The selector only matches trees, so the reuse of x is checked below. Parents are matched greedily from the
+        // last one given, so the wildcards go first
+        let (x, w) = (node(), node());
+        let max = op::<MetalMaxReduce<T>>();
+        let eq = binary::<MetalEqual<T>>(x.clone(), max.clone());
+        let mul = binary::<MetalMul<T>>(w.clone(), eq.clone());
+        let out = unary::<MetalMaxReduce<T>>(mul.clone());
+
+        let mut s = out.clone().search(graph);
+        while s.next_match() {
+            // Only the first output of a node can be retrieved, so the weighted max must stay internal
+            if s.check_no_delete(&[x.id, w.id, max.id]) {
+                continue;
+            }
+            let (max, out) = (s.get(&max), s.get(&out));
+            let dim = graph.get_op::<MetalMaxReduce<T>>(max).dim;
+            if graph.get_op::<MetalMaxReduce<T>>(out).dim != dim {
+                continue;
+            }
+            let shape = |a, b| {
+                graph
+                    .edges_connecting(a, b)
+                    .next()
+                    .unwrap()
+                    .weight()
+                    .as_data()
+                    .unwrap()
+            };
+            // The max must be taken over the same view of x that's compared against it
+            let x_edge = shape(s.get(&x), s.get(&eq));
+            if graph.get_sources(max)[0] != (s.get(&x), x_edge.1, x_edge.2) {
+                continue;
+            }
+            if [(s.get(&eq), s.get(&mul)), (s.get(&mul), out)]
+                .iter()
+                .any(|(a, b)| shape(*a, *b).2.is_reshaped())
+            {
+                continue;
+            }
+            // The max must be broadcast back along the reduced axis
+            let mut sh = shape(max, s.get(&eq)).2;
+            if !sh.fake[sh.indexes[dim]] {
+                continue;
+            }
+            sh.remove_dim(dim);
+            if sh.is_reshaped() {
+                continue;
+            }
+
+            let w_edge = shape(s.get(&w), s.get(&mul));
+            let fused = graph
+                .add_op(MetalMaxWithIndex::<T>::new(
+                    dim,
+                    x_edge.2,
+                    w_edge.2,
+                    dev.clone(),
+                    queue.clone(),
+                    &graph.dyn_map,
+                ))
+                .input(s.get(&x), x_edge.1, x_edge.2)
+                .input(s.get(&w), w_edge.1, w_edge.2)
+                .finish();
+
+            // Create edges to dests
+            move_outgoing_edge_to_output(max, fused, 0, graph);
+            remap(max, fused, &mut ids, graph);
+            move_outgoing_edge_to_output(out, fused, 1, graph);
+
+            // Remove the old ops
+            graph.remove_node(out);
+            graph.remove_node(max);
+            s.try_delete();
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct MetalExp<T> {
     pipeline: ComputePipelineState,
@@ -31,6 +31,15 @@ impl Data for MetalBuffer {
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
         self
     }
+
+    fn copy_data(&self) -> Option<Box<dyn Data>> {
+        // Cloning only bumps the buffer's reference count
+        Some(Box::new(MetalBuffer(self.device().new_buffer_with_data(
+            self.contents(),
+            self.length(),
+            MTLResourceOptions::StorageModeShared,
+        ))))
+    }
 }
 
 pub trait MetalFloat: Copy + Debug + PartialEq + 'static + Default {
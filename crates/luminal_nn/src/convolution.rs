@@ -1,13 +1,15 @@
 use luminal::prelude::*;
 
 pub struct Conv1D {
-    pub weight: GraphTensor, // ch_out, ch_in * kernel
+    pub weight: GraphTensor, // ch_out, ch_in / groups * kernel
     pub bias: Option<GraphTensor>,
     padding: usize,
     dilation: usize,
     stride: usize,
     kernel: usize,
+    groups: usize,
     ch_in: usize,
+    ch_out: usize,
 }
 
 impl Conv1D {
@@ -23,8 +25,31 @@ impl Conv1D {
         bias: bool,
         cx: &mut Graph,
     ) -> Self {
+        Self::new_grouped(
+            ch_in, ch_out, kernel, stride, dilation, padding, 1, bias, cx,
+        )
+    }
+
+    /// Create a new grouped 1D convolution layer, where each of the `groups` slices of input channels is convolved separately.
+    /// `groups == ch_in` gives a depthwise convolution.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_grouped(
+        ch_in: usize,
+        ch_out: usize,
+        kernel: usize,
+        stride: usize,
+        dilation: usize,
+        padding: usize,
+        groups: usize,
+        bias: bool,
+        cx: &mut Graph,
+    ) -> Self {
+        assert!(
+            groups > 0 && ch_in % groups == 0 && ch_out % groups == 0,
+            "Channels in ({ch_in}) and out ({ch_out}) must be divisible by groups ({groups})"
+        );
         Self {
-            weight: cx.named_tensor("Weight", (ch_out, ch_in * kernel)),
+            weight: cx.named_tensor("Weight", (ch_out, ch_in / groups * kernel)),
             bias: if bias {
                 Some(cx.named_tensor("Bias", ch_out))
             } else {
@@ -34,7 +59,9 @@ impl Conv1D {
             dilation,
             stride,
             kernel,
+            groups,
             ch_in,
+            ch_out,
         }
     }
 }
@@ -51,45 +78,28 @@ impl SerializeModule for Conv1D {
 impl Module<GraphTensor> for Conv1D {
     type Output = GraphTensor;
     fn forward(&self, input: GraphTensor) -> Self::Output {
-        assert_eq!(input.dims()[input.shape.len() - 2], self.ch_in);
         // Input: batch_dims, ch_in, dim_in
-        // Reshape to 2 batch dims
-        let n_expands = 4 - input.shape.len();
-        let mut inp = input;
-        for _ in 0..n_expands {
-            inp = inp.expand(0, 1);
-        }
-
-        let batch1 = inp.dims()[0];
-        let batch2 = inp.dims()[1];
-        let dim_in = *input.dims().last().unwrap();
-        let dim_out = (((dim_in + 2 * self.padding - self.dilation * (self.kernel - 1) - 1)
-            / self.stride)
-            + 1)
-        .simplify();
-        let w = self.weight.permute((1, 0));
-        let mut out = inp
-            // Add padding
-            .pad(((0, 0), (0, 0), (0, 0), (self.padding, 0)))
-            .contiguous()
-            .pad(((0, 0), (0, 0), (0, 0), (0, self.padding)))
-            // Pool
-            .pool_last_dim(self.kernel, self.stride, self.dilation)
-            // Combine channel_in and kernel
-            .permute((0, 1, 3, 2, 4))
-            .reshape((batch1, batch2, dim_out, self.ch_in * self.kernel))
-            .matmul(w)
-            .permute((0, 1, 3, 2));
+        assert_eq!(input.dims()[input.shape.len() - 2], self.ch_in);
+        let weight = self
+            .weight
+            .reshape((self.ch_out, self.ch_in / self.groups, self.kernel));
+        let mut out = input.conv1d(
+            weight,
+            self.stride,
+            self.padding,
+            self.dilation,
+            self.groups,
+        );
         if let Some(b) = self.bias {
-            out += b.expand_to(out.shape);
+            // Bias is per output channel, broadcast over the batch and length dims
+            let n = out.shape.len();
+            let mut b = b.expand(1, out.dims()[n - 1]);
+            for (i, dim) in out.dims()[..n - 2].iter().enumerate() {
+                b = b.expand(i, *dim);
+            }
+            out += b;
         }
-
-        // Reshape back to original shape
-        let mut final_shape = out.dims();
-        for _ in 0..n_expands {
-            final_shape.remove(0);
-        }
-        out.reshape(final_shape) // Output: batch_dims, ch_out, dim_out
+        out // Output: batch_dims, ch_out, dim_out
     }
 }
 
@@ -339,6 +349,122 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_conv1d_grouped() {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        const CH_IN: usize = 6;
+        const CH_OUT: usize = 4;
+        const GROUPS: usize = 2;
+        const KERNEL: usize = 3;
+        const DIM_IN: usize = 9;
+        let kernel_data = random_vec_rng(CH_OUT * CH_IN / GROUPS * KERNEL, &mut rng);
+        let bias_data = random_vec_rng(CH_OUT, &mut rng);
+        let input_data = random_vec_rng(2 * CH_IN * DIM_IN, &mut rng);
+
+        let model = Conv1D::new_grouped(CH_IN, CH_OUT, KERNEL, 2, 1, 1, GROUPS, true, &mut cx);
+        model.weight.set(kernel_data.clone());
+        model.bias.unwrap().set(bias_data.clone());
+        let inp1 = cx.tensor((2, CH_IN, DIM_IN)).set(input_data.clone());
+        let out1 = model.forward(inp1).retrieve();
+        cx.execute();
+
+        let input = Tensor::from_vec(input_data, (2, CH_IN, DIM_IN), &Device::Cpu).unwrap();
+        let kernel =
+            Tensor::from_vec(kernel_data, (CH_OUT, CH_IN / GROUPS, KERNEL), &Device::Cpu).unwrap();
+        let bias = Tensor::from_vec(bias_data, (1, CH_OUT, 1), &Device::Cpu).unwrap();
+        let output = input
+            .conv1d(&kernel, 1, 2, 1, GROUPS)
+            .unwrap()
+            .broadcast_add(&bias)
+            .unwrap();
+
+        assert_eq!(
+            out1.dims(),
+            vec![
+                Expression::from(2),
+                Expression::from(CH_OUT),
+                Expression::from(5)
+            ]
+        );
+        assert_close(
+            &out1.data(),
+            &output.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_conv1d_depthwise() {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(1);
+
+        const CH: usize = 5;
+        const KERNEL: usize = 3;
+        const DIM_IN: usize = 8;
+        let kernel_data = random_vec_rng(CH * KERNEL, &mut rng);
+        let input_data = random_vec_rng(CH * DIM_IN, &mut rng);
+
+        let model = Conv1D::new_grouped(CH, CH, KERNEL, 1, 1, 2, CH, false, &mut cx);
+        model.weight.set(kernel_data.clone());
+        let inp1 = cx
+            .tensor((CH, 's'))
+            .set_dyn(input_data.clone(), (CH, DIM_IN));
+        let out1 = model.forward(inp1).retrieve();
+        cx.execute();
+
+        let input = Tensor::from_vec(input_data, (1, CH, DIM_IN), &Device::Cpu).unwrap();
+        let kernel = Tensor::from_vec(kernel_data, (CH, 1, KERNEL), &Device::Cpu).unwrap();
+        let output = input.conv1d(&kernel, 2, 1, 1, CH).unwrap();
+
+        assert_close(
+            &out1.data(),
+            &output.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_conv1d_output_length() {
+        const CH_IN: usize = 2;
+        const CH_OUT: usize = 2;
+        const KERNEL: usize = 3;
+        const DIM_IN: usize = 11;
+        let mut rng = StdRng::seed_from_u64(2);
+        let kernel_data = random_vec_rng(CH_OUT * CH_IN * KERNEL, &mut rng);
+        let input_data = random_vec_rng(CH_IN * DIM_IN, &mut rng);
+        let input = Tensor::from_vec(input_data.clone(), (1, CH_IN, DIM_IN), &Device::Cpu).unwrap();
+        let kernel =
+            Tensor::from_vec(kernel_data.clone(), (CH_OUT, CH_IN, KERNEL), &Device::Cpu).unwrap();
+
+        let mut cx = Graph::new();
+        for stride in 1..=3 {
+            for padding in 0..=2 {
+                for dilation in 1..=3 {
+                    let model = Conv1D::new(
+                        CH_IN, CH_OUT, KERNEL, stride, dilation, padding, false, &mut cx,
+                    );
+                    model.weight.set(kernel_data.clone());
+                    let inp = cx.tensor((CH_IN, DIM_IN)).set(input_data.clone());
+                    let out = model.forward(inp).retrieve();
+                    cx.execute();
+
+                    let expected = input.conv1d(&kernel, padding, stride, dilation, 1).unwrap();
+                    let dim_out = (DIM_IN + 2 * padding - dilation * (KERNEL - 1) - 1) / stride + 1;
+                    assert_eq!(expected.dims3().unwrap().2, dim_out);
+                    assert_eq!(
+                        out.shape.n_elements().to_usize().unwrap(),
+                        CH_OUT * dim_out,
+                        "stride {stride}, padding {padding}, dilation {dilation}"
+                    );
+                    assert_close(
+                        &out.data(),
+                        &expected.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+                    );
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_conv1d() {
         let mut cx = Graph::new();
@@ -0,0 +1,63 @@
+use luminal::prelude::*;
+
+/// Stochastic depth (DropPath) from [*Deep Networks with Stochastic Depth*](https://arxiv.org/abs/1603.09382).
+///
+/// Zeroes the whole output of a residual branch for each sample with probability `p`, rescaling kept samples.
+/// Set `eval` to build the inference graph, where it's the identity.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct DropPath {
+    pub p: f32,
+    /// Pass the input through untouched, for inference
+    pub eval: bool,
+}
+
+impl DropPath {
+    pub fn new(p: f32) -> Self {
+        Self { p, eval: false }
+    }
+}
+
+impl SerializeModule for DropPath {
+    fn serialize(&self, _: &mut Serializer) {}
+}
+
+impl Module<GraphTensor> for DropPath {
+    type Output = GraphTensor;
+
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        if self.p > 0. && !self.eval {
+            input.drop_path(self.p)
+        } else {
+            input
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DropPath;
+    use luminal::{prelude::*, tests::assert_exact};
+
+    #[test]
+    fn test_drop_path_disabled() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 3)).set(vec![1., 2., 3., 4., 5., 6.]);
+        let b = DropPath::new(0.).forward(a).retrieve();
+        cx.execute();
+
+        assert_exact(&b.data(), &[1., 2., 3., 4., 5., 6.]);
+    }
+
+    #[test]
+    fn test_drop_path_eval() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((40, 3)).set(vec![1.; 120]);
+        let b = DropPath { p: 0.5, eval: true }.forward(a).retrieve();
+        let c = DropPath::new(0.5).forward(a).retrieve();
+        cx.execute();
+
+        // The same drop probability zeroes some samples in training, but nothing in eval
+        assert_exact(&b.data(), &[1.; 120]);
+        assert!(c.data().contains(&0.));
+    }
+}
@@ -1,7 +1,7 @@
 use luminal::{prelude::*, tests::random_vec};
 
 pub struct Embedding {
-    permute: bool,
+    pub(crate) permute: bool,
     pub weight: GraphTensor, // n embeddings x embedding dim
     embedding_dim: usize,
 }
@@ -0,0 +1,99 @@
+use luminal::prelude::*;
+
+use crate::{GeLU, Linear, Sigmoid, Swish};
+
+/// A gated linear unit: `activation(gate_proj(x)) * up_proj(x)`, with the flavor picked by the activation
+#[derive(SerializeModule)]
+pub struct GLU<A = Sigmoid> {
+    pub gate_proj: Linear, // inp -> out
+    pub up_proj: Linear,   // inp -> out
+    #[serialize(skip)]
+    pub activation: A,
+}
+
+/// GLU gated with swish (SiLU), as in Llama and Mistral MLPs
+pub type SwiGLU = GLU<Swish>;
+/// GLU gated with GeLU, as in Gemma MLPs
+pub type GeGLU = GLU<GeLU>;
+
+impl<A: Default> GLU<A> {
+    pub fn new(inp: usize, out: usize, cx: &mut Graph) -> Self {
+        Self {
+            gate_proj: Linear::new_permuted(inp, out, false, cx),
+            up_proj: Linear::new_permuted(inp, out, false, cx),
+            activation: A::default(),
+        }
+    }
+
+    pub fn initialize(self) -> Self {
+        Self {
+            gate_proj: self.gate_proj.initialize(),
+            up_proj: self.up_proj.initialize(),
+            activation: self.activation,
+        }
+    }
+}
+
+impl<A: Module<GraphTensor, Output = GraphTensor>> Module<GraphTensor> for GLU<A> {
+    type Output = GraphTensor;
+
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        self.activation.forward(self.gate_proj.forward(input)) * self.up_proj.forward(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GeGLU, SwiGLU, GLU};
+    use crate::Sigmoid;
+    use luminal::prelude::Module;
+    luminal::test_imports!();
+
+    #[test]
+    fn test_glu_flavors() {
+        let mut cx = Graph::new();
+        let inp = cx.tensor((2, 3)).set(random_vec(6));
+        let glu = GLU::<Sigmoid>::new(3, 4, &mut cx).initialize();
+        let swiglu = SwiGLU::new(3, 4, &mut cx).initialize();
+        let geglu = GeGLU::new(3, 4, &mut cx).initialize();
+        let sigmoid = |x: f32| 1. / (1. + (-x).exp());
+        let flavors: [(
+            GraphTensor,
+            GraphTensor,
+            GraphTensor,
+            Box<dyn Fn(f32) -> f32>,
+        ); 3] = [
+            (
+                glu.forward(inp).retrieve(),
+                glu.gate_proj.forward(inp).retrieve(),
+                glu.up_proj.forward(inp).retrieve(),
+                Box::new(sigmoid),
+            ),
+            (
+                swiglu.forward(inp).retrieve(),
+                swiglu.gate_proj.forward(inp).retrieve(),
+                swiglu.up_proj.forward(inp).retrieve(),
+                Box::new(move |x| x * sigmoid(x)),
+            ),
+            (
+                geglu.forward(inp).retrieve(),
+                geglu.gate_proj.forward(inp).retrieve(),
+                geglu.up_proj.forward(inp).retrieve(),
+                Box::new(|x: f32| {
+                    0.5 * x * (1. + (0.7978845608 * x * (1. + 0.044715 * x * x)).tanh())
+                }),
+            ),
+        ];
+        cx.execute();
+
+        for (out, gate, up, activation) in flavors {
+            let expected = gate
+                .data()
+                .into_iter()
+                .zip(up.data())
+                .map(|(g, u)| activation(g) * u)
+                .collect::<Vec<_>>();
+            assert_close(&out.data(), &expected);
+        }
+    }
+}
@@ -2,10 +2,18 @@ mod activation;
 pub use activation::*;
 mod convolution;
 pub use convolution::*;
+mod drop_path;
+pub use drop_path::*;
 mod embedding;
 pub use embedding::*;
+mod glu;
+pub use glu::*;
 mod linear;
 pub use linear::*;
+mod lora;
+pub use lora::*;
+mod moe;
+pub use moe::*;
 mod norm;
 pub use norm::*;
 mod transformer;
@@ -2,6 +2,8 @@ use rand::{thread_rng, Rng};
 
 use luminal::prelude::*;
 
+use crate::Embedding;
+
 /// A simple unbiased linear layer
 pub struct Linear {
     pub weight: GraphTensor,
@@ -34,6 +36,26 @@ impl Linear {
         }
     }
 
+    /// An unbiased layer around an existing (inp, out) weight, like a slice of a larger fused weight
+    pub fn from_weight(weight: GraphTensor) -> Self {
+        Self {
+            weight,
+            bias: None,
+            permute: false,
+        }
+    }
+
+    /// An unbiased output projection tied to an embedding table, mapping hidden states back to logits over it.
+    /// The weight is shared rather than copied, so it's only stored and loaded once.
+    pub fn tied(embedding: &Embedding) -> Self {
+        Self {
+            weight: embedding.weight,
+            bias: None,
+            // Embedding tables are (n, dim) unless permuted, and this projects dim -> n
+            permute: !embedding.permute,
+        }
+    }
+
     pub fn initialize(self) -> Self {
         // Init weight as uniform(-1, 1)
         let mut rng = thread_rng();
@@ -59,11 +81,11 @@ impl Module<GraphTensor> for Linear {
     type Output = GraphTensor;
 
     fn forward(&self, input: GraphTensor) -> Self::Output {
-        let mut output = input.matmul(if self.permute {
-            self.weight.permute((1, 0))
+        let mut output = if self.permute {
+            input.matmul_transposed_b(self.weight)
         } else {
-            self.weight
-        });
+            input.matmul(self.weight)
+        };
         if let Some(bias) = self.bias {
             output += bias.expand_to(output.shape);
         }
@@ -74,6 +96,7 @@ impl Module<GraphTensor> for Linear {
 #[cfg(test)]
 mod tests {
     use super::Linear;
+    use crate::Embedding;
     use luminal::{prelude::*, tests::assert_close};
     #[test]
     fn test_linear() {
@@ -96,4 +119,43 @@ mod tests {
         assert_close(&unoptimized_b, &b.data());
         assert_close(&unoptimized_batch_out, &batch_out.data());
     }
+
+    #[test]
+    fn test_parameters() {
+        let mut cx = Graph::new();
+        let model = (
+            Linear::new(3, 4, true, &mut cx),
+            Linear::new(4, 2, false, &mut cx),
+        );
+        let parameters = model.parameters();
+        assert_eq!(
+            parameters.iter().map(|p| p.id).collect::<Vec<_>>(),
+            params(&model)
+        );
+        let count = parameters
+            .iter()
+            .map(|p| p.shape.n_elements().to_usize().unwrap())
+            .sum::<usize>();
+        assert_eq!(count, 3 * 4 + 4 + 4 * 2);
+    }
+
+    #[test]
+    fn test_tied_embedding() {
+        let mut cx = Graph::new();
+        let embedding = Embedding::new(5, 3, &mut cx).initialize();
+        let (table, head) = (embedding.weight, Linear::tied(&embedding));
+        let model = (embedding, head);
+        let inp = cx.tensor(2).set([1., 4.]);
+        let out = model.forward(inp).retrieve();
+        let hidden = model.0.forward(inp).retrieve();
+        let expected = hidden.matmul(table.permute((1, 0))).retrieve();
+        cx.execute();
+
+        assert_close(&out.data(), &expected.data());
+        // The shared table is only serialized (and loaded) once, under the embedding's name
+        assert_eq!(
+            param_dict(&model).into_iter().collect::<Vec<_>>(),
+            [("0/weight".to_string(), table.id)]
+        );
+    }
 }
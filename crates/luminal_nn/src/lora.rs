@@ -0,0 +1,165 @@
+use rand::{thread_rng, Rng};
+
+use crate::Linear;
+use luminal::prelude::*;
+
+/// The trainable low rank factors of a LoRA adapter, as laid out in [*LoRA: Low-Rank Adaptation of Large Language Models*](https://arxiv.org/abs/2106.09685).
+pub struct LoRAAdapter {
+    /// Down projection, rank x in
+    pub a: GraphTensor,
+    /// Up projection, out x rank
+    pub b: GraphTensor,
+    /// Scale applied to the adapter output, usually alpha / rank
+    pub scaling: f32,
+}
+
+impl LoRAAdapter {
+    pub fn new(inp: usize, out: usize, rank: usize, alpha: f32, cx: &mut Graph) -> Self {
+        Self {
+            a: cx.named_tensor("LoRA A", (rank, inp)),
+            b: cx.named_tensor("LoRA B", (out, rank)),
+            scaling: alpha / rank as f32,
+        }
+    }
+
+    pub fn initialize(self) -> Self {
+        // Init A as uniform(-1/sqrt(in), 1/sqrt(in)) and B as zeros so the adapter starts as a no-op
+        let mut rng = thread_rng();
+        let bound = 1. / (self.a.dims()[1].to_usize().unwrap() as f32).sqrt();
+        self.a.set(
+            (0..self.a.shape.n_elements().to_usize().unwrap())
+                .map(|_| rng.gen_range(-bound..bound))
+                .collect::<Vec<_>>(),
+        );
+        self.b
+            .set(vec![0.; self.b.shape.n_elements().to_usize().unwrap()]);
+        self
+    }
+}
+
+impl SerializeModule for LoRAAdapter {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("a", self.a);
+        s.tensor("b", self.b);
+    }
+}
+
+impl Module<GraphTensor> for LoRAAdapter {
+    type Output = GraphTensor;
+
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        input
+            .matmul_transposed_b(self.a)
+            .matmul_transposed_b(self.b)
+            * self.scaling
+    }
+}
+
+/// A frozen linear layer with a low rank adapter: `base(x) + scaling * (x @ A^T) @ B^T`
+///
+/// The adapter serializes under its own `lora` prefix, so `params(&layer.adapter)` can be saved and loaded without the base weights.
+pub struct LoRALinear {
+    pub base: Linear,
+    pub adapter: LoRAAdapter,
+}
+
+impl LoRALinear {
+    pub fn new(
+        inp: usize,
+        out: usize,
+        rank: usize,
+        alpha: f32,
+        bias: bool,
+        cx: &mut Graph,
+    ) -> Self {
+        Self {
+            base: Linear::new(inp, out, bias, cx),
+            adapter: LoRAAdapter::new(inp, out, rank, alpha, cx),
+        }
+    }
+
+    /// Wrap an existing linear layer with a new adapter
+    pub fn from_linear(base: Linear, rank: usize, alpha: f32, cx: &mut Graph) -> Self {
+        let (inp, out) = base.weight.dims2();
+        let (inp, out) = (inp.to_usize().unwrap(), out.to_usize().unwrap());
+        Self {
+            adapter: LoRAAdapter::new(inp, out, rank, alpha, cx),
+            base,
+        }
+    }
+}
+
+impl SerializeModule for LoRALinear {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("base", &self.base);
+        s.module("lora", &self.adapter);
+    }
+}
+
+impl Module<GraphTensor> for LoRALinear {
+    type Output = GraphTensor;
+
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        self.base.forward(input) + self.adapter.forward(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoRALinear;
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
+
+    #[test]
+    fn test_lora_linear() {
+        let mut cx = Graph::new();
+        let model = LoRALinear::new(3, 4, 2, 4., false, &mut cx);
+        let (w, a, b) = (random_vec(12), random_vec(6), random_vec(8));
+        model.base.weight.set(w.clone());
+        model.adapter.a.set(a.clone());
+        model.adapter.b.set(b.clone());
+        let inp = random_vec(6);
+        let x = cx.tensor((2, 3)).set(inp.clone());
+        let out = model.forward(x).retrieve();
+        cx.execute();
+
+        // x @ (W + scaling * A^T @ B^T), with scaling = alpha / rank = 2
+        let mut expected = vec![0.; 8];
+        for r in 0..2 {
+            for o in 0..4 {
+                for i in 0..3 {
+                    let lora = (0..2).map(|k| a[k * 3 + i] * b[o * 2 + k]).sum::<f32>();
+                    expected[r * 4 + o] += inp[r * 3 + i] * (w[i * 4 + o] + 2. * lora);
+                }
+            }
+        }
+        assert_close(&out.data(), &expected);
+    }
+
+    #[test]
+    fn test_lora_initialize_and_params() {
+        let mut cx = Graph::new();
+        let model = LoRALinear::new(3, 4, 2, 4., false, &mut cx);
+        model.base.weight.set(random_vec(12));
+        let model = LoRALinear {
+            adapter: model.adapter.initialize(),
+            ..model
+        };
+        let x = cx.tensor((2, 3)).set(random_vec(6));
+        let out = model.forward(x).retrieve();
+        let base_out = model.base.forward(x).retrieve();
+        cx.execute();
+
+        // Zero B means the adapter starts as a no-op
+        assert_close(&out.data(), &base_out.data());
+
+        let mut names = param_dict(&model.adapter).into_keys().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["a", "b"]);
+        let mut names = param_dict(&model).into_keys().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["base/weight", "lora/a", "lora/b"]);
+    }
+}
@@ -0,0 +1,144 @@
+use luminal::prelude::*;
+
+use crate::{Linear, SwiGLU};
+
+/// A gated (SwiGLU) feed-forward block, as used in Llama and Mixtral
+#[derive(SerializeModule)]
+pub struct Mlp {
+    /// Gate and up projections, hidden -> intermediate. Flattened so their weights keep their own names.
+    #[serialize(rename = "")]
+    pub glu: SwiGLU,
+    pub down_proj: Linear, // intermediate -> hidden
+}
+
+impl Mlp {
+    pub fn new(hidden: usize, intermediate: usize, cx: &mut Graph) -> Self {
+        Self {
+            glu: SwiGLU::new(hidden, intermediate, cx),
+            down_proj: Linear::new_permuted(intermediate, hidden, false, cx),
+        }
+    }
+
+    pub fn initialize(self) -> Self {
+        Self {
+            glu: self.glu.initialize(),
+            down_proj: self.down_proj.initialize(),
+        }
+    }
+}
+
+impl Module<GraphTensor> for Mlp {
+    type Output = GraphTensor;
+
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        self.down_proj.forward(self.glu.forward(input))
+    }
+}
+
+/// A sparse mixture of experts, like Mixtral's: a router picks the `top_k` experts for each token, and the expert
+/// outputs are summed weighted by a softmax over the picked router logits.
+///
+/// Dispatch is currently dense: every expert runs on every token, and the unpicked experts are weighted by zero.
+/// Sparse dispatch, gathering each expert's tokens so it only runs on those, is future work.
+#[derive(SerializeModule)]
+pub struct MoE {
+    #[serialize(rename = "gate")]
+    pub router: Linear, // hidden -> experts
+    pub experts: Vec<Mlp>,
+    #[serialize(skip)]
+    pub top_k: usize,
+}
+
+impl MoE {
+    pub fn new(
+        hidden: usize,
+        intermediate: usize,
+        experts: usize,
+        top_k: usize,
+        cx: &mut Graph,
+    ) -> Self {
+        assert!(
+            top_k > 0 && top_k <= experts,
+            "Can't route to {top_k} of {experts} experts"
+        );
+        Self {
+            router: Linear::new_permuted(hidden, experts, false, cx),
+            experts: (0..experts)
+                .map(|_| Mlp::new(hidden, intermediate, cx))
+                .collect(),
+            top_k,
+        }
+    }
+
+    pub fn initialize(self) -> Self {
+        Self {
+            router: self.router.initialize(),
+            experts: self.experts.into_iter().map(Mlp::initialize).collect(),
+            top_k: self.top_k,
+        }
+    }
+}
+
+impl Module<GraphTensor> for MoE {
+    type Output = GraphTensor;
+
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        // Input: batch_dims, hidden
+        let last = input.shape.last_axis();
+        let hidden = input.dims()[last];
+        let gates = self.router.forward(input).top_k_gate(self.top_k);
+        self.experts
+            .iter()
+            .enumerate()
+            .map(|(i, expert)| {
+                let gate = gates.slice_along(i..i + 1, last).squeeze(last);
+                expert.forward(input) * gate.expand(last, hidden)
+            })
+            .reduce(|a, b| a + b)
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MoE;
+    use luminal::prelude::Module;
+    luminal::test_imports!();
+
+    #[test]
+    fn test_moe() {
+        let mut cx = Graph::new();
+        let model = MoE::new(4, 8, 4, 2, &mut cx).initialize();
+        let inp = cx.tensor((3, 4)).set(random_vec(3 * 4));
+        let out = model.forward(inp).retrieve();
+        let logits = model.router.forward(inp).retrieve();
+        let expert_outs = model
+            .experts
+            .iter()
+            .map(|e| e.forward(inp).retrieve())
+            .collect::<Vec<_>>();
+        cx.execute();
+
+        // Combine the top 2 experts of each token by hand
+        let (logits, expert_outs) = (
+            logits.data(),
+            expert_outs.iter().map(|e| e.data()).collect::<Vec<_>>(),
+        );
+        let mut expected = vec![0.; 3 * 4];
+        for (t, row) in logits.chunks(4).enumerate() {
+            let mut order = (0..4).collect::<Vec<_>>();
+            order.sort_by(|a, b| row[*b].total_cmp(&row[*a]));
+            let exps = order[..2]
+                .iter()
+                .map(|e| (*e, (row[*e] - row[order[0]]).exp()))
+                .collect::<Vec<_>>();
+            let sum = exps.iter().map(|(_, v)| v).sum::<f32>();
+            for (e, v) in exps {
+                for h in 0..4 {
+                    expected[t * 4 + h] += expert_outs[e][t * 4 + h] * v / sum;
+                }
+            }
+        }
+        assert_close(&out.data(), &expected);
+    }
+}
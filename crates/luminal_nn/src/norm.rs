@@ -56,10 +56,11 @@ impl LayerNorm {
 impl Module<GraphTensor> for LayerNorm {
     type Output = GraphTensor;
     fn forward(&self, mut input: GraphTensor) -> Self::Output {
-        if self.mean_norm {
-            input = input.mean_norm(input.shape.last_axis());
-        }
-        input = input.std_norm(input.shape.last_axis(), self.epsilon);
+        input = if self.mean_norm {
+            input.layer_norm(input.shape.last_axis(), self.epsilon)
+        } else {
+            input.std_norm(input.shape.last_axis(), self.epsilon)
+        };
         if let Some(w) = self.weight {
             input *= w.expand_to(input.shape);
         }
@@ -80,3 +81,173 @@ impl SerializeModule for LayerNorm {
         }
     }
 }
+
+/// A root-mean-square norm with a learned scale, as used in Llama-style models.
+///
+/// The mean of squares is taken over the last axis with the RMS convention of dividing by `N` (not `N - 1`).
+pub struct RMSNorm {
+    pub weight: GraphTensor,
+    pub epsilon: f32,
+    /// Number of equal blocks of the last axis normalized independently
+    pub groups: usize,
+}
+
+impl RMSNorm {
+    pub fn new(dim: usize, epsilon: f32, cx: &mut Graph) -> Self {
+        Self {
+            weight: cx.named_tensor("RMSNorm Weight", dim),
+            epsilon,
+            groups: 1,
+        }
+    }
+
+    /// An RMSNorm that normalizes `groups` equal blocks of `dim` separately, sharing one weight across the full `dim`
+    pub fn new_grouped(dim: usize, groups: usize, epsilon: f32, cx: &mut Graph) -> Self {
+        assert!(
+            dim % groups == 0,
+            "RMSNorm dim {dim} can't be split into {groups} groups"
+        );
+        Self {
+            groups,
+            ..Self::new(dim, epsilon, cx)
+        }
+    }
+
+    pub fn initialize(self) -> Self {
+        // Init the gain as ones, so a fresh norm only rescales to unit RMS
+        self.weight
+            .set(vec![1.; self.weight.shape.n_elements().to_usize().unwrap()]);
+        self
+    }
+}
+
+impl Module<GraphTensor> for RMSNorm {
+    type Output = GraphTensor;
+    fn forward(&self, input: GraphTensor) -> Self::Output {
+        if self.groups == 1 {
+            input.rms_norm(self.weight, self.epsilon)
+        } else {
+            input.rms_norm_grouped(self.weight, self.groups, self.epsilon)
+        }
+    }
+}
+
+impl SerializeModule for RMSNorm {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("weight", self.weight);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LayerNorm, RMSNorm};
+    use luminal::prelude::Module;
+    use rand::{rngs::StdRng, SeedableRng};
+    luminal::test_imports!();
+
+    /// Reference RMSNorm over the last axis of a row-major (rows, dim) buffer
+    fn reference_rms_norm(x: &[f32], weight: &[f32], epsilon: f32) -> Vec<f32> {
+        x.chunks(weight.len())
+            .flat_map(|row| {
+                let mean_sq = row.iter().map(|v| v * v).sum::<f32>() / row.len() as f32;
+                let scale = (mean_sq + epsilon).sqrt().recip();
+                row.iter()
+                    .zip(weight)
+                    .map(move |(v, w)| v * scale * w)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rms_norm() {
+        let mut cx = Graph::new();
+        let model = RMSNorm::new(4, 1e-6, &mut cx);
+        let weight = random_vec(4);
+        model.weight.set(weight.clone());
+        let inp = cx.tensor((2, 's', 4));
+        let out = model.forward(inp).retrieve();
+        for seq in [1, 3, 7] {
+            let inp_data = random_vec(2 * seq * 4);
+            inp.set_dyn(inp_data.clone(), (2, seq, 4));
+            cx.execute();
+
+            assert_close(&out.data(), &reference_rms_norm(&inp_data, &weight, 1e-6));
+            out.drop();
+        }
+    }
+
+    #[test]
+    fn test_rms_norm_initialize() {
+        let mut cx = Graph::new();
+        let model = RMSNorm::new(4, 1e-6, &mut cx).initialize();
+        let inp_data = vec![1., -2., 3., -4.];
+        let inp = cx.tensor(4).set(inp_data.clone());
+        let out = model.forward(inp).retrieve();
+        cx.execute();
+
+        assert_close(&out.data(), &reference_rms_norm(&inp_data, &[1.; 4], 1e-6));
+    }
+
+    #[test]
+    fn test_rms_norm_epsilon() {
+        // A large epsilon should dominate the mean of squares for small inputs
+        let mut cx = Graph::new();
+        let model = RMSNorm::new(3, 1.0, &mut cx);
+        model.weight.set(vec![1., 1., 1.]);
+        let inp_data = vec![0.1, -0.2, 0.3];
+        let inp = cx.tensor(3).set(inp_data.clone());
+        let out = model.forward(inp).retrieve();
+        cx.execute();
+
+        assert_close(
+            &out.data(),
+            &reference_rms_norm(&inp_data, &[1., 1., 1.], 1.0),
+        );
+    }
+
+    #[test]
+    fn test_grouped_rms_norm() {
+        let mut cx = Graph::new();
+        let model = RMSNorm::new_grouped(8, 2, 1e-6, &mut cx);
+        let weight = random_vec(8);
+        model.weight.set(weight.clone());
+        let inp_data = random_vec(3 * 8);
+        let inp = cx.tensor((3, 8)).set(inp_data.clone());
+        let out = model.forward(inp).retrieve();
+        cx.execute();
+
+        // Each group normalizes on its own, then the full weight scales the row
+        let expected = reference_rms_norm(&inp_data, &[1.; 4], 1e-6)
+            .chunks(8)
+            .flat_map(|row| row.iter().zip(&weight).map(|(v, w)| v * w))
+            .collect::<Vec<_>>();
+        assert_close(&out.data(), &expected);
+    }
+
+    #[test]
+    fn test_layer_norm_offset_input() {
+        let mut cx = Graph::new();
+        let model = LayerNorm::new(4096, false, false, true, 1e-5, &mut cx);
+        // Offset with a small spread, so the mean has to be taken precisely for the variance to come out right
+        let inp_data = random_vec_rng(4096, &mut StdRng::seed_from_u64(0))
+            .into_iter()
+            .map(|v| 30. + v)
+            .collect::<Vec<_>>();
+        let inp = cx.tensor(4096).set(inp_data.clone());
+        let out = model.forward(inp).retrieve();
+        cx.execute();
+
+        let mean = inp_data.iter().map(|v| *v as f64).sum::<f64>() / 4096.;
+        let var = inp_data
+            .iter()
+            .map(|v| (*v as f64 - mean).powi(2))
+            .sum::<f64>()
+            / 4096.;
+        let expected = inp_data
+            .iter()
+            .map(|v| ((*v as f64 - mean) / (var + 1e-5).sqrt()) as f32)
+            .collect::<Vec<_>>();
+        assert_close(&out.data(), &expected);
+    }
+}
@@ -9,30 +9,140 @@ pub struct MultiHeadSelfAttention {
     pub w_k: Linear, // dim x k_dim
     pub w_v: Linear, // dim x v_dim
     pub w_o: Linear, // v_dim x dim
+    /// The q / k / v projections fused into one dim x (k_dim + 2 * kv_dim) weight, as some checkpoints store them.
+    /// `w_q`, `w_k` and `w_v` are then views into it, and self attention projects with a single matmul.
+    pub w_qkv: Option<Linear>,
+    /// Apply the `1 / sqrt(head_dim)` scale to the queries before the QK^T matmul rather than to the scores after it.
+    ///
+    /// Scaling the queries first keeps the matmul accumulation smaller, which is more numerically stable for large
+    /// head dims in reduced precision, since the unscaled scores can overflow before the scale is applied.
+    pub scale_queries: bool,
+    /// Add the [ALiBi](https://arxiv.org/abs/2108.12409) linear position bias to the attention scores
+    pub alibi: bool,
+    /// Soft-cap the attention scores to `(-cap, cap)` with `tanh(scores / cap) * cap` before the softmax, like Gemma-2
+    pub softcap: Option<f32>,
     k_dim: usize,
     v_dim: usize,
     heads: usize,
+    kv_heads: usize,
 }
 
 impl MultiHeadSelfAttention {
     pub fn new(dim: usize, k_dim: usize, v_dim: usize, heads: usize, cx: &mut Graph) -> Self {
+        Self::new_grouped(dim, k_dim, v_dim, heads, heads, cx)
+    }
+
+    /// Grouped-query attention, where each of the `kv_heads` key / value heads is shared by `heads / kv_heads` query heads.
+    /// A single kv head gives multi-query attention.
+    pub fn new_grouped(
+        dim: usize,
+        k_dim: usize,
+        v_dim: usize,
+        heads: usize,
+        kv_heads: usize,
+        cx: &mut Graph,
+    ) -> Self {
+        assert!(
+            kv_heads > 0 && heads % kv_heads == 0,
+            "Number of heads ({heads}) must be a multiple of the number of kv heads ({kv_heads})"
+        );
         Self {
             w_q: Linear::new(dim, k_dim, false, cx),
-            w_k: Linear::new(dim, k_dim, false, cx),
-            w_v: Linear::new(dim, v_dim, false, cx),
+            w_k: Linear::new(dim, k_dim / heads * kv_heads, false, cx),
+            w_v: Linear::new(dim, v_dim / heads * kv_heads, false, cx),
             w_o: Linear::new(v_dim, dim, false, cx),
+            w_qkv: None,
+            scale_queries: false,
+            alibi: false,
+            softcap: None,
             k_dim,
             v_dim,
             heads,
+            kv_heads,
+        }
+    }
+
+    /// Grouped-query attention with the q / k / v projections fused into a single `w_qkv` weight
+    pub fn new_fused(
+        dim: usize,
+        k_dim: usize,
+        heads: usize,
+        kv_heads: usize,
+        cx: &mut Graph,
+    ) -> Self {
+        assert!(
+            kv_heads > 0 && heads % kv_heads == 0,
+            "Number of heads ({heads}) must be a multiple of the number of kv heads ({kv_heads})"
+        );
+        let kv_dim = k_dim / heads * kv_heads;
+        let w_qkv = Linear::new(dim, k_dim + 2 * kv_dim, false, cx);
+        let (w_q, w_k, w_v) = w_qkv.weight.split_qkv(heads, kv_heads, k_dim / heads);
+        Self {
+            w_q: Linear::from_weight(w_q),
+            w_k: Linear::from_weight(w_k),
+            w_v: Linear::from_weight(w_v),
+            w_o: Linear::new(k_dim, dim, false, cx),
+            w_qkv: Some(w_qkv),
+            scale_queries: false,
+            alibi: false,
+            softcap: None,
+            k_dim,
+            v_dim: k_dim,
+            heads,
+            kv_heads,
         }
     }
+
+    /// Repeat each kv head in a (batch, kv_heads, a, b) tensor so there's one per query head
+    fn repeat_kv_heads(&self, x: GraphTensor) -> GraphTensor {
+        if self.kv_heads == self.heads {
+            return x;
+        }
+        let (batch, _, a, b) = x.dims4();
+        x.expand(2, self.heads / self.kv_heads)
+            .reshape((batch, self.heads, a, b))
+    }
+}
+
+/// The per-head slopes from the ALiBi paper: a geometric sequence starting at `2^(-8 / n)`.
+/// When `num_heads` isn't a power of 2, the slopes of the nearest lower power of 2 are topped up with every other slope of the next one.
+pub fn alibi_slopes(num_heads: usize) -> Vec<f32> {
+    fn pow2_slopes(n: usize) -> Vec<f32> {
+        (1..=n)
+            .map(|i| 2f32.powf(-8. * i as f32 / n as f32))
+            .collect()
+    }
+    let closest = 1 << num_heads.ilog2();
+    let mut slopes = pow2_slopes(closest);
+    slopes.extend(
+        pow2_slopes(2 * closest)
+            .into_iter()
+            .step_by(2)
+            .take(num_heads - closest),
+    );
+    slopes
+}
+
+/// The ALiBi attention bias of shape (num_heads, seq, seq), where `bias[h, i, j] = slope_h * (j - i)`
+pub fn alibi_bias(cx: &mut Graph, num_heads: usize, seq: impl Into<Expression>) -> GraphTensor {
+    let seq = seq.into();
+    let slopes = cx
+        .named_tensor("ALiBi Slopes", num_heads)
+        .set(alibi_slopes(num_heads));
+    let positions = cx.arange(seq);
+    let distance = positions.expand(0, seq) - positions.expand(1, seq);
+    distance.expand(0, num_heads) * slopes.expand(1, seq).expand(2, seq)
 }
 
 impl SerializeModule for MultiHeadSelfAttention {
     fn serialize(&self, s: &mut Serializer) {
-        s.module("w_q", &self.w_q);
-        s.module("w_k", &self.w_k);
-        s.module("w_v", &self.w_v);
+        if let Some(w_qkv) = &self.w_qkv {
+            s.module("w_qkv", w_qkv);
+        } else {
+            s.module("w_q", &self.w_q);
+            s.module("w_k", &self.w_k);
+            s.module("w_v", &self.w_v);
+        }
         s.module("w_o", &self.w_o);
     }
 }
@@ -62,6 +172,43 @@ impl Module<(GraphTensor, GraphTensor, GraphTensor)> for MultiHeadSelfAttention
             GraphTensor, // batch, s1, dim
         ),
     ) -> Self::Output {
+        self.attend(keys, queries, values, None)
+    }
+}
+
+/// Score added to masked-out keys: the lowest finite f16, so it can't overflow to NaN on reduced-precision backends
+const MASKED_SCORE: f32 = -65504.;
+
+impl MultiHeadSelfAttention {
+    /// Bidirectional self attention for encoder (BERT-style) models: no causal mask, and padded keys masked out.
+    ///
+    /// `padding_mask` is (batch_dims, sequence), 1 for real tokens and 0 for padding. Like the plain forward pass
+    /// this applies no rotary embedding, so learned or sinusoidal positions should already be added to the input.
+    pub fn encoder_attention(&self, input: GraphTensor, padding_mask: GraphTensor) -> GraphTensor {
+        // Input: batch_dims, sequence, dim
+        assert_eq!(
+            padding_mask.dims()[..],
+            input.dims()[..input.shape.len() - 1],
+            "Padding mask must be (batch_dims, sequence)"
+        );
+        self.attend(
+            input,
+            input,
+            input,
+            Some((1.0 - padding_mask) * MASKED_SCORE),
+        )
+    }
+
+    /// Attention with an optional additive score bias over the keys, of shape (batch_dims, s1)
+    fn attend(
+        &self,
+        keys: GraphTensor,             // batch, s1, dim
+        queries: GraphTensor,          // batch, s2, dim
+        values: GraphTensor,           // batch, s1, dim
+        key_bias: Option<GraphTensor>, // batch, s1
+    ) -> GraphTensor {
+        // A fused projection can only be used when keys, queries and values all come from the same input
+        let fused = keys.id == queries.id && values.id == queries.id;
         let orig_query_shape = queries.dims();
         let s1 = keys.dims()[keys.shape.len() - 2];
         let s2 = queries.dims()[queries.shape.len() - 2];
@@ -75,28 +222,54 @@ impl Module<(GraphTensor, GraphTensor, GraphTensor)> for MultiHeadSelfAttention
         let keys = keys.reshape((n_batches, s1, dim));
         let values = values.reshape((n_batches, s1, dim));
         let queries = queries.reshape((n_batches, s2, dim));
-        let values = self
-            .w_v
-            .forward(values)
-            .reshape((n_batches, s1, self.heads, self.k_dim / self.heads))
-            .permute((0, 2, 1, 3));
-        let keys = self
-            .w_k
-            .forward(keys)
-            .reshape((n_batches, s1, self.heads, self.k_dim / self.heads))
-            .permute((0, 2, 3, 1));
-        let queries = self
-            .w_q
-            .forward(queries)
+        let (queries, keys, values) = match &self.w_qkv {
+            Some(w_qkv) if fused => {
+                w_qkv
+                    .forward(queries)
+                    .split_qkv(self.heads, self.kv_heads, self.k_dim / self.heads)
+            }
+            _ => (
+                self.w_q.forward(queries),
+                self.w_k.forward(keys),
+                self.w_v.forward(values),
+            ),
+        };
+        let values = self.repeat_kv_heads(
+            values
+                .reshape((n_batches, s1, self.kv_heads, self.v_dim / self.heads))
+                .permute((0, 2, 1, 3)),
+        );
+        let keys = self.repeat_kv_heads(
+            keys.reshape((n_batches, s1, self.kv_heads, self.k_dim / self.heads))
+                .permute((0, 2, 3, 1)),
+        );
+        let queries = queries
             .reshape((n_batches, s2, self.heads, self.k_dim / self.heads))
             .permute((0, 2, 1, 3));
 
-        let weights = queries
-            .matmul(keys)
-            .mul((1.0 / ((self.k_dim / self.heads) as f64).sqrt()) as f32)
-            .softmax(3);
-
-        let tokens = weights
+        let scale = (1.0 / ((self.k_dim / self.heads) as f64).sqrt()) as f32;
+        let mut scores = if self.scale_queries {
+            queries.mul(scale).matmul(keys)
+        } else {
+            queries.matmul(keys).mul(scale)
+        };
+        if let Some(cap) = self.softcap {
+            scores = scores.softcap(cap);
+        }
+        if self.alibi {
+            // Queries are the last s2 positions of the s1 keys
+            scores += alibi_bias(scores.graph(), self.heads, s1)
+                .slice_along(s1 - s2.., 1)
+                .expand(0, n_batches);
+        }
+        if let Some(bias) = key_bias {
+            scores += bias
+                .reshape((n_batches, s1))
+                .expand(1, s2)
+                .expand(1, self.heads);
+        }
+        let tokens = scores
+            .softmax(3)
             .matmul(values)
             .permute((0, 2, 1, 3))
             .reshape((n_batches, s2, self.v_dim));
@@ -109,10 +282,11 @@ mod tests {
     use dfdx::prelude::{Module as DfdxModule, *};
     use luminal::{
         prelude::{Module, *},
-        tests::assert_close,
+        tests::{assert_close, random_vec, random_vec_rng},
     };
+    use rand::{rngs::StdRng, SeedableRng};
 
-    use super::MultiHeadSelfAttention;
+    use super::{alibi_bias, alibi_slopes, MultiHeadSelfAttention};
     #[test]
     fn test_self_attention() {
         let mut cx = Graph::new();
@@ -194,4 +368,280 @@ mod tests {
 
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    #[test]
+    fn test_grouped_query_attention() {
+        let head_dim = 2;
+        let mut cx = Graph::new();
+        // Multi-query (1 kv head), grouped (2 kv heads) and an odd group count (3 query heads per kv head), each checked
+        // against full multi-head attention with the kv projections repeated for every query head that shares them
+        let mut outputs = vec![];
+        for (heads, kv_heads) in [(4, 1), (4, 2), (6, 2)] {
+            let dim = heads * head_dim;
+            let inp = cx.tensor((3, dim)).set(random_vec(3 * dim));
+            let model =
+                MultiHeadSelfAttention::new_grouped(dim, dim, dim, heads, kv_heads, &mut cx);
+            let reference = MultiHeadSelfAttention::new(dim, dim, dim, heads, &mut cx);
+            let (q, o) = (random_vec(dim * dim), random_vec(dim * dim));
+            let (k, v) = (
+                random_vec(dim * kv_heads * head_dim),
+                random_vec(dim * kv_heads * head_dim),
+            );
+            let repeat = |w: &[f32]| {
+                w.chunks(kv_heads * head_dim)
+                    .flat_map(|row| {
+                        (0..heads).flat_map(move |h| {
+                            let kv = h / (heads / kv_heads);
+                            row[kv * head_dim..(kv + 1) * head_dim].to_vec()
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            };
+            for (m, (q, k, v, o)) in [
+                (&model, (q.clone(), k.clone(), v.clone(), o.clone())),
+                (&reference, (q, repeat(&k), repeat(&v), o)),
+            ] {
+                m.w_q.weight.set(q);
+                m.w_k.weight.set(k);
+                m.w_v.weight.set(v);
+                m.w_o.weight.set(o);
+            }
+            outputs.push((
+                model.forward(inp).retrieve(),
+                reference.forward(inp).retrieve(),
+            ));
+        }
+        cx.execute();
+
+        for (out, reference) in outputs {
+            assert_close(&out.data(), &reference.data());
+        }
+    }
+
+    #[test]
+    fn test_fused_qkv_attention() {
+        let (heads, kv_heads, head_dim) = (4, 2, 2);
+        let (dim, kv_dim) = (heads * head_dim, kv_heads * head_dim);
+        let mut cx = Graph::new();
+        let inp = cx.tensor((3, dim)).set(random_vec(3 * dim));
+        let model = MultiHeadSelfAttention::new_fused(dim, dim, heads, kv_heads, &mut cx);
+        let reference =
+            MultiHeadSelfAttention::new_grouped(dim, dim, dim, heads, kv_heads, &mut cx);
+        let (qkv, o) = (random_vec(dim * (dim + 2 * kv_dim)), random_vec(dim * dim));
+        // Each row of the fused weight is a row of q, then k, then v
+        let columns = |start: usize, width: usize| {
+            qkv.chunks(dim + 2 * kv_dim)
+                .flat_map(|row| row[start..start + width].to_vec())
+                .collect::<Vec<_>>()
+        };
+        reference.w_q.weight.set(columns(0, dim));
+        reference.w_k.weight.set(columns(dim, kv_dim));
+        reference.w_v.weight.set(columns(dim + kv_dim, kv_dim));
+        reference.w_o.weight.set(o.clone());
+        model.w_qkv.as_ref().unwrap().weight.set(qkv.clone());
+        model.w_o.weight.set(o);
+        let out = model.forward(inp).retrieve();
+        let expected = reference.forward(inp).retrieve();
+        // Cross attention falls back to the views into the fused weight
+        let other = cx.tensor((2, dim)).set(random_vec(2 * dim));
+        let cross = model.forward((other, inp, other)).retrieve();
+        let expected_cross = reference.forward((other, inp, other)).retrieve();
+        cx.execute();
+
+        assert_close(&out.data(), &expected.data());
+        assert_close(&cross.data(), &expected_cross.data());
+        let mut params = param_dict(&model).into_keys().collect::<Vec<_>>();
+        params.sort();
+        assert_eq!(params, ["w_o/weight", "w_qkv/weight"]);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Number of heads (6) must be a multiple of the number of kv heads (4)"
+    )]
+    fn test_grouped_query_attention_uneven_groups() {
+        let mut cx = Graph::new();
+        MultiHeadSelfAttention::new_grouped(12, 12, 12, 6, 4, &mut cx);
+    }
+
+    #[test]
+    fn test_attention_query_scaling() {
+        let identity = (0..16)
+            .map(|i| if i % 5 == 0 { 1. } else { 0. })
+            .collect::<Vec<_>>();
+        let mut cx = Graph::new();
+        // Unscaled scores are 4e38, beyond f32::MAX. Scaled by 1/2 they fit.
+        let a = cx.tensor((2, 4)).set(vec![1e19; 8]);
+        let mut run = |scale_queries: bool| {
+            let mut model = MultiHeadSelfAttention::new(4, 4, 4, 1, &mut cx);
+            model.scale_queries = scale_queries;
+            for w in [&model.w_q, &model.w_k, &model.w_v, &model.w_o] {
+                w.weight.set(identity.clone());
+            }
+            model.forward(a).retrieve()
+        };
+        let (unscaled, scaled) = (run(false), run(true));
+        cx.execute();
+
+        assert!(unscaled.data().iter().any(|i| !i.is_finite()));
+        assert!(scaled.data().iter().all(|i| (i / 1e19 - 1.).abs() < 1e-3));
+    }
+
+    #[test]
+    fn test_attention_softcap() {
+        let mut cx = Graph::new();
+        let mut model = MultiHeadSelfAttention::new(3, 3, 3, 1, &mut cx);
+        model.softcap = Some(0.5);
+        for w in [&model.w_q, &model.w_k, &model.w_v, &model.w_o] {
+            w.weight.set(random_vec(9));
+        }
+        let a = cx.tensor((4, 3)).set(random_vec(12));
+        let b = model.forward(a).retrieve();
+
+        // Single head reference: softmax(softcap(q k^T / sqrt(d))) v
+        let q = a.matmul(model.w_q.weight);
+        let k = a.matmul(model.w_k.weight);
+        let v = a.matmul(model.w_v.weight);
+        let scores = (q.matmul(k.permute((1, 0))) * (1. / 3f32.sqrt())).softcap(0.5);
+        let c = scores
+            .softmax(1)
+            .matmul(v)
+            .matmul(model.w_o.weight)
+            .retrieve();
+        cx.execute();
+
+        assert_close(&b.data(), &c.data());
+    }
+
+    #[test]
+    fn test_attention_long_softmax() {
+        let (dim, seq) = (4, 8192);
+        let identity = (0..16)
+            .map(|i| if i % 5 == 0 { 1. } else { 0. })
+            .collect::<Vec<_>>();
+        let mut cx = Graph::new();
+        let model = MultiHeadSelfAttention::new(dim, dim, dim, 1, &mut cx);
+        for w in [&model.w_q, &model.w_k, &model.w_v, &model.w_o] {
+            w.weight.set(identity.clone());
+        }
+        let mut rng = StdRng::seed_from_u64(0);
+        let kv_data = random_vec_rng(seq * dim, &mut rng);
+        let q_data = random_vec_rng(dim, &mut rng);
+        let kv = cx.tensor((seq, dim)).set(kv_data.clone());
+        let q = cx.tensor((1, dim)).set(q_data.clone());
+        let out = model.forward((kv, q, kv)).retrieve();
+        cx.execute();
+
+        // Exact single query attention: softmax(q k^T / sqrt(d)) v
+        let exps = kv_data
+            .chunks(dim)
+            .map(|k| {
+                let score = k.iter().zip(&q_data).map(|(a, b)| a * b).sum::<f32>() / 2.;
+                (score as f64).exp()
+            })
+            .collect::<Vec<_>>();
+        let sum = exps.iter().sum::<f64>();
+        let weighted = |weights: &[f32]| {
+            (0..dim)
+                .map(|d| {
+                    weights
+                        .iter()
+                        .zip(kv_data.chunks(dim))
+                        .map(|(w, v)| w * v[d])
+                        .sum::<f32>()
+                })
+                .collect::<Vec<_>>()
+        };
+        let expected = weighted(&exps.iter().map(|e| (e / sum) as f32).collect::<Vec<_>>());
+        assert_close(&out.data(), &expected);
+    }
+
+    #[test]
+    fn test_alibi_slopes() {
+        assert_close(&alibi_slopes(4), &[0.25, 0.0625, 0.015625, 0.00390625]);
+        assert_close(
+            &alibi_slopes(6),
+            &[0.25, 0.0625, 0.015625, 0.00390625, 0.5, 0.125],
+        );
+    }
+
+    #[test]
+    fn test_alibi_bias() {
+        let mut cx = Graph::new();
+        let bias = alibi_bias(&mut cx, 2, 3).retrieve();
+        cx.execute();
+
+        assert_close(
+            &bias.data(),
+            &[
+                0.,
+                0.0625,
+                0.125,
+                -0.0625,
+                0.,
+                0.0625,
+                -0.125,
+                -0.0625,
+                0., //
+                0.,
+                0.00390625,
+                0.0078125,
+                -0.00390625,
+                0.,
+                0.00390625,
+                -0.0078125,
+                -0.00390625,
+                0.,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_attention_alibi() {
+        let mut cx = Graph::new();
+        let mut model = MultiHeadSelfAttention::new(3, 3, 3, 1, &mut cx);
+        model.alibi = true;
+        for w in [&model.w_q, &model.w_k, &model.w_v, &model.w_o] {
+            w.weight.set(random_vec(9));
+        }
+        let a = cx.tensor((4, 3)).set(random_vec(12));
+        let b = model.forward(a).retrieve();
+
+        // Single head reference: softmax(q k^T / sqrt(d) + bias) v
+        let q = a.matmul(model.w_q.weight);
+        let k = a.matmul(model.w_k.weight);
+        let v = a.matmul(model.w_v.weight);
+        let scores = q.matmul(k.permute((1, 0))) * (1. / 3f32.sqrt())
+            + alibi_bias(&mut cx, 1, 4).reshape((4, 4));
+        let c = scores
+            .softmax(1)
+            .matmul(v)
+            .matmul(model.w_o.weight)
+            .retrieve();
+        cx.execute();
+
+        assert_close(&b.data(), &c.data());
+    }
+
+    #[test]
+    fn test_encoder_attention_padding() {
+        let mut cx = Graph::new();
+        let model = MultiHeadSelfAttention::new(4, 4, 4, 2, &mut cx);
+        for w in [&model.w_q, &model.w_k, &model.w_v, &model.w_o] {
+            w.weight.set(random_vec(16));
+        }
+        let data = random_vec(2 * 4 * 4);
+        let a = cx.tensor((2, 4, 4)).set(data.clone());
+        let mask = cx.tensor((2, 4)).set(vec![1., 1., 1., 1., 1., 1., 0., 0.]);
+        let b = model.encoder_attention(a, mask).retrieve();
+        // The unpadded sequence attends to everything, the padded one only to its two real tokens
+        let full = model.forward(a.slice_along(..1, 0)).retrieve();
+        let short = cx.tensor((2, 4)).set(data[16..24].to_vec());
+        let short = model.forward(short).retrieve();
+        cx.execute();
+
+        let b = b.data();
+        assert_close(&b[..16], &full.data());
+        assert_close(&b[16..24], &short.data());
+    }
 }
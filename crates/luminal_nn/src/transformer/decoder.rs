@@ -1,4 +1,4 @@
-use crate::{Linear, ReLU};
+use crate::{DropPath, Linear, ReLU};
 use luminal::prelude::*;
 
 use super::attention::MultiHeadSelfAttention;
@@ -16,6 +16,13 @@ impl TransformerDecoder {
                 .collect(),
         }
     }
+
+    /// Switch every layer between training and eval, where dropout and drop path are skipped
+    pub fn set_training(&mut self, training: bool) {
+        for layer in &mut self.layers {
+            layer.training = training;
+        }
+    }
 }
 
 impl SerializeModule for TransformerDecoder {
@@ -37,11 +44,52 @@ impl Module<(GraphTensor, GraphTensor)> for TransformerDecoder {
     }
 }
 
+/// Where the layer norms sit relative to each residual sublayer
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NormPlacement {
+    /// `x + f(norm(x))`
+    Pre,
+    /// `norm(x + f(x))`, as in the original transformer
+    #[default]
+    Post,
+    /// `x + norm(f(norm(x)))`
+    Sandwich,
+}
+
+impl NormPlacement {
+    /// Apply a sublayer with a residual connection, normalizing according to the placement
+    pub(crate) fn residual(
+        &self,
+        x: GraphTensor,
+        dropout: f32,
+        drop_path: DropPath,
+        f: impl FnOnce(GraphTensor) -> GraphTensor,
+    ) -> GraphTensor {
+        let norm = |t: GraphTensor| t.layer_norm(t.shape.len() - 1, 1e-5);
+        let drop = |t: GraphTensor| {
+            let t = if dropout > 0. { t.dropout(dropout) } else { t };
+            drop_path.forward(t)
+        };
+        match self {
+            NormPlacement::Pre => x + drop(f(norm(x))),
+            NormPlacement::Post => norm(x + drop(f(x))),
+            NormPlacement::Sandwich => x + drop(norm(f(norm(x)))),
+        }
+    }
+}
+
 /// A single transformer decoder block
 pub struct TransformerDecoderBlock {
     pub self_attention: MultiHeadSelfAttention,
     pub cross_attention: MultiHeadSelfAttention,
     pub ff: (Linear, ReLU, Linear),
+    pub norm: NormPlacement,
+    /// Probability of dropping residual branch outputs, disabled when 0
+    pub dropout: f32,
+    /// Stochastic depth applied to each residual branch, disabled when `p` is 0
+    pub drop_path: DropPath,
+    /// Apply dropout and drop path. When unset the block is in eval mode, and both are skipped
+    pub training: bool,
 }
 
 impl TransformerDecoderBlock {
@@ -54,8 +102,30 @@ impl TransformerDecoderBlock {
                 ReLU,
                 Linear::new(ff, dim, false, cx),
             ),
+            norm: NormPlacement::default(),
+            dropout: 0.,
+            drop_path: DropPath::default(),
+            training: true,
         }
     }
+
+    /// Set where the layer norms are placed
+    pub fn with_norm(mut self, norm: NormPlacement) -> Self {
+        self.norm = norm;
+        self
+    }
+
+    /// Set the residual dropout probability
+    pub fn with_dropout(mut self, dropout: f32) -> Self {
+        self.dropout = dropout;
+        self
+    }
+
+    /// Set the probability of skipping each residual branch for a whole sample (stochastic depth)
+    pub fn with_drop_path(mut self, p: f32) -> Self {
+        self.drop_path = DropPath::new(p);
+        self
+    }
 }
 
 impl SerializeModule for TransformerDecoderBlock {
@@ -84,13 +154,28 @@ impl Module<(GraphTensor, GraphTensor)> for TransformerDecoderBlock {
             .max(1);
         let inp = input.reshape((n_batches, seq1, dim));
         let fe = from_enc.reshape((n_batches, seq2, dim));
+        let (dropout, drop_path) = if self.training {
+            (self.dropout, self.drop_path)
+        } else {
+            (
+                0.,
+                DropPath {
+                    eval: true,
+                    ..self.drop_path
+                },
+            )
+        };
         // Batched forward pass
-        let y = self.self_attention.forward(inp);
-        let x = (y + inp).layer_norm(2, 1e-5);
-        let y = self.cross_attention.forward((fe, x, fe));
-        let x = (y + x).layer_norm(2, 1e-5);
-        let y = self.ff.forward(x);
-        (y + x).layer_norm(2, 1e-5).reshape(input.shape)
+        let x = self
+            .norm
+            .residual(inp, dropout, drop_path, |x| self.self_attention.forward(x));
+        let x = self.norm.residual(x, dropout, drop_path, |x| {
+            self.cross_attention.forward((fe, x, fe))
+        });
+        let x = self
+            .norm
+            .residual(x, dropout, drop_path, |x| self.ff.forward(x));
+        x.reshape(input.shape)
     }
 }
 
@@ -104,10 +189,11 @@ mod tests {
 
     use luminal::{
         prelude::{Module, *},
-        tests::assert_close,
+        tests::{assert_close, assert_exact, random_vec},
     };
 
-    use super::TransformerDecoderBlock;
+    use super::{NormPlacement, TransformerDecoderBlock};
+    use crate::DropPath;
     #[test]
     fn test_transformer_decoder_block() {
         let mut cx = Graph::new();
@@ -270,4 +356,98 @@ mod tests {
 
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    fn set_random_weights(model: &TransformerDecoderBlock) {
+        for attn in [&model.self_attention, &model.cross_attention] {
+            for w in [&attn.w_q, &attn.w_k, &attn.w_v, &attn.w_o] {
+                w.weight.set(random_vec(9));
+            }
+        }
+        model.ff.0.weight.set(random_vec(12));
+        model.ff.2.weight.set(random_vec(12));
+    }
+
+    #[test]
+    fn test_decoder_block_norm_placement() {
+        let mut cx = Graph::new();
+        let model = TransformerDecoderBlock::new(3, 4, 1, &mut cx).with_norm(NormPlacement::Pre);
+        set_random_weights(&model);
+        let a = cx.tensor((2, 3)).set(vec![-1., 2., 3., 3., 3., -1.]);
+        let e = cx
+            .tensor((3, 3))
+            .set(vec![-1., 2., 3., 3., 3., -1., -1., 2., 3.]);
+        let b = model.forward((a, e)).retrieve();
+
+        let x = a.expand(0, 1);
+        let fe = e.expand(0, 1);
+        let x = x + model.self_attention.forward(x.layer_norm(2, 1e-5));
+        let x = x + model
+            .cross_attention
+            .forward((fe, x.layer_norm(2, 1e-5), fe));
+        let x = x + model.ff.forward(x.layer_norm(2, 1e-5));
+        let c = x.reshape((2, 3)).retrieve();
+        cx.execute();
+
+        assert_close(&b.data(), &c.data());
+    }
+
+    #[test]
+    fn test_decoder_block_dropout() {
+        let mut cx = Graph::new();
+        let model = TransformerDecoderBlock::new(3, 4, 1, &mut cx)
+            .with_norm(NormPlacement::Sandwich)
+            .with_dropout(0.5);
+        set_random_weights(&model);
+        let a = cx.tensor((2, 3)).set(vec![-1., 2., 3., 3., 3., -1.]);
+        let e = cx
+            .tensor((3, 3))
+            .set(vec![-1., 2., 3., 3., 3., -1., -1., 2., 3.]);
+        let b = model.forward((a, e)).retrieve();
+        cx.execute();
+
+        assert_eq!(b.data().len(), 6);
+        assert!(b.data().iter().all(|i| i.is_finite()));
+    }
+
+    #[test]
+    fn test_decoder_block_drop_path() {
+        let mut cx = Graph::new();
+        let model = TransformerDecoderBlock::new(3, 4, 1, &mut cx)
+            .with_norm(NormPlacement::Pre)
+            .with_drop_path(0.5);
+        set_random_weights(&model);
+        let a = cx.tensor((8, 2, 3)).set(random_vec(48));
+        let e = cx.tensor((8, 3, 3)).set(random_vec(72));
+        let b = model.forward((a, e)).retrieve();
+        cx.execute();
+
+        assert_eq!(b.data().len(), 48);
+        assert!(b.data().iter().all(|i| i.is_finite()));
+    }
+
+    #[test]
+    fn test_decoder_block_eval() {
+        let mut cx = Graph::new();
+        let mut model = TransformerDecoderBlock::new(3, 4, 1, &mut cx)
+            .with_norm(NormPlacement::Pre)
+            .with_dropout(0.5)
+            .with_drop_path(0.5);
+        model.training = false;
+        set_random_weights(&model);
+        let a = cx.tensor((8, 2, 3)).set(random_vec(48));
+        let e = cx.tensor((8, 3, 3)).set(random_vec(72));
+        let b = model.forward((a, e)).retrieve();
+        // The same weights without any dropout
+        model.training = true;
+        model.dropout = 0.;
+        model.drop_path = DropPath::default();
+        let c = model.forward((a, e)).retrieve();
+        cx.execute();
+        let first = b.data();
+        b.drop();
+        cx.execute();
+
+        assert_exact(&b.data(), &first);
+        assert_close(&b.data(), &c.data());
+    }
 }
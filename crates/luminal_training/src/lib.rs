@@ -4,3 +4,5 @@ mod loss;
 pub use loss::*;
 mod optimizer;
 pub use optimizer::*;
+mod schedule;
+pub use schedule::*;
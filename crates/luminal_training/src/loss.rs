@@ -85,6 +85,36 @@ pub fn cross_entropy_with_logits_loss(
         / inv_last_axis_numel
 }
 
+/// [Cross entropy loss](https://en.wikipedia.org/wiki/Cross_entropy#Cross-entropy_loss_function_and_logistic_regression)
+/// against targets smoothed towards the uniform distribution, as in [*Rethinking the Inception Architecture*](https://arxiv.org/abs/1512.00567).
+///
+/// The target becomes `target_probs * (1 - label_smoothing) + label_smoothing / num_classes`, then this is
+/// [cross_entropy_with_logits_loss()]. A `label_smoothing` of 0 is plain cross entropy.
+///
+/// ### Inputs
+///
+/// - `logits`: The un-normalized output from a model. [log_softmax()] is called **in** this function
+/// - `target_probabilities`: Target containing probability vectors **NOT** class indices.
+/// - `label_smoothing`: How much probability mass to spread over all classes, in `[0, 1]`.
+pub fn label_smoothed_cross_entropy_with_logits_loss(
+    logits: GraphTensor,
+    target_probabilities: GraphTensor,
+    label_smoothing: f32,
+) -> GraphTensor {
+    assert!(
+        (0.0..=1.0).contains(&label_smoothing),
+        "Label smoothing must be between 0 and 1, got {label_smoothing}"
+    );
+    let uniform = logits
+        .graph()
+        .constant(*logits.shape.dims().last().unwrap())
+        .recip()
+        * label_smoothing;
+    let smoothed = target_probabilities * (1.0 - label_smoothing)
+        + uniform.expand_to(target_probabilities.shape);
+    cross_entropy_with_logits_loss(logits, smoothed)
+}
+
 /// [KL Divergence loss](https://en.wikipedia.org/wiki/Kullback%E2%80%93Leibler_divergence).
 /// This computes `(target_probs * (target_probs.log() - logits.log_softmax())).sum(-1).mean()`
 ///
@@ -125,3 +155,45 @@ pub fn binary_cross_entropy_with_logits_loss(
     let bce = (1.0 - target_probabilities) * logits + (1.0 + (-logits).exp()).ln();
     bce.mean_reduce(bce.shape.all_axes())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{cross_entropy_with_logits_loss, label_smoothed_cross_entropy_with_logits_loss};
+    luminal::test_imports!();
+
+    #[test]
+    fn test_label_smoothed_cross_entropy() {
+        let mut cx = Graph::new();
+        let logits_data = vec![2.0, -1.0, 0.5, 0.1, 3.0, -2.0];
+        let target_data = vec![1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+        let logits = cx.tensor((2, 3)).set(logits_data.clone());
+        let target = cx.tensor((2, 3)).set(target_data.clone());
+        let plain = cross_entropy_with_logits_loss(logits, target).retrieve();
+        let unsmoothed =
+            label_smoothed_cross_entropy_with_logits_loss(logits, target, 0.0).retrieve();
+        let smoothed =
+            label_smoothed_cross_entropy_with_logits_loss(logits, target, 0.3).retrieve();
+        cx.execute();
+
+        // Reference: -sum(smoothed_target * log_softmax(logits)) averaged over rows
+        let reference = |eps: f32| {
+            logits_data
+                .chunks(3)
+                .zip(target_data.chunks(3))
+                .map(|(l, t)| {
+                    let log_sum = l.iter().map(|i| i.exp()).sum::<f32>().ln();
+                    -l.iter()
+                        .zip(t)
+                        .map(|(l, t)| (t * (1.0 - eps) + eps / 3.0) * (l - log_sum))
+                        .sum::<f32>()
+                })
+                .sum::<f32>()
+                / 2.0
+        };
+        assert_close(&unsmoothed.data(), &plain.data());
+        assert_close(&unsmoothed.data(), &[reference(0.0)]);
+        assert_close(&smoothed.data(), &[reference(0.3)]);
+        // Confident correct predictions are penalized once the target is smoothed
+        assert!(smoothed.data()[0] > unsmoothed.data()[0]);
+    }
+}
@@ -58,5 +58,64 @@ pub fn sgd_on_graph(
     (new_weights, lr)
 }
 
+/// Clip gradients by their global L2 norm
+///
+/// `grad = grad * max_norm / max(global_norm, max_norm)`
+///
+/// Output: Clipped gradients, in the same order as the inputs
+pub fn clip_grad_norm(grads: &[GraphTensor], max_norm: f32) -> Vec<GraphTensor> {
+    assert!(!grads.is_empty(), "Can't clip an empty set of gradients");
+    let global_norm = grads
+        .iter()
+        .map(|g| (*g * *g).sum_reduce((0..g.shape.len()).collect::<Vec<_>>()))
+        .reduce(|a, b| a + b)
+        .unwrap()
+        .sqrt();
+    let scale = global_norm.max_f32(max_norm).recip() * max_norm;
+    grads
+        .iter()
+        .map(|g| *g * scale.expand_to(g.shape))
+        .collect()
+}
+
 // /// Implements the [Adam](https://arxiv.org/abs/1412.6980) algorithm.
 // pub fn adam(grads: &[(NodeIndex, ShapeTracker)]) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    luminal::test_imports!();
+
+    #[test]
+    fn test_clip_grad_norm() {
+        let mut cx = Graph::new();
+        // Global norm of [300, 400] and [1200] is 1300
+        let a = cx.tensor(2).set([300., 400.]);
+        let b = cx.tensor((1, 1)).set([1200.]);
+        let clipped = clip_grad_norm(&[a, b], 13.);
+        let (c, d) = (clipped[0].retrieve(), clipped[1].retrieve());
+        // Gradients already under the limit pass through unchanged
+        let e = clip_grad_norm(&[a, b], 2000.)[0].retrieve();
+        cx.execute();
+
+        assert_close(&c.data(), &[3., 4.]);
+        assert_close(&d.data(), &[12.]);
+        assert_close(&e.data(), &[300., 400.]);
+    }
+
+    #[test]
+    fn test_clip_autograd_grads() {
+        let mut cx = Graph::new();
+        let w = cx.named_tensor("Weight", 4).set([1., 2., 3., 4.]);
+        let loss = (w * 1e4).sum_reduce(0);
+        let grads = cx.compile(crate::Autograd::new(w, loss), ());
+        let grads = grads
+            .iter()
+            .map(|(id, shape)| GraphTensor::from_id(*id, *shape, &mut cx))
+            .collect::<Vec<_>>();
+        let clipped = clip_grad_norm(&grads, 1.)[0].retrieve();
+        cx.execute();
+
+        assert_close(&clipped.data(), &[0.5; 4]);
+    }
+}
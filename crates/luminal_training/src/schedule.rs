@@ -0,0 +1,62 @@
+use std::f32::consts::PI;
+
+/// Linear warmup from 0 to `base_lr` over the first `warmup` steps, then constant
+pub fn linear_warmup(step: usize, warmup: usize, base_lr: f32) -> f32 {
+    if step < warmup {
+        base_lr * step as f32 / warmup as f32
+    } else {
+        base_lr
+    }
+}
+
+/// Linear warmup from 0 to `base_lr` over the first `warmup` steps, then cosine decay down to `min_lr` at step `total`.
+/// Steps past `total` stay at `min_lr`.
+///
+/// Set the result each step on the learning rate tensor returned by the optimizer.
+pub fn cosine_with_warmup(
+    step: usize,
+    warmup: usize,
+    total: usize,
+    base_lr: f32,
+    min_lr: f32,
+) -> f32 {
+    assert!(
+        warmup <= total,
+        "Warmup ({warmup} steps) must not be longer than the schedule ({total} steps)"
+    );
+    if step < warmup {
+        return linear_warmup(step, warmup, base_lr);
+    }
+    let progress = if total == warmup {
+        1.
+    } else {
+        ((step - warmup) as f32 / (total - warmup) as f32).min(1.)
+    };
+    min_lr + 0.5 * (base_lr - min_lr) * (1. + (PI * progress).cos())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use luminal::tests::assert_close;
+
+    #[test]
+    fn test_linear_warmup() {
+        let lrs = (0..6)
+            .map(|s| linear_warmup(s, 4, 1e-3))
+            .collect::<Vec<_>>();
+        assert_close(&lrs, &[0., 2.5e-4, 5e-4, 7.5e-4, 1e-3, 1e-3]);
+    }
+
+    #[test]
+    fn test_cosine_with_warmup() {
+        let lrs = [0, 5, 10, 15, 20, 25, 30]
+            .into_iter()
+            .map(|s| cosine_with_warmup(s, 10, 20, 1.0, 0.1))
+            .collect::<Vec<_>>();
+        let mid = 0.1 + 0.45 * (1. + (PI * 0.5).cos());
+        assert_close(&lrs, &[0., 0.5, 1.0, mid, 0.1, 0.1, 0.1]);
+        // No warmup starts at the base rate
+        assert_close(&[cosine_with_warmup(0, 0, 10, 1.0, 0.0)], &[1.0]);
+    }
+}
@@ -7,13 +7,15 @@ use clap::Parser;
 use colored::Colorize;
 use itertools::Itertools;
 use model::{HEAD_DIM, N_KV_HEADS};
-use tokenizers::Tokenizer;
+use tokenizer::PromptTokenizer;
 
 mod gguf;
 mod loader;
 mod model;
+mod streaming;
+mod tokenizer;
 
-use crate::model::KVCache;
+use crate::{model::KVCache, streaming::AttentionSink};
 use luminal::prelude::*;
 
 // Command args parser
@@ -24,14 +26,50 @@ pub struct CLIArgs {
     #[clap(short = 't', long = "gen_tokens", default_value = "256")]
     gen_tokens: i32,
 
-    /// Prompt for the model
+    /// Prompt for the model (token ids if built without the `tokenizer` feature)
     #[clap(short = 'p', long = "prompt", default_value = include_str!("../prompts/merge_sort.txt"))]
     prompt: String,
+
+    /// Path to the tokenizer.json used to encode the prompt and decode the output
+    #[clap(long = "tokenizer", default_value = "setup/tokenizer.json")]
+    tokenizer: String,
+
+    /// Token id that ends generation early
+    #[clap(long = "eos", default_value = "128001")]
+    eos_token: Option<u32>,
+
+    /// Keep only this many recent tokens (plus the sink tokens) in the KV cache, StreamingLLM-style. The caches are
+    /// evicted on the host, so Metal and CUDA builds reject this flag.
+    #[clap(long = "window")]
+    window: Option<usize>,
+
+    /// Number of leading tokens always kept in the KV cache when `window` is set
+    #[clap(long = "sink_tokens", default_value = "4")]
+    sink_tokens: usize,
+}
+
+/// Settings for a single generation run
+pub struct GenerationConfig {
+    /// Maximum number of tokens to produce, including the one sampled from the prompt
+    pub max_tokens: usize,
+    /// Stop as soon as this token is produced
+    pub eos_token: Option<u32>,
+    /// Bound the KV cache by evicting old tokens between the sinks and the recent window
+    pub attention_sink: Option<AttentionSink>,
 }
 
 fn main() {
     let cli_args = CLIArgs::parse();
-    let tokenizer = Tokenizer::from_file("setup/tokenizer.json").unwrap();
+    #[cfg(any(feature = "metal", feature = "cuda"))]
+    if cli_args.window.is_some() {
+        <CLIArgs as clap::CommandFactory>::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--window evicts the KV caches on the host, so it's only supported on the CPU backend",
+            )
+            .exit();
+    }
+    let mut tokenizer = PromptTokenizer::load(&cli_args.tokenizer);
 
     print!("Defining graph");
     io::stdout().flush().unwrap();
@@ -48,14 +86,17 @@ fn main() {
             )
         })
         .collect();
-    cache_src.set_dyn(vec![], (1, model::N_KV_HEADS, 0, model::HEAD_DIM));
+    cache_src.set_dyn(
+        Vec::<f32>::new(),
+        (1, model::N_KV_HEADS, 0, model::HEAD_DIM),
+    );
     let model = model::Llama::new(&mut cx);
     let mut model_weights = params(&model);
+    // The rotary tables are computed once and kept around like the weights
+    model_weights.extend(model.rotary.iter().flat_map(|r| [r.cos.id, r.sin.id]));
     cx.keep_tensors(&model_weights);
     let (logits, mut cache_dest) = model.forward((input, &cache_src));
-    let mut logits = logits
-        .slice((.., Expression::from('s') - 1.., ..))
-        .retrieve();
+    let mut logits = logits.select_last_token().retrieve();
     cache_dest.keep();
     println!("\t\t - {}ms", now.elapsed().as_millis());
 
@@ -103,6 +144,7 @@ fn main() {
     let now = Instant::now();
     input.set_dyn(vec![1.], (1, 1));
     cx.set_dyn_dim('t', 1);
+    cx.set_dyn_dim('e', 0);
     cx.execute();
     logits.drop();
     transfer_data_same_graph(&cache_dest, &cache_src, &mut cx);
@@ -110,76 +152,155 @@ fn main() {
 
     // Now that weights are loaded, delete the loading nodes so they don't run again
     delete_inputs(&cache_src, &mut cx);
-    delete_inputs(&downstream(model_weights, &cx), &mut cx);
+    delete_inputs(downstream(model_weights, &cx), &mut cx);
 
     // Run prompt processing pass
-    let input_ids = tokenizer
-        .encode(&cli_args.prompt as &str, false)
-        .unwrap()
-        .get_ids()
-        .to_vec();
-    input.set_dyn(
-        input_ids.iter().map(|i| *i as f32).collect::<Vec<_>>(),
-        (1, input_ids.len()),
-    );
-    cx.set_dyn_dim('t', input_ids.len());
+    let input_ids = tokenizer.encode(&cli_args.prompt);
     print!("Processing Prompt");
     io::stdout().flush().unwrap();
+    let config = GenerationConfig {
+        max_tokens: cli_args.gen_tokens as usize + 1,
+        eos_token: cli_args.eos_token,
+        attention_sink: cli_args.window.map(|window| AttentionSink {
+            sink_tokens: cli_args.sink_tokens,
+            window,
+        }),
+    };
     let now = Instant::now();
-    cx.execute();
-    let elapsed_ms = now.elapsed().as_millis();
-    println!(
-        "\t - {elapsed_ms}ms ({:.2} tok/s, {} prompt tokens)",
-        1000.0 * (input_ids.len() as f64) / (elapsed_ms as f64),
-        input_ids.len()
+    let mut start_decode = now;
+    let mut output_ids = vec![];
+    generate(
+        &mut cx,
+        (input, logits),
+        (&cache_src, &cache_dest),
+        &input_ids,
+        &config,
+        |token| {
+            if output_ids.is_empty() {
+                let elapsed_ms = now.elapsed().as_millis();
+                println!(
+                    "\t - {elapsed_ms}ms ({:.2} tok/s, {} prompt tokens)",
+                    1000.0 * (input_ids.len() as f64) / (elapsed_ms as f64),
+                    input_ids.len()
+                );
+                print!("{}", cli_args.prompt.white().bold());
+                start_decode = Instant::now();
+            }
+            output_ids.push(token);
+
+            // Print the new substring added to the decoded output
+            print!("{}", tokenizer.decode_next(&output_ids).bright_green());
+            io::stdout().flush().unwrap();
+        },
     );
-    let mut output_ids = vec![argmax(&logits.data())];
-    logits.drop();
 
-    // Decode token
-    print!("{}", cli_args.prompt.white().bold());
-    let initial = tokenizer.decode(&output_ids, false).unwrap().bright_green();
-    print!("{initial}",);
-    io::stdout().flush().unwrap();
+    println!();
+    let avg_token_time =
+        start_decode.elapsed().as_micros() as f32 / (output_ids.len() - 1).max(1) as f32 / 1000.0;
+    println!(
+        "\nAverage token generated in {:.2}ms\t - ({:.2} tok/s)",
+        avg_token_time,
+        1000.0 / avg_token_time
+    );
+}
 
-    // Swap caches
-    transfer_data_same_graph(&cache_dest, &cache_src, &mut cx);
+/// Process the prompt and decode tokens until `max_tokens` are produced or the EOS token is hit.
+///
+/// `on_token` is called after every decode step so callers can stream output as it's generated.
+/// The KV caches are swapped between steps, so the graph is ready for another call when this returns.
+fn generate(
+    cx: &mut Graph,
+    (input, logits): (GraphTensor, GraphTensor),
+    (cache_src, cache_dest): (&Vec<NodeIndex>, &Vec<KVCache>),
+    prompt: &[u32],
+    config: &GenerationConfig,
+    mut on_token: impl FnMut(u32),
+) -> Vec<u32> {
+    let mut output_ids = vec![];
+    if config.max_tokens == 0 {
+        return output_ids;
+    }
 
-    // Decode loop
-    let start_decode = std::time::Instant::now();
-    let mut prev_output_len = initial.len();
-    for _ in 0..cli_args.gen_tokens {
-        input.set_dyn(vec![*output_ids.last().unwrap() as f32], (1, 1));
-        cx.set_dyn_dim('p', input_ids.len() + output_ids.len() - 1);
-        cx.execute();
+    // Prompt processing pass (no previous cache)
+    input.set_dyn(
+        prompt.iter().map(|i| *i as f32).collect::<Vec<_>>(),
+        (1, prompt.len()),
+    );
+    cx.set_dyn_dim('t', prompt.len());
+    cx.set_dyn_dim('p', 0);
+    cx.set_dyn_dim('e', 0);
+    cx.execute();
+    let (mut cache_len, mut evicted) = slide_window(prompt.len(), 0);
 
+    loop {
         // Sample tokens
         let output_id = argmax(&logits.data());
         logits.drop();
         output_ids.push(output_id);
 
-        // Get the current decoded output
-        let current_output = tokenizer.decode(&output_ids, false).unwrap();
+        // Swap caches
+        transfer_data_same_graph(cache_dest, cache_src, cx);
+        on_token(output_id);
 
-        // Print the new substring added to the decoded output
-        print!("{}", current_output[prev_output_len..].bright_green());
-        io::stdout().flush().unwrap();
+        if output_ids.len() >= config.max_tokens || Some(output_id) == config.eos_token {
+            break;
+        }
 
-        // Update the previous output
-        prev_output_len = current_output.len();
+        // Make room for the next token
+        if let Some(sink) = &config.attention_sink {
+            assert!(
+                model::KV_WINDOW.is_none(),
+                "Attention sinks and the in-graph KV window can't be combined"
+            );
+            cache_len = evict_caches(cx, cache_src, sink, cache_len);
+        }
 
-        // Swap caches
-        transfer_data_same_graph(&cache_dest, &cache_src, &mut cx);
+        // Decode next token
+        input.set_dyn(vec![output_id as f32], (1, 1));
+        cx.set_dyn_dim('p', cache_len);
+        cx.set_dyn_dim('e', evicted);
+        cx.execute();
+        (cache_len, evicted) = slide_window(cache_len + 1, evicted);
     }
+    output_ids
+}
 
-    println!();
-    let avg_token_time =
-        start_decode.elapsed().as_micros() as f32 / (output_ids.len() - 1) as f32 / 1000.0;
-    println!(
-        "\nAverage token generated in {:.2}ms\t - ({:.2} tok/s)",
-        avg_token_time,
-        1000.0 / avg_token_time
-    );
+/// The cache length and number of evicted positions after the graph's KV window slides over a cache of `cache_len`
+fn slide_window(cache_len: usize, evicted: usize) -> (usize, usize) {
+    match model::KV_WINDOW {
+        Some(window) if cache_len > window => (window, evicted + cache_len - window),
+        _ => (cache_len, evicted),
+    }
+}
+
+/// Evict old tokens from every layer's KV cache so one more token fits, returning the new cache length.
+///
+/// Cache positions are remapped to stay contiguous, so the model embeds the next token at the returned length.
+fn evict_caches(
+    cx: &mut Graph,
+    cache_src: &[NodeIndex],
+    sink: &AttentionSink,
+    cache_len: usize,
+) -> usize {
+    let mut new_len = cache_len;
+    for layer in cache_src.chunks_exact(2) {
+        let (mut keys, mut values) = (
+            cx.tensors.remove(&(layer[0], 0)).unwrap(),
+            cx.tensors.remove(&(layer[1], 0)).unwrap(),
+        );
+        new_len = sink.evict(
+            keys.downcast_mut::<Vec<f32>>()
+                .expect("Attention sink eviction needs the KV cache in host memory"),
+            values
+                .downcast_mut::<Vec<f32>>()
+                .expect("Attention sink eviction needs the KV cache in host memory"),
+            cache_len,
+            1,
+        );
+        cx.tensors.insert((layer[0], 0), keys);
+        cx.tensors.insert((layer[1], 0), values);
+    }
+    new_len
 }
 
 // Currently just an argmax, do actual sampling here
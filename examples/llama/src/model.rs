@@ -10,15 +10,42 @@ pub const N_KV_HEADS: usize = 8;
 pub const MLP_DIM: usize = 14336;
 
 pub const N_ATTENTION_GROUPS: usize = N_HEADS / N_KV_HEADS;
+// Query heads are split evenly into groups sharing a kv head, so a config that doesn't divide evenly is rejected at compile time
+const _: () = assert!(
+    N_HEADS % N_KV_HEADS == 0,
+    "N_HEADS must be a multiple of N_KV_HEADS"
+);
 pub const HEAD_DIM: usize = HIDDEN_DIM / N_HEADS;
 pub const ATTN_PROJ_DIM: usize = HEAD_DIM * N_KV_HEADS;
+pub const ROPE_THETA: f32 = 500_000.;
+// Precompute the rotary cos / sin tables for this many positions rather than recomputing them every step.
+// Generation must stay below it, so it should cover the full context (or the attention sink capacity).
+pub const ROPE_CACHE_POSITIONS: Option<usize> = Some(8192);
+// Gemma-2 style soft-capping of the attention scores and final logits. Llama doesn't use it.
+pub const ATTN_LOGIT_SOFTCAP: Option<f32> = None;
+pub const FINAL_LOGIT_SOFTCAP: Option<f32> = None;
+// Cap the KV cache at this many positions, sliding the oldest ones out in-graph every step. Kept keys stay rotated at
+// their absolute positions, so new tokens are embedded at the cache length plus the 'e' positions evicted so far.
+pub const KV_WINDOW: Option<usize> = None;
+// Windowed generation keeps embedding new tokens at ever-growing absolute positions, which would run off the end of the
+// rotary table
+const _: () = assert!(
+    KV_WINDOW.is_none() || ROPE_CACHE_POSITIONS.is_none(),
+    "KV_WINDOW can't be combined with ROPE_CACHE_POSITIONS, set ROPE_CACHE_POSITIONS to None"
+);
+// Share the token embedding table with the LM head, for checkpoints (like Llama 3.2 1B / 3B) that have no output weight
+pub const TIED_EMBEDDINGS: bool = false;
 
 pub type KVCache = (GraphTensor, GraphTensor);
 
+#[derive(SerializeModule)]
 pub struct Mlp {
+    #[serialize(rename = "ffn_gate")]
     pub gate_proj: Linear, // hidden -> intermediate
+    #[serialize(rename = "ffn_down")]
     pub down_proj: Linear, // intermediate -> hidden
-    pub up_proj: Linear,   // hidden -> intermediate
+    #[serialize(rename = "ffn_up")]
+    pub up_proj: Linear, // hidden -> intermediate
 }
 
 impl Module<GraphTensor> for Mlp {
@@ -41,41 +68,93 @@ impl Mlp {
     }
 }
 
-impl SerializeModule for Mlp {
-    fn serialize(&self, s: &mut Serializer) {
-        s.module("ffn_gate", &self.gate_proj);
-        s.module("ffn_up", &self.up_proj);
-        s.module("ffn_down", &self.down_proj);
-    }
-}
-
 fn apply_rotary_embeddings_ggml(input: GraphTensor, prev_seq: Expression) -> GraphTensor {
     assert_eq!(input.shape.len(), 4); // batch, n_heads, seq, head_dim
-    let (batch, n_heads, seq, head_dim) = input.dims4();
+    let (_, _, seq, head_dim) = input.dims4();
     // Get freqs
     let freqs = (input.graph().arange(head_dim / 2) * 2.0) / (head_dim.to_usize().unwrap() as f32);
-    let freqs = 500_000_f32.pow(freqs);
+    let freqs = ROPE_THETA.pow(freqs);
     let pos = input.graph().arange(seq) + prev_seq;
     let emb = pos.expand(1, 1).matmul(freqs.expand(0, 1));
 
+    rotate_pairs(input, emb.cos(), emb.sin())
+}
+
+/// Rotary embeddings with the cos / sin rows for the current positions sliced out of a precomputed table
+fn apply_rotary_embeddings_cached(
+    input: GraphTensor,
+    prev_seq: Expression,
+    rotary: RotaryCache,
+) -> GraphTensor {
+    assert_eq!(input.shape.len(), 4); // batch, n_heads, seq, head_dim
+    let (_, _, seq, head_dim) = input.dims4();
+    // The positions are consecutive, so the rows are a contiguous slice of the table
+    let rows = |table: GraphTensor| {
+        table
+            .slice_along(prev_seq..prev_seq + seq, 0)
+            .reshape((seq, head_dim / 2))
+    };
+    rotate_pairs(input, rows(rotary.cos), rows(rotary.sin))
+}
+
+/// Rotate each (even, odd) pair of a (batch, n_heads, seq, head_dim) tensor by the angles given as (seq, head_dim / 2) cos / sin
+fn rotate_pairs(input: GraphTensor, cos: GraphTensor, sin: GraphTensor) -> GraphTensor {
+    let (batch, n_heads, seq, head_dim) = input.dims4();
     // Split input into evens and odds
     let split = input.reshape((batch, n_heads, seq, head_dim / 2, 2));
     let x0 = split.slice((.., .., .., .., ..1));
     let x1 = split.slice((.., .., .., .., 1..));
 
     // Apply sin and cos embeddings
-    let x0_out = x0 * emb.cos().expand_to(x0.shape) - x1 * emb.sin().expand_to(x1.shape);
-    let x1_out = x0 * emb.sin().expand_to(x0.shape) + x1 * emb.cos().expand_to(x1.shape);
+    let x0_out = x0 * cos.expand_to(x0.shape) - x1 * sin.expand_to(x1.shape);
+    let x1_out = x0 * sin.expand_to(x0.shape) + x1 * cos.expand_to(x1.shape);
 
     // Combine back into output
     x0_out.concat_along(x1_out, 4).reshape(input.shape)
 }
 
+/// Rotary cos / sin tables of shape (positions, head_dim / 2), computed once on the host
+#[derive(Clone, Copy)]
+pub struct RotaryCache {
+    pub cos: GraphTensor,
+    pub sin: GraphTensor,
+}
+
+impl RotaryCache {
+    pub fn new(positions: usize, cx: &mut Graph) -> Self {
+        // Same angles as apply_rotary_embeddings_ggml: position * theta^(-2i / head_dim)
+        let (sin, cos): (Vec<f32>, Vec<f32>) = (0..positions)
+            .flat_map(|pos| {
+                (0..HEAD_DIM / 2).map(move |i| {
+                    let freq = ROPE_THETA.powf((2 * i) as f32 / HEAD_DIM as f32).recip();
+                    (pos as f32 * freq).sin_cos()
+                })
+            })
+            .unzip();
+        Self {
+            cos: cx
+                .named_tensor("RoPE Cos", (positions, HEAD_DIM / 2))
+                .set(cos),
+            sin: cx
+                .named_tensor("RoPE Sin", (positions, HEAD_DIM / 2))
+                .set(sin),
+        }
+    }
+}
+
+#[derive(SerializeModule)]
 pub struct SelfAttention {
+    #[serialize(rename = "attn_q/weight")]
     pub q_proj: GraphTensor, // Hidden -> hidden
+    #[serialize(rename = "attn_k/weight")]
     pub k_proj: GraphTensor, // Proj dim -> hidden
+    #[serialize(rename = "attn_v/weight")]
     pub v_proj: GraphTensor, // Proj dim -> hidden
+    #[serialize(rename = "attn_output/weight")]
     pub o_proj: GraphTensor, // Hidden -> hidden
+    // Shared tables owned by the model, not weights
+    #[serialize(skip)]
+    pub rotary: Option<RotaryCache>,
 }
 
 impl Module<(GraphTensor, KVCache)> for SelfAttention {
@@ -85,6 +164,12 @@ impl Module<(GraphTensor, KVCache)> for SelfAttention {
         // cache: batch, kv_heads, prev_seq, head_dim
         let (batch, seq, _) = x.dims3();
         let (_, _, prev_seq, _) = k_cache.dims4();
+        // Absolute position of the first new token
+        let position = if KV_WINDOW.is_some() {
+            prev_seq + 'e'
+        } else {
+            prev_seq
+        };
         // Apply the Projections
         let queries = x
             .matmul(self.q_proj.permute((1, 0)))
@@ -102,8 +187,17 @@ impl Module<(GraphTensor, KVCache)> for SelfAttention {
             .permute((0, 2, 1, 3));
 
         // Rotary embed queries and keys
-        let queries = apply_rotary_embeddings_ggml(queries, prev_seq);
-        let keys = apply_rotary_embeddings_ggml(keys, prev_seq);
+        let (queries, keys) = if let Some(rotary) = self.rotary {
+            (
+                apply_rotary_embeddings_cached(queries, position, rotary),
+                apply_rotary_embeddings_cached(keys, position, rotary),
+            )
+        } else {
+            (
+                apply_rotary_embeddings_ggml(queries, position),
+                apply_rotary_embeddings_ggml(keys, position),
+            )
+        };
 
         // Add KV cache
         let keys = k_cache.concat_along(keys, 2);
@@ -118,9 +212,11 @@ impl Module<(GraphTensor, KVCache)> for SelfAttention {
             .reshape((batch, N_KV_HEADS, N_ATTENTION_GROUPS, seq, HEAD_DIM)) // Split query heads into groups
             .matmul(repeated_keys.permute((0, 1, 2, 4, 3)))
             / (HEAD_DIM as f32).sqrt();
+        if let Some(cap) = ATTN_LOGIT_SOFTCAP {
+            attention_weights = attention_weights.softcap(cap);
+        }
 
-        let attention_mask = self.k_proj.graph().triu(seq, 1) * f16::MIN.to_f32();
-        attention_weights += attention_mask
+        let attention_mask = (self.k_proj.graph().triu(seq, 1) * f16::MIN.to_f32())
             .pad(((0, 0), (prev_seq, 0)))
             .expand(0, batch)
             .expand(1, N_KV_HEADS)
@@ -128,7 +224,7 @@ impl Module<(GraphTensor, KVCache)> for SelfAttention {
 
         // Calculate final outputs
         let output = attention_weights
-            .softmax(4)
+            .masked_softmax(attention_mask, 4)
             // Apply distribution to values
             .matmul(repeated_values)
             // Merge heads
@@ -137,34 +233,36 @@ impl Module<(GraphTensor, KVCache)> for SelfAttention {
         let output = output
             // Apply output projection
             .matmul(self.o_proj.permute((1, 0)));
+        // Only the most recent positions are handed on to the next step
+        let (keys, values) = match KV_WINDOW {
+            Some(window) => (keys.keep_last(2, window), values.keep_last(2, window)),
+            None => (keys, values),
+        };
         (output, (keys.contiguous(), values.contiguous())) // Cache needs to be contiguous for transferring to another graph
     }
 }
 
 impl SelfAttention {
-    pub fn new(cx: &mut Graph) -> Self {
+    pub fn new(rotary: Option<RotaryCache>, cx: &mut Graph) -> Self {
         Self {
             q_proj: cx.named_tensor("Q Proj", (HIDDEN_DIM, HIDDEN_DIM)),
             k_proj: cx.named_tensor("K Proj", (ATTN_PROJ_DIM, HIDDEN_DIM)),
             v_proj: cx.named_tensor("V Proj", (ATTN_PROJ_DIM, HIDDEN_DIM)),
             o_proj: cx.named_tensor("O Proj", (HIDDEN_DIM, HIDDEN_DIM)),
+            rotary,
         }
     }
 }
 
-impl SerializeModule for SelfAttention {
-    fn serialize(&self, s: &mut Serializer) {
-        s.tensor("attn_q/weight", self.q_proj);
-        s.tensor("attn_v/weight", self.v_proj);
-        s.tensor("attn_k/weight", self.k_proj);
-        s.tensor("attn_output/weight", self.o_proj);
-    }
-}
-
+#[derive(SerializeModule)]
 pub struct TransformerBlock {
+    #[serialize(rename = "")]
     pub attention: SelfAttention,
+    #[serialize(rename = "attn_norm")]
     pub attention_norm: LayerNorm,
+    #[serialize(rename = "")]
     pub feed_forward: Mlp,
+    #[serialize(rename = "ffn_norm")]
     pub feed_forward_norm: LayerNorm,
 }
 
@@ -188,9 +286,9 @@ impl Module<(GraphTensor, KVCache)> for TransformerBlock {
 }
 
 impl TransformerBlock {
-    pub fn new(cx: &mut Graph) -> Self {
+    pub fn new(rotary: Option<RotaryCache>, cx: &mut Graph) -> Self {
         Self {
-            attention: SelfAttention::new(cx),
+            attention: SelfAttention::new(rotary, cx),
             attention_norm: LayerNorm::new(HIDDEN_DIM, true, false, false, 1e-5, cx),
             feed_forward: Mlp::new(HIDDEN_DIM, MLP_DIM, cx),
             feed_forward_norm: LayerNorm::new(HIDDEN_DIM, true, false, false, 1e-5, cx),
@@ -198,15 +296,6 @@ impl TransformerBlock {
     }
 }
 
-impl SerializeModule for TransformerBlock {
-    fn serialize(&self, s: &mut Serializer) {
-        s.module("", &self.attention);
-        s.module("attn_norm", &self.attention_norm);
-        s.module("ffn_norm", &self.feed_forward_norm);
-        s.module("", &self.feed_forward);
-    }
-}
-
 pub struct Llama {
     // Token embeddings
     pub embedding: Embedding,
@@ -214,35 +303,46 @@ pub struct Llama {
     pub layers: Vec<TransformerBlock>,
     // Norm + LM head
     pub head: (LayerNorm, Linear),
+    // Rotary tables shared by every layer, if cached
+    pub rotary: Option<RotaryCache>,
 }
 
 impl Module<(GraphTensor, &[KVCache])> for Llama {
     type Output = (GraphTensor, Vec<KVCache>);
     fn forward(&self, (input, cache): (GraphTensor, &[KVCache])) -> Self::Output {
         // Embed tokens
-        let mut x = self.embedding.forward(input);
+        let x = self.embedding.forward(input);
 
         // Run through layers and collect new caches
-        let mut new_caches = vec![];
-        let mut new_cache;
-        for (i, layer) in self.layers.iter().enumerate() {
-            (x, new_cache) = layer.forward((x, cache[i]));
-            new_caches.push(new_cache);
-        }
+        let (x, new_caches) = forward_with_states(&self.layers, x, cache.iter().copied());
         // Run through last norm and output projection
-        (self.head.forward(x), new_caches)
+        let mut logits = self.head.forward(x);
+        if let Some(cap) = FINAL_LOGIT_SOFTCAP {
+            logits = logits.softcap(cap);
+        }
+        (logits, new_caches)
     }
 }
 
 impl Llama {
     pub fn new(cx: &mut Graph) -> Self {
+        let rotary = ROPE_CACHE_POSITIONS.map(|positions| RotaryCache::new(positions, cx));
+        let embedding = Embedding::new(VOCAB_SIZE, HIDDEN_DIM, cx);
+        let lm_head = if TIED_EMBEDDINGS {
+            Linear::tied(&embedding)
+        } else {
+            Linear::new_permuted(HIDDEN_DIM, VOCAB_SIZE, false, cx)
+        };
         Self {
-            embedding: Embedding::new(VOCAB_SIZE, HIDDEN_DIM, cx),
+            embedding,
             head: (
                 LayerNorm::new(HIDDEN_DIM, true, false, false, 1e-5, cx),
-                Linear::new_permuted(HIDDEN_DIM, VOCAB_SIZE, false, cx),
+                lm_head,
             ),
-            layers: (0..NUM_LAYERS).map(|_| TransformerBlock::new(cx)).collect(),
+            layers: (0..NUM_LAYERS)
+                .map(|_| TransformerBlock::new(rotary, cx))
+                .collect(),
+            rotary,
         }
     }
 }
@@ -251,6 +351,7 @@ impl SerializeModule for Llama {
     fn serialize(&self, s: &mut Serializer) {
         s.module("token_embd", &self.embedding);
         s.module("output_norm", &self.head.0);
+        // A tied head adds nothing: its weight was already recorded as token_embd
         s.module("output", &self.head.1);
         for (i, layer) in self.layers.iter().enumerate() {
             s.module(&format!("blk/{i}"), layer);
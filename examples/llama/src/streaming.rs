@@ -0,0 +1,101 @@
+use crate::model::{HEAD_DIM, N_KV_HEADS, ROPE_THETA};
+
+/// StreamingLLM-style attention sink cache policy (https://arxiv.org/abs/2309.17453)
+///
+/// The KV cache keeps the first `sink_tokens` positions plus the most recent `window` positions. Everything in between
+/// is evicted, and the keys left behind are re-rotated so cache positions stay contiguous. That way the model only
+/// ever sees positions below `capacity()`, no matter how long generation runs.
+#[derive(Debug, Clone, Copy)]
+pub struct AttentionSink {
+    /// Number of leading tokens that are never evicted
+    pub sink_tokens: usize,
+    /// Number of most recent tokens kept after the sinks
+    pub window: usize,
+}
+
+impl AttentionSink {
+    /// Maximum number of positions the cache holds
+    pub fn capacity(&self) -> usize {
+        self.sink_tokens + self.window
+    }
+
+    /// Evict tokens from a layer's (1, kv_heads, seq, head_dim) key and value caches so `incoming` more tokens fit.
+    ///
+    /// Returns the new cache length. Nothing is evicted if the cache already has room.
+    pub fn evict(
+        &self,
+        keys: &mut Vec<f32>,
+        values: &mut Vec<f32>,
+        seq: usize,
+        incoming: usize,
+    ) -> usize {
+        assert!(
+            incoming <= self.window,
+            "Can't fit {incoming} new tokens into a window of {}",
+            self.window
+        );
+        assert_eq!(keys.len(), N_KV_HEADS * seq * HEAD_DIM);
+        assert_eq!(values.len(), N_KV_HEADS * seq * HEAD_DIM);
+        if seq + incoming <= self.capacity() {
+            return seq;
+        }
+        let new_seq = self.capacity() - incoming;
+        let kept_recent = new_seq.saturating_sub(self.sink_tokens);
+        // Everything before this position is evicted unless it's a sink
+        let recent_start = seq - kept_recent;
+        let sinks = self.sink_tokens.min(new_seq);
+        *keys = keep_positions(keys, seq, sinks, recent_start);
+        *values = keep_positions(values, seq, sinks, recent_start);
+        // The recent window moves from `recent_start` down to `sinks`
+        shift_rotary_positions(
+            keys,
+            new_seq,
+            sinks..new_seq,
+            sinks as f32 - recent_start as f32,
+        );
+        new_seq
+    }
+}
+
+/// Keep positions `..sinks` and `recent_start..` of a (1, kv_heads, seq, head_dim) cache
+fn keep_positions(cache: &[f32], seq: usize, sinks: usize, recent_start: usize) -> Vec<f32> {
+    cache
+        .chunks_exact(seq * HEAD_DIM)
+        .flat_map(|head| {
+            head[..sinks * HEAD_DIM]
+                .iter()
+                .chain(&head[recent_start * HEAD_DIM..])
+        })
+        .copied()
+        .collect()
+}
+
+/// Move already-rotated keys by `delta` positions, for the positions in `range` of a (1, kv_heads, seq, head_dim) key cache.
+///
+/// Rotary embeddings are a rotation by `position * freq` on each (even, odd) pair, so rotating again by `delta * freq`
+/// gives the same keys as if they had been embedded at `position + delta`. This must match the rotary embedding in the model.
+pub fn shift_rotary_positions(
+    keys: &mut [f32],
+    seq: usize,
+    range: std::ops::Range<usize>,
+    delta: f32,
+) {
+    let (sin, cos): (Vec<f32>, Vec<f32>) = (0..HEAD_DIM / 2)
+        .map(|i| {
+            let freq = ROPE_THETA.powf((2 * i) as f32 / HEAD_DIM as f32).recip();
+            (delta * freq).sin_cos()
+        })
+        .unzip();
+    for head in keys.chunks_exact_mut(seq * HEAD_DIM) {
+        for pos in range.clone() {
+            for (i, pair) in head[pos * HEAD_DIM..(pos + 1) * HEAD_DIM]
+                .chunks_exact_mut(2)
+                .enumerate()
+            {
+                let (x0, x1) = (pair[0], pair[1]);
+                pair[0] = x0 * cos[i] - x1 * sin[i];
+                pair[1] = x0 * sin[i] + x1 * cos[i];
+            }
+        }
+    }
+}
@@ -0,0 +1,68 @@
+/// Turns text prompts into token ids and streams generated ids back out as text.
+///
+/// With the `tokenizer` feature this wraps a `tokenizer.json` from the `tokenizers` crate. Without it, prompts
+/// are read as whitespace or comma separated token ids, and generated ids are printed as-is.
+pub struct PromptTokenizer {
+    #[cfg(feature = "tokenizer")]
+    tokenizer: tokenizers::Tokenizer,
+    /// Length of the output already handed out by `decode_next`
+    decoded_len: usize,
+}
+
+#[cfg(feature = "tokenizer")]
+impl PromptTokenizer {
+    pub fn load(path: &str) -> Self {
+        Self {
+            tokenizer: tokenizers::Tokenizer::from_file(path)
+                .unwrap_or_else(|e| panic!("Failed to load tokenizer from {path}: {e}")),
+            decoded_len: 0,
+        }
+    }
+
+    pub fn encode(&self, prompt: &str) -> Vec<u32> {
+        self.tokenizer
+            .encode(prompt, false)
+            .unwrap()
+            .get_ids()
+            .to_vec()
+    }
+
+    /// Decode all generated ids so far, returning only the text added since the last call
+    pub fn decode_next(&mut self, output_ids: &[u32]) -> String {
+        let output = self.tokenizer.decode(output_ids, false).unwrap();
+        let new = output[self.decoded_len.min(output.len())..].to_string();
+        self.decoded_len = output.len();
+        new
+    }
+}
+
+#[cfg(not(feature = "tokenizer"))]
+impl PromptTokenizer {
+    pub fn load(_: &str) -> Self {
+        Self { decoded_len: 0 }
+    }
+
+    pub fn encode(&self, prompt: &str) -> Vec<u32> {
+        prompt
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                s.parse().unwrap_or_else(|_| {
+                    panic!(
+                        "Without the `tokenizer` feature the prompt must be token ids, got {s:?}"
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Format the ids generated since the last call
+    pub fn decode_next(&mut self, output_ids: &[u32]) -> String {
+        let new = output_ids[self.decoded_len..]
+            .iter()
+            .map(|i| format!("{i} "))
+            .collect();
+        self.decoded_len = output_ids.len();
+        new
+    }
+}
@@ -10,6 +10,11 @@ pub const N_KV_HEADS: usize = 8;
 pub const MLP_DIM: usize = 14336;
 
 pub const N_ATTENTION_GROUPS: usize = N_HEADS / N_KV_HEADS;
+// Query heads are split evenly into groups sharing a kv head, so a config that doesn't divide evenly is rejected at compile time
+const _: () = assert!(
+    N_HEADS % N_KV_HEADS == 0,
+    "N_HEADS must be a multiple of N_KV_HEADS"
+);
 pub const HEAD_DIM: usize = HIDDEN_DIM / N_HEADS;
 pub const ATTN_PROJ_DIM: usize = HEAD_DIM * N_KV_HEADS;
 
@@ -114,13 +119,12 @@ impl Module<(GraphTensor, KVCache)> for SelfAttention {
         let repeated_values = values.expand(2, N_ATTENTION_GROUPS);
 
         // Calculate attention weights
-        let mut attention_weights = queries
+        let attention_weights = queries
             .reshape((batch, N_KV_HEADS, N_ATTENTION_GROUPS, seq, HEAD_DIM)) // Split query heads into groups
             .matmul(repeated_keys.permute((0, 1, 2, 4, 3)))
             / (HEAD_DIM as f32).sqrt();
 
-        let attention_mask = self.k_proj.graph().triu(seq, 1) * f16::MIN.to_f32();
-        attention_weights += attention_mask
+        let attention_mask = (self.k_proj.graph().triu(seq, 1) * f16::MIN.to_f32())
             .pad(((0, 0), (prev_seq, 0)))
             .expand(0, batch)
             .expand(1, N_KV_HEADS)
@@ -128,7 +132,7 @@ impl Module<(GraphTensor, KVCache)> for SelfAttention {
 
         // Calculate final outputs
         let output = attention_weights
-            .softmax(4)
+            .masked_softmax(attention_mask, 4)
             // Apply distribution to values
             .matmul(repeated_values)
             // Merge heads
@@ -220,15 +224,10 @@ impl Module<(GraphTensor, &[KVCache])> for Llama {
     type Output = (GraphTensor, Vec<KVCache>);
     fn forward(&self, (input, cache): (GraphTensor, &[KVCache])) -> Self::Output {
         // Embed tokens
-        let mut x = self.embedding.forward(input);
+        let x = self.embedding.forward(input);
 
         // Run through layers and collect new caches
-        let mut new_caches = vec![];
-        let mut new_cache;
-        for (i, layer) in self.layers.iter().enumerate() {
-            (x, new_cache) = layer.forward((x, cache[i]));
-            new_caches.push(new_cache);
-        }
+        let (x, new_caches) = forward_with_states(&self.layers, x, cache.iter().copied());
         // Run through last norm and output projection
         (self.head.forward(x), new_caches)
     }
@@ -56,7 +56,7 @@ impl Model {
                 )
             })
             .collect();
-        cache_src.set_dyn(vec![], (1, N_KV_HEADS, 0, HEAD_DIM));
+        cache_src.set_dyn(Vec::<f32>::new(), (1, N_KV_HEADS, 0, HEAD_DIM));
         let model = Llama::new(&mut cx);
         let mut model_weights = params(&model);
         cx.keep_tensors(&model_weights);
@@ -48,14 +48,12 @@ fn main() {
             )
         })
         .collect();
-    cache_src.set_dyn(vec![], (1, N_HEADS, 0, HEAD_DIM));
+    cache_src.set_dyn(Vec::<f32>::new(), (1, N_HEADS, 0, HEAD_DIM));
     let model = Phi::new(&mut cx);
     let mut model_weights = params(&model);
     cx.keep_tensors(&model_weights);
     let (logits, mut cache_dest) = model.forward((input, &cache_src));
-    let mut logits = logits
-        .slice((.., (Expression::from('s') - 1).., ..))
-        .retrieve();
+    let mut logits = logits.select_last_token().retrieve();
     cache_dest.keep();
 
     // Set up model loading
@@ -112,7 +110,7 @@ fn main() {
 
     // Now that weights are loaded, delete the loading nodes so they don't run again
     delete_inputs(&cache_src, &mut cx);
-    delete_inputs(&downstream(model_weights, &cx), &mut cx);
+    delete_inputs(downstream(model_weights, &cx), &mut cx);
 
     // Run prompt processing pass
     let input_ids = tokenizer
@@ -211,15 +211,10 @@ impl Module<(GraphTensor, &[KVCache])> for Phi {
     type Output = (GraphTensor, Vec<KVCache>);
     fn forward(&self, (input, cache): (GraphTensor, &[KVCache])) -> Self::Output {
         // Embed tokens
-        let mut x = self.embedding.forward(input);
+        let x = self.embedding.forward(input);
 
         // Run through layers and collect new caches
-        let mut new_caches = vec![];
-        let mut new_cache;
-        for (i, layer) in self.layers.iter().enumerate() {
-            (x, new_cache) = layer.forward((x, cache[i]));
-            new_caches.push(new_cache);
-        }
+        let (x, new_caches) = forward_with_states(&self.layers, x, cache.iter().copied());
         // Run through last norm and output projection
         (self.head.forward(x), new_caches)
     }
@@ -42,7 +42,7 @@ fn main() {
             )
         })
         .collect::<Vec<_>>();
-    cache_src.set_dyn(vec![], (1, 6, 64, 0));
+    cache_src.set_dyn(Vec::<f32>::new(), (1, 6, 64, 0));
     let (logits, _, mut cache_dest) = decoder.forward((encoder_output, text_input, &cache_src));
     let mut logits = logits
         .slice((.., Expression::from('s') - 1.., ..))
@@ -111,7 +111,7 @@ fn main() {
     logits.drop();
     transfer_data_same_graph(&cache_dest, &cache_src, &mut dec_cx);
     delete_inputs(&cache_src, &mut dec_cx);
-    delete_inputs(&downstream(decoder_params, &dec_cx), &mut dec_cx);
+    delete_inputs(downstream(decoder_params, &dec_cx), &mut dec_cx);
     println!("\t\t - {}ms", now.elapsed().as_millis());
 
     // Process audio into mel spectrogram
@@ -245,10 +245,7 @@ pub struct AudioEncoder {
 fn sinusoids(channels: usize, length: Expression, cx: &mut Graph) -> GraphTensor {
     let max_timescale = 10_000_f32;
     let log_timescale_increment = max_timescale.ln() / (channels / 2 - 1) as f32;
-    let inv_timescales = (0..channels / 2)
-        .map(|i| (i as f32 * (-log_timescale_increment)).exp())
-        .collect::<Vec<_>>();
-    let mut inv_timescales = cx.tensor(channels / 2).set(inv_timescales);
+    let inv_timescales = (cx.arange(channels / 2) * -log_timescale_increment).exp();
     let scaled_time = cx.arange(length).expand(1, channels / 2) * inv_timescales.expand(0, length);
     scaled_time.sin().concat_along(scaled_time.cos(), 1)
 }
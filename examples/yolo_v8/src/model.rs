@@ -47,10 +47,10 @@ impl ConvBlock {
         let eps = 1e-3;
         let mut conv = Conv2D::new(ch_in, ch_out, kernel, stride, dilation, false, cx);
         let original_weight = conv.weight;
-        let running_mean = cx.constant(0.).expand(0, ch_out);
-        let running_var = cx.constant(1.).expand(0, ch_out);
-        let o_weight = cx.constant(1.).expand(0, ch_out);
-        let o_bias = cx.constant(0.).expand(0, ch_out);
+        let running_mean = cx.zeros(ch_out);
+        let running_var = cx.ones(ch_out);
+        let o_weight = cx.ones(ch_out);
+        let o_bias = cx.zeros(ch_out);
         let std_ = o_weight / ((running_var + eps).sqrt());
         let weight = conv.weight * std_.expand(1, conv.weight.dims2().1);
         let bias = o_bias - (std_ * running_mean);
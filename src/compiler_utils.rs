@@ -329,7 +329,7 @@ impl Graph {
     ///     .finish();
     /// let b = GraphTensor::from_id(b_id, a.shape, a.graph());
     /// ```
-    pub fn add_op<O: Operator + 'static>(&mut self, op: O) -> NewOp {
+    pub fn add_op<O: Operator + 'static>(&mut self, op: O) -> NewOp<'_> {
         self.linearized_graph = None;
         NewOp {
             new_op_id: self.graph.add_node(Box::new(op)),
@@ -338,7 +338,7 @@ impl Graph {
         }
     }
     /// Add op on the graph, and get back a NewOp. Just like add_op, except a boxed op is expected.
-    pub fn add_boxed_op(&mut self, op: Box<dyn Operator + 'static>) -> NewOp {
+    pub fn add_boxed_op(&mut self, op: Box<dyn Operator + 'static>) -> NewOp<'_> {
         self.linearized_graph = None;
         NewOp {
             new_op_id: self.graph.add_node(op),
@@ -434,6 +434,17 @@ impl Graph {
             .is::<T>()
     }
 
+    /// Count the ops in the graph, keyed by op name (the debug output without any parameters)
+    pub fn op_counts(&self) -> FxHashMap<String, usize> {
+        let mut counts = FxHashMap::default();
+        for op in self.graph.node_weights() {
+            let name = format!("{op:?}");
+            let name = name.split(['(', '{']).next().unwrap().trim().to_string();
+            *counts.entry(name).or_default() += 1;
+        }
+        counts
+    }
+
     pub fn display(&self) {
         let (g, e, _) = self.debug_graph(false);
         display_graph(&g, &e, &[]);
@@ -453,6 +464,29 @@ impl Graph {
         );
     }
 
+    /// Replace `old` with a new op fed by `inputs`, returning the new node.
+    ///
+    /// `old`'s consumers are moved to the new node, `ids` (along with `no_delete` and `to_retrieve`) are remapped, and
+    /// `old` is removed. Any of `old`'s sources that are now unused still need to be removed by the caller.
+    pub fn replace_op<O: Operator + 'static, T: ToIdsMut>(
+        &mut self,
+        old: NodeIndex,
+        op: O,
+        inputs: &[(NodeIndex, u8, ShapeTracker)],
+        ids: T,
+    ) -> NodeIndex {
+        let new = inputs
+            .iter()
+            .fold(self.add_op(op), |new, (id, output, shape)| {
+                new.input(*id, *output, *shape)
+            })
+            .finish();
+        move_outgoing_edge(old, new, &mut self.graph);
+        remap(old, new, ids, self);
+        self.graph.remove_node(old);
+        new
+    }
+
     /// Remove node if it only has n dests
     pub fn safe_remove_node(&mut self, node: NodeIndex, dests: usize) {
         if self
@@ -596,6 +630,32 @@ pub fn move_outgoing_edge<N, E: Clone>(
     }
 }
 
+/// Carry over outgoing edges from `from` to output `output` of a multi-output node `to`
+pub fn move_outgoing_edge_to_output<N>(
+    from: NodeIndex,
+    to: NodeIndex,
+    output: u8,
+    graph: &mut StableGraph<N, Dependency>,
+) {
+    for (weight, target) in graph
+        .edges_directed(from, petgraph::Direction::Outgoing)
+        .map(|e| (*e.weight(), e.target()))
+        .collect::<Vec<_>>()
+    {
+        let weight = match weight {
+            Dependency::Data {
+                input_order, shape, ..
+            } => Dependency::Data {
+                input_order,
+                output_order: output,
+                shape,
+            },
+            Dependency::Schedule => Dependency::Schedule,
+        };
+        graph.add_edge(to, target, weight);
+    }
+}
+
 pub fn move_incoming_edge<N, E: Clone>(
     from: NodeIndex,
     to: NodeIndex,
@@ -720,7 +780,7 @@ fn backtrack_match(
             if let Some(new_mapping) =
                 backtrack_match(pattern_parent, pattern_graph, *parent, main_graph)
             {
-                mapping.extend(new_mapping.into_iter());
+                mapping.extend(new_mapping);
                 continue 'pattern_loop;
             }
         }
@@ -763,7 +823,7 @@ fn test_node(
             if a_sh.len() != b_sh.dims.len() {
                 return false;
             }
-            for (a, b) in a_sh.iter().zip(b_sh.dims().into_iter()) {
+            for (a, b) in a_sh.iter().zip(b_sh.dims()) {
                 match a.to_usize() {
                     Some(n) => {
                         if b.to_usize().map(|i| i != n).unwrap_or(true) {
@@ -872,6 +932,16 @@ impl SelectGraph {
     pub fn check<F: Fn(&mut dyn Operator, &[ShapeTracker]) -> bool + 'static>(&mut self, check: F) {
         self.graph.node_weight_mut(self.node).unwrap().1.check = Some(Arc::new(Box::new(check)));
     }
+    /// Constrain the op to a type, and to a predicate on that op's attributes
+    pub fn attr<O: Operator + 'static, F: Fn(&O) -> bool + 'static>(&mut self, attr: F) {
+        self.ty::<O>();
+        self.check(move |o, _| {
+            o.as_any()
+                .downcast_ref::<O>()
+                .map(&attr)
+                .unwrap_or_default()
+        });
+    }
     /// Constrain the op to input shapes
     pub fn shapes<E: Into<Expression>, V: Into<Vec<E>>, S: Into<Vec<V>>>(&mut self, shapes: S) {
         self.graph.node_weight_mut(self.node).unwrap().1.shape = Some(
@@ -176,6 +176,34 @@ impl Compiler for RemoveUnusedNodes {
     }
 }
 
+/// Remove `Barrier`s, pointing their dests at the barrier's input. Meant to be ran **after** a backend's fusion passes,
+/// which don't match across the barrier.
+#[derive(Default, Debug)]
+pub struct RemoveBarriers;
+
+impl Compiler for RemoveBarriers {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        for barrier in graph
+            .node_indices()
+            .filter(|n| graph.check_node_type::<Barrier>(*n))
+            .collect::<Vec<_>>()
+        {
+            let (src, src_output, _) = graph.get_sources(barrier)[0];
+            // A retrieved barrier can only hand its retrieval over to output 0 of a source that isn't retrieved itself
+            if graph.to_retrieve.contains_key(&barrier)
+                && (src_output != 0 || graph.to_retrieve.contains_key(&src))
+            {
+                continue;
+            }
+            // The barrier's output is its input's data, so dests read the source with the same shapes
+            move_outgoing_edge_to_output(barrier, src, src_output, graph);
+            remap(barrier, src, &mut ids, graph);
+            graph.remove_node(barrier);
+        }
+    }
+}
+
 /// Enforce the graph gets ran in strictly depth-first order
 #[derive(Default, Debug)]
 pub struct DepthFirst;
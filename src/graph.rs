@@ -1,7 +1,9 @@
 use crate::prelude::*;
 use std::{
+    cell::Cell,
     io::Write,
     ops::{Deref, DerefMut},
+    rc::Rc,
     time::Duration,
 };
 
@@ -34,6 +36,42 @@ pub struct Graph {
     pub(crate) linearized_graph: Option<Vec<(NodeIndex, Vec<(NodeIndex, u8, ShapeTracker)>)>>,
     /// Cached consumers (for execution only)
     consumers_map: Option<FxHashMap<(NodeIndex, u8), usize>>,
+    /// The seed shared with all random ops
+    pub(crate) seed: Rc<Cell<random::SeedState>>,
+    /// The number of random streams handed out so far
+    pub(crate) random_streams: u32,
+    /// The seeds of graphs merged into this one, which follow this graph's seed
+    pub(crate) merged_seeds: Vec<Rc<Cell<random::SeedState>>>,
+    /// Flops of each op, counted on the primitive graph before the first compile swapped in backend ops. Cleared
+    /// along with `linearized_graph` whenever the graph is edited outside a compile, since the count no longer fits it.
+    pub(crate) primitive_flops: Option<Vec<Expression>>,
+}
+
+/// Difference between a compiled run and the reference run of a retrieved tensor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TensorError {
+    /// Largest absolute difference of any element
+    pub max_abs: f32,
+    /// Largest absolute difference divided by the reference magnitude (floored at `f32::EPSILON`)
+    pub max_rel: f32,
+}
+
+/// Where a node without inputs gets its data from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputKind {
+    /// A tensor made with `tensor` / `named_tensor`, whose data is set by the user
+    Load,
+    /// A host function that generates its data when ran, like random numbers
+    Generated,
+    /// Any other op, like a constant
+    Op,
+}
+
+/// Named tensor data and dynamic dimensions captured by `Graph::checkpoint`, to be put back with `Graph::restore`
+#[derive(Debug, Clone, Default)]
+pub struct Checkpoint {
+    pub tensors: FxHashMap<String, Tensor>,
+    pub dyn_map: FxHashMap<char, usize>,
 }
 
 /// A dependency between two nodes
@@ -101,6 +139,33 @@ impl Graph {
         }
     }
 
+    /// Mark an intermediate node's output for readback, returning a handle to retrieve it with.
+    /// The shape is taken from the views its consumers read it through.
+    ///
+    /// Tapped nodes are kept, so compilers won't fuse across them (e.g. a tapped matmul output won't get folded into
+    /// the following elementwise ops), and their buffers stay alive after execution. Pass the handle to `compile` so
+    /// it follows the node if it gets replaced.
+    pub fn tap(&mut self, node: NodeIndex) -> GraphTensor {
+        let shape = self
+            .graph
+            .edges_directed(node, Direction::Outgoing)
+            .filter_map(|e| e.weight().as_data())
+            .find(|(_, output, _)| *output == 0)
+            .map(|(_, _, sh)| {
+                // Consumers see a view of the node's output, so strip back to the physical dims
+                ShapeTracker::new(
+                    (0..sh.len())
+                        .filter(|i| !sh.fake[*i])
+                        .map(|i| sh.dims[i])
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .unwrap_or_else(|| {
+                panic!("Can't tap node {node:?}, it has no consumers to take its shape from")
+            });
+        GraphTensor::from_id(node, shape, self).retrieve()
+    }
+
     /// Set a tensor's data
     pub fn set_tensor(&mut self, id: NodeIndex, ind: u8, tensor: Tensor) {
         self.tensors.insert((id, ind), tensor);
@@ -131,12 +196,36 @@ impl Graph {
 
     /// Compile the graph using the given compiler
     pub fn compile<T: ToIdsMut, C: Compiler>(&mut self, compiler: C, remap: T) -> C::Output {
+        if self.primitive_flops.is_none() {
+            self.primitive_flops = Some(self.op_flops());
+        }
         let output = compiler.compile(self, remap);
+        self.assert_no_cycles();
         self.toposort();
         self.reset();
         output
     }
 
+    /// Panic if the graph contains a cycle, listing the ops that form it.
+    ///
+    /// Ran after every compile, since a compiler rewiring edges can accidentally create one.
+    pub fn assert_no_cycles(&self) {
+        let Some(cycle) = petgraph::algo::tarjan_scc(&self.graph)
+            .into_iter()
+            .find(|c| c.len() > 1 || self.graph.contains_edge(c[0], c[0]))
+        else {
+            return;
+        };
+        panic!(
+            "Graph contains a cycle between nodes: {}",
+            cycle
+                .into_iter()
+                .sorted()
+                .map(|n| format!("{} ({:?})", n.index(), self.graph.node_weight(n).unwrap()))
+                .join(", ")
+        );
+    }
+
     /// Refresh the internally sorted graph
     pub(crate) fn toposort(&mut self) {
         self.linearized_graph = Some(
@@ -164,6 +253,56 @@ impl Graph {
         );
     }
 
+    /// How a node gets its data if it has no inputs, or `None` if it's computed from other nodes
+    pub fn input_kind(&self, node: NodeIndex) -> Option<InputKind> {
+        if self
+            .graph
+            .edges_directed(node, Direction::Incoming)
+            .any(|e| !e.weight().is_schedule())
+        {
+            return None;
+        }
+        Some(
+            match self
+                .graph
+                .node_weight(node)?
+                .as_any()
+                .downcast_ref::<Function>()
+            {
+                Some(Function(name, _)) if name.ends_with(" Load") => InputKind::Load,
+                Some(_) => InputKind::Generated,
+                None => InputKind::Op,
+            },
+        )
+    }
+
+    /// Nodes whose data is set by the user, in node order
+    pub fn input_tensors(&self) -> Vec<NodeIndex> {
+        self.graph
+            .node_indices()
+            .filter(|n| self.input_kind(*n) == Some(InputKind::Load))
+            .sorted()
+            .collect()
+    }
+
+    /// Nodes marked to be retrieved, in node order
+    pub fn output_tensors(&self) -> Vec<NodeIndex> {
+        self.to_retrieve.keys().copied().sorted().collect()
+    }
+
+    /// Get the order nodes will be run in when the graph is executed
+    pub fn execution_order(&mut self) -> Vec<NodeIndex> {
+        if self.linearized_graph.is_none() {
+            self.toposort();
+        }
+        self.linearized_graph
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|(node, _)| *node)
+            .collect()
+    }
+
     /// Swap the tensors with these ids
     pub fn swap_tensors(&mut self, a: GraphTensor, b: GraphTensor) {
         // Swap tensors
@@ -182,6 +321,127 @@ impl Graph {
         }
     }
 
+    /// Merge another graph into this one, returning a map from the other graph's node ids to their new ids.
+    ///
+    /// Ops, edges, tensor data, `no_delete` and `to_retrieve` are all carried over, and random ops from `other` follow
+    /// this graph's seed. GraphTensors built on `other` point at the old graph, so rebuild them with the remapped ids.
+    pub fn merge(&mut self, mut other: Graph) -> FxHashMap<NodeIndex, NodeIndex> {
+        let edges = other
+            .graph
+            .edge_indices()
+            .map(|e| {
+                let (src, dest) = other.graph.edge_endpoints(e).unwrap();
+                (src, dest, *other.graph.edge_weight(e).unwrap())
+            })
+            .collect::<Vec<_>>();
+        let mut map = FxHashMap::default();
+        for node in other.graph.node_indices().sorted().collect::<Vec<_>>() {
+            let mut op = other.graph.remove_node(node).unwrap();
+            // Constants read dynamic dimensions through a pointer to their graph's dyn map
+            if let Some(constant) = op.as_any_mut().downcast_mut::<crate::op::Constant>() {
+                constant.1 = &self.dyn_map;
+            }
+            map.insert(node, self.graph.add_node(op));
+        }
+        for (src, dest, weight) in edges {
+            self.graph.add_edge(map[&src], map[&dest], weight);
+        }
+        for ((node, ind), tensor) in other.tensors.drain() {
+            self.tensors.insert((map[&node], ind), tensor);
+        }
+        self.no_delete
+            .extend(other.no_delete.iter().map(|n| map[n]));
+        self.to_retrieve
+            .extend(other.to_retrieve.iter().map(|(n, v)| (map[n], *v)));
+        for (dim, val) in other.dyn_map.drain() {
+            self.dyn_map.entry(dim).or_insert(val);
+        }
+        self.merge_seed(&other);
+        self.linearized_graph = None;
+        self.primitive_flops = None;
+        // Dropping a graph clears the thread-local expression storage, which our shapes still use
+        other.drop_keeping_expressions();
+        map
+    }
+
+    /// Free everything this graph owns without clearing the thread-local expression storage, for graphs torn down
+    /// while another graph sharing that storage is still alive.
+    ///
+    /// Every field is taken out and dropped here, and only the emptied graph is forgotten so its `Drop` doesn't run.
+    /// That leaves just the fresh seed cell of the empty graph unfreed.
+    pub(crate) fn drop_keeping_expressions(mut self) {
+        let Graph {
+            tensors,
+            dyn_map,
+            graph,
+            no_delete,
+            to_retrieve,
+            linearized_graph,
+            consumers_map,
+            seed,
+            random_streams,
+            merged_seeds,
+            primitive_flops,
+        } = &mut self;
+        drop((
+            std::mem::take(tensors),
+            std::mem::take(dyn_map),
+            std::mem::take(graph),
+            std::mem::take(no_delete),
+            std::mem::take(to_retrieve),
+            std::mem::take(linearized_graph),
+            std::mem::take(consumers_map),
+            std::mem::take(seed),
+            std::mem::take(random_streams),
+            std::mem::take(merged_seeds),
+            std::mem::take(primitive_flops),
+        ));
+        std::mem::forget(self);
+    }
+
+    /// Merge duplicated constants, so identical tables and masks built more than once are only computed once.
+    ///
+    /// Unkept input tensors already holding bitwise-identical host data (from `set_tensor`) are merged into one node.
+    /// Kept tensors, like model weights, are never merged, since they may later be given different data. Common
+    /// subexpression elimination then folds the identical op chains built on top of them, along with any built from
+    /// constant ops like `arange` and the causal masks. Host functions are opaque, so they're never merged.
+    pub fn dedup_constants<T: ToIdsMut>(&mut self, mut remap: T) {
+        let mut seen: FxHashMap<(String, Vec<u32>), NodeIndex> = FxHashMap::default();
+        for node in self.graph.node_indices().collect_vec() {
+            if self.no_delete.contains(&node)
+                || self
+                    .graph
+                    .edges_directed(node, Direction::Incoming)
+                    .next()
+                    .is_some()
+            {
+                continue;
+            }
+            let Some(data) = self
+                .tensors
+                .get(&(node, 0))
+                .and_then(|t| t.downcast_ref::<Vec<f32>>())
+            else {
+                continue;
+            };
+            let key = (
+                format!("{:?}", self.graph.node_weight(node).unwrap()),
+                data.iter().map(|f| f.to_bits()).collect_vec(),
+            );
+            if let Some(&kept) = seen.get(&key) {
+                crate::compiler_utils::move_outgoing_edge(node, kept, &mut self.graph);
+                crate::compiler_utils::remap(node, kept, &mut remap, self);
+                self.tensors.remove(&(node, 0));
+                self.graph.remove_node(node);
+            } else {
+                seen.insert(key, node);
+            }
+        }
+        CSE.compile(self, &mut remap);
+        self.linearized_graph = None;
+        self.primitive_flops = None;
+    }
+
     /// Clear any remaining tensors that may be around from old executions
     pub fn reset(&mut self) {
         self.tensors.retain(|(n, _), _| self.no_delete.contains(n));
@@ -189,15 +449,183 @@ impl Graph {
 
     /// Execute the graph.
     pub fn execute(&mut self) {
+        self.run(None, None);
+    }
+
+    /// Execute the graph, returning the total wall-clock time. This includes any time spent waiting on the GPU,
+    /// so it's a quick way to check whether a change made things faster.
+    pub fn execute_timed(&mut self) -> Duration {
+        let start = std::time::Instant::now();
+        self.run(None, None);
+        start.elapsed()
+    }
+
+    /// Execute the graph, returning the total wall-clock time along with the CPU-side time spent in each op, in execution order.
+    ///
+    /// Op times cover the op's `process` call. Ops that enqueue GPU work without waiting on it (e.g. inside a shared
+    /// command buffer) only report the time to encode it, and the wait shows up on whichever op commits the buffer.
+    pub fn execute_timed_ops(&mut self) -> (Duration, Vec<(NodeIndex, Duration)>) {
+        let mut op_times = vec![];
+        let start = std::time::Instant::now();
+        self.run(Some(&mut op_times), None);
+        (start.elapsed(), op_times)
+    }
+
+    /// Total floating point operations one execution of the graph performs, with dynamic dimensions resolved from the
+    /// current dyn map. Divide by `execute_timed` to get the achieved FLOP/s.
+    ///
+    /// Elementwise primitives count one op per output element and reductions one per input element, so a matmul lowered
+    /// to a broadcast multiply and sum reduce counts `2 * M * N * K`. Backend ops report their own count through the
+    /// `"flops"` custom key, which is given the input shapes as a `Vec<ShapeTracker>` and answers with an `Expression`.
+    /// Ops that do neither, like copies and host functions, count as zero.
+    ///
+    /// Compiled graphs are counted as they were before the first compile, since fused and device ops can't all report
+    /// what they do. Merging or deduplicating a compiled graph drops that count, after which its ops are counted as
+    /// they are now.
+    pub fn flop_count(&mut self) -> usize {
+        let flops = match &self.primitive_flops {
+            Some(flops) => flops.clone(),
+            None => self.op_flops(),
+        };
+        flops
+            .into_iter()
+            .map(|f| {
+                f.exec(&self.dyn_map).unwrap_or_else(|| {
+                    panic!("Can't count the flops of {f:?}, are all dynamic dimensions set?")
+                })
+            })
+            .sum()
+    }
+
+    /// Symbolic flops of every op in the graph that does any
+    fn op_flops(&mut self) -> Vec<Expression> {
+        let nodes = self.graph.node_indices().collect::<Vec<_>>();
+        let mut counts = vec![];
+        for node in nodes {
+            let shapes = self
+                .get_sources(node)
+                .into_iter()
+                .map(|(_, _, sh)| sh)
+                .collect::<Vec<_>>();
+            let flops = if let Some(flops) = self
+                .graph
+                .node_weight_mut(node)
+                .unwrap()
+                .custom("flops", Box::new(shapes.clone()))
+            {
+                *flops.downcast::<Expression>().unwrap()
+            } else if self.check_node_type::<crate::op::Log2>(node)
+                || self.check_node_type::<crate::op::Exp2>(node)
+                || self.check_node_type::<crate::op::Sin>(node)
+                || self.check_node_type::<crate::op::Recip>(node)
+                || self.check_node_type::<crate::op::Sqrt>(node)
+                || self.check_node_type::<crate::op::Add>(node)
+                || self.check_node_type::<crate::op::Mul>(node)
+                || self.check_node_type::<crate::op::Mod>(node)
+                || self.check_node_type::<crate::op::LessThan>(node)
+                || self.check_node_type::<crate::op::SumReduce>(node)
+                || self.check_node_type::<crate::op::MaxReduce>(node)
+            {
+                // Binary inputs share the output shape, and reductions read every input element
+                shapes[0].n_elements()
+            } else {
+                continue;
+            };
+            counts.push(flops);
+        }
+        counts
+    }
+
+    /// Execute only the ops the `outputs` depend on, skipping the rest of the graph.
+    ///
+    /// Any other retrieved tensors aren't computed, so they keep whatever data they had before this call.
+    pub fn execute_subset(&mut self, outputs: &[NodeIndex]) {
+        let mut needed = FxHashSet::default();
+        let mut stack = outputs.to_vec();
+        while let Some(node) = stack.pop() {
+            if needed.insert(node) {
+                stack.extend(self.graph.neighbors_directed(node, Direction::Incoming));
+            }
+        }
+        self.run(None, Some(&needed));
+    }
+
+    /// Copy the data of computed tensors out under the given names, along with the current dynamic dimensions.
+    ///
+    /// The copies don't change when the graph runs again, so something like a prefilled KV cache can be checkpointed
+    /// once and restored before each continuation. The tensors must still be around, so mark them with `keep_tensors`
+    /// or `retrieve` them.
+    pub fn checkpoint<S: ToString>(
+        &self,
+        tensors: impl IntoIterator<Item = (S, NodeIndex)>,
+    ) -> Checkpoint {
+        Checkpoint {
+            tensors: tensors
+                .into_iter()
+                .map(|(name, id)| {
+                    let tensor = self.get_tensor_ref(id, 0).unwrap_or_else(|| {
+                        panic!(
+                            "Can't checkpoint {}, node {id:?} has no data",
+                            name.to_string()
+                        )
+                    });
+                    (name.to_string(), tensor.deep_clone())
+                })
+                .collect(),
+            dyn_map: self.dyn_map.clone(),
+        }
+    }
+
+    /// Set the named tensors of a checkpoint as the data of the given nodes, and restore its dynamic dimensions.
+    ///
+    /// The nodes don't need to be the ones the data was checkpointed from, so a prefill's cache outputs can be restored
+    /// into a decode graph's cache inputs. Each restore gets its own copy, so a checkpoint can be restored any number
+    /// of times.
+    pub fn restore<S: AsRef<str>>(
+        &mut self,
+        checkpoint: &Checkpoint,
+        tensors: impl IntoIterator<Item = (S, NodeIndex)>,
+    ) {
+        for (name, id) in tensors {
+            let tensor = checkpoint
+                .tensors
+                .get(name.as_ref())
+                .unwrap_or_else(|| panic!("Checkpoint has no tensor named {:?}", name.as_ref()));
+            self.set_tensor(id, 0, tensor.deep_clone());
+        }
+        self.dyn_map
+            .extend(checkpoint.dyn_map.iter().map(|(d, v)| (*d, *v)));
+    }
+
+    fn run(
+        &mut self,
+        mut op_times: Option<&mut Vec<(NodeIndex, Duration)>>,
+        only: Option<&FxHashSet<NodeIndex>>,
+    ) {
         // Track the number of views pointing to each tensor so we know when to clear
         if self.linearized_graph.is_none() {
             self.toposort();
         }
-        let mut consumers = self.consumers_map.as_ref().unwrap().clone();
+        let mut consumers = if let Some(only) = only {
+            // Skipped ops never consume their sources, so only count the ones that will run
+            let mut consumers = FxHashMap::default();
+            for (node, src_ids) in self.linearized_graph.as_ref().unwrap() {
+                if only.contains(node) {
+                    for (id, ind, _) in src_ids {
+                        *consumers.entry((*id, *ind)).or_default() += 1;
+                    }
+                }
+            }
+            consumers
+        } else {
+            self.consumers_map.as_ref().unwrap().clone()
+        };
         let mut dim_stack = Vec::new();
 
         for (node, src_ids) in self.linearized_graph.as_ref().unwrap() {
-            if self.tensors.contains_key(&(*node, 0)) {
+            if only.is_some_and(|only| !only.contains(node))
+                || self.tensors.contains_key(&(*node, 0))
+            {
                 continue;
             }
 
@@ -210,7 +638,11 @@ impl Graph {
             }
 
             // Execute
+            let now = op_times.is_some().then(std::time::Instant::now);
             let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
+            if let (Some(op_times), Some(now)) = (op_times.as_mut(), now) {
+                op_times.push((*node, now.elapsed()));
+            }
             for (i, tensor) in tensors.into_iter().enumerate() {
                 self.tensors.insert((*node, i as u8), tensor);
             }
@@ -223,6 +655,94 @@ impl Graph {
         self.reset();
     }
 
+    /// Set the data of a batch of input nodes, execute the graph, and return the data of every retrieved tensor.
+    ///
+    /// The data is set like `set_tensor`, so it's only used for this run and the input ops are left alone: inputs not
+    /// passed fall back to whatever they were given with `set`. Outputs are taken out of the graph, so calling this
+    /// again with new inputs recomputes them. Input ids should be the ones remapped by `compile`, and any dynamic
+    /// dimensions still need to be set with `set_dyn_dim`.
+    pub fn execute_with_inputs(
+        &mut self,
+        inputs: impl IntoIterator<Item = (NodeIndex, Vec<f32>)>,
+    ) -> FxHashMap<NodeIndex, Vec<f32>> {
+        for (id, data) in inputs {
+            self.set_tensor(id, 0, Tensor::new(data));
+        }
+        // Clear stale outputs so they get recomputed
+        self.drop_tensors(self.to_retrieve.keys().copied().collect::<Vec<_>>());
+        self.execute();
+        let outputs = self
+            .to_retrieve
+            .clone()
+            .into_iter()
+            .map(|(id, (_, shape))| (id, GraphTensor::from_id(id, shape, self).data()))
+            .collect();
+        self.drop_tensors(self.to_retrieve.keys().copied().collect::<Vec<_>>());
+        outputs
+    }
+
+    /// Differentially test a compiler: build the graph twice with `build`, run one copy through `compiler` and the other
+    /// unoptimized on the CPU, and report the error of each tensor `build` returns, in order.
+    ///
+    /// `build` must set every input itself, with the same data both times. Mismatched NaNs and infinities count as an
+    /// infinite error. The graphs built here are torn down without clearing expression storage, so other graphs stay
+    /// usable across this call.
+    pub fn verify_against_cpu<C: Compiler>(
+        compiler: C,
+        build: impl Fn(&mut Graph) -> Vec<GraphTensor>,
+    ) -> Vec<TensorError> {
+        let mut reference = Graph::new();
+        let expected = build(&mut reference)
+            .into_iter()
+            .map(|t| t.retrieve())
+            .collect::<Vec<_>>();
+        reference.execute();
+
+        let mut cx = Graph::new();
+        let mut outputs = build(&mut cx)
+            .into_iter()
+            .map(|t| t.retrieve())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outputs.len(),
+            expected.len(),
+            "Graph builder returned a different number of tensors between runs"
+        );
+        cx.compile(compiler, &mut outputs);
+        cx.execute();
+
+        let errors = outputs
+            .iter()
+            .zip(&expected)
+            .map(|(out, exp)| {
+                let (out, exp) = (out.data(), exp.data());
+                assert_eq!(out.len(), exp.len(), "Compiled output has a different size");
+                out.iter().zip(&exp).fold(
+                    TensorError {
+                        max_abs: 0.,
+                        max_rel: 0.,
+                    },
+                    |err, (a, b)| {
+                        let abs = if a.is_nan() && b.is_nan() || a == b {
+                            0.
+                        } else if a.is_finite() && b.is_finite() {
+                            (a - b).abs()
+                        } else {
+                            f32::INFINITY
+                        };
+                        TensorError {
+                            max_abs: err.max_abs.max(abs),
+                            max_rel: err.max_rel.max(abs / b.abs().max(f32::EPSILON)),
+                        }
+                    },
+                )
+            })
+            .collect();
+        reference.drop_keeping_expressions();
+        cx.drop_keeping_expressions();
+        errors
+    }
+
     /// Execute the graph without deleting intermediate tensors
     pub fn execute_no_delete(&mut self) {
         // Track the number of views pointing to each tensor so we know when to clear;
@@ -65,7 +65,7 @@ impl GraphTensor {
     /// ```
     pub fn set_dyn(self, data: impl Data + Clone, shape: impl ToShape) -> Self {
         // Report dyn dim values to graph dyn map
-        for (d, s) in self.shape.dims().iter().zip(shape.to_shape().into_iter()) {
+        for (d, s) in self.shape.dims().iter().zip(shape.to_shape()) {
             if let Some(c) = d.to_symbols().pop() {
                 self.graph().dyn_map.insert(c, s.to_usize().unwrap());
             }
@@ -86,15 +86,60 @@ impl GraphTensor {
             .graph()
             .get_tensor_ref(self.id, 0)
             .expect("Tensor not found in the graph!");
-        let orig_data = tensor
+        let waited = tensor.wait_for_data();
+        let orig_data = waited
+            .as_ref()
+            .unwrap_or(tensor)
             .downcast_ref::<Vec<f32>>()
             .expect("Data for tensor is not Vec<f32>!");
+        self.contiguous_data(orig_data)
+    }
+
+    /// Whether the tensor's data has finished being computed, for polling outputs retrieved without waiting on the
+    /// device. Reading the data of a tensor that isn't ready blocks until it is.
+    pub fn is_ready(&self) -> bool {
+        self.graph()
+            .get_tensor_ref(self.id, 0)
+            .map(|t| t.is_ready())
+            .unwrap_or_default()
+    }
+
+    /// Get the contiguous data of an integer tensor
+    pub fn data_i32(&self) -> Vec<i32> {
+        let tensor = self
+            .graph()
+            .get_tensor_ref(self.id, 0)
+            .expect("Tensor not found in the graph!");
+        let waited = tensor.wait_for_data();
+        let orig_data = waited
+            .as_ref()
+            .unwrap_or(tensor)
+            .downcast_ref::<Vec<i32>>()
+            .expect("Data for tensor is not Vec<i32>!");
+        self.contiguous_data(orig_data)
+    }
+
+    /// Get the contiguous data of the tensor along with its concrete shape, with dynamic dimensions resolved from the
+    /// last execution
+    pub fn to_cpu_vec(&self) -> (Vec<f32>, Vec<usize>) {
+        (self.data(), self.resolved_shape())
+    }
+
+    /// The concrete shape of this tensor, with dynamic dimensions substituted from the graph
+    pub fn resolved_shape(&self) -> Vec<usize> {
+        let mut st = self.shape;
+        st.resolve_global_dyn_dims(&self.graph().dyn_map);
+        st.shape_usize()
+    }
+
+    /// Resolve this tensor's shape tracker over a raw buffer
+    fn contiguous_data<T: Copy + Default>(&self, orig_data: &[T]) -> Vec<T> {
         let mut st = self.shape;
         if !st.is_reshaped() {
-            return orig_data.clone();
+            return orig_data.to_vec();
         }
         st.resolve_global_dyn_dims(&self.graph().dyn_map);
-        let mut data = vec![0.; st.n_elements().to_usize().unwrap()];
+        let mut data = vec![T::default(); st.n_elements().to_usize().unwrap()];
         let (ind, val) = (
             st.index_expression_no_simplify(),
             st.valid_expression_no_simplify(),
@@ -170,6 +215,37 @@ impl GraphTensor {
         self
     }
 
+    /// Set the value of the tensor from a `.npy` file of f32 or f16 data. Dynamic dimensions are taken from the file's shape.
+    pub fn set_from_npy(self, path: impl AsRef<std::path::Path>) -> Self {
+        let path = path.as_ref();
+        let (shape, data) = crate::npy::read_npy(path)
+            .unwrap_or_else(|e| panic!("Failed to read {}: {e}", path.display()));
+        assert_eq!(
+            shape.len(),
+            self.shape.len(),
+            "{} has shape {shape:?}, which doesn't match the tensor's rank",
+            path.display()
+        );
+        for (dim, file_dim) in self.dims().iter().zip(&shape) {
+            if let Some(dim) = dim.to_usize() {
+                assert_eq!(
+                    dim,
+                    *file_dim,
+                    "{} has shape {shape:?}, which doesn't match the tensor's shape {:?}",
+                    path.display(),
+                    self.dims()
+                );
+            }
+        }
+        self.set_dyn(data, shape)
+    }
+
+    /// Write the retrieved value of the tensor to a `.npy` file
+    pub fn save_npy(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        let (data, shape) = self.to_cpu_vec();
+        crate::npy::write_npy(path, &shape, &data)
+    }
+
     /// Set the tensor with a generating closure to be ran at runtime
     pub fn set_deferred(self, loader: impl Fn() -> Vec<f32> + 'static) -> Self {
         self.graph().get_op_mut::<Function>(self.id).1 =
@@ -397,6 +473,17 @@ impl ToData<Vec<f32>> for Vec<f32> {
         (self, vec![l])
     }
 }
+impl ToData<Vec<i32>> for Vec<i32> {
+    fn to_data_vec(self) -> (Vec<i32>, Vec<usize>) {
+        let l = self.len();
+        (self, vec![l])
+    }
+}
+impl<const A: usize> ToData<Vec<i32>> for [i32; A] {
+    fn to_data_vec(self) -> (Vec<i32>, Vec<usize>) {
+        (self.to_vec(), vec![A])
+    }
+}
 impl ToData<Vec<f32>> for f32 {
     fn to_data_vec(self) -> (Vec<f32>, Vec<usize>) {
         (vec![self], vec![1])
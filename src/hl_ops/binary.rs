@@ -11,17 +11,7 @@ impl Add for GraphTensor {
     type Output = GraphTensor;
 
     fn add(self, rhs: GraphTensor) -> Self::Output {
-        // assert_eq!(
-        //     self.dims()
-        //         .into_iter()
-        //         .map(|i| i.simplify())
-        //         .collect::<Vec<_>>(),
-        //     rhs.dims()
-        //         .into_iter()
-        //         .map(|i| i.simplify())
-        //         .collect::<Vec<_>>(),
-        //     "Dims must match to add tensors."
-        // );
+        self.check_binary_shapes(rhs, "add");
         let new_id = self
             .graph()
             .add_op(op::Add)
@@ -72,11 +62,7 @@ impl Mul for GraphTensor {
     type Output = GraphTensor;
 
     fn mul(self, rhs: GraphTensor) -> Self::Output {
-        // assert_eq!(
-        //     self.dims(),
-        //     rhs.dims(),
-        //     "Dims must match to multiply tensors."
-        // );
+        self.check_binary_shapes(rhs, "mul");
         let new_id = self
             .graph()
             .add_op(op::Mul)
@@ -129,7 +115,7 @@ impl Rem<GraphTensor> for GraphTensor {
     type Output = GraphTensor;
 
     fn rem(self, rhs: GraphTensor) -> Self::Output {
-        assert_eq!(self.dims(), rhs.dims(), "Dims must match to mod tensors.");
+        self.check_binary_shapes(rhs, "mod");
         let new_id = self
             .graph()
             .add_op(op::Mod)
@@ -227,10 +213,32 @@ impl<S: Into<Expression>> Rem<S> for GraphTensor {
     }
 }
 
+impl GraphTensor {
+    /// Panic at build time if two operands of an elementwise op don't have the same shape.
+    ///
+    /// Symbolic dimensions are only compared when they simplify to known sizes.
+    fn check_binary_shapes(self, rhs: GraphTensor, op: &str) {
+        let (a, b) = (self.dims(), rhs.dims());
+        assert_eq!(
+            a.len(),
+            b.len(),
+            "Can't {op} tensors of shape {a:?} and {b:?}: ranks differ, expand one side first"
+        );
+        for (i, (da, db)) in a.iter().zip(&b).enumerate() {
+            if let (Some(x), Some(y)) = (da.to_usize(), db.to_usize()) {
+                assert_eq!(
+                    x, y,
+                    "Can't {op} tensors of shape {a:?} and {b:?}: axis {i} is {x} vs {y}"
+                );
+            }
+        }
+    }
+}
+
 // Comparisons (based on https://github.com/tinygrad/tinygrad/blob/3e0c2d256fe9f4f5f85cd3e4d8733a51d7b4a984/tinygrad/tensor.py#L653)
 impl GraphTensor {
     pub fn less_than(self, rhs: GraphTensor) -> GraphTensor {
-        assert_eq!(self.dims(), rhs.dims(), "Dims must match to lt tensors.");
+        self.check_binary_shapes(rhs, "less_than");
         let new_id = self
             .graph()
             .add_op(op::LessThan)
@@ -260,6 +268,90 @@ impl GraphTensor {
         -self.not_equals(rhs) + 1.0
     }
 
+    /// Broadcast two tensors to a common shape, aligning dimensions from the right
+    fn broadcast_with(self, rhs: GraphTensor) -> (GraphTensor, GraphTensor) {
+        let (mut a, mut b) = (self, rhs);
+        while a.shape.len() < b.shape.len() {
+            a = a.expand(0, 1);
+        }
+        while b.shape.len() < a.shape.len() {
+            b = b.expand(0, 1);
+        }
+        for (i, (da, db)) in a.dims().into_iter().zip(b.dims()).enumerate() {
+            if da == db {
+                continue;
+            }
+            if da.to_usize() == Some(1) {
+                a.shape.remove_dim(i);
+                a = a.expand(i, db);
+            } else if db.to_usize() == Some(1) {
+                b.shape.remove_dim(i);
+                b = b.expand(i, da);
+            } else {
+                panic!("Cannot broadcast dimension {i}: {da} and {db}");
+            }
+        }
+        (a, b)
+    }
+
+    /// Elementwise `self == rhs` with broadcasting, producing a 0/1 mask
+    pub fn eq(self, rhs: GraphTensor) -> GraphTensor {
+        let (a, b) = self.broadcast_with(rhs);
+        a.equals(b)
+    }
+
+    /// Elementwise `self != rhs` with broadcasting, producing a 0/1 mask
+    pub fn ne(self, rhs: GraphTensor) -> GraphTensor {
+        let (a, b) = self.broadcast_with(rhs);
+        a.not_equals(b)
+    }
+
+    /// Elementwise `self > rhs` with broadcasting, producing a 0/1 mask
+    pub fn gt(self, rhs: GraphTensor) -> GraphTensor {
+        let (a, b) = self.broadcast_with(rhs);
+        a.greater_than(b)
+    }
+
+    /// Elementwise `self >= rhs` with broadcasting, producing a 0/1 mask
+    pub fn ge(self, rhs: GraphTensor) -> GraphTensor {
+        let (a, b) = self.broadcast_with(rhs);
+        a.greater_than_equal(b)
+    }
+
+    /// Elementwise `self < rhs` with broadcasting, producing a 0/1 mask
+    pub fn lt(self, rhs: GraphTensor) -> GraphTensor {
+        let (a, b) = self.broadcast_with(rhs);
+        a.less_than(b)
+    }
+
+    /// Elementwise `self <= rhs` with broadcasting, producing a 0/1 mask
+    pub fn le(self, rhs: GraphTensor) -> GraphTensor {
+        let (a, b) = self.broadcast_with(rhs);
+        a.less_than_equal(b)
+    }
+
+    /// Convert to a 0/1 mask where any nonzero element is true
+    fn truthy(self) -> GraphTensor {
+        self.not_equals(self.graph().constant(0.0).expand_to(self.shape))
+    }
+
+    /// Elementwise logical not, producing a 0/1 mask. Any nonzero element is treated as true.
+    pub fn logical_not(self) -> GraphTensor {
+        -self.truthy() + 1.0
+    }
+
+    /// Elementwise logical and with broadcasting, producing a 0/1 mask. Any nonzero element is treated as true.
+    pub fn logical_and(self, rhs: GraphTensor) -> GraphTensor {
+        let (a, b) = self.broadcast_with(rhs);
+        a.truthy() * b.truthy()
+    }
+
+    /// Elementwise logical or with broadcasting, producing a 0/1 mask. Any nonzero element is treated as true.
+    pub fn logical_or(self, rhs: GraphTensor) -> GraphTensor {
+        let (a, b) = self.broadcast_with(rhs);
+        (a.logical_not() * b.logical_not()).logical_not()
+    }
+
     /// Raise the tensor to a power
     pub fn pow<T>(self, e: T) -> GraphTensor
     where
@@ -298,6 +390,25 @@ impl GraphTensor {
     }
 }
 
+// Modulo ops (fmod, remainder)
+impl GraphTensor {
+    /// Elementwise floating point modulo with broadcasting, like C's `fmod`: the result takes the sign of `self`
+    pub fn fmod(self, rhs: GraphTensor) -> GraphTensor {
+        let (a, b) = self.broadcast_with(rhs);
+        a % b
+    }
+
+    /// Elementwise Python-style modulo with broadcasting, like `torch.remainder`: the result takes the sign of `rhs`
+    pub fn remainder(self, rhs: GraphTensor) -> GraphTensor {
+        let (a, b) = self.broadcast_with(rhs);
+        let r = a % b;
+        let zeros = self.graph().constant(0.).expand_to(r.shape);
+        // A nonzero fmod with the opposite sign to the divisor is one divisor off
+        let wrong_sign = r.less_than(zeros).not_equals(b.less_than(zeros)) * r.not_equals(zeros);
+        r + b * wrong_sign
+    }
+}
+
 pub trait F32Pow {
     fn pow(self, e: GraphTensor) -> GraphTensor;
 }
@@ -312,6 +423,22 @@ impl F32Pow for f32 {
 mod tests {
     crate::test_imports!();
 
+    #[test]
+    fn test_fmod_remainder() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(6).set(vec![5.5, -5.5, 5.5, -5.5, 6., -0.5]);
+        let b = cx.tensor(6).set(vec![2., 2., -2., -2., 3., 4.]);
+        let fmod = a.fmod(b).retrieve();
+        let remainder = a.remainder(b).retrieve();
+        // Broadcast against a single divisor
+        let wrapped = a.remainder(cx.tensor(1).set(vec![4.])).retrieve();
+        cx.execute();
+
+        assert_close(&fmod.data(), &[1.5, -1.5, 1.5, -1.5, 0., -0.5]);
+        assert_close(&remainder.data(), &[1.5, 0.5, -0.5, -1.5, 0., 3.5]);
+        assert_close(&wrapped.data(), &[1.5, 2.5, 1.5, 2.5, 2., 3.5]);
+    }
+
     #[test]
     fn test_clip() {
         let mut cx = Graph::new();
@@ -327,4 +454,55 @@ mod tests {
 
         assert_close(&result.data(), &expected_result.data());
     }
+
+    #[test]
+    #[should_panic(expected = "Can't add tensors of shape [2, 3] and [2, 4]: axis 1 is 3 vs 4")]
+    fn test_add_shape_mismatch() {
+        let mut cx = Graph::new();
+        let _ = cx.tensor((2, 3)) + cx.tensor((2, 4));
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't mul tensors of shape [2, 3] and [3]: ranks differ")]
+    fn test_mul_rank_mismatch() {
+        let mut cx = Graph::new();
+        let _ = cx.tensor((2, 3)) * cx.tensor(3);
+    }
+
+    #[test]
+    fn test_comparisons_broadcast() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 3)).set([[1., 2., 3.], [4., 5., 6.]]);
+        let b = cx.tensor(3).set([2., 5., 3.]);
+        let c = cx.tensor((2, 1)).set([[2.], [5.]]);
+        let eq = a.eq(b).retrieve();
+        let ne = a.ne(b).retrieve();
+        let gt = a.gt(b).retrieve();
+        let ge = a.ge(c).retrieve();
+        let lt = b.lt(a).retrieve();
+        let le = a.le(c).retrieve();
+        cx.execute();
+
+        assert_exact(&eq.data(), &[0., 0., 1., 0., 1., 0.]);
+        assert_exact(&ne.data(), &[1., 1., 0., 1., 0., 1.]);
+        assert_exact(&gt.data(), &[0., 0., 0., 1., 0., 1.]);
+        assert_exact(&ge.data(), &[0., 1., 1., 0., 1., 1.]);
+        assert_exact(&lt.data(), &[0., 0., 0., 1., 0., 1.]);
+        assert_exact(&le.data(), &[1., 1., 0., 1., 1., 0.]);
+    }
+
+    #[test]
+    fn test_logical_ops() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 3)).set([[0., 1., 2.], [-0.5, 0., 1.]]);
+        let b = cx.tensor(3).set([1., 0., 3.]);
+        let not = a.logical_not().retrieve();
+        let and = a.logical_and(b).retrieve();
+        let or = a.logical_or(b).retrieve();
+        cx.execute();
+
+        assert_exact(&not.data(), &[1., 0., 0., 0., 1., 0.]);
+        assert_exact(&and.data(), &[0., 0., 1., 1., 0., 1.]);
+        assert_exact(&or.data(), &[1., 1., 1., 1., 0., 1.]);
+    }
 }
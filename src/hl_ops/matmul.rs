@@ -1,3 +1,6 @@
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+
 use crate::prelude::*;
 
 impl GraphTensor {
@@ -5,7 +8,8 @@ impl GraphTensor {
         if (self.shape.len() == 1 || self.shape.len() == 2) && rhs.shape.len() == 2 {
             let vec = self.shape.len() == 1;
             if vec {
-                self = self.expand(0, 1);
+                // A real (not fake) M of 1, so every backend's 2D matmul pattern matches and picks its matvec path
+                self = self.reshape((1, self.dims1()));
             }
             let (m, _) = self.dims2();
             let (_, n) = rhs.dims2();
@@ -99,6 +103,324 @@ impl GraphTensor {
         }
     }
 
+    /// Matmul against the transpose of `rhs` (its last two axes swapped), for weights stored as (out, in).
+    ///
+    /// This is the same graph as `self.matmul(rhs.permute(..))`: the transpose is a view, and the backend matmul
+    /// compilers pick their transposed kernels from the swapped axes of `rhs`, without copying it.
+    pub fn matmul_transposed_b(self, rhs: GraphTensor) -> Self {
+        let n = rhs.shape.len();
+        assert!(n >= 2, "Can't transpose rhs {:?}", rhs.dims());
+        let mut axes = (0..n).collect::<Vec<_>>();
+        axes.swap(n - 2, n - 1);
+        self.matmul(rhs.permute(axes))
+    }
+
+    /// Contract `lhs_axes` of this tensor against `rhs_axes` of `rhs`, with the same semantics as numpy's `tensordot`.
+    ///
+    /// The output has the remaining axes of this tensor followed by the remaining axes of `rhs`.
+    pub fn tensordot(
+        self,
+        rhs: GraphTensor,
+        lhs_axes: impl ToAxes,
+        rhs_axes: impl ToAxes,
+    ) -> GraphTensor {
+        let (lhs_axes, rhs_axes) = (lhs_axes.to_axes(), rhs_axes.to_axes());
+        assert_eq!(
+            lhs_axes.len(),
+            rhs_axes.len(),
+            "Must contract the same number of axes on both sides"
+        );
+        let (lhs_dims, rhs_dims) = (self.dims(), rhs.dims());
+        for (l, r) in lhs_axes.iter().zip(&rhs_axes) {
+            assert!(
+                *l < lhs_dims.len() && *r < rhs_dims.len(),
+                "Contracted axes {lhs_axes:?} and {rhs_axes:?} out of range for {lhs_dims:?} and {rhs_dims:?}"
+            );
+            assert_eq!(
+                lhs_dims[*l], rhs_dims[*r],
+                "Can't contract lhs axis {l} against rhs axis {r}: sizes differ"
+            );
+        }
+        assert!(
+            lhs_axes.iter().all_unique() && rhs_axes.iter().all_unique(),
+            "Contracted axes must be unique"
+        );
+        let lhs_free = (0..lhs_dims.len())
+            .filter(|i| !lhs_axes.contains(i))
+            .collect::<Vec<_>>();
+        let rhs_free = (0..rhs_dims.len())
+            .filter(|i| !rhs_axes.contains(i))
+            .collect::<Vec<_>>();
+        let size = |dims: &[Expression], axes: &[usize]| {
+            axes.iter().map(|i| dims[*i]).product::<Expression>().max(1)
+        };
+        let (m, k, n) = (
+            size(&lhs_dims, &lhs_free),
+            size(&lhs_dims, &lhs_axes),
+            size(&rhs_dims, &rhs_free),
+        );
+        // Move contracted axes to the end of lhs and the start of rhs, then a single 2D matmul does the contraction
+        let lhs = self
+            .permute(
+                lhs_free
+                    .iter()
+                    .chain(&lhs_axes)
+                    .copied()
+                    .collect::<Vec<_>>(),
+            )
+            .reshape((m, k));
+        let rhs = rhs
+            .permute(
+                rhs_axes
+                    .iter()
+                    .chain(&rhs_free)
+                    .copied()
+                    .collect::<Vec<_>>(),
+            )
+            .reshape((k, n));
+        lhs.matmul(rhs).reshape(
+            lhs_free
+                .iter()
+                .map(|i| lhs_dims[*i])
+                .chain(rhs_free.iter().map(|i| rhs_dims[*i]))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// Two-operand einsum, like `a.einsum(b, "ijk,jkl->il")`. Labels missing from the output are summed over.
+    ///
+    /// This always lowers to a broadcasted multiply and a sum reduce over the contracted axes, so any contraction
+    /// runs on every backend. Ones that happen to line up as a gemm get picked up by the backends' matmul compilers;
+    /// use `tensordot` to reshape a contraction into a gemm when it doesn't. Repeated labels within one operand
+    /// (diagonals) aren't supported.
+    pub fn einsum(self, rhs: GraphTensor, spec: &str) -> GraphTensor {
+        let spec = spec.replace(' ', "");
+        let (inputs, out) = spec.split_once("->").unwrap_or_else(|| {
+            panic!("Einsum spec {spec:?} needs an explicit output, like \"ij,jk->ik\"")
+        });
+        let (lhs, rhs_labels) = inputs
+            .split_once(',')
+            .unwrap_or_else(|| panic!("Einsum spec {spec:?} needs two comma-separated inputs"));
+        let (lhs, rhs_labels, out) = (
+            lhs.chars().collect::<Vec<_>>(),
+            rhs_labels.chars().collect::<Vec<_>>(),
+            out.chars().collect::<Vec<_>>(),
+        );
+        let mut sizes = FxHashMap::<char, Expression>::default();
+        for (labels, tensor) in [(&lhs, self), (&rhs_labels, rhs)] {
+            assert_eq!(
+                labels.len(),
+                tensor.shape.len(),
+                "Einsum labels {labels:?} don't match a tensor with shape {:?}",
+                tensor.dims()
+            );
+            assert!(
+                labels.iter().all_unique(),
+                "Repeated einsum labels within an input aren't supported: {labels:?}"
+            );
+            for (label, dim) in labels.iter().zip(tensor.dims()) {
+                let size = *sizes.entry(*label).or_insert(dim);
+                assert_eq!(size, dim, "Einsum label {label:?} has mismatched sizes");
+            }
+        }
+        assert!(
+            out.iter().all_unique() && out.iter().all(|l| sizes.contains_key(l)),
+            "Einsum output {out:?} must be unique labels found in the inputs"
+        );
+        // Output labels first, then the contracted ones, in order of appearance
+        let full = out
+            .iter()
+            .chain(
+                lhs.iter()
+                    .chain(&rhs_labels)
+                    .filter(|l| !out.contains(*l))
+                    .unique(),
+            )
+            .copied()
+            .collect::<Vec<_>>();
+        let align = |tensor: GraphTensor, labels: &[char]| {
+            let axes = full
+                .iter()
+                .filter_map(|l| labels.iter().position(|i| i == l))
+                .collect::<Vec<_>>();
+            let mut tensor = tensor.permute(axes);
+            for (i, label) in full.iter().enumerate() {
+                if !labels.contains(label) {
+                    tensor = tensor.expand(i, sizes[label]);
+                }
+            }
+            tensor
+        };
+        let product = align(self, &lhs) * align(rhs, &rhs_labels);
+        if full.len() > out.len() {
+            product.sum_reduce((out.len()..full.len()).collect::<Vec<_>>())
+        } else {
+            product
+        }
+    }
+
+    /// 1D convolution over the last axis of a (batch.., ch_in, length) input, lowered to im2col + matmul.
+    ///
+    /// `weight` is (ch_out, ch_in / groups, kernel). Input channels are split into `groups` independent convolutions,
+    /// so `groups == ch_in` is a depthwise convolution. The output is (batch.., ch_out, length_out), where
+    /// `length_out = (length + 2 * padding - dilation * (kernel - 1) - 1) / stride + 1`.
+    pub fn conv1d(
+        self,
+        weight: GraphTensor,
+        stride: usize,
+        padding: usize,
+        dilation: usize,
+        groups: usize,
+    ) -> GraphTensor {
+        let n = self.shape.len();
+        assert!(
+            n >= 2,
+            "Conv1d input must be at least (ch_in, length), got {:?}",
+            self.dims()
+        );
+        assert_eq!(
+            weight.shape.len(),
+            3,
+            "Conv1d weight must be (ch_out, ch_in / groups, kernel), got {:?}",
+            weight.dims()
+        );
+        assert!(
+            stride > 0 && dilation > 0 && groups > 0,
+            "Conv1d stride, dilation and groups must be nonzero"
+        );
+        let dims = self.dims();
+        let (ch_in, dim_in) = (dims[n - 2], dims[n - 1]);
+        let [ch_out, ch_in_group, kernel] = [0, 1, 2].map(|i| {
+            weight.dims()[i]
+                .to_usize()
+                .expect("Conv1d weight dimensions must be known")
+        });
+        assert_eq!(
+            ch_in,
+            Expression::from(ch_in_group * groups),
+            "Conv1d input has {ch_in} channels, but the weight expects {ch_in_group} channels in each of {groups} groups"
+        );
+        assert_eq!(
+            ch_out % groups,
+            0,
+            "Conv1d output channels {ch_out} must be divisible by groups {groups}"
+        );
+        let span = dilation * (kernel - 1) + 1;
+        if let Some(dim_in) = dim_in.to_usize() {
+            assert!(
+                dim_in + 2 * padding >= span,
+                "Conv1d kernel spans {span} elements, but the padded input only has {}",
+                dim_in + 2 * padding
+            );
+        }
+        let dim_out = ((dim_in + 2 * padding - span) / stride + 1).simplify();
+        let batch = dims[..n - 2].iter().copied().product::<Expression>().max(1);
+
+        // im2col: (batch, dim_out, ch_in, kernel)
+        let patches = self
+            .reshape((batch, ch_in, dim_in))
+            .unfold(kernel, stride, dilation, padding)
+            .permute((0, 2, 1, 3));
+        let out = if groups == 1 {
+            patches
+                .reshape((batch, dim_out, ch_in_group * kernel))
+                .matmul_transposed_b(weight.reshape((ch_out, ch_in_group * kernel)))
+                .permute((0, 2, 1))
+        } else {
+            // Batch the matmul over groups: (groups, batch * dim_out, ch_in / groups * kernel)
+            let ch_out_group = ch_out / groups;
+            patches
+                .reshape((batch, dim_out, groups, ch_in_group * kernel))
+                .permute((2, 0, 1, 3))
+                .reshape((groups, batch * dim_out, ch_in_group * kernel))
+                .matmul_transposed_b(weight.reshape((groups, ch_out_group, ch_in_group * kernel)))
+                .reshape((groups, batch, dim_out, ch_out_group))
+                .permute((1, 0, 3, 2))
+        };
+        out.reshape(
+            dims[..n - 2]
+                .iter()
+                .copied()
+                .chain([Expression::from(ch_out), dim_out])
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// Scaled dot product attention matching torch's SDPA: `softmax(q k^T / sqrt(E) + mask) v`.
+    ///
+    /// `self` is the queries (.., L, E), `keys` are (.., S, E) and `values` are (.., S, Ev), sharing the same leading
+    /// dims. `mask` is an additive mask (0 to attend, large negative to mask out) of shape (L, S) or the full score
+    /// shape (.., L, S). `causal` masks out keys past each query position, aligned to the top left like torch.
+    /// Backends with a fused masked softmax (like Metal's) apply the causal mask by comparing indices in the kernel,
+    /// so the (L, S) mask is never materialized.
+    pub fn scaled_dot_product_attention(
+        self,
+        keys: GraphTensor,
+        values: GraphTensor,
+        mask: Option<GraphTensor>,
+        causal: bool,
+    ) -> GraphTensor {
+        let n = self.shape.len();
+        assert!(
+            n >= 2,
+            "Attention queries must be at least (L, E), got {:?}",
+            self.dims()
+        );
+        assert!(
+            keys.shape.len() == n && values.shape.len() == n,
+            "Attention queries, keys and values must have the same rank, got {:?}, {:?} and {:?}",
+            self.dims(),
+            keys.dims(),
+            values.dims()
+        );
+        assert!(
+            mask.is_none() || !causal,
+            "Attention can't take both an explicit mask and causal masking"
+        );
+        let (dims, k_dims, v_dims) = (self.dims(), keys.dims(), values.dims());
+        assert_eq!(
+            dims[n - 1],
+            k_dims[n - 1],
+            "Attention queries and keys have different embedding dims"
+        );
+        assert_eq!(
+            k_dims[n - 2],
+            v_dims[n - 2],
+            "Attention keys and values have different sequence lengths"
+        );
+        let (l, e, s, ev) = (dims[n - 2], dims[n - 1], k_dims[n - 2], v_dims[n - 1]);
+        let batch = dims[..n - 2].iter().copied().product::<Expression>().max(1);
+        let embed = e.to_usize().expect("Attention embedding dim must be known");
+
+        let scores = self
+            .reshape((batch, l, e))
+            .matmul(keys.reshape((batch, s, e)).permute((0, 2, 1)))
+            * (1.0 / (embed as f32).sqrt());
+        let mask = if causal {
+            let cx = self.graph();
+            let cols = cx.arange(s).expand(0, l);
+            let rows = cx.arange(l).expand(1, s);
+            Some(cols.greater_than(rows) * f16::MIN.to_f32())
+        } else {
+            mask
+        };
+        let weights = if let Some(mask) = mask {
+            let mask = if mask.shape.len() == 2 {
+                mask.expand(0, batch)
+            } else {
+                mask.reshape((batch, l, s))
+            };
+            scores.masked_softmax(mask, 2)
+        } else {
+            scores.softmax(2)
+        };
+        let mut out_dims = dims;
+        out_dims[n - 1] = ev;
+        weights
+            .matmul(values.reshape((batch, s, ev)))
+            .reshape(out_dims)
+    }
+
     /// Simple dot product of two vectors
     pub fn dot(self, rhs: GraphTensor) -> GraphTensor {
         (self * rhs).sum_reduce(0)
@@ -109,6 +431,78 @@ impl GraphTensor {
 mod tests {
     crate::test_imports!();
 
+    /// Naive attention over `batch` independent (L, E) x (S, E) x (S, Ev) problems
+    #[allow(clippy::too_many_arguments)]
+    fn attention_reference(
+        q: &[f32],
+        k: &[f32],
+        v: &[f32],
+        mask: impl Fn(usize, usize) -> f32,
+        batch: usize,
+        l: usize,
+        s: usize,
+        e: usize,
+        ev: usize,
+    ) -> Vec<f32> {
+        let mut out = vec![];
+        for b in 0..batch {
+            for i in 0..l {
+                let scores = (0..s)
+                    .map(|j| {
+                        (0..e)
+                            .map(|x| q[(b * l + i) * e + x] * k[(b * s + j) * e + x])
+                            .sum::<f32>()
+                            / (e as f32).sqrt()
+                            + mask(i, j)
+                    })
+                    .collect::<Vec<_>>();
+                let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                let exps = scores.iter().map(|i| (i - max).exp()).collect::<Vec<_>>();
+                let sum = exps.iter().sum::<f32>();
+                for y in 0..ev {
+                    out.push(
+                        (0..s)
+                            .map(|j| exps[j] / sum * v[(b * s + j) * ev + y])
+                            .sum::<f32>(),
+                    );
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_scaled_dot_product_attention() {
+        let mut cx = Graph::new();
+        let (q_data, k_data, v_data) = (
+            random_vec(2 * 2 * 3 * 4),
+            random_vec(2 * 2 * 5 * 4),
+            random_vec(2 * 2 * 5 * 3),
+        );
+        let mask_data = random_vec(3 * 5);
+        let q = cx.tensor((2, 2, 3, 4)).set(q_data.clone());
+        let k = cx.tensor((2, 2, 5, 4)).set(k_data.clone());
+        let v = cx.tensor((2, 2, 5, 3)).set(v_data.clone());
+        let mask = cx.tensor((3, 5)).set(mask_data.clone());
+        let plain = q.scaled_dot_product_attention(k, v, None, false).retrieve();
+        let masked = q
+            .scaled_dot_product_attention(k, v, Some(mask), false)
+            .retrieve();
+        let causal = q.scaled_dot_product_attention(k, v, None, true).retrieve();
+        cx.execute();
+
+        assert_eq!(plain.shape.shape_usize(), vec![2, 2, 3, 3]);
+        let reference = |mask: &dyn Fn(usize, usize) -> f32| {
+            attention_reference(&q_data, &k_data, &v_data, mask, 4, 3, 5, 4, 3)
+        };
+        assert_close(&plain.data(), &reference(&|_, _| 0.));
+        assert_close(&masked.data(), &reference(&|i, j| mask_data[i * 5 + j]));
+        assert_close(
+            &causal.data(),
+            &reference(&|i, j| if j > i { f32::NEG_INFINITY } else { 0. }),
+        );
+    }
+
     #[test]
     fn test_matrix_vector() {
         let mut cx = Graph::new();
@@ -149,6 +543,111 @@ mod tests {
         assert_close(&c.data(), &d_c.as_vec());
     }
 
+    #[test]
+    fn test_matmul_transposed_b() {
+        let mut cx = Graph::new();
+        let (a_data, b_data, c_data) = (random_vec(6), random_vec(12), random_vec(24));
+        let a = cx.tensor((2, 3)).set(a_data.clone());
+        let b = cx.tensor((4, 3)).set(b_data.clone());
+        let c = cx.tensor((2, 4, 3)).set(c_data.clone());
+        let d = a.matmul_transposed_b(b).retrieve();
+        let e = a.expand(0, 2).matmul_transposed_b(c).retrieve();
+
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<3>));
+        let d_b = d_dev.tensor_from_vec(b_data, (DConst::<4>, DConst::<3>));
+        let d_c = d_dev.tensor_from_vec(c_data, (DConst::<2>, DConst::<4>, DConst::<3>));
+        let d_d = d_a.clone().matmul(d_b.permute());
+        let d_e = d_a
+            .broadcast::<Rank3<2, 2, 3>, DAxis<0>>()
+            .matmul(d_c.permute::<Rank3<2, 3, 4>, DAxes3<0, 2, 1>>());
+
+        assert_close(&d.data(), &d_d.as_vec());
+        assert_close(&e.data(), &d_e.as_vec());
+    }
+
+    #[test]
+    fn test_tensordot() {
+        let mut cx = Graph::new();
+        let (a_data, b_data) = (random_vec(24), random_vec(60));
+        let a = cx.tensor((2, 3, 4)).set(a_data.clone());
+        let b = cx.tensor((4, 5, 3)).set(b_data.clone());
+        // Contract a's (1, 2) against b's (2, 0) -> (2, 5)
+        let c = a.tensordot(b, (1, 2), (2, 0)).retrieve();
+        // Contract a single axis -> (2, 3, 5, 3)
+        let d = a.tensordot(b, 2, 0).retrieve();
+
+        cx.execute();
+
+        let mut c_ref = vec![0.; 10];
+        let mut d_ref = vec![0.; 90];
+        for i in 0..2 {
+            for j in 0..3 {
+                for k in 0..4 {
+                    for l in 0..5 {
+                        for m in 0..3 {
+                            let prod = a_data[i * 12 + j * 4 + k] * b_data[k * 15 + l * 3 + m];
+                            d_ref[i * 45 + j * 15 + l * 3 + m] += prod;
+                            if m == j {
+                                c_ref[i * 5 + l] += prod;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        assert_eq!(c.shape.shape_usize(), vec![2, 5]);
+        assert_eq!(d.shape.shape_usize(), vec![2, 3, 5, 3]);
+        assert_close(&c.data(), &c_ref);
+        assert_close(&d.data(), &d_ref);
+    }
+
+    #[test]
+    fn test_einsum() {
+        let mut cx = Graph::new();
+        let (a_data, b_data) = (random_vec(24), random_vec(60));
+        let a = cx.tensor((2, 3, 4)).set(a_data.clone());
+        let b = cx.tensor((3, 4, 5)).set(b_data.clone());
+        // Three-way contraction that isn't a single gemm without reshaping
+        let c = a.einsum(b, "ijk,jkl->il").retrieve();
+        // Outer product over k, keeping every axis
+        let d = a.einsum(b, "ijk, jkl -> lkji").retrieve();
+        // Plain matmul still lowers the same way
+        let e = a.einsum(b, "ijk,jkl->ijl").retrieve();
+
+        cx.execute();
+
+        let mut c_ref = vec![0.; 10];
+        let mut d_ref = vec![0.; 120];
+        let mut e_ref = vec![0.; 30];
+        for i in 0..2 {
+            for j in 0..3 {
+                for k in 0..4 {
+                    for l in 0..5 {
+                        let prod = a_data[i * 12 + j * 4 + k] * b_data[j * 20 + k * 5 + l];
+                        c_ref[i * 5 + l] += prod;
+                        d_ref[l * 24 + k * 6 + j * 2 + i] = prod;
+                        e_ref[i * 15 + j * 5 + l] += prod;
+                    }
+                }
+            }
+        }
+        assert_eq!(c.shape.shape_usize(), vec![2, 5]);
+        assert_eq!(d.shape.shape_usize(), vec![5, 4, 3, 2]);
+        assert_close(&c.data(), &c_ref);
+        assert_close(&d.data(), &d_ref);
+        assert_close(&e.data(), &e_ref);
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't contract lhs axis 0 against rhs axis 0: sizes differ")]
+    fn test_tensordot_mismatch() {
+        let mut cx = Graph::new();
+        cx.tensor((2, 3)).tensordot(cx.tensor((3, 2)), 0, 0);
+    }
+
     #[test]
     fn test_batch_matmul() {
         let mut cx = Graph::new();
@@ -3,5 +3,6 @@ pub mod binary;
 pub mod matmul;
 pub mod movement;
 pub mod other;
+pub mod random;
 pub mod reduction;
 pub mod unary;
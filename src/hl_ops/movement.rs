@@ -7,12 +7,60 @@ impl GraphTensor {
         self
     }
 
+    /// Reverse the tensor along an axis. This is a view, so no data is moved until the tensor is consumed.
+    pub fn flip(mut self, axis: usize) -> GraphTensor {
+        assert!(
+            axis < self.shape.len(),
+            "Can't flip axis {axis} of a tensor with {} dimensions",
+            self.shape.len()
+        );
+        self.shape.flip(axis);
+        self
+    }
+
     /// Broadcast tensor along new dimensions
     pub fn expand(mut self, axis: usize, size: impl Into<Expression>) -> GraphTensor {
         self.shape.expand(axis, size);
         self
     }
 
+    /// Insert a unit axis at `axis`. Only the shape changes, no data is moved.
+    pub fn unsqueeze(self, axis: usize) -> GraphTensor {
+        assert!(
+            axis <= self.shape.len(),
+            "Can't unsqueeze axis {axis} of a tensor with {} dimensions",
+            self.shape.len()
+        );
+        self.expand(axis, 1)
+    }
+
+    /// Remove the unit axis `axis`. Panics if the axis isn't of size 1.
+    pub fn squeeze(mut self, axis: usize) -> GraphTensor {
+        assert!(
+            axis < self.shape.len(),
+            "Can't squeeze axis {axis} of a tensor with {} dimensions",
+            self.shape.len()
+        );
+        let size = self.dims()[axis];
+        assert!(
+            size.to_usize() == Some(1),
+            "Can't squeeze axis {axis} of size {size:?}"
+        );
+        // A sliced or padded unit axis still carries an offset, so bake it in first
+        let index = self.shape.indexes[axis];
+        let ((start, end), (pad_start, pad_end)) =
+            (self.shape.mask[index], self.shape.padding[index]);
+        if [start, pad_start, pad_end]
+            .iter()
+            .any(|e| e.to_usize() != Some(0))
+            || end.to_usize() != Some(i32::MAX as usize)
+        {
+            self = self.contiguous();
+        }
+        self.shape.remove_dim(axis);
+        self
+    }
+
     /// Broadcast tensor along new dimensions (with explicitly given dest shape)
     pub fn expand_to(mut self, shape: impl ToShape) -> GraphTensor {
         for (i, s) in shape.to_shape().into_iter().enumerate() {
@@ -56,6 +104,12 @@ impl GraphTensor {
         }) {
             self = self.contiguous();
         }
+        // Slices are stored in unflipped coordinates, so bake in any flips first
+        if ranges.iter().enumerate().any(|(i, range)| {
+            (range.0 != 0 || range.1 != i32::MAX) && self.shape.flipped[self.shape.indexes[i]]
+        }) {
+            self = self.contiguous();
+        }
         self.shape.slice(&ranges);
         self
     }
@@ -66,6 +120,21 @@ impl GraphTensor {
         self.slice(s)
     }
 
+    /// Take the final position along the sequence (second to last) axis, turning (.., S, V) into (.., V). S can be dynamic.
+    pub fn select_last_token(self) -> GraphTensor {
+        assert!(
+            self.shape.len() >= 2,
+            "Tensor must have a sequence and feature dimension"
+        );
+        let axis = self.shape.len() - 2;
+        let seq = self.dims()[axis];
+        let mut dims = self.dims();
+        dims.remove(axis);
+        self.slice_along((seq - 1).., axis)
+            .contiguous()
+            .reshape(dims)
+    }
+
     /// Cut out 'size' elements every 'spacing' elements in the last dimension. 'size' must be smaller than the last dimension
     pub fn excise(mut self, spacing: usize, size: usize) -> GraphTensor {
         let n_dims = self.shape.len();
@@ -137,6 +206,29 @@ impl GraphTensor {
         }
     }
 
+    /// Extract sliding local blocks along the last dimension (im2col), turning (.., L) into (.., windows, kernel).
+    /// The last dimension is zero-padded by `padding` on both sides. The output is contiguous, so patches can be fed straight into a matmul.
+    pub fn unfold(
+        mut self,
+        kernel: usize,
+        stride: usize,
+        dilation: usize,
+        padding: usize,
+    ) -> GraphTensor {
+        assert!(
+            kernel > 0 && stride > 0 && dilation > 0,
+            "Unfold kernel, stride and dilation must be nonzero"
+        );
+        let axis = self.shape.len() - 1;
+        if padding > 0 {
+            self = self
+                .pad_along(padding, 0, axis)
+                .contiguous()
+                .pad_along(0, padding, axis);
+        }
+        self.pool_last_dim(kernel, stride, dilation).contiguous()
+    }
+
     pub fn pad(mut self, padding: impl ToPad) -> GraphTensor {
         let padding = padding.to_pad_vec();
         // This exists because currently padding and slicing on the same dimension (even on opposite sides) is unsupported
@@ -147,6 +239,12 @@ impl GraphTensor {
         }) {
             self = self.contiguous();
         }
+        // Padding is stored in unflipped coordinates, so bake in any flips first
+        if padding.iter().enumerate().any(|(i, range)| {
+            (range.0 != 0 || range.1 != 0) && self.shape.flipped[self.shape.indexes[i]]
+        }) {
+            self = self.contiguous();
+        }
         self.shape.pad(&padding);
         self
     }
@@ -162,6 +260,65 @@ impl GraphTensor {
         self.pad(p)
     }
 
+    /// Zero-pad the end of an axis up to the next multiple of `multiple`, to meet a kernel's alignment requirement.
+    /// Use `unpad_to` with the original size to slice the padding back off.
+    ///
+    /// For example, the Metal gemm kernels only take their unchecked fast path when K is a multiple of their 16-wide k tile.
+    pub fn pad_to_multiple(self, axis: usize, multiple: usize) -> GraphTensor {
+        assert!(multiple > 0, "Can't pad to a multiple of 0");
+        let size = self.dims()[axis];
+        let padded = (size + (multiple - 1)) / multiple * multiple;
+        self.pad_along(0, padded - size, axis)
+    }
+
+    /// Slice an axis back down to its first `size` elements, undoing `pad_to_multiple`
+    pub fn unpad_to(self, axis: usize, size: impl Into<Expression>) -> GraphTensor {
+        let size = size.into();
+        self.slice_along(..size, axis)
+    }
+
+    /// Keep only the last `window` elements of `axis`, or all of them if there are fewer. `window` can be dynamic.
+    ///
+    /// Slicing a KV cache's sequence axis with this each step caps it at `window` positions, evicting the oldest ones.
+    pub fn keep_last(self, axis: usize, window: impl Into<Expression>) -> GraphTensor {
+        let size = self.dims()[axis];
+        self.slice_along((size - window.into()).max(0).., axis)
+    }
+
+    /// Tile the tensor `times` times along `axis`, so a (1, seq, dim) prompt becomes (times, seq, dim).
+    ///
+    /// Unlike `expand`, the copies are materialized, so each one can diverge afterwards, like the per-sample KV caches
+    /// of parallel sampling.
+    pub fn repeat(self, axis: usize, times: impl Into<Expression>) -> GraphTensor {
+        let times = times.into();
+        let mut dims = self.dims();
+        dims[axis] = dims[axis] * times;
+        // The fake axis goes outside the repeated one so whole copies are laid end to end, and the reshape copies them
+        self.expand(axis, times).reshape(dims)
+    }
+
+    /// Split a fused QKV projection along the last axis into queries of `heads * head_dim` and keys / values of
+    /// `kv_heads * head_dim` each, in that order. With grouped-query attention the kv parts are smaller than the queries.
+    pub fn split_qkv(
+        self,
+        heads: usize,
+        kv_heads: usize,
+        head_dim: usize,
+    ) -> (GraphTensor, GraphTensor, GraphTensor) {
+        let axis = self.shape.last_axis();
+        let (q, kv) = (heads * head_dim, kv_heads * head_dim);
+        assert_eq!(
+            self.dims()[axis].to_usize(),
+            Some(q + 2 * kv),
+            "Fused QKV size doesn't match {heads} query heads and {kv_heads} kv heads of size {head_dim}"
+        );
+        (
+            self.slice_along(..q, axis),
+            self.slice_along(q..q + kv, axis),
+            self.slice_along(q + kv.., axis),
+        )
+    }
+
     pub fn concat_along(self, rhs: GraphTensor, axis: usize) -> GraphTensor {
         // Pad and add
         self.pad_along(0, rhs.shape.dims()[axis], axis)
@@ -213,6 +370,64 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
     }
 
+    #[test]
+    fn test_keep_last() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(('s', 2));
+        let b = a.keep_last(0, 3).contiguous().retrieve();
+        for (seq, expected) in [
+            (2, vec![0., 1., 2., 3.]),
+            (3, vec![0., 1., 2., 3., 4., 5.]),
+            (5, vec![4., 5., 6., 7., 8., 9.]),
+        ] {
+            a.set_dyn((0..seq * 2).map(|i| i as f32).collect::<Vec<_>>(), (seq, 2));
+            cx.execute();
+            assert_exact(&b.data(), &expected);
+            b.drop();
+        }
+    }
+
+    #[test]
+    fn test_repeat() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((1, 2, 3)).set(vec![1., 2., 3., 4., 5., 6.]);
+        let b = a.repeat(0, 3);
+        assert_eq!(b.shape.shape_usize(), vec![3, 2, 3]);
+        assert!(!b.shape.is_reshaped());
+        // Each copy picks up its own update
+        let c = (b + cx.arange(3).expand(1, 2).expand(2, 3) * 10.).retrieve();
+        let d = cx
+            .tensor((2, 3))
+            .set(vec![1., 2., 3., 4., 5., 6.])
+            .repeat(1, 2)
+            .retrieve();
+        cx.execute();
+
+        assert_exact(
+            &c.data(),
+            &[
+                1., 2., 3., 4., 5., 6., 11., 12., 13., 14., 15., 16., 21., 22., 23., 24., 25., 26.,
+            ],
+        );
+        assert_exact(&d.data(), &[1., 2., 3., 1., 2., 3., 4., 5., 6., 4., 5., 6.]);
+    }
+
+    #[test]
+    fn test_split_qkv() {
+        let mut cx = Graph::new();
+        // 2 query heads and 1 kv head of size 2, for 2 tokens
+        let a = cx
+            .tensor((2, 8))
+            .set((0..16).map(|i| i as f32).collect::<Vec<_>>());
+        let (q, k, v) = a.split_qkv(2, 1, 2);
+        let (q, k, v) = (q.retrieve(), k.retrieve(), v.retrieve());
+        cx.execute();
+
+        assert_exact(&q.data(), &[0., 1., 2., 3., 8., 9., 10., 11.]);
+        assert_exact(&k.data(), &[4., 5., 12., 13.]);
+        assert_exact(&v.data(), &[6., 7., 14., 15.]);
+    }
+
     #[test]
     fn test_concat_2d() {
         let mut cx = Graph::new();
@@ -297,6 +512,78 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
     }
 
+    #[test]
+    fn test_unsqueeze_squeeze() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(6);
+        let a = cx.tensor((2, 3)).set(a_data.clone());
+        let b = a.unsqueeze(1);
+        assert_eq!(b.shape.shape_usize(), vec![2, 1, 3]);
+        let c = b.squeeze(1).retrieve();
+        assert_eq!(c.shape.shape_usize(), vec![2, 3]);
+        // No ops are added
+        assert_eq!(c.id, a.id);
+        // Unit axis produced by slicing keeps its offset
+        let d = a.slice((.., 2..)).squeeze(1).retrieve();
+        let e = (a.unsqueeze(0) + 1.).retrieve();
+        cx.execute();
+
+        assert_exact(&c.data(), &a_data);
+        assert_exact(&d.data(), &[a_data[2], a_data[5]]);
+        assert_eq!(e.shape.shape_usize(), vec![1, 2, 3]);
+        assert_close(
+            &e.data(),
+            &a_data.iter().map(|i| i + 1.).collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't squeeze axis 1 of size")]
+    fn test_squeeze_non_unit() {
+        let mut cx = Graph::new();
+        cx.tensor((2, 3)).squeeze(1);
+    }
+
+    #[test]
+    fn test_flip() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor((2, 3))
+            .set((0..6).map(|i| i as f32).collect::<Vec<_>>());
+        let b = a.flip(1).retrieve();
+        let c = a.flip(0).flip(1).retrieve();
+        let d = a.permute((1, 0)).flip(0).retrieve();
+        let e = a.flip(1).slice((.., ..2)).retrieve();
+        let f = (a.flip(0).flip(0) * 1.).retrieve();
+        // Flipping twice gives back the original view
+        assert_eq!(a.flip(1).flip(1).shape, a.shape);
+        cx.execute();
+
+        assert_exact(&b.data(), &[2., 1., 0., 5., 4., 3.]);
+        assert_exact(&c.data(), &[5., 4., 3., 2., 1., 0.]);
+        assert_exact(&d.data(), &[2., 5., 1., 4., 0., 3.]);
+        assert_exact(&e.data(), &[2., 1., 5., 4.]);
+        assert_exact(&f.data(), &[0., 1., 2., 3., 4., 5.]);
+    }
+
+    #[test]
+    fn test_select_last_token() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 's', 3));
+        let b = a.select_last_token().retrieve();
+        a.set_dyn((0..18).map(|i| i as f32).collect::<Vec<_>>(), (2, 3, 3));
+        cx.execute();
+
+        assert_exact(&b.data(), &[6., 7., 8., 15., 16., 17.]);
+
+        // Sequence length changes between runs
+        a.set_dyn((0..6).map(|i| i as f32).collect::<Vec<_>>(), (2, 1, 3));
+        b.drop();
+        cx.execute();
+
+        assert_exact(&b.data(), &[0., 1., 2., 3., 4., 5.]);
+    }
+
     #[test]
     fn test_cumsum() {
         let mut cx = Graph::new();
@@ -314,6 +601,21 @@ mod tests {
         assert_exact(&c.data(), &[1., 1., 1., 2., 2., 2., 3., 3., 3.]);
     }
 
+    #[test]
+    fn test_cumsum_exclusive() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(5).set([1., 0., 1., 1., 0.]);
+        let inclusive = a.cumsum(0, false).retrieve();
+        let exclusive = a.cumsum(0, true).retrieve();
+        let b = cx.tensor((2, 3)).set([[1., 2., 3.], [4., 5., 6.]]);
+        let rows = b.cumsum(0, true).retrieve();
+        cx.execute();
+
+        assert_exact(&inclusive.data(), &[1., 1., 2., 3., 3.]);
+        assert_exact(&exclusive.data(), &[0., 1., 1., 2., 3.]);
+        assert_exact(&rows.data(), &[0., 0., 0., 1., 2., 3.]);
+    }
+
     #[test]
     fn test_pool_1d() {
         let mut cx = Graph::new();
@@ -437,6 +739,67 @@ mod tests {
         assert_exact(&out3.data(), &[1., 3.]);
     }
 
+    #[test]
+    fn test_unfold() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor((2, 5))
+            .set([[1., 2., 3., 4., 5.], [6., 7., 8., 9., 10.]]);
+        let b = cx.tensor(5).set([1., 2., 3., 4., 5.]);
+        let padded = a.unfold(3, 2, 1, 1).retrieve();
+        let dilated = b.unfold(3, 1, 2, 0).retrieve();
+        // Patches feed straight into a matmul
+        let summed = a
+            .unfold(2, 1, 1, 0)
+            .matmul(cx.tensor((2, 1)).set([[1.], [1.]]))
+            .retrieve();
+        cx.execute();
+
+        assert_eq!(padded.shape.shape_usize(), vec![2, 3, 3]);
+        assert!(!padded.shape.is_reshaped());
+        assert_exact(
+            &padded.data(),
+            &[
+                0., 1., 2., 2., 3., 4., 4., 5., 0., 0., 6., 7., 7., 8., 9., 9., 10., 0.,
+            ],
+        );
+        assert_exact(&dilated.data(), &[1., 3., 5.]);
+        assert_exact(&summed.data(), &[3., 5., 7., 9., 13., 15., 17., 19.]);
+    }
+
+    #[test]
+    fn test_pad_to_multiple() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(2 * 5);
+        let a = cx.tensor((2, 5)).set(a_data.clone());
+        let b = cx.tensor(('s', 3)).set_dyn(random_vec(15), (5, 3));
+        let w = cx.tensor((5, 3)).set(random_vec(15));
+        let padded = a.pad_to_multiple(1, 4).retrieve();
+        let aligned = a.pad_to_multiple(1, 5).retrieve();
+        let roundtrip = a.pad_to_multiple(1, 4).unpad_to(1, 5).retrieve();
+        let dyn_padded = b.pad_to_multiple(0, 4).retrieve();
+        // Zero-padding K on both sides of a matmul doesn't change the result
+        let matmul = a.matmul(w).retrieve();
+        let padded_matmul = a
+            .pad_to_multiple(1, 4)
+            .matmul(w.pad_to_multiple(0, 4))
+            .retrieve();
+        cx.execute();
+
+        assert_eq!(padded.shape.shape_usize(), vec![2, 8]);
+        let mut expected = vec![0.; 16];
+        for (i, v) in a_data.iter().enumerate() {
+            expected[i / 5 * 8 + i % 5] = *v;
+        }
+        assert_exact(&padded.data(), &expected);
+        assert_eq!(aligned.shape.shape_usize(), vec![2, 5]);
+        assert_exact(&aligned.data(), &a_data);
+        assert_exact(&roundtrip.data(), &a_data);
+        assert_eq!(dyn_padded.resolved_shape(), vec![8, 3]);
+        assert_exact(&dyn_padded.data()[15..], &[0.; 9]);
+        assert_close(&padded_matmul.data(), &matmul.data());
+    }
+
     #[test]
     fn test_rotate_half() {
         let mut cx = Graph::new();
@@ -12,9 +12,8 @@ impl GraphTensor {
     /// Cumulative sum last dimension
     pub fn cumsum_last_dim(mut self) -> Self {
         let axis = self.shape.len() - 1;
-        if !self.shape.is_contiguous() {
-            self = self.contiguous();
-        }
+        // Bake in any existing padding or slicing, since the raw dims are padded below
+        self = self.contiguous();
         // Pad out length
         let orig_length = self.shape.dims[self.shape.indexes[axis]];
         self.shape.padding[self.shape.indexes[axis]].0 = orig_length - 1;
@@ -32,12 +31,35 @@ impl GraphTensor {
         GraphTensor::from_id(final_id, pooled.shape, self.graph_ref)
     }
 
+    /// Cumulative sum along an axis.
+    ///
+    /// An exclusive sum is shifted by one, so each element is the sum of the elements before it and the first element is 0.
+    pub fn cumsum(self, axis: usize, exclusive: bool) -> GraphTensor {
+        let n = self.shape.len();
+        assert!(
+            axis < n,
+            "Can't cumsum axis {axis} of a tensor with {n} dimensions"
+        );
+        // Swap the axis to the end (and back afterwards)
+        let mut axes = (0..n).collect::<Vec<_>>();
+        axes.swap(axis, n - 1);
+        let mut x = self.permute(axes.clone());
+        if exclusive {
+            // Drop the last element and shift in a 0 at the start
+            let len = x.dims()[n - 1];
+            x = x
+                .slice_along(..len - 1, n - 1)
+                .contiguous()
+                .pad_along(1, 0, n - 1);
+        }
+        x.cumsum_last_dim().permute(axes)
+    }
+
     /// Cumulative max last dimension
     pub fn cummax_last_dim(mut self) -> Self {
         let axis = self.shape.len() - 1;
-        if !self.shape.is_contiguous() {
-            self = self.contiguous();
-        }
+        // Bake in any existing padding or slicing, since the raw dims are padded below
+        self = self.contiguous();
         // Pad out length
         let orig_length = self.shape.dims[self.shape.indexes[axis]];
         self.shape.padding[self.shape.indexes[axis]].0 = orig_length - 1;
@@ -59,6 +81,54 @@ impl GraphTensor {
     pub fn cumprod_last_dim(self) -> Self {
         self.ln().cumsum_last_dim().exp()
     }
+
+    /// Build a square matrix with this vector along the main diagonal and 0 everywhere else
+    pub fn diag(self) -> GraphTensor {
+        assert_eq!(
+            self.shape.len(),
+            1,
+            "Can only build a diagonal matrix from a vector"
+        );
+        let n = self.dims()[0];
+        let arange = self.graph().arange(n);
+        self.expand(0, n) * arange.expand(0, n).equals(arange.expand(1, n))
+    }
+
+    /// Extract a diagonal of a matrix as a vector.
+    ///
+    /// `offset` 0 is the main diagonal, positive offsets are above it and negative offsets below it,
+    /// so element `i` comes from row `i` and column `i + offset` (or row `i - offset` and column `i` below the diagonal).
+    /// The output length is the number of elements on that diagonal, so the offset must land inside the matrix: below
+    /// the column count when positive, and below the row count when negative.
+    pub fn diagonal(self, offset: i32) -> GraphTensor {
+        assert_eq!(
+            self.shape.len(),
+            2,
+            "Can only take the diagonal of a matrix"
+        );
+        let (m, n) = self.dims2();
+        let (axis, dim) = if offset >= 0 {
+            ("columns", n)
+        } else {
+            ("rows", m)
+        };
+        if let Some(dim) = dim.to_usize() {
+            assert!(
+                (offset.unsigned_abs() as usize) < dim,
+                "Diagonal offset {offset} is outside a matrix with {dim} {axis}"
+            );
+        }
+        let rows = self.graph().arange(m).expand(1, n);
+        let cols = self.graph().arange(n).expand(0, m);
+        // Pick out the diagonal elements in each row, then keep only the rows the diagonal passes through
+        let picked = (self * (cols - offset as f32).equals(rows)).sum_reduce(1);
+        let (start, len) = if offset >= 0 {
+            (Expression::from(0), m.min(n - offset))
+        } else {
+            (Expression::from(-offset), (m + offset).min(n))
+        };
+        picked.slice_along(start..start + len, 0)
+    }
 }
 
 impl Graph {
@@ -71,14 +141,29 @@ impl Graph {
         )
     }
 
+    /// A tensor of `shape` filled with `value`. It's a broadcasted scalar constant, so no data is materialized.
+    pub fn full(&mut self, shape: impl ToShape, value: impl Into<ConstantValue>) -> GraphTensor {
+        self.constant(value).expand_to(shape)
+    }
+
+    /// A tensor of `shape` filled with 0s
+    pub fn zeros(&mut self, shape: impl ToShape) -> GraphTensor {
+        self.full(shape, 0.)
+    }
+
+    /// A tensor of `shape` filled with 1s
+    pub fn ones(&mut self, shape: impl ToShape) -> GraphTensor {
+        self.full(shape, 1.)
+    }
+
     /// ARange from 0 to N
     pub fn arange(&mut self, to: impl Into<Expression>) -> GraphTensor {
         let to = to.into();
         if to.to_usize().map(|i| i == 1).unwrap_or_default() {
             // Single number ARange is just 0
-            self.constant(0.).expand(0, to)
+            self.zeros(to)
         } else {
-            self.constant(1.).expand(0, to).cumsum_last_dim() - 1.
+            self.ones(to).cumsum_last_dim() - 1.
         }
     }
 
@@ -103,10 +188,285 @@ impl Graph {
 
         (horizontal - (diagonal as f32 - 1.)).greater_than(vertical)
     }
+
+    /// Padding mask for a batch of variable-length sequences: a (batch, max_seq) tensor with 1s where `position < length` and 0s in the padding.
+    ///
+    /// `lengths` is a (batch,) tensor of sequence lengths. For `masked_softmax`, turn it into an additive mask with `(mask - 1.) * large`.
+    pub fn length_mask(
+        &mut self,
+        lengths: GraphTensor,
+        max_seq: impl Into<Expression>,
+    ) -> GraphTensor {
+        assert_eq!(
+            lengths.shape.len(),
+            1,
+            "Lengths must be a 1D (batch,) tensor"
+        );
+        let max_seq = max_seq.into();
+        self.arange(max_seq)
+            .expand(0, lengths.dims1())
+            .less_than(lengths.expand(1, max_seq))
+    }
+}
+
+/// A floating point type a tensor can be saturate-cast into
+pub trait SaturatingCast {
+    /// Smallest finite value of the type
+    const MIN: f32;
+    /// Largest finite value of the type
+    const MAX: f32;
+}
+
+impl SaturatingCast for f32 {
+    const MIN: f32 = f32::MIN;
+    const MAX: f32 = f32::MAX;
+}
+
+impl SaturatingCast for f16 {
+    const MIN: f32 = f16::MIN.to_f32_const();
+    const MAX: f32 = f16::MAX.to_f32_const();
+}
+
+impl SaturatingCast for bf16 {
+    const MIN: f32 = bf16::MIN.to_f32_const();
+    const MAX: f32 = bf16::MAX.to_f32_const();
+}
+
+/// How `embedding_bag` pools the rows in each bag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmbeddingBagMode {
+    Sum,
+    Mean,
+}
+
+impl EmbeddingBagMode {
+    /// Name of the op building the bag segment matrix, so backends can find it and fuse the whole bag
+    pub fn segments_op_name(&self) -> String {
+        format!("EmbeddingBag{self:?}Segments")
+    }
+
+    /// The mode of an op named by `segments_op_name`
+    pub fn from_segments_op_name(name: &str) -> Option<Self> {
+        [Self::Sum, Self::Mean]
+            .into_iter()
+            .find(|m| m.segments_op_name() == name)
+    }
+}
+
+/// Resolve the `(start, end)` range of each bag in `0..n` from an f32 or i32 offsets tensor
+pub fn embedding_bag_ranges(
+    offsets: &Tensor,
+    shape: ShapeTracker,
+    n: usize,
+) -> Vec<(usize, usize)> {
+    let get: Box<dyn Fn(usize) -> f32> = if let Some(v) = offsets.downcast_ref::<Vec<i32>>() {
+        Box::new(|i| v[i] as f32)
+    } else {
+        let v = offsets.downcast_ref::<Vec<f32>>().unwrap();
+        Box::new(|i| v[i])
+    };
+    let (ind, val) = (shape.index_expression(), shape.valid_expression());
+    let mut stack = vec![];
+    let starts = (0..shape.n_elements().to_usize().unwrap())
+        .map(|i| {
+            if val.exec_single_var_stack(i, &mut stack) != 0 {
+                get(ind.exec_single_var_stack(i, &mut stack)) as usize
+            } else {
+                0
+            }
+        })
+        .collect::<Vec<_>>();
+    starts
+        .iter()
+        .enumerate()
+        .map(|(b, &start)| {
+            let end = starts.get(b + 1).copied().unwrap_or(n);
+            assert!(
+                start <= end && end <= n,
+                "Embedding bag offsets must be increasing and within the {n} indexes, got {starts:?}"
+            );
+            (start, end)
+        })
+        .collect()
+}
+
+/// Name of the op `nonzero` builds, so backends can find it and swap in their own compaction
+pub const NONZERO_OP_NAME: &str = "Nonzero";
+
+/// Indexes of the nonzero elements of a 1D tensor viewed through `shape`, padded with -1 to the tensor's length. Each
+/// index lands at the running count of nonzeros before it, which is what the exclusive cumsum in a parallel compaction
+/// computes.
+pub fn nonzero_indexes(inp: &Tensor, shape: ShapeTracker) -> Vec<f32> {
+    let is_nonzero: Box<dyn Fn(usize) -> bool> = if let Some(v) = inp.downcast_ref::<Vec<i32>>() {
+        Box::new(|i| v[i] != 0)
+    } else {
+        let v = inp.downcast_ref::<Vec<f32>>().unwrap();
+        Box::new(|i| v[i] != 0.)
+    };
+    let (ind, val) = (shape.index_expression(), shape.valid_expression());
+    let mut stack = vec![];
+    let n = shape.n_elements().to_usize().unwrap();
+    let mut indexes = (0..n)
+        .filter(|i| {
+            val.exec_single_var_stack(*i, &mut stack) != 0
+                && is_nonzero(ind.exec_single_var_stack(*i, &mut stack))
+        })
+        .map(|i| i as f32)
+        .collect::<Vec<_>>();
+    indexes.resize(n, -1.);
+    indexes
+}
+
+/// Mask of the smallest set of highest probability tokens whose total reaches `top_p` of the total probability.
+///
+/// Rather than sorting the whole vocab, the top `k` tokens are partitioned to the front in O(V) and only those are
+/// sorted and accumulated, doubling `k` until the threshold is crossed. That's O(V log(n / k0) + n log n) for a nucleus
+/// of `n` tokens, where sharp distributions have `n` far smaller than the vocab.
+fn top_p_mask_data(probs: &[f32], top_p: f32) -> Vec<f32> {
+    const INITIAL_K: usize = 64;
+    let target = top_p * probs.iter().sum::<f32>();
+    // Descending probability, ties going to the lower token
+    let desc = |a: &usize, b: &usize| probs[*b].total_cmp(&probs[*a]).then(a.cmp(b));
+    let mut order = (0..probs.len()).collect::<Vec<_>>();
+    let mut mask = vec![0.; probs.len()];
+    let (mut sorted, mut k, mut mass) = (0, INITIAL_K.min(probs.len()), 0.);
+    loop {
+        // Pull the next best k - sorted tokens up behind the ones already accumulated, then sort just those
+        if k < order.len() {
+            order[sorted..].select_nth_unstable_by(k - sorted - 1, desc);
+        }
+        order[sorted..k].sort_unstable_by(desc);
+        for &i in &order[sorted..k] {
+            mask[i] = 1.;
+            mass += probs[i];
+            if mass >= target {
+                return mask;
+            }
+        }
+        if k == order.len() {
+            return mask;
+        }
+        (sorted, k) = (k, (k * 2).min(order.len()));
+    }
+}
+
+/// How to sample between source pixels when resizing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterpolateMode {
+    /// Take the nearest source pixel (rounded down)
+    Nearest,
+    /// Linearly interpolate between the two nearest source pixels on each axis.
+    ///
+    /// With `align_corners`, the corner pixels of the input and output are aligned exactly.
+    /// Same semantics as https://pytorch.org/docs/stable/generated/torch.nn.functional.interpolate
+    Bilinear { align_corners: bool },
+}
+
+impl Graph {
+    /// The source taps of each output position along an axis, as `(indexes, weights)` vectors of length `out`.
+    /// Nearest takes a single unweighted tap, bilinear the two neighbouring positions.
+    fn interpolation_taps(
+        &mut self,
+        inp: usize,
+        out: usize,
+        mode: InterpolateMode,
+    ) -> Vec<(GraphTensor, Option<GraphTensor>)> {
+        let src = self.arange(out);
+        match mode {
+            InterpolateMode::Nearest => {
+                let src = src * (inp as f32 / out as f32);
+                // Floor and clamp to the last input position
+                vec![((src - src % 1.0).min_f32((inp - 1) as f32), None)]
+            }
+            InterpolateMode::Bilinear { align_corners } => {
+                let src = if align_corners {
+                    src * if out > 1 {
+                        (inp - 1) as f32 / (out - 1) as f32
+                    } else {
+                        0.0
+                    }
+                } else {
+                    ((src + 0.5) * (inp as f32 / out as f32) - 0.5).max_f32(0.)
+                };
+                let src = src.min_f32((inp - 1) as f32);
+                let lo = src - src % 1.0;
+                let hi = (lo + 1.0).min_f32((inp - 1) as f32);
+                let frac = src - lo;
+                vec![(lo, Some(1.0 - frac)), (hi, Some(frac))]
+            }
+        }
+    }
 }
 
 impl GraphTensor {
-    /// Gather a batch of vectors from a matrix
+    /// Resize the last two (spatial) dimensions by `scale`. Spatial dimensions must be known.
+    ///
+    /// The output size of each spatial axis is `floor(size * scale)`. Each axis is resampled with row gathers at
+    /// the computed source positions, one per tap of the mode.
+    pub fn interpolate(self, scale: f32, mode: InterpolateMode) -> GraphTensor {
+        assert!(
+            self.shape.len() >= 2,
+            "Interpolation needs at least 2 dimensions, got {}",
+            self.shape.len()
+        );
+        let n_dims = self.shape.len();
+        let dims = self.dims();
+        let (h, w) = (
+            dims[n_dims - 2]
+                .to_usize()
+                .expect("Interpolation requires known spatial dimensions"),
+            dims[n_dims - 1]
+                .to_usize()
+                .expect("Interpolation requires known spatial dimensions"),
+        );
+        let (out_h, out_w) = ((h as f32 * scale) as usize, (w as f32 * scale) as usize);
+
+        // Flatten batch dims, then move each spatial axis to the front in turn so its positions are rows
+        let batch = dims[..n_dims - 2]
+            .iter()
+            .copied()
+            .product::<Expression>()
+            .max(1);
+        let x = self.reshape((batch, h, w));
+        // Resample width, then height
+        let x = x
+            .permute((2, 0, 1))
+            .reshape((w, batch * h))
+            .resample_rows(w, out_w, mode);
+        let x = x
+            .reshape((out_w, batch, h))
+            .permute((2, 1, 0))
+            .reshape((h, batch * out_w))
+            .resample_rows(h, out_h, mode);
+        let mut out_shape = dims;
+        out_shape[n_dims - 2] = out_h.into();
+        out_shape[n_dims - 1] = out_w.into();
+        x.reshape((out_h, batch, out_w))
+            .permute((1, 0, 2))
+            .reshape(out_shape)
+    }
+
+    /// Resample the `inp` rows of a matrix to `out` rows, blending the gathered taps by their weights
+    fn resample_rows(self, inp: usize, out: usize, mode: InterpolateMode) -> GraphTensor {
+        let cols = self.dims()[1];
+        self.graph()
+            .interpolation_taps(inp, out, mode)
+            .into_iter()
+            .map(|(indexes, weights)| {
+                let rows = self.gather(indexes);
+                match weights {
+                    Some(weights) => rows * weights.expand(1, cols),
+                    None => rows,
+                }
+            })
+            .reduce(|a, b| a + b)
+            .unwrap()
+    }
+
+    /// Gather a batch of vectors from a matrix with a vector of f32 indexes.
+    ///
+    /// i32 indexes need an explicit [`GraphTensor::int_to_float`] first, which runs on the host, so keep index
+    /// tensors in f32 when the gather runs on a device.
     pub fn gather(self, indexes: GraphTensor) -> GraphTensor {
         let (vocab, dim) = self.dims2();
         let batch = indexes.dims1();
@@ -118,6 +478,297 @@ impl GraphTensor {
         (one_hot.expand(2, dim) * self.expand(0, batch)).sum_reduce(1)
     }
 
+    /// Gather slices using coordinate tuples. `indices` has shape (.., D), where each row of the last axis selects
+    /// into the first D dims of this tensor, so the result has shape (.., remaining source dims).
+    /// Indexes can be either f32 or i32 tensors.
+    pub fn gather_nd(self, indices: GraphTensor) -> GraphTensor {
+        let (src_dims, idx_dims) = (self.dims(), indices.dims());
+        let d = idx_dims
+            .last()
+            .and_then(|d| d.to_usize())
+            .expect("gather_nd indices need a known coordinate axis");
+        assert!(
+            d >= 1 && d <= src_dims.len(),
+            "gather_nd coordinates have {d} dims, but the source only has {}",
+            src_dims.len()
+        );
+        let batch_dims = idx_dims[..idx_dims.len() - 1].to_vec();
+        let batch = batch_dims.iter().copied().product::<Expression>().max(1);
+        // Flatten coordinates into row-major indexes on the host, so they stay exact on low precision backends. The
+        // source dims come in as the resolved shape of a broadcast zero, so they follow the dynamic dims of each run.
+        let coord_dims = self.graph().zeros(src_dims[..d].to_vec());
+        let id = self
+            .graph()
+            .add_op(op::Function(
+                "GatherNdIndex".to_string(),
+                Box::new(move |inp| {
+                    let t = inp[0].0.borrowed();
+                    let coord: Box<dyn Fn(usize) -> f32> =
+                        if let Some(v) = t.downcast_ref::<Vec<i32>>() {
+                            Box::new(|i| v[i] as f32)
+                        } else {
+                            let v = t.downcast_ref::<Vec<f32>>().unwrap();
+                            Box::new(|i| v[i])
+                        };
+                    let (ind, val) = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+                    let mut stack = vec![];
+                    let mut get = |i| {
+                        if val.exec_single_var_stack(i, &mut stack) != 0 {
+                            coord(ind.exec_single_var_stack(i, &mut stack))
+                        } else {
+                            0.
+                        }
+                    };
+                    let dims = inp[1]
+                        .1
+                        .dims()
+                        .into_iter()
+                        .map(|e| e.to_usize().unwrap() as f32)
+                        .collect::<Vec<_>>();
+                    let n = inp[0].1.n_elements().to_usize().unwrap() / d;
+                    let out = (0..n)
+                        .map(|b| (0..d).fold(0., |acc, i| acc * dims[i] + get(b * d + i)))
+                        .collect::<Vec<_>>();
+                    vec![Tensor::new(out)]
+                }),
+            ))
+            .input(indices.id, 0, indices.shape)
+            .input(coord_dims.id, 0, coord_dims.shape)
+            .finish();
+        let linear = GraphTensor::from_id(id, ShapeTracker::new(batch), self.graph_ref);
+        let rest = src_dims[d..].iter().copied().product::<Expression>().max(1);
+        let out = self
+            .reshape((src_dims[..d].iter().copied().product::<Expression>(), rest))
+            .gather(linear);
+        let mut out_dims = batch_dims;
+        out_dims.extend_from_slice(&src_dims[d..]);
+        if out_dims.is_empty() {
+            out_dims.push(Expression::from(1));
+        }
+        out.reshape(out_dims)
+    }
+
+    /// Pool rows of this (vocab, dim) embedding matrix over bags of indexes, like torch's `EmbeddingBag`.
+    ///
+    /// `indices` is a flat vector of row indexes and `offsets` holds the start of each bag in it, so bag `b` covers
+    /// `indices[offsets[b]..offsets[b + 1]]` and the last bag runs to the end. Empty bags pool to zeros.
+    /// Returns a (bags, dim) tensor. Indexes are gathered on the device so they must be f32, while the offsets are
+    /// read on the host and can be either f32 or i32 tensors.
+    pub fn embedding_bag(
+        self,
+        indices: GraphTensor,
+        offsets: GraphTensor,
+        mode: EmbeddingBagMode,
+    ) -> GraphTensor {
+        let (n, bags) = (indices.dims1(), offsets.dims1());
+        // Weight of each index in each bag, built on the host from the offsets
+        let id = self
+            .graph()
+            .add_op(op::Function(
+                mode.segments_op_name(),
+                Box::new(move |inp| {
+                    let n = inp[1].1.n_elements().to_usize().unwrap();
+                    let ranges = embedding_bag_ranges(inp[0].0.borrowed(), inp[0].1, n);
+                    let mut segments = vec![0.; ranges.len() * n];
+                    for (row, (start, end)) in segments.chunks_exact_mut(n.max(1)).zip(ranges) {
+                        let weight = match mode {
+                            EmbeddingBagMode::Sum => 1.,
+                            EmbeddingBagMode::Mean => 1. / (end - start).max(1) as f32,
+                        };
+                        row[start..end].fill(weight);
+                    }
+                    vec![Tensor::new(segments)]
+                }),
+            ))
+            .input(offsets.id, 0, offsets.shape)
+            .input(indices.id, 0, indices.shape)
+            .finish();
+        let segments = GraphTensor::from_id(id, ShapeTracker::new((bags, n)), self.graph_ref);
+        segments.matmul(self.gather(indices))
+    }
+
+    /// Indexes of the nonzero elements of a 1D f32 tensor, in order, along with the number of nonzeros as a `(1,)`
+    /// tensor.
+    ///
+    /// The count is only known once the graph runs, so the indexes are sized at its upper bound, the input length, and
+    /// padded with -1 past the count. Padded indexes match no row, so `gather` turns them into zeros. To work on just
+    /// the nonzeros, read the count after running and slice the indexes (or set a dynamic dimension for a later graph)
+    /// from the caller.
+    pub fn nonzero(self) -> (GraphTensor, GraphTensor) {
+        let n = self.dims1();
+        let id = self
+            .graph()
+            .add_op(op::Function(
+                NONZERO_OP_NAME.to_string(),
+                Box::new(|inp| vec![Tensor::new(nonzero_indexes(inp[0].0.borrowed(), inp[0].1))]),
+            ))
+            .input(self.id, 0, self.shape)
+            .finish();
+        let count = self
+            .not_equals(self.graph().zeros(self.shape))
+            .sum_reduce(0)
+            .expand(0, 1);
+        (
+            GraphTensor::from_id(id, ShapeTracker::new(n), self.graph_ref),
+            count,
+        )
+    }
+
+    /// Nucleus (top-p) sampling mask over a `(V,)` probability vector, in the original vocab order. The highest
+    /// probability tokens are kept until their cumulative probability reaches `top_p`, including the token that
+    /// crosses it, so at least one token is always kept.
+    ///
+    /// Only the nucleus gets sorted, not the whole vocab, so this is roughly linear in the vocab size for the sharp
+    /// distributions seen when sampling. Runs on the host.
+    pub fn top_p_mask(self, top_p: f32) -> GraphTensor {
+        assert_eq!(self.shape.len(), 1, "top_p_mask expects a (V,) tensor");
+        let probs = self.contiguous();
+        let id = self
+            .graph()
+            .add_op(op::Function(
+                "TopPMask".to_string(),
+                Box::new(move |inp| {
+                    let probs = inp[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+                    vec![Tensor::new(top_p_mask_data(probs, top_p))]
+                }),
+            ))
+            .input(probs.id, 0, probs.shape)
+            .finish();
+        GraphTensor::from_id(id, probs.shape, self.graph_ref)
+    }
+
+    /// Add sparse per-token biases to logits, like OpenAI's `logit_bias`. The last axis is the vocab, and each
+    /// `(token, bias)` is added to that token in every row, so a bias of `f32::NEG_INFINITY` bans the token.
+    ///
+    /// The biases are scatter-added into the logits on the host, touching only the biased tokens. Repeated tokens
+    /// have their biases summed.
+    pub fn logit_bias(self, biases: &[(usize, f32)]) -> GraphTensor {
+        if let Some(vocab) = self.dims().last().and_then(|d| d.to_usize()) {
+            if let Some((token, _)) = biases.iter().find(|(t, _)| *t >= vocab) {
+                panic!("Can't bias token {token}, the vocab only has {vocab} tokens");
+            }
+        }
+        let logits = self.contiguous();
+        let biases = biases.to_vec();
+        let id = self
+            .graph()
+            .add_op(op::Function(
+                "LogitBias".to_string(),
+                Box::new(move |mut inp| {
+                    let vocab = inp[0].1.dims().last().unwrap().to_usize().unwrap();
+                    let mut logits = inp.pop().unwrap().0.cloned();
+                    let data = logits.downcast_mut::<Vec<f32>>().unwrap();
+                    for row in data.chunks_mut(vocab) {
+                        for (token, bias) in &biases {
+                            row[*token] += bias;
+                        }
+                    }
+                    vec![logits]
+                }),
+            ))
+            .input(logits.id, 0, logits.shape)
+            .finish();
+        GraphTensor::from_id(id, logits.shape, self.graph_ref)
+    }
+
+    /// Count the occurrences of each index in a vector of indexes, producing a vector of `num_bins` counts.
+    /// Indexes are an f32 tensor, and indexes outside of `0..num_bins` aren't counted.
+    pub fn bincount(self, num_bins: impl Into<Expression>) -> GraphTensor {
+        let num_bins = num_bins.into();
+        let n = self.dims1();
+        let one_hot = self
+            .graph()
+            .arange(num_bins)
+            .expand(0, n)
+            .equals(self.expand(1, num_bins));
+        one_hot.sum_reduce(0)
+    }
+
+    /// Convert an i32 tensor to f32. f32 tensors are passed through unchanged.
+    ///
+    /// i32 tensors are host-only. Both casts are host functions, so on a device backend each one copies the data to
+    /// the host and back, and no device kernel binds an integer buffer. Keep them out of hot paths: device ops like
+    /// [`GraphTensor::gather`] and [`GraphTensor::bincount`] take f32 indexes, so cast once up front (or keep the
+    /// indexes in f32) rather than on every step.
+    pub fn int_to_float(self) -> GraphTensor {
+        let id = self
+            .graph()
+            .add_op(op::Function(
+                "IntToFloat".to_string(),
+                Box::new(|inp| {
+                    let t = inp[0].0.borrowed();
+                    if let Some(d) = t.downcast_ref::<Vec<i32>>() {
+                        vec![Tensor::new(d.iter().map(|i| *i as f32).collect::<Vec<_>>())]
+                    } else {
+                        vec![t.clone()]
+                    }
+                }),
+            ))
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(id, self.shape, self.graph_ref)
+    }
+
+    /// Convert an f32 tensor to i32, rounding to the nearest integer. i32 tensors are passed through unchanged.
+    /// Runs on the host, see [`GraphTensor::int_to_float`].
+    pub fn float_to_int(self) -> GraphTensor {
+        let id = self
+            .graph()
+            .add_op(op::Function(
+                "FloatToInt".to_string(),
+                Box::new(|inp| {
+                    let t = inp[0].0.borrowed();
+                    if let Some(d) = t.downcast_ref::<Vec<f32>>() {
+                        vec![Tensor::new(
+                            d.iter().map(|i| i.round() as i32).collect::<Vec<_>>(),
+                        )]
+                    } else {
+                        vec![t.clone()]
+                    }
+                }),
+            ))
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(id, self.shape, self.graph_ref)
+    }
+
+    /// Symmetrically quantize to int8 at execution time, with one absmax scale for each slice along `axis`.
+    ///
+    /// Returns the quantized values as an i32 tensor in `-127..=127` and the f32 scales, which have `axis` reduced.
+    /// Use `dequantize` with the same axis to map back to f32.
+    pub fn quantize_dynamic(self, axis: usize) -> (GraphTensor, GraphTensor) {
+        let n = self.dims()[axis];
+        let scale = self.abs().max_reduce(axis) / 127.;
+        // All-zero slices get a zero scale, so guard the division and let them quantize to zero
+        let inv_scale = scale.max_f32(f32::MIN_POSITIVE).recip().expand(axis, n);
+        ((self * inv_scale).float_to_int(), scale)
+    }
+
+    /// Map a quantized tensor from `quantize_dynamic` back to f32, broadcasting `scale` along `axis`
+    pub fn dequantize(self, scale: GraphTensor, axis: usize) -> GraphTensor {
+        let n = self.dims()[axis];
+        self.int_to_float() * scale.expand(axis, n)
+    }
+
+    /// Clamp values into the finite range of `T`, so a later cast to `T` (such as the f16 copy onto a device)
+    /// saturates at `T::MIN` / `T::MAX` instead of overflowing to Inf
+    pub fn cast_saturating<T: SaturatingCast>(self) -> GraphTensor {
+        self.clip(T::MIN, T::MAX)
+    }
+
+    /// Insert a fusion boundary: the tensor is materialized here and no compiler pass fuses ops across this point.
+    ///
+    /// The barrier is an identity op that backends remove once their fusion passes have run, so unlike `keep` it
+    /// doesn't hold the data around between runs, and it never leaves the device.
+    pub fn barrier(self) -> GraphTensor {
+        let id = self
+            .graph()
+            .add_op(op::Barrier)
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(id, self.shape, self.graph_ref)
+    }
+
     /// Print the value of this tensor when the graph is ran
     pub fn print<T: ToString>(&self, message: T) -> Self {
         let message = message.to_string();
@@ -148,6 +799,35 @@ impl GraphTensor {
         *self
     }
 
+    /// Write the value of this tensor to a `.npy` file every time the graph is ran
+    pub fn dump(&self, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let id = self
+            .graph()
+            .add_op(op::Function(
+                "Dump".to_string(),
+                Box::new(move |inp| {
+                    let (tensor, shape) = &inp[0];
+                    let d = tensor.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+                    let mut data = vec![0.; shape.n_elements().to_usize().unwrap()];
+                    let (ind, val) = (shape.index_expression(), shape.valid_expression());
+                    let mut stack = vec![];
+                    #[allow(unused_mut)]
+                    for (i, mut r) in data.iter_mut().enumerate() {
+                        if val.exec_single_var_stack(i, &mut stack) != 0 {
+                            *r = d[ind.exec_single_var_stack(i, &mut stack)];
+                        }
+                    }
+                    crate::npy::write_npy(&path, &shape.shape_usize(), &data).unwrap();
+                    vec![]
+                }),
+            ))
+            .input(self.id, 0, self.shape)
+            .finish();
+        self.graph().no_delete.insert(id);
+        *self
+    }
+
     /// Check the tensor value against a binary file
     pub fn diff(&self, file: impl Fn() -> Option<PathBuf> + 'static, threshold: f32) -> Self {
         let id = self
@@ -322,6 +1002,8 @@ impl GraphTensor {
 
 #[cfg(test)]
 mod tests {
+    use super::{EmbeddingBagMode, InterpolateMode};
+    use itertools::Itertools;
     crate::test_imports!();
     #[test]
     fn test_arange() {
@@ -333,6 +1015,325 @@ mod tests {
         assert_exact(&arange.data(), &[0., 1., 2., 3., 4., 5., 6., 7., 8., 9.]);
     }
 
+    #[test]
+    fn test_full() {
+        let mut cx = Graph::new();
+        let a = cx.full((2, 'a'), 2.5);
+        let b = (a + cx.ones((2, 'a')) - cx.zeros((2, 'a'))).retrieve();
+        cx.set_dyn_dim('a', 3);
+        cx.execute();
+
+        assert_exact(&b.data(), &[3.5; 6]);
+    }
+
+    #[test]
+    fn test_top_p_mask() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(5).set(vec![0.1, 0.4, 0.05, 0.3, 0.15]);
+        let b = a.top_p_mask(0.75).retrieve();
+        let c = a.top_p_mask(0.).retrieve();
+        // Large enough that the nucleus takes a few rounds of doubling
+        let n = 1000;
+        let probs = (0..n)
+            .map(|i| ((i * 7919) % n) as f32 + 1.)
+            .collect::<Vec<_>>();
+        let d = cx.tensor(n).set(probs.clone()).top_p_mask(0.5).retrieve();
+        cx.execute();
+
+        assert_exact(&b.data(), &[0., 1., 0., 1., 1.]);
+        assert_exact(&c.data(), &[0., 1., 0., 0., 0.]);
+
+        // Reference: full sort and cumsum
+        let total = probs.iter().sum::<f32>();
+        let order = (0..n)
+            .sorted_by(|a, b| probs[*b].total_cmp(&probs[*a]))
+            .collect::<Vec<_>>();
+        let mut expected = vec![0.; n];
+        let mut mass = 0.;
+        for i in order {
+            expected[i] = 1.;
+            mass += probs[i];
+            if mass >= 0.5 * total {
+                break;
+            }
+        }
+        assert_exact(&d.data(), &expected);
+        assert!(expected.iter().sum::<f32>() > 64.);
+    }
+
+    #[test]
+    fn test_logit_bias() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor((2, 4))
+            .set(vec![1., 2., 3., 4., -1., -2., -3., -4.]);
+        // Transposed input to check the bias follows the logical vocab axis
+        let biases = [(1, f32::NEG_INFINITY), (3, 2.5), (3, 0.5)];
+        let b = a.logit_bias(&biases).retrieve();
+        let c = a.permute((1, 0)).logit_bias(&[(0, 10.)]).retrieve();
+        let probs = b.softmax(1).retrieve();
+        cx.execute();
+
+        assert_exact(
+            &b.data(),
+            &[
+                1.,
+                f32::NEG_INFINITY,
+                3.,
+                7.,
+                -1.,
+                f32::NEG_INFINITY,
+                -3.,
+                -1.,
+            ],
+        );
+        assert_exact(&c.data(), &[11., -1., 12., -2., 13., -3., 14., -4.]);
+        assert!(probs.data()[1] == 0. && probs.data()[5] == 0.);
+    }
+
+    #[test]
+    fn test_nonzero() {
+        let mut cx = Graph::new();
+        let a = cx.tensor('n');
+        let (idx, count) = a.nonzero();
+        let count = count.retrieve();
+        let b = (idx * 2.).retrieve();
+        // Padded indexes gather zeros
+        let c = a.expand(1, 1).gather(idx).retrieve();
+
+        a.set_dyn(vec![0., 3., 0., -1., 2., 0.], 6);
+        cx.execute();
+        assert_exact(&count.data(), &[3.]);
+        assert_exact(&b.data(), &[2., 6., 8., -2., -2., -2.]);
+        assert_exact(&c.data(), &[3., -1., 2., 0., 0., 0.]);
+        // The caller narrows the indexes down to the count once it's known
+        let n = count.data()[0] as usize;
+        assert_exact(&b.data()[..n], &[2., 6., 8.]);
+        count.drop();
+        b.drop();
+        c.drop();
+
+        // No nonzeros
+        a.set_dyn(vec![0.; 4], 4);
+        cx.execute();
+        assert_exact(&count.data(), &[0.]);
+        assert_exact(&b.data(), &[-2.; 4]);
+    }
+
+    #[test]
+    fn test_length_mask() {
+        let mut cx = Graph::new();
+        let lengths = cx.tensor(3).set(vec![2., 0., 4.]);
+        let mask = cx.length_mask(lengths, 4).retrieve();
+        cx.execute();
+
+        assert_exact(
+            &mask.data(),
+            &[1., 1., 0., 0., 0., 0., 0., 0., 1., 1., 1., 1.],
+        );
+    }
+
+    #[test]
+    fn test_interpolate_nearest() {
+        let mut cx = Graph::new();
+
+        let a = cx.tensor((1, 2, 2)).set([[[1., 2.], [3., 4.]]]);
+        let b = a.interpolate(2., InterpolateMode::Nearest).retrieve();
+        let c = cx
+            .tensor((3, 2))
+            .set([[1., 2.], [3., 4.], [5., 6.]])
+            .interpolate(0.5, InterpolateMode::Nearest)
+            .retrieve();
+        cx.execute();
+
+        assert_eq!(b.dims(), [1, 4, 4].map(Expression::from));
+        assert_exact(
+            &b.data(),
+            &[
+                1., 1., 2., 2., 1., 1., 2., 2., 3., 3., 4., 4., 3., 3., 4., 4.,
+            ],
+        );
+        assert_exact(&c.data(), &[1.]);
+    }
+
+    #[test]
+    fn test_interpolate_bilinear() {
+        let mut cx = Graph::new();
+
+        let a = cx.tensor((2, 2)).set([[0., 1.], [2., 3.]]);
+        let aligned = a
+            .interpolate(
+                2.,
+                InterpolateMode::Bilinear {
+                    align_corners: true,
+                },
+            )
+            .retrieve();
+        let unaligned = a
+            .interpolate(
+                2.,
+                InterpolateMode::Bilinear {
+                    align_corners: false,
+                },
+            )
+            .retrieve();
+        cx.execute();
+
+        // Each output is 2 * row + col, with row / col sampled along the axis
+        let expected = |axis: [f32; 4]| {
+            axis.iter()
+                .flat_map(|r| axis.iter().map(move |c| 2. * r + c))
+                .collect::<Vec<_>>()
+        };
+        assert_close(&aligned.data(), &expected([0., 1. / 3., 2. / 3., 1.]));
+        assert_close(&unaligned.data(), &expected([0., 0.25, 0.75, 1.]));
+    }
+
+    #[test]
+    fn test_dump() {
+        let path = std::env::temp_dir().join("luminal_test_dump.npy");
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 3)).set(vec![1., 2., 3., 4., 5., 6.]);
+        let b = a.permute((1, 0)).dump(&path) * 2.;
+        b.retrieve();
+        cx.execute();
+
+        let (shape, data) = crate::npy::read_npy(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(shape, vec![3, 2]);
+        assert_exact(&data, &[1., 4., 2., 5., 3., 6.]);
+    }
+
+    #[test]
+    fn test_gather_i32() {
+        let mut cx = Graph::new();
+        let matrix = cx.tensor((3, 2)).set(vec![1., 2., 3., 4., 5., 6.]);
+        let indexes = cx.tensor(3).set(vec![2, 0, 1]);
+        let out = matrix.gather(indexes.int_to_float()).retrieve();
+        cx.execute();
+
+        assert_exact(&out.data(), &[5., 6., 1., 2., 3., 4.]);
+    }
+
+    #[test]
+    fn test_gather_nd() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor((2, 3, 2))
+            .set((0..12).map(|i| i as f32).collect::<Vec<_>>());
+        // Select rows from the first two dims
+        let rows = cx
+            .tensor((2, 2, 2))
+            .set(vec![1., 2., 0., 0., 0., 1., 1., 1.]);
+        let b = a.gather_nd(rows).retrieve();
+        // Select single elements with i32 coordinates
+        let points = cx
+            .tensor((2, 3))
+            .set(vec![1., 0., 1., 0., 2., 0.])
+            .float_to_int();
+        let c = a.gather_nd(points).retrieve();
+        // Select whole matrices
+        let mats = cx.tensor((1, 1)).set(vec![1.]);
+        let d = a.gather_nd(mats).retrieve();
+        cx.execute();
+
+        assert_eq!(b.shape.shape_usize(), vec![2, 2, 2]);
+        assert_exact(&b.data(), &[10., 11., 0., 1., 2., 3., 8., 9.]);
+        assert_eq!(c.shape.shape_usize(), vec![2]);
+        assert_exact(&c.data(), &[7., 4.]);
+        assert_eq!(d.shape.shape_usize(), vec![1, 3, 2]);
+        assert_exact(&d.data(), &[6., 7., 8., 9., 10., 11.]);
+    }
+
+    #[test]
+    fn test_embedding_bag() {
+        let mut cx = Graph::new();
+        let weights = cx
+            .tensor((5, 2))
+            .set(vec![0., 1., 2., 3., 4., 5., 6., 7., 8., 9.]);
+        let indices = cx.tensor(6).set(vec![1., 3., 3., 0., 4., 2.]);
+        // The second bag is empty
+        let offsets = cx.tensor(4).set(vec![0, 3, 3, 4]);
+        let sum = weights
+            .embedding_bag(indices, offsets, EmbeddingBagMode::Sum)
+            .retrieve();
+        let mean = weights
+            .embedding_bag(indices, offsets, EmbeddingBagMode::Mean)
+            .retrieve();
+        cx.execute();
+
+        assert_exact(&sum.data(), &[14., 17., 0., 0., 0., 1., 12., 14.]);
+        assert_close(&mean.data(), &[14. / 3., 17. / 3., 0., 0., 0., 1., 6., 7.]);
+    }
+
+    #[test]
+    fn test_bincount() {
+        let mut cx = Graph::new();
+        let indexes = cx.tensor(7).set(vec![1., 3., 1., 0., 5., 1., 3.]);
+        let int_indexes = cx.tensor(4).set(vec![2, 2, 0, 2]);
+        // Index 5 is outside the bins, so it isn't counted
+        let counts = indexes.bincount(4).retrieve();
+        let int_counts = int_indexes.int_to_float().bincount(3).retrieve();
+        cx.execute();
+
+        assert_exact(&counts.data(), &[1., 3., 0., 2.]);
+        assert_exact(&int_counts.data(), &[1., 0., 3.]);
+    }
+
+    #[test]
+    fn test_quantize_dynamic() {
+        let mut cx = Graph::new();
+        let data = random_vec(4 * 6);
+        let a = cx.tensor((4, 6)).set(data.clone());
+        let zeros = cx.tensor((2, 3)).set(vec![0.; 6]);
+        let (q, scale) = a.quantize_dynamic(1);
+        let (q, scale) = (q.retrieve(), scale.retrieve());
+        let out = q.dequantize(scale, 1).retrieve();
+        let (zq, zscale) = zeros.quantize_dynamic(0);
+        let zout = zq.dequantize(zscale, 0).retrieve();
+        cx.execute();
+
+        let q = q.data_i32();
+        let scale = scale.data();
+        assert_eq!(scale.len(), 4);
+        for (row, s) in data.chunks(6).zip(&scale) {
+            let absmax = row.iter().fold(0_f32, |m, x| m.max(x.abs()));
+            assert!((s - absmax / 127.).abs() < 1e-6);
+        }
+        assert!(q.iter().all(|v| (-127..=127).contains(v)));
+        assert!(q.iter().any(|v| v.abs() == 127));
+        // Round to nearest means every element is within half a step of the original
+        for (i, (o, d)) in out.data().iter().zip(&data).enumerate() {
+            assert!((o - d).abs() <= scale[i / 6] / 2. + 1e-6);
+        }
+        assert_exact(&zout.data(), &[0.; 6]);
+    }
+
+    #[test]
+    fn test_int_float_cast() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(4).set(vec![1, -2, 3, 40]);
+        let b = (a.int_to_float() * 0.5).retrieve();
+        let c = (a.int_to_float() * 2.).float_to_int().retrieve();
+        cx.execute();
+
+        assert_exact(&b.data(), &[0.5, -1., 1.5, 20.]);
+        assert_eq!(c.data_i32(), vec![2, -4, 6, 80]);
+    }
+
+    #[test]
+    fn test_cast_saturating() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(4).set(vec![1e5, -1e6, 3.5, -0.25]);
+        let b = a.cast_saturating::<f16>().retrieve();
+        let c = a.cast_saturating::<f32>().retrieve();
+        cx.execute();
+
+        assert_exact(&b.data(), &[65504., -65504., 3.5, -0.25]);
+        assert!(b.data().iter().all(|i| f16::from_f32(*i).is_finite()));
+        assert_exact(&c.data(), &[1e5, -1e6, 3.5, -0.25]);
+    }
+
     #[test]
     fn test_cumprod() {
         let mut cx = Graph::new();
@@ -356,6 +1357,55 @@ mod tests {
         assert_exact(&arange.data(), &[0., 1., 2., 3., 4., 5.]);
     }
 
+    #[test]
+    fn test_diag() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(3).set(vec![1., 2., 3.]);
+        let b = a.diag().retrieve();
+        let c = a.diag().diagonal(0).retrieve();
+        cx.execute();
+
+        assert_exact(&b.data(), &[1., 0., 0., 0., 2., 0., 0., 0., 3.]);
+        assert_exact(&c.data(), &[1., 2., 3.]);
+    }
+
+    #[test]
+    fn test_diagonal() {
+        let mut cx = Graph::new();
+        // [[0, 1, 2, 3],
+        //  [4, 5, 6, 7],
+        //  [8, 9, 10, 11]]
+        let a = cx
+            .tensor((3, 4))
+            .set((0..12).map(|i| i as f32).collect::<Vec<_>>());
+        let main = a.diagonal(0).retrieve();
+        let above = a.diagonal(1).retrieve();
+        let far_above = a.diagonal(3).retrieve();
+        let below = a.diagonal(-1).retrieve();
+        let tall = a.permute((1, 0)).diagonal(-2).retrieve();
+        cx.execute();
+
+        assert_exact(&main.data(), &[0., 5., 10.]);
+        assert_exact(&above.data(), &[1., 6., 11.]);
+        assert_exact(&far_above.data(), &[3.]);
+        assert_exact(&below.data(), &[4., 9.]);
+        assert_exact(&tall.data(), &[2., 7.]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Diagonal offset 4 is outside a matrix with 4 columns")]
+    fn test_diagonal_offset_past_columns() {
+        let mut cx = Graph::new();
+        cx.tensor((3, 4)).diagonal(4);
+    }
+
+    #[test]
+    #[should_panic(expected = "Diagonal offset -3 is outside a matrix with 3 rows")]
+    fn test_diagonal_offset_past_rows() {
+        let mut cx = Graph::new();
+        cx.tensor((3, 4)).diagonal(-3);
+    }
+
     #[test]
     fn test_tril() {
         let mut cx = Graph::new();
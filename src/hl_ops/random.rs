@@ -0,0 +1,474 @@
+use std::{cell::Cell, rc::Rc};
+
+use crate::{op, prelude::*};
+
+/// The seed shared between a graph and all of its random ops
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SeedState {
+    pub seed: u64,
+    /// Bumped every time the seed is set, so random ops know to restart their sequence
+    pub generation: u64,
+    /// Added to the stream of every random op built on a graph that was later merged into another, so they don't
+    /// repeat the streams of the graph they were merged into
+    pub stream_offset: u32,
+}
+
+/// A Philox4x32-10 counter-based generator. The same (counter, key) always gives the same output,
+/// so kernels on any device can reproduce a stream without sharing state.
+pub fn philox4x32(counter: [u32; 4], key: [u32; 2]) -> [u32; 4] {
+    const M0: u64 = 0xD2511F53;
+    const M1: u64 = 0xCD9E8D57;
+    const W0: u32 = 0x9E3779B9;
+    const W1: u32 = 0xBB67AE85;
+    let (mut c, mut k) = (counter, key);
+    for _ in 0..10 {
+        let p0 = M0 * c[0] as u64;
+        let p1 = M1 * c[2] as u64;
+        c = [
+            (p1 >> 32) as u32 ^ c[1] ^ k[0],
+            p1 as u32,
+            (p0 >> 32) as u32 ^ c[3] ^ k[1],
+            p0 as u32,
+        ];
+        k = [k[0].wrapping_add(W0), k[1].wrapping_add(W1)];
+    }
+    c
+}
+
+/// An independent stream of random numbers belonging to a single random op
+#[derive(Debug, Clone)]
+pub struct RandomStream {
+    state: Rc<Cell<SeedState>>,
+    stream: u32,
+    generation: Cell<u64>,
+    invocation: Cell<u32>,
+}
+
+impl RandomStream {
+    /// Draw `n` uniform numbers in [0, 1). Each call advances the stream.
+    pub fn uniform(&self, n: usize) -> Vec<f32> {
+        let SeedState {
+            seed,
+            generation,
+            stream_offset,
+        } = self.state.get();
+        if generation != self.generation.get() {
+            self.generation.set(generation);
+            self.invocation.set(0);
+        }
+        let invocation = self.invocation.get();
+        let key = [seed as u32, (seed >> 32) as u32];
+        let out = (0..n.div_ceil(4))
+            .flat_map(|i| philox4x32([i as u32, invocation, self.stream + stream_offset, 0], key))
+            .take(n)
+            .map(|r| (r >> 8) as f32 * (1.0 / (1 << 24) as f32))
+            .collect();
+        self.invocation.set(invocation + 1);
+        out
+    }
+}
+
+/// A type a tensor can be stochastically rounded into
+pub trait StochasticCast {
+    /// The closest values of the type at or below `x` and at or above it
+    fn neighbours(x: f32) -> (f32, f32);
+}
+
+/// Step a finite half-precision bit pattern one value towards +inf (`up`) or -inf, crossing zero between -0 and +0
+fn step_half_bits(bits: u16, up: bool) -> u16 {
+    match (bits, up) {
+        (0x8000, true) => 0x0001,
+        (0x0000, false) => 0x8001,
+        (b, up) if ((b & 0x8000) == 0) == up => b + 1,
+        (b, _) => b - 1,
+    }
+}
+
+macro_rules! half_stochastic_cast {
+    ($t:ty) => {
+        impl StochasticCast for $t {
+            fn neighbours(x: f32) -> (f32, f32) {
+                let x = x.clamp(<$t>::MIN.to_f32(), <$t>::MAX.to_f32());
+                let nearest = <$t>::from_f32(x);
+                let step = |up| <$t>::from_bits(step_half_bits(nearest.to_bits(), up)).to_f32();
+                match nearest.to_f32() {
+                    n if n == x => (n, n),
+                    n if n > x => (step(false), n),
+                    n => (n, step(true)),
+                }
+            }
+        }
+    };
+}
+half_stochastic_cast!(f16);
+half_stochastic_cast!(bf16);
+
+impl StochasticCast for i8 {
+    fn neighbours(x: f32) -> (f32, f32) {
+        let x = x.clamp(i8::MIN as f32, i8::MAX as f32);
+        (x.floor(), x.ceil())
+    }
+}
+
+impl Graph {
+    /// Seed all random ops in the graph. Running the same graph after setting the same seed reproduces the same outputs.
+    pub fn set_seed(&mut self, seed: u64) {
+        for state in std::iter::once(&self.seed).chain(&self.merged_seeds) {
+            let SeedState {
+                generation,
+                stream_offset,
+                ..
+            } = state.get();
+            state.set(SeedState {
+                seed,
+                generation: generation + 1,
+                stream_offset,
+            });
+        }
+    }
+
+    /// Make the random ops of a graph being merged into this one follow this graph's seed, on streams after its own
+    pub(crate) fn merge_seed(&mut self, other: &Graph) {
+        if self.seed.get().generation == 0 && other.seed.get().generation != 0 {
+            // Only the merged graph was seeded, so keep its seed
+            self.set_seed(other.seed.get().seed);
+        }
+        let seed = self.seed.get().seed;
+        for state in std::iter::once(&other.seed).chain(&other.merged_seeds) {
+            let SeedState {
+                generation,
+                stream_offset,
+                ..
+            } = state.get();
+            state.set(SeedState {
+                seed,
+                generation: generation + 1,
+                stream_offset: stream_offset + self.random_streams,
+            });
+            self.merged_seeds.push(state.clone());
+        }
+        self.random_streams += other.random_streams;
+    }
+
+    /// Create a new random stream tied to this graph's seed
+    pub fn random_stream(&mut self) -> RandomStream {
+        self.random_streams += 1;
+        RandomStream {
+            state: self.seed.clone(),
+            stream: self.random_streams,
+            generation: Cell::new(self.seed.get().generation),
+            invocation: Cell::new(0),
+        }
+    }
+
+    /// A tensor of uniform random numbers in [0, 1), redrawn every run
+    pub fn rand(&mut self, shape: impl ToShape) -> GraphTensor {
+        let shape = ShapeTracker::new(shape);
+        let n_elements = shape
+            .n_elements()
+            .to_usize()
+            .expect("Random tensors must have a static shape");
+        let stream = self.random_stream();
+        GraphTensor::from_id(
+            self.add_op(op::Function(
+                "Rand".to_string(),
+                Box::new(move |_| vec![Tensor::new(stream.uniform(n_elements))]),
+            ))
+            .finish(),
+            shape,
+            self,
+        )
+    }
+}
+
+impl GraphTensor {
+    /// Randomly zero elements with probability `p`, scaling the rest by `1 / (1 - p)`. A new mask is drawn every run.
+    pub fn dropout(self, p: f32) -> GraphTensor {
+        assert!(
+            (0.0..1.0).contains(&p),
+            "Dropout probability must be in [0, 1)"
+        );
+        let stream = self.graph().random_stream();
+        // The mask only needs the shape, so read it off a broadcast zero instead of taking the activation as an input,
+        // which would copy it to the host every run on device backends
+        let shape = self.graph().zeros(self.dims());
+        let mask_id = self
+            .graph()
+            .add_op(op::Function(
+                "Dropout Mask".to_string(),
+                Box::new(move |inp| {
+                    let n_elements = inp[0].1.n_elements().to_usize().unwrap();
+                    vec![Tensor::new(
+                        stream
+                            .uniform(n_elements)
+                            .into_iter()
+                            .map(|r| if r < p { 0.0 } else { 1.0 / (1.0 - p) })
+                            .collect::<Vec<_>>(),
+                    )]
+                }),
+            ))
+            .input(shape.id, 0, shape.shape)
+            .finish();
+        self * GraphTensor::from_id(mask_id, self.shape.contiguous(), self.graph_ref)
+    }
+
+    /// Round every element to one of its two neighbouring values in `T` at random, rounding up with probability
+    /// proportional to how far it is past the lower one, so the rounding is unbiased on average. The output stays f32,
+    /// but every value is representable in `T`. Values past the range of `T` round to its nearest finite value.
+    ///
+    /// The rounding is drawn from the graph's RNG every run, so it's nondeterministic unless a fixed seed is set with
+    /// `Graph::set_seed`.
+    pub fn cast_stochastic<T: StochasticCast>(self) -> GraphTensor {
+        let input = self.contiguous();
+        let stream = self.graph().random_stream();
+        let id = self
+            .graph()
+            .add_op(op::Function(
+                "Stochastic Cast".to_string(),
+                Box::new(move |inp| {
+                    let x = inp[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+                    vec![Tensor::new(
+                        x.iter()
+                            .zip(stream.uniform(x.len()))
+                            .map(|(x, r)| {
+                                if !x.is_finite() {
+                                    return *x;
+                                }
+                                let (lo, hi) = T::neighbours(*x);
+                                if lo != hi && r < (x - lo) / (hi - lo) {
+                                    hi
+                                } else {
+                                    lo
+                                }
+                            })
+                            .collect::<Vec<_>>(),
+                    )]
+                }),
+            ))
+            .input(input.id, 0, input.shape)
+            .finish();
+        GraphTensor::from_id(id, input.shape, self.graph_ref)
+    }
+
+    /// Stochastic depth: zero whole samples along the first (batch) axis with probability `p`, scaling the kept
+    /// samples by `1 / (1 - p)`. Meant for residual branches, so a dropped sample skips the branch entirely.
+    /// A new mask is drawn every run.
+    pub fn drop_path(self, p: f32) -> GraphTensor {
+        assert!(
+            (0.0..1.0).contains(&p),
+            "Drop path probability must be in [0, 1)"
+        );
+        let n_samples = self.dims()[0];
+        let stream = self.graph().random_stream();
+        // Read the batch size off a broadcast zero, so it's resolved for each run without touching the activation
+        let samples = self.graph().zeros(n_samples);
+        let mask_id = self
+            .graph()
+            .add_op(op::Function(
+                "Drop Path Mask".to_string(),
+                Box::new(move |inp| {
+                    let n_samples = inp[0].1.n_elements().to_usize().unwrap();
+                    vec![Tensor::new(
+                        stream
+                            .uniform(n_samples)
+                            .into_iter()
+                            .map(|r| if r < p { 0.0 } else { 1.0 / (1.0 - p) })
+                            .collect::<Vec<_>>(),
+                    )]
+                }),
+            ))
+            .input(samples.id, 0, samples.shape)
+            .finish();
+        let mut mask = GraphTensor::from_id(mask_id, ShapeTracker::new(n_samples), self.graph_ref);
+        for (i, dim) in self.dims().into_iter().enumerate().skip(1) {
+            mask = mask.expand(i, dim);
+        }
+        self * mask
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_philox_known_answer() {
+        // Reference vector from the Random123 test suite
+        assert_eq!(
+            super::philox4x32([0, 0, 0, 0], [0, 0]),
+            [0x6627e8d5, 0xe169c58d, 0xbc57ac4c, 0x9b00dbd8]
+        );
+    }
+
+    #[test]
+    fn test_dropout() {
+        let mut cx = Graph::new();
+
+        let a = cx.tensor((4, 25)).set(vec![1.; 100]);
+        let b = a.dropout(0.5).retrieve();
+        let c = a.dropout(0.).retrieve();
+        cx.execute();
+
+        let b = b.data();
+        assert!(b.iter().all(|i| *i == 0. || *i == 2.));
+        assert!(b.contains(&0.) && b.contains(&2.));
+        assert_exact(&c.data(), &[1.; 100]);
+    }
+
+    #[test]
+    fn test_dropout_mask_reads_no_data() {
+        let mut cx = Graph::new();
+
+        let a = cx.tensor(('s', 4));
+        let b = a.dropout(0.5).retrieve();
+        // The mask is built from the shape alone, so the activation only feeds the multiply
+        assert_eq!(
+            cx.graph
+                .neighbors_directed(a.id, petgraph::Direction::Outgoing)
+                .count(),
+            1
+        );
+        for seq in [2, 30] {
+            a.set_dyn(vec![1.; seq * 4], (seq, 4));
+            cx.execute();
+
+            let out = b.data();
+            assert_eq!(out.len(), seq * 4);
+            assert!(out.iter().all(|i| *i == 0. || *i == 2.));
+            b.drop();
+        }
+    }
+
+    #[test]
+    fn test_drop_path() {
+        let mut cx = Graph::new();
+
+        let a = cx.tensor((40, 3, 2)).set(vec![1.; 240]);
+        let b = a.drop_path(0.5).retrieve();
+        let c = a.drop_path(0.).retrieve();
+        cx.execute();
+
+        // Each sample is either dropped or kept as a whole
+        let b = b.data();
+        for sample in b.chunks(6) {
+            assert!(sample == [0.; 6] || sample == [2.; 6]);
+        }
+        assert!(b.contains(&0.) && b.contains(&2.));
+        assert_exact(&c.data(), &[1.; 240]);
+    }
+
+    #[test]
+    fn test_drop_path_dynamic_batch() {
+        let mut cx = Graph::new();
+
+        let a = cx.tensor(('b', 4));
+        let b = a.drop_path(0.5).retrieve();
+        for batch in [3, 50] {
+            a.set_dyn(vec![1.; batch * 4], (batch, 4));
+            cx.execute();
+
+            // The mask is drawn for the batch size of each run
+            let out = b.data();
+            assert_eq!(out.len(), batch * 4);
+            for sample in out.chunks(4) {
+                assert!(sample == [0.; 4] || sample == [2.; 4]);
+            }
+            b.drop();
+        }
+    }
+
+    #[test]
+    fn test_cast_stochastic() {
+        let mut cx = Graph::new();
+        // A quarter of an f16 ulp above 1, and values between and beyond the i8 integers
+        let x = 1. + 2_f32.powi(-12);
+        let a = cx.tensor(10_000).set(vec![x; 10_000]);
+        let b = cx.tensor(4).set(vec![2.3, -1.5, 300., 1.]);
+        let c = a.cast_stochastic::<f16>().retrieve();
+        let d = b.expand(0, 2_500).cast_stochastic::<i8>().retrieve();
+        cx.set_seed(0);
+        cx.execute();
+
+        let c = c.data();
+        let up = 1. + 2_f32.powi(-10);
+        assert!(c.iter().all(|v| *v == 1. || *v == up));
+        // Unbiased: rounds up about a quarter of the time
+        let mean = c.iter().map(|v| *v as f64).sum::<f64>() / 10_000.;
+        assert!((mean - x as f64).abs() < 2e-5, "{mean} vs {x}");
+
+        let d = d.data();
+        for (i, (lo, hi)) in [(2., 3.), (-2., -1.), (127., 127.), (1., 1.)]
+            .into_iter()
+            .enumerate()
+        {
+            let column = d.iter().skip(i).step_by(4).copied().collect::<Vec<_>>();
+            assert!(column.iter().all(|v| *v == lo || *v == hi));
+            let mean = column.iter().sum::<f32>() / 2_500.;
+            let expected = [2.3, -1.5, 127., 1.][i];
+            assert!((mean - expected).abs() < 0.05, "{mean} vs {expected}");
+        }
+    }
+
+    #[test]
+    fn test_rand() {
+        let mut cx = Graph::new();
+        let a = cx.rand((10, 10)).retrieve();
+        cx.execute();
+
+        let data = a.data();
+        assert_eq!(data.len(), 100);
+        assert!(data.iter().all(|i| (0.0..1.0).contains(i)));
+        assert!(data.iter().any(|i| *i != data[0]));
+    }
+
+    #[test]
+    fn test_seed_reproducibility() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(50).set(vec![1.; 50]);
+        let b = a.dropout(0.5).retrieve();
+        let c = cx.rand(50).retrieve();
+
+        let run = |cx: &mut Graph| {
+            cx.execute();
+            let out = (b.data(), c.data());
+            b.drop();
+            c.drop();
+            out
+        };
+
+        cx.set_seed(42);
+        let first = run(&mut cx);
+        // Streams advance between runs
+        let second = run(&mut cx);
+        assert_ne!(first, second);
+        // Reseeding replays the sequence bit-for-bit
+        cx.set_seed(42);
+        assert_eq!(run(&mut cx), first);
+        assert_eq!(run(&mut cx), second);
+        // A different seed gives different numbers
+        cx.set_seed(7);
+        assert_ne!(run(&mut cx).1, first.1);
+    }
+
+    #[test]
+    fn test_merged_graph_follows_seed() {
+        let mut cx = Graph::new();
+        let a = cx.rand(20).retrieve();
+        let mut other = Graph::new();
+        other.set_seed(1);
+        let b = other.rand(20).retrieve();
+        let map = cx.merge(other);
+        let b = GraphTensor::from_id(map[&b.id], b.shape, &mut cx);
+
+        cx.set_seed(3);
+        cx.execute();
+        let first = (a.data(), b.data());
+        // The merged op gets its own stream rather than repeating ours
+        assert_ne!(first.0, first.1);
+        a.drop();
+        b.drop();
+        cx.set_seed(3);
+        cx.execute();
+        assert_eq!((a.data(), b.data()), first);
+    }
+}
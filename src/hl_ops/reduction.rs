@@ -3,18 +3,101 @@ use crate::{
     prelude::*,
 };
 
+/// An associative reduction for [`GraphTensor::reduce`]. This only picks which primitives get built, it never shows
+/// up as an op in the graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReduceOp {
+    Sum,
+    Max,
+    Min,
+    /// Approximate, see [`GraphTensor::prod_reduce`]
+    Prod,
+    /// `ln(sum(exp(x)))`, computed stably around the max
+    LogSumExp,
+}
+
+impl ReduceOp {
+    /// The identity of the reduction's combine, so `combine(identity, x) == x`
+    pub fn identity(&self) -> f32 {
+        match self {
+            Self::Sum => 0.,
+            Self::Prod => 1.,
+            Self::Max | Self::LogSumExp => f32::NEG_INFINITY,
+            Self::Min => f32::INFINITY,
+        }
+    }
+}
+
 impl GraphTensor {
+    /// Reduce the given axes with a [`ReduceOp`].
+    ///
+    /// A shorthand for building the reduction out of primitive ops: backends only see the sum and max reduces and
+    /// elementwise ops it expands to, not the `ReduceOp`, so there's nothing for them to match a dedicated kernel on.
+    pub fn reduce(self, axes: impl ToAxes, op: ReduceOp) -> GraphTensor {
+        match op {
+            ReduceOp::Sum => self.sum_reduce(axes),
+            ReduceOp::Max => self.max_reduce(axes),
+            ReduceOp::Min => -(-self).max_reduce(axes),
+            ReduceOp::Prod => self.prod_reduce(axes),
+            ReduceOp::LogSumExp => {
+                let axes = axes.to_axes();
+                let max = self.max_reduce(axes.clone());
+                (self - max.expand_to(self.shape))
+                    .exp()
+                    .sum_reduce(axes)
+                    .ln()
+                    + max
+            }
+        }
+    }
+
     /// Reduce a dimension of the tensor by summing all elements along that axis.
+    ///
+    /// Runs of adjacent axes on an unmodified shape are merged and summed with a single reduce op.
     pub fn sum_reduce(self, axes: impl ToAxes) -> GraphTensor {
         let (mut shape, mut id) = (self.shape, self.id);
-        // Sum reduce each dimension
-        for dim in axes.to_axes().into_iter().rev() {
-            id = self
-                .graph()
-                .add_op(op::SumReduce(dim))
-                .input(id, 0, shape)
-                .finish();
-            shape.remove_dim(dim);
+        let mut axes = axes.to_axes();
+        axes.sort_unstable();
+        axes.dedup();
+        // Group axes into runs of adjacent axes
+        let mut runs: Vec<(usize, usize)> = vec![];
+        for ax in axes {
+            match runs.last_mut() {
+                Some((_, end)) if *end + 1 == ax => *end = ax,
+                _ => runs.push((ax, ax)),
+            }
+        }
+        // Sum reduce each run, starting at the back so earlier axes aren't shifted
+        for (start, end) in runs.into_iter().rev() {
+            if start != end && !shape.is_reshaped() {
+                // Merge the run into a single dimension
+                let dims = shape.dims();
+                shape = ShapeTracker::new(
+                    dims[..start]
+                        .iter()
+                        .copied()
+                        .chain(std::iter::once(
+                            dims[start..=end].iter().copied().product::<Expression>(),
+                        ))
+                        .chain(dims[end + 1..].iter().copied())
+                        .collect::<Vec<_>>(),
+                );
+                id = self
+                    .graph()
+                    .add_op(op::SumReduce(start))
+                    .input(id, 0, shape)
+                    .finish();
+                shape.remove_dim(start);
+            } else {
+                for dim in (start..=end).rev() {
+                    id = self
+                        .graph()
+                        .add_op(op::SumReduce(dim))
+                        .input(id, 0, shape)
+                        .finish();
+                    shape.remove_dim(dim);
+                }
+            }
         }
         GraphTensor::from_id(id, shape, self.graph_ref)
     }
@@ -34,6 +117,30 @@ impl GraphTensor {
         GraphTensor::from_id(id, shape, self.graph_ref)
     }
 
+    /// Reduce an axis by taking the maximum, returning `(values, indices)`. Ties resolve to the lowest index.
+    ///
+    /// Backends fuse this into a single pass over the input.
+    pub fn max_with_index(self, axis: usize) -> (GraphTensor, GraphTensor) {
+        let values = self.max_reduce(axis);
+        let n = self.dims()[axis];
+        // Weight each position by n - i, so the lowest index matching the max has the largest weight
+        let mut weights = -self.graph().arange(n) + n;
+        for (i, d) in self.dims().into_iter().enumerate() {
+            if i != axis {
+                weights = weights.expand(i, d);
+            }
+        }
+        let one_hot = self.equals(values.expand(axis, n));
+        let indices = -(one_hot * weights).max_reduce(axis) + n;
+        (values, indices)
+    }
+
+    /// Reduce an axis by taking the minimum, returning `(values, indices)`. Ties resolve to the lowest index.
+    pub fn min_with_index(self, axis: usize) -> (GraphTensor, GraphTensor) {
+        let (values, indices) = (-self).max_with_index(axis);
+        (-values, indices)
+    }
+
     /// Reduce a dimension of the tensor by taking the mean of all elements along that axis.
     pub fn mean_reduce(self, axes: impl ToAxes) -> GraphTensor {
         let reduced_elements = axes
@@ -44,14 +151,47 @@ impl GraphTensor {
         (self / reduced_elements).sum_reduce(axes)
     }
 
+    /// Population variance and mean along the given axes, as `(var, mean)`.
+    ///
+    /// The variance is the mean of squared deviations from the mean, so it stays accurate for inputs far from zero.
+    /// Backends may fuse both statistics into a single pass over the input.
+    pub fn var_mean(self, axes: impl ToAxes) -> (GraphTensor, GraphTensor) {
+        let axes = axes.to_axes();
+        let mean = self.mean_reduce(axes.clone());
+        let centered = self - mean.expand_to(self.shape);
+        let var = (centered * centered).mean_reduce(axes);
+        (var, mean)
+    }
+
+    /// Population standard deviation and mean along the given axes, as `(std, mean)`. See [`GraphTensor::var_mean`].
+    pub fn std_mean(self, axes: impl ToAxes) -> (GraphTensor, GraphTensor) {
+        let (var, mean) = self.var_mean(axes);
+        (var.sqrt(), mean)
+    }
+
     /// Reduce a dimension of the tensor by multiplying all elements along that axis.
+    ///
+    /// There's no product primitive, so the magnitude is summed in log space and the sign and zeros are tracked
+    /// separately: an odd count of negatives flips the sign, and any zero zeroes the product.
+    ///
+    /// The `ln`/`exp` round trip makes the result approximate, with the error growing with the number of elements
+    /// reduced, so don't rely on it for exact integer products. It also costs three reduces and several elementwise ops.
     pub fn prod_reduce(self, axes: impl ToAxes) -> GraphTensor {
-        self.ln().sum_reduce(axes).exp()
+        let axes = axes.to_axes();
+        let zero = self.graph().constant(0.).expand_to(self.shape);
+        let is_zero = self.equals(zero);
+        // Zeros are swapped for ones so their log stays finite
+        let magnitude = (self.abs() + is_zero).ln().sum_reduce(axes.clone()).exp();
+        let sign = -(self.less_than(zero).sum_reduce(axes.clone()) % 2.) * 2. + 1.;
+        let nonzero = -is_zero.max_reduce(axes) + 1.;
+        magnitude * sign * nonzero
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::ReduceOp;
+    use crate::op;
     crate::test_imports!();
 
     #[test]
@@ -70,6 +210,74 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
     }
 
+    #[test]
+    fn test_sum_reduce_multi_axis() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(2 * 3 * 4 * 5);
+        let a = cx.tensor((2, 3, 4, 5)).set(a_data.clone());
+        let b = a.sum_reduce((1, 2)).retrieve();
+        let c = a.sum_reduce((0, 1, 3)).retrieve();
+        let d = a.permute((0, 2, 1, 3)).sum_reduce((1, 2)).retrieve();
+
+        // Adjacent axes on an unmodified shape are summed by a single op
+        assert_eq!(
+            cx.node_indices()
+                .filter(|n| cx.check_node_type::<op::SumReduce>(*n))
+                .count(),
+            1 + 2 + 2
+        );
+
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a =
+            d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<3>, DConst::<4>, DConst::<5>));
+        let d_b = d_a.clone().sum::<_, DAxes2<1, 2>>();
+        let d_c = d_a.clone().sum::<_, DAxes3<0, 1, 3>>();
+
+        assert_close(&b.data(), &d_b.as_vec());
+        assert_close(&c.data(), &d_c.as_vec());
+        assert_close(&d.data(), &d_b.as_vec());
+    }
+
+    #[test]
+    fn test_std_mean() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(2 * 3 * 4);
+        let a = cx.tensor((2, 3, 4)).set(a_data) + 3.;
+        let (std, mean) = a.std_mean(2);
+        let (var, _) = a.var_mean((0, 2));
+        let (std, mean, var) = (std.retrieve(), mean.retrieve(), var.retrieve());
+
+        // Separate two-pass statistics
+        let sep_mean = a.mean_reduce(2).retrieve();
+        let centered = a.mean_norm(2);
+        let sep_std = (centered * centered).mean_reduce(2).sqrt().retrieve();
+        let centered = a.mean_norm((0, 2));
+        let sep_var = (centered * centered).mean_reduce((0, 2)).retrieve();
+
+        cx.execute();
+
+        assert_close(&mean.data(), &sep_mean.data());
+        assert_close(&std.data(), &sep_std.data());
+        assert_close(&var.data(), &sep_var.data());
+    }
+
+    #[test]
+    fn test_var_mean_large_offset() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(4 * 16);
+        let a = cx.tensor((4, 16)).set(a_data);
+        // Shifting the input leaves the variance unchanged, which E[x^2] - E[x]^2 loses to cancellation
+        let (var, _) = a.var_mean(1);
+        let (shifted_var, _) = (a + 4096.).var_mean(1);
+        let (var, shifted_var) = (var.retrieve(), shifted_var.retrieve());
+
+        cx.execute();
+
+        assert_close(&shifted_var.data(), &var.data());
+    }
+
     #[test]
     fn test_max_reduce() {
         let mut cx = Graph::new();
@@ -86,6 +294,35 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
     }
 
+    #[test]
+    fn test_max_min_with_index() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor((2, 4))
+            .set(vec![1., 3., 3., -2., -5., -1., -5., -1.]);
+        let (max, argmax) = a.max_with_index(1);
+        let (min, argmin) = a.min_with_index(1);
+        let (max0, argmax0) = a.max_with_index(0);
+        let (max, argmax, min, argmin, max0, argmax0) = (
+            max.retrieve(),
+            argmax.retrieve(),
+            min.retrieve(),
+            argmin.retrieve(),
+            max0.retrieve(),
+            argmax0.retrieve(),
+        );
+
+        cx.execute();
+
+        // Ties go to the lowest index
+        assert_exact(&max.data(), &[3., -1.]);
+        assert_exact(&argmax.data(), &[1., 1.]);
+        assert_exact(&min.data(), &[-2., -5.]);
+        assert_exact(&argmin.data(), &[3., 0.]);
+        assert_exact(&max0.data(), &[1., 3., 3., -1.]);
+        assert_exact(&argmax0.data(), &[0., 0., 0., 1.]);
+    }
+
     #[test]
     fn test_mean_reduce() {
         let mut cx = Graph::new();
@@ -101,4 +338,51 @@ mod tests {
 
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    #[test]
+    fn test_reduce_ops() {
+        let mut cx = Graph::new();
+        let a_data = vec![0.5, 2., 1.5, 3., 0.25, 1., 4., 2.5];
+        let a = cx.tensor((2, 4)).set(a_data.clone());
+        let ops = [
+            ReduceOp::Sum,
+            ReduceOp::Max,
+            ReduceOp::Min,
+            ReduceOp::Prod,
+            ReduceOp::LogSumExp,
+        ];
+        let rows = ops.map(|op| a.reduce(1, op).retrieve());
+        let all = ops.map(|op| a.reduce((0, 1), op).retrieve());
+
+        cx.execute();
+
+        let fold = |op: ReduceOp, x: &[f32]| {
+            x.iter().fold(op.identity(), |acc, v| match op {
+                ReduceOp::Sum => acc + v,
+                ReduceOp::Max => acc.max(*v),
+                ReduceOp::Min => acc.min(*v),
+                ReduceOp::Prod => acc * v,
+                ReduceOp::LogSumExp => (acc.exp() + v.exp()).ln(),
+            })
+        };
+        for ((op, row), all) in ops.into_iter().zip(rows).zip(all) {
+            let expected = a_data.chunks(4).map(|r| fold(op, r)).collect::<Vec<_>>();
+            assert_close(&row.data(), &expected);
+            assert_close(&all.data(), &[fold(op, &a_data)]);
+        }
+    }
+
+    #[test]
+    fn test_prod_reduce_signs() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((4, 4)).set(vec![
+            -2., 3., 0.5, -1., -2., 1., 1., 1.5, 0., -4., 2., 1., -0.5, -2., -3., -1.,
+        ]);
+        let rows = a.prod_reduce(1).retrieve();
+        let cols = a.prod_reduce(0).retrieve();
+        cx.execute();
+
+        assert_close(&rows.data(), &[3., -3., 0., 3.]);
+        assert_close(&cols.data(), &[0., 24., -3., 1.5]);
+    }
 }
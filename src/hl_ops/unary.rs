@@ -60,6 +60,21 @@ impl GraphTensor {
         GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
     }
 
+    /// Replace NaN with `nan`, and positive / negative infinity with `posinf` / `neginf`, like torch's `nan_to_num`.
+    /// Infinities without a replacement become the largest / smallest finite value of the dtype the graph runs in.
+    pub fn nan_to_num(self, nan: f32, posinf: Option<f32>, neginf: Option<f32>) -> GraphTensor {
+        let new_id = self
+            .graph()
+            .add_op(op::NanToNum {
+                nan,
+                posinf,
+                neginf,
+            })
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+    }
+
     /// The cos(x) function
     pub fn cos(self) -> GraphTensor {
         ((std::f32::consts::PI / 2.) - self).sin()
@@ -99,12 +114,45 @@ impl GraphTensor {
         self - self.mean_reduce(axes).expand_to(self.shape)
     }
 
-    /// Applies a layer norm along an axis
+    /// Applies a layer norm along an axis, taking the statistics from `var_mean`
     pub fn layer_norm<T>(self, axes: impl ToAxes, epsilon: T) -> GraphTensor
     where
         GraphTensor: Add<T, Output = GraphTensor>,
     {
-        self.mean_norm(axes.to_axes()).std_norm(axes, epsilon)
+        let (var, mean) = self.var_mean(axes);
+        // The same subtraction `var_mean` centers with, so the two are merged by CSE
+        let centered = self - mean.expand_to(self.shape);
+        (var + epsilon)
+            .sqrt()
+            .recip()
+            .expand_to(self.shape)
+            .mul(centered)
+    }
+
+    /// Root-mean-square norm over the last axis, scaled by a `weight` of the last axis' size.
+    /// This is the same graph `RMSNorm` builds, so backends fuse it into the same kernel.
+    pub fn rms_norm(self, weight: GraphTensor, epsilon: f32) -> GraphTensor {
+        self.std_norm(self.shape.last_axis(), epsilon) * weight.expand_to(self.shape)
+    }
+
+    /// Root-mean-square norm computed separately over `groups` equal contiguous blocks of the last axis, then scaled by
+    /// a `weight` of the full last axis' size. With one group this is `rms_norm`.
+    pub fn rms_norm_grouped(self, weight: GraphTensor, groups: usize, epsilon: f32) -> GraphTensor {
+        let dims = self.dims();
+        let dim = *dims.last().unwrap();
+        if let Some(dim) = dim.to_usize() {
+            assert!(
+                dim % groups == 0,
+                "Last axis of size {dim} can't be split into {groups} groups"
+            );
+        }
+        let mut grouped = dims[..dims.len() - 1].to_vec();
+        grouped.extend([Expression::from(groups), dim / groups]);
+        let group_axis = grouped.len() - 1;
+        self.reshape(grouped)
+            .std_norm(group_axis, epsilon)
+            .reshape(dims)
+            * weight.expand_to(self.shape)
     }
 
     /// Applies a softmax function along an axis
@@ -114,6 +162,52 @@ impl GraphTensor {
         exp / exp.sum_reduce(axes).expand_to(exp.shape)
     }
 
+    /// Applies a softmax function along a single axis chosen at runtime
+    pub fn softmax_dim(self, axis: usize) -> GraphTensor {
+        assert!(
+            axis < self.shape.len(),
+            "Softmax axis {axis} out of range for tensor of rank {}",
+            self.shape.len()
+        );
+        self.softmax(axis)
+    }
+
+    /// Applies a softmax function along an axis after adding an additive mask (0 to keep, large negative to mask out).
+    /// The mask must already be expanded to this tensor's shape. Backends with a fused masked softmax kernel never
+    /// materialize the masked scores, and return zeros for rows where every position is masked to -inf.
+    pub fn masked_softmax(self, mask: GraphTensor, axis: usize) -> GraphTensor {
+        assert!(
+            axis < self.shape.len(),
+            "Softmax axis {axis} out of range for tensor of rank {}",
+            self.shape.len()
+        );
+        assert_eq!(
+            self.shape.len(),
+            mask.shape.len(),
+            "Mask rank doesn't match the scores rank"
+        );
+        (self + mask).softmax(axis)
+    }
+
+    /// Mixture-of-experts gating over router logits on the last axis: keeps the `k` largest logits of each row
+    /// (ties go to the lower index), softmaxes over just those, and zeros the rest. The output has the logits' shape.
+    pub fn top_k_gate(self, k: usize) -> GraphTensor {
+        let last = self.shape.last_axis();
+        let n = self.dims()[last];
+        // Rank each logit by how many logits beat it: (.., j, i) compares logit i against logit j
+        let (others, this) = (self.expand(last, n), self.expand(last + 1, n));
+        let mut lower = self.graph().tril(n, -1);
+        for (axis, size) in self.dims()[..last].iter().enumerate() {
+            lower = lower.expand(axis, *size);
+        }
+        let ties_before = others.equals(this) * lower;
+        let rank = (others.greater_than(this) + ties_before).sum_reduce(last + 1);
+        let selected = rank.less_than(self.graph().constant(k as f32).expand_to(rank.shape));
+        // The max logit is always selected, so subtracting it keeps the selected exps in range
+        let exp = (self - self.max_reduce(last).expand_to(self.shape)).exp() * selected;
+        exp / exp.sum_reduce(last).expand_to(exp.shape)
+    }
+
     /// Applies a log softmax function along an axis
     pub fn log_softmax(self, axes: impl ToAxes) -> GraphTensor {
         let m = self - self.max_reduce(axes.to_axes()).expand_to(self.shape);
@@ -135,6 +229,12 @@ impl GraphTensor {
         (x_equal * r.expand_to(self.shape)).max_reduce(self.shape.len() - 1)
     }
 
+    /// Get the indicies of the max elements along the last axis as an i32 tensor. The cast runs on the host, see
+    /// [`GraphTensor::int_to_float`].
+    pub fn argmax_i32(self) -> GraphTensor {
+        self.argmax().float_to_int()
+    }
+
     /// Take the absolute value
     pub fn abs(self) -> GraphTensor {
         self.relu() + (-self).relu()
@@ -166,6 +266,11 @@ impl GraphTensor {
         (self * 2.0).sigmoid() * 2.0 - 1.0
     }
 
+    /// Smoothly squash values into (-cap, cap) with `tanh(x / cap) * cap`, as used on Gemma-2's attention scores and logits
+    pub fn softcap(self, cap: f32) -> GraphTensor {
+        (self / cap).tanh() * cap
+    }
+
     /// The leaky relu activation function
     pub fn leaky_relu(self, neg_slope: f32) -> GraphTensor {
         self.relu() - (self * -neg_slope).relu()
@@ -233,6 +338,50 @@ mod tests {
         assert_close(&r, &d_b.as_vec());
     }
 
+    #[test]
+    fn test_softmax_dim() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(24);
+        let a = cx.tensor((2, 3, 4)).set(a_data.clone());
+        let b = a.softmax_dim(0).retrieve();
+        let c = a.softmax_dim(1).retrieve();
+        let d = a.softmax_dim(2).retrieve();
+
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<3>, DConst::<4>));
+        assert_close(&b.data(), &d_a.clone().softmax::<DAxis<0>>().as_vec());
+        assert_close(&c.data(), &d_a.clone().softmax::<DAxis<1>>().as_vec());
+        assert_close(&d.data(), &d_a.softmax::<DAxis<2>>().as_vec());
+    }
+
+    #[test]
+    #[should_panic(expected = "Softmax axis 2 out of range for tensor of rank 2")]
+    fn test_softmax_dim_out_of_range() {
+        let mut cx = Graph::new();
+        cx.tensor((2, 3)).softmax_dim(2);
+    }
+
+    #[test]
+    fn test_masked_softmax() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(6);
+        let mask_data = vec![0., -1e9, -1e9, 0., 0., -1e9];
+        let a = cx.tensor((2, 3)).set(a_data.clone());
+        let mask = cx.tensor((2, 3)).set(mask_data.clone());
+        let b = a.masked_softmax(mask, 1).retrieve();
+
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<3>));
+        let d_mask = d_dev.tensor_from_vec(mask_data, (DConst::<2>, DConst::<3>));
+        let d_b = (d_a + d_mask).softmax::<DAxis<1>>();
+
+        assert_close(&b.data(), &d_b.as_vec());
+    }
+
     #[test]
     fn test_sin() {
         let mut cx = Graph::new();
@@ -265,6 +414,26 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
     }
 
+    #[test]
+    fn test_nan_to_num() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor(5)
+            .set(vec![1., f32::NAN, f32::INFINITY, f32::NEG_INFINITY, -2.]);
+        let b = a.nan_to_num(0., None, None).retrieve();
+        let c = a.nan_to_num(-1., Some(100.), Some(-100.)).retrieve();
+        // 0 * -inf from a fully masked score comes out as NaN
+        let mask = cx.tensor(2).set(vec![0., f32::NEG_INFINITY]);
+        let d = (cx.tensor(2).set(vec![0., 0.]) * mask)
+            .nan_to_num(0., None, None)
+            .retrieve();
+        cx.execute();
+
+        assert_exact(&b.data(), &[1., 0., f32::MAX, f32::MIN, -2.]);
+        assert_exact(&c.data(), &[1., -1., 100., -100., -2.]);
+        assert_exact(&d.data(), &[0., 0.]);
+    }
+
     #[test]
     fn test_relu() {
         let mut cx = Graph::new();
@@ -338,4 +507,102 @@ mod tests {
         let d_b = d_a.tanh();
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    #[test]
+    fn test_rms_norm() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(6);
+        let w_data = random_vec(3);
+        let a = cx.tensor((2, 3)).set(a_data.clone());
+        let w = cx.tensor(3).set(w_data.clone());
+        let b = a.rms_norm(w, 1e-5).retrieve();
+        cx.execute();
+
+        let expected = a_data
+            .chunks(3)
+            .flat_map(|row| {
+                let scale = (row.iter().map(|x| x * x).sum::<f32>() / 3. + 1e-5)
+                    .sqrt()
+                    .recip();
+                row.iter()
+                    .zip(&w_data)
+                    .map(move |(x, w)| x * scale * w)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_close(&b.data(), &expected);
+    }
+
+    #[test]
+    fn test_rms_norm_grouped() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(16);
+        let w_data = random_vec(8);
+        let a = cx.tensor((2, 8)).set(a_data.clone());
+        let w = cx.tensor(8).set(w_data.clone());
+        let b = a.rms_norm_grouped(w, 4, 1e-5).retrieve();
+        cx.execute();
+
+        let expected = a_data
+            .chunks(8)
+            .flat_map(|row| {
+                row.chunks(2)
+                    .flat_map(|group| {
+                        let scale = (group.iter().map(|x| x * x).sum::<f32>() / 2. + 1e-5)
+                            .sqrt()
+                            .recip();
+                        group.iter().map(move |x| x * scale)
+                    })
+                    .zip(&w_data)
+                    .map(|(x, w)| x * w)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_close(&b.data(), &expected);
+    }
+
+    #[test]
+    fn test_top_k_gate() {
+        let mut cx = Graph::new();
+        // The tie in the second row goes to the lower index
+        let logits = cx
+            .tensor((2, 4))
+            .set(vec![0.5, 2.0, -1.0, 1.5, 1.0, 3.0, 1.0, 0.0]);
+        let gates = logits.top_k_gate(2).retrieve();
+        cx.execute();
+
+        let pair = |a: f32, b: f32| {
+            let (ea, eb) = (a.exp(), b.exp());
+            (ea / (ea + eb), eb / (ea + eb))
+        };
+        let (g1, g3) = pair(2.0, 1.5);
+        let (g4, g5) = pair(1.0, 3.0);
+        assert_close(&gates.data(), &[0., g1, 0., g3, g4, g5, 0., 0.]);
+    }
+
+    #[test]
+    fn test_softcap() {
+        let mut cx = Graph::new();
+        let a_data = vec![-100., -3., 0., 1., 3., 100.];
+        let a = cx.tensor((2, 3)).set(a_data.clone());
+        let b = a.softcap(5.).retrieve();
+        cx.execute();
+
+        let expected = a_data
+            .iter()
+            .map(|x| (x / 5.).tanh() * 5.)
+            .collect::<Vec<_>>();
+        assert_close(&b.data(), &expected);
+        assert!(b.data().iter().all(|x| x.abs() <= 5.));
+    }
+
+    #[test]
+    fn test_argmax_i32() {
+        let mut cx = Graph::new();
+        let a = cx.tensor((2, 3)).set(vec![1., 5., 2., 7., -1., 3.]);
+        let b = a.argmax_i32().retrieve();
+        cx.execute();
+
+        assert_eq!(b.data_i32(), vec![1, 0]);
+    }
 }
@@ -1,10 +1,15 @@
+// Lets the derive macros' `::luminal` paths resolve inside this crate too
+extern crate self as luminal;
+
 pub mod compiler_utils;
 pub mod generic_compiler;
 pub mod graph;
 pub mod graph_tensor;
 pub mod hl_ops;
 pub mod module;
+pub mod npy;
 pub mod op;
+pub mod serialize;
 pub mod shape;
 
 pub mod tests;
@@ -17,8 +22,10 @@ pub mod prelude {
     pub use crate::hl_ops::*;
     pub use crate::module::*;
     pub use crate::op::*;
+    pub use crate::serialize::*;
     pub use crate::shape::*;
     pub use half::{bf16, f16};
+    pub use luminal_derive::SerializeModule;
     pub use petgraph;
     pub use petgraph::stable_graph::NodeIndex;
     pub use tinyvec;
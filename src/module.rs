@@ -34,7 +34,7 @@ pub fn transfer_data(
     dests: impl ToIds,
     dest_graph: &mut Graph,
 ) {
-    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids().into_iter()) {
+    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids()) {
         let mut output_num = 0;
         while let Some(tensor) = src_graph.tensors.remove(&(src, output_num)) {
             dest_graph.tensors.insert((dest, output_num), tensor);
@@ -48,7 +48,7 @@ pub fn transfer_data(
 
 /// Transfer data from one set of nodes to another set in the same graph
 pub fn transfer_data_same_graph(srcs: impl ToIds, dests: impl ToIds, graph: &mut Graph) {
-    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids().into_iter()) {
+    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids()) {
         let mut output_num = 0;
         while let Some(tensor) = graph.tensors.remove(&(src, output_num)) {
             graph.tensors.insert((dest, output_num), tensor);
@@ -211,9 +211,97 @@ tuple_impls!([M1, M2, M3, M4, M5, M6, M7, M8] [0, 1, 2, 3, 4, 5, 6, 7], M8, [M7,
 tuple_impls!([M1, M2, M3, M4, M5, M6, M7, M8, M9] [0, 1, 2, 3, 4, 5, 6, 7, 8], M9, [M8, M7, M6, M5, M4, M3, M2, M1]);
 tuple_impls!([M1, M2, M3, M4, M5, M6, M7, M8, M9, M10] [0, 1, 2, 3, 4, 5, 6, 7, 8, 9], M10, [M9, M8, M7, M6, M5, M4, M3, M2, M1]);
 
+/// Runs the wrapped module on the first element of an `(input, state)` pair, passing the state through untouched
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MapFirst<M>(pub M);
+
+impl<X, S, M: Module<X>> Module<(X, S)> for MapFirst<M> {
+    type Output = (M::Output, S);
+    fn forward(&self, (x, state): (X, S)) -> Self::Output {
+        (self.0.forward(x), state)
+    }
+}
+
+impl<M: SerializeModule> SerializeModule for MapFirst<M> {
+    fn serialize(&self, s: &mut Serializer) {
+        self.0.serialize(s)
+    }
+}
+
+/// A residual connection around the wrapped module: `x + inner(x)`.
+///
+/// Compose it with a norm for either layout: `Residual((norm, inner))` is pre-norm, `(Residual(inner), norm)` is post-norm.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Residual<M>(pub M);
+
+impl<X: Clone + std::ops::Add<Output = X>, M: Module<X, Output = X>> Module<X> for Residual<M> {
+    type Output = X;
+    fn forward(&self, x: X) -> Self::Output {
+        x.clone() + self.0.forward(x)
+    }
+}
+
+impl<M: SerializeModule> SerializeModule for Residual<M> {
+    fn serialize(&self, s: &mut Serializer) {
+        self.0.serialize(s)
+    }
+}
+
+/// Run a stack of stateful layers, handing each layer its own state and collecting the new states they return.
+/// Panics unless there's exactly one state per layer.
+pub fn forward_with_states<'a, X, S, T, M: Module<(X, S), Output = (X, T)> + 'a>(
+    layers: impl IntoIterator<Item = &'a M>,
+    mut x: X,
+    states: impl IntoIterator<Item = S>,
+) -> (X, Vec<T>) {
+    let (layers, states) = (
+        layers.into_iter().collect_vec(),
+        states.into_iter().collect_vec(),
+    );
+    assert_eq!(
+        layers.len(),
+        states.len(),
+        "Got {} states for {} layers",
+        states.len(),
+        layers.len()
+    );
+    let mut new_states = vec![];
+    for (layer, state) in layers.into_iter().zip(states) {
+        let new_state;
+        (x, new_state) = layer.forward((x, state));
+        new_states.push(new_state);
+    }
+    (x, new_states)
+}
+
+/// Run a stack of layers, tapping each layer's output (e.g. the residual stream after every transformer block) for readback.
+/// The taps are retrieved, so fusion can't cross layer boundaries. See [`Graph::tap`].
+pub fn forward_with_taps<'a, M: Module<GraphTensor, Output = GraphTensor> + 'a>(
+    layers: impl IntoIterator<Item = &'a M>,
+    mut x: GraphTensor,
+) -> (GraphTensor, Vec<GraphTensor>) {
+    let mut taps = vec![];
+    for layer in layers {
+        x = layer.forward(x);
+        taps.push(x.retrieve());
+    }
+    (x, taps)
+}
+
 /// Tell luminal how to represent the module as a dict of (String, NodeIndex)'s
 pub trait SerializeModule {
     fn serialize(&self, s: &mut Serializer);
+
+    /// All weight tensors of the module, in the same order as `params`
+    fn parameters(&self) -> Vec<GraphTensor> {
+        let mut s = Serializer::default();
+        self.serialize(&mut s);
+        s.tensors
+            .into_iter()
+            .sorted_by_key(|(k, _)| k.clone())
+            .map(|(_, v)| v)
+            .collect()
+    }
 }
 
 impl<T: SerializeModule> SerializeModule for &T {
@@ -222,21 +310,55 @@ impl<T: SerializeModule> SerializeModule for &T {
     }
 }
 
-/// Serializer keeps track of the tensors and modules that make up a model
+/// A tensor is stored at the current path, so it can be a field of a derived module
+impl SerializeModule for GraphTensor {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("", *self)
+    }
+}
+
+impl<T: SerializeModule> SerializeModule for Option<T> {
+    fn serialize(&self, s: &mut Serializer) {
+        if let Some(m) = self {
+            m.serialize(s);
+        }
+    }
+}
+
+/// Each element is stored under its index, like `layers/0`
+impl<T: SerializeModule> SerializeModule for Vec<T> {
+    fn serialize(&self, s: &mut Serializer) {
+        for (i, m) in self.iter().enumerate() {
+            s.module(&i.to_string(), m);
+        }
+    }
+}
+
+/// Serializer keeps track of the tensors and modules that make up a model.
+///
+/// A tensor shared between modules (like tied input and output embeddings) is only recorded under the first
+/// name it's serialized with, so it's loaded once from a checkpoint that stores it once.
 #[derive(Debug, Default)]
 pub struct Serializer {
     current_path: Vec<String>,
+    seen: FxHashSet<NodeIndex>,
     pub state: FxHashMap<String, NodeIndex>,
+    pub tensors: FxHashMap<String, GraphTensor>,
 }
 
 impl Serializer {
     pub fn tensor(&mut self, name: &str, tensor: GraphTensor) {
+        if !self.seen.insert(tensor.id) {
+            return;
+        }
         if !name.is_empty() {
             // Add new path component
             self.current_path.push(name.to_string());
         }
         // Insert tensor id
-        self.state.insert(self.current_path.join("/"), tensor.id);
+        let path = self.current_path.join("/");
+        self.state.insert(path.clone(), tensor.id);
+        self.tensors.insert(path, tensor);
         if !name.is_empty() {
             // Remove new path component
             self.current_path.pop();
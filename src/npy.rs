@@ -0,0 +1,310 @@
+//! Minimal reading and writing of numpy `.npy` and `.npz` files, for comparing tensors against reference implementations.
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use half::f16;
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+/// Write f32 data with the given shape as a little-endian, C-ordered `.npy` file
+pub fn write_npy(path: impl AsRef<Path>, shape: &[usize], data: &[f32]) -> Result<()> {
+    fs::write(path, encode_npy(shape, data))
+}
+
+/// Read a `.npy` file of little-endian f32 or f16 data, returning the shape and the C-ordered data as f32
+pub fn read_npy(path: impl AsRef<Path>) -> Result<(Vec<usize>, Vec<f32>)> {
+    decode_npy(&fs::read(path)?)
+}
+
+/// Encode f32 data with the given shape in the `.npy` format
+pub fn encode_npy(shape: &[usize], data: &[f32]) -> Vec<u8> {
+    let shape_str = match shape {
+        [d] => format!("({d},)"),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': {shape_str}, }}");
+    // Pad so the data starts on a 64 byte boundary, with the header ending in a newline
+    let unpadded = MAGIC.len() + 4 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+
+    let mut bytes = Vec::with_capacity(MAGIC.len() + 4 + header.len() + data.len() * 4);
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&[1, 0]);
+    bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    for d in data {
+        bytes.extend_from_slice(&d.to_le_bytes());
+    }
+    bytes
+}
+
+/// Decode a `.npy` buffer of little-endian f32 or f16 data
+pub fn decode_npy(bytes: &[u8]) -> Result<(Vec<usize>, Vec<f32>)> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+    if bytes.len() < 10 || &bytes[..6] != MAGIC {
+        return Err(invalid("Not a .npy file"));
+    }
+    let (header_len, header_start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 if bytes.len() >= 12 => (
+            u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+            12,
+        ),
+        v => return Err(invalid(&format!("Unsupported .npy version {v}"))),
+    };
+    let header = std::str::from_utf8(
+        bytes
+            .get(header_start..header_start + header_len)
+            .ok_or_else(|| invalid("Truncated .npy header"))?,
+    )
+    .map_err(|_| invalid("Invalid .npy header"))?;
+
+    let descr = header_value(header, "descr").ok_or_else(|| invalid("Missing dtype"))?;
+    let descr = descr.split([',', '}']).next().unwrap().trim();
+    let descr = descr.trim_matches(|c| c == '\'' || c == '"');
+    let elem_size = match descr {
+        "<f4" => 4,
+        "<f2" => 2,
+        _ => {
+            return Err(invalid(&format!(
+                "Unsupported dtype {descr}, expected <f4 or <f2"
+            )))
+        }
+    };
+    if header_value(header, "fortran_order").is_some_and(|v| v.starts_with("True")) {
+        return Err(invalid("Fortran ordered arrays are unsupported"));
+    }
+    let shape = header_value(header, "shape").ok_or_else(|| invalid("Missing shape"))?;
+    let shape = shape
+        .trim_start_matches('(')
+        .split(')')
+        .next()
+        .unwrap()
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse::<usize>().map_err(|_| invalid("Invalid shape")))
+        .collect::<Result<Vec<_>>>()?;
+
+    let data = &bytes[header_start + header_len..];
+    let n_elements = shape.iter().product::<usize>();
+    if data.len() != n_elements * elem_size {
+        return Err(invalid(&format!(
+            "Expected {n_elements} elements from shape {shape:?}, found {} bytes",
+            data.len()
+        )));
+    }
+    let data = if elem_size == 4 {
+        data.chunks(4)
+            .map(|i| f32::from_le_bytes([i[0], i[1], i[2], i[3]]))
+            .collect()
+    } else {
+        data.chunks(2)
+            .map(|i| f16::from_le_bytes([i[0], i[1]]).to_f32())
+            .collect()
+    };
+    Ok((shape, data))
+}
+
+/// Write a set of named f32 arrays as an uncompressed `.npz` archive
+pub fn write_npz(path: impl AsRef<Path>, arrays: &[(&str, &[usize], &[f32])]) -> Result<()> {
+    let mut out = vec![];
+    let mut central = vec![];
+    for (name, shape, data) in arrays {
+        let file_name = format!("{name}.npy");
+        let contents = encode_npy(shape, data);
+        let crc = crc32(&contents);
+        let offset = out.len() as u32;
+        // Local file header, stored (no compression)
+        out.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        out.extend_from_slice(&[20, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        out.extend_from_slice(&crc.to_le_bytes());
+        out.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(file_name.len() as u16).to_le_bytes());
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(file_name.as_bytes());
+        out.extend_from_slice(&contents);
+        // Central directory entry
+        central.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        central.extend_from_slice(&[20, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        central.extend_from_slice(&crc.to_le_bytes());
+        central.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        central.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        central.extend_from_slice(&(file_name.len() as u16).to_le_bytes());
+        central.extend_from_slice(&[0; 12]);
+        central.extend_from_slice(&offset.to_le_bytes());
+        central.extend_from_slice(file_name.as_bytes());
+    }
+    let central_offset = out.len() as u32;
+    out.extend_from_slice(&central);
+    // End of central directory
+    out.extend_from_slice(&0x06054b50u32.to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&(arrays.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(arrays.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+    out.extend_from_slice(&central_offset.to_le_bytes());
+    out.extend_from_slice(&[0, 0]);
+    fs::write(path, out)
+}
+
+/// A named array from an `.npz` archive: (name, shape, data)
+pub type NpzArray = (String, Vec<usize>, Vec<f32>);
+
+/// Read every array in an `.npz` archive, as written by `numpy.savez`. Compressed archives are unsupported.
+pub fn read_npz(path: impl AsRef<Path>) -> Result<Vec<NpzArray>> {
+    let bytes = fs::read(path)?;
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+    let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]) as usize;
+    let u32_at =
+        |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+    let mut arrays = vec![];
+    let mut i = 0;
+    while i + 30 <= bytes.len() && u32_at(i) == 0x04034b50 {
+        if u16_at(i + 6) & 0x8 != 0 {
+            return Err(invalid("Streamed .npz entries are unsupported"));
+        }
+        if u16_at(i + 8) != 0 {
+            return Err(invalid(
+                "Compressed .npz archives are unsupported, save with numpy.savez",
+            ));
+        }
+        let mut size = u32_at(i + 18) as usize;
+        let (name_len, extra_len) = (u16_at(i + 26), u16_at(i + 28));
+        let name_start = i + 30;
+        let data_start = name_start + name_len + extra_len;
+        if size == u32::MAX as usize {
+            // numpy forces zip64, which moves the sizes into an extra field
+            let mut e = name_start + name_len;
+            while e + 4 <= data_start {
+                if u16_at(e) == 0x0001 && e + 20 <= data_start {
+                    size = u32_at(e + 12) as usize | (u32_at(e + 16) as usize) << 32;
+                    break;
+                }
+                e += 4 + u16_at(e + 2);
+            }
+        }
+        let name = std::str::from_utf8(
+            bytes
+                .get(name_start..name_start + name_len)
+                .ok_or_else(|| invalid("Truncated .npz entry"))?,
+        )
+        .map_err(|_| invalid("Invalid .npz entry name"))?;
+        let contents = bytes
+            .get(data_start..data_start + size)
+            .ok_or_else(|| invalid("Truncated .npz entry"))?;
+        if crc32(contents) != u32_at(i + 14) {
+            return Err(invalid(&format!("Checksum mismatch for {name}")));
+        }
+        let (shape, data) = decode_npy(contents)?;
+        arrays.push((name.trim_end_matches(".npy").to_string(), shape, data));
+        i = data_start + size;
+    }
+    if arrays.is_empty() {
+        return Err(invalid("No arrays found in .npz archive"));
+    }
+    Ok(arrays)
+}
+
+/// The CRC-32 checksum used by zip archives
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Get the raw text of a value in the header dict
+fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header
+        .find(&format!("'{key}'"))
+        .or_else(|| header.find(&format!("\"{key}\"")))?;
+    let rest = &header[start + key.len() + 2..];
+    Some(rest[rest.find(':')? + 1..].trim_start())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, decode_npy, read_npy, read_npz, write_npy, write_npz};
+
+    #[test]
+    fn test_npy_roundtrip() {
+        let path = std::env::temp_dir().join("luminal_test_roundtrip.npy");
+        for shape in [vec![5], vec![2, 3, 1]] {
+            let data = (0..shape.iter().product::<usize>())
+                .map(|i| i as f32 * 0.5 - 1.)
+                .collect::<Vec<_>>();
+            write_npy(&path, &shape, &data).unwrap();
+            let bytes = std::fs::read(&path).unwrap();
+            assert_eq!((bytes.len() - data.len() * 4) % 64, 0);
+            assert_eq!(read_npy(&path).unwrap(), (shape, data));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_npy_unsupported_dtype() {
+        let path = std::env::temp_dir().join("luminal_test_complex.npy");
+        let header = "{'descr': '<c8', 'fortran_order': False, 'shape': (1,), }\n";
+        let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+        bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(header.as_bytes());
+        bytes.extend_from_slice(&[0; 8]);
+        std::fs::write(&path, bytes).unwrap();
+        let err = read_npy(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.to_string().contains("Unsupported dtype <c8"));
+    }
+
+    #[test]
+    fn test_npy_f16() {
+        let header = "{'descr': '<f2', 'fortran_order': False, 'shape': (3,), }\n";
+        let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+        bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(header.as_bytes());
+        for v in [1.5f32, -2., 0.25] {
+            bytes.extend_from_slice(&half::f16::from_f32(v).to_le_bytes());
+        }
+        assert_eq!(decode_npy(&bytes).unwrap(), (vec![3], vec![1.5, -2., 0.25]));
+    }
+
+    #[test]
+    fn test_npz_roundtrip() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        let path = std::env::temp_dir().join("luminal_test_roundtrip.npz");
+        write_npz(
+            &path,
+            &[("a", &[2, 2], &[1., 2., 3., 4.]), ("b", &[1], &[5.])],
+        )
+        .unwrap();
+        let arrays = read_npz(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            arrays,
+            vec![
+                ("a".to_string(), vec![2, 2], vec![1., 2., 3., 4.]),
+                ("b".to_string(), vec![1], vec![5.]),
+            ]
+        );
+    }
+}
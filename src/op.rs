@@ -31,12 +31,42 @@ impl Tensor {
     pub fn is<T: Data>(&self) -> bool {
         self.data.as_any().is::<T>()
     }
+    /// Whether the tensor's data has finished being computed
+    pub fn is_ready(&self) -> bool {
+        self.data.is_ready()
+    }
+    /// Block until the tensor's data is computed, returning the host copy if it wasn't already available
+    pub fn wait_for_data(&self) -> Option<Self> {
+        self.data.wait_for_data().map(|data| Self { data })
+    }
+    /// Clone the tensor into its own storage, so it doesn't change when the original buffer gets reused
+    pub fn deep_clone(&self) -> Self {
+        match self.data.copy_data() {
+            Some(data) => Self { data },
+            None => self.clone(),
+        }
+    }
 }
 
 /// Some sort of data, for instance a Vec<f32> on CPU, CudaSlice<f32> on Nvidia GPUs, or metal::Buffer for Apple GPUs
 pub trait Data: Any + Debug + DynClone {
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
+    /// Copy the underlying data into new storage. Only needed when `Clone` shares storage, like a GPU buffer handle,
+    /// so the default of `None` means a clone is already a full copy.
+    fn copy_data(&self) -> Option<Box<dyn Data>> {
+        None
+    }
+    /// Whether the data has finished being computed. Only backends that hand back data before their device finishes
+    /// need to override this.
+    fn is_ready(&self) -> bool {
+        true
+    }
+    /// Block until the data is computed and return it in host memory. The default of `None` means the data is already
+    /// available as-is.
+    fn wait_for_data(&self) -> Option<Box<dyn Data>> {
+        None
+    }
 }
 
 clone_trait_object!(Data);
@@ -50,6 +80,16 @@ impl Data for Vec<f32> {
     }
 }
 
+/// Integer tensors are host-only: device backends convert them on upload and never bind integer buffers
+impl Data for Vec<i32> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
 /// Either an owned or borrowed tensor that gets consumed by ops
 pub enum InputTensor<'a> {
     /// An owned tensor
@@ -132,7 +172,7 @@ impl Debug for Function {
 }
 
 /// A constant value placed on the graph at runtime. Can either be an expression evaluated at runtime, or a constant float
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum ConstantValue {
     Expression(Expression),
     Float(f32),
@@ -182,10 +222,11 @@ impl Operator for Constant {
 // Unary Op (A -> A)
 
 /// Ensure a tensor is contiguously layed out in memory. May involve copying
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Contiguous;
 impl Operator for Contiguous {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 1);
         // Copy data over to new tensor
         let inp_data = get_vec(&inp[0].0);
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
@@ -198,10 +239,22 @@ impl Operator for Contiguous {
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+/// Pass a tensor through untouched, marking a point no compiler fuses across. Backends splice it out with
+/// `RemoveBarriers` once their fusion passes have run.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Barrier;
+impl Operator for Barrier {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 1);
+        vec![inp.pop().unwrap().0.cloned()]
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Log2;
 impl Operator for Log2 {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 1);
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
         let inp_data = get_vec(&inp[0].0);
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
@@ -213,10 +266,11 @@ impl Operator for Log2 {
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Exp2;
 impl Operator for Exp2 {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 1);
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
         let inp_data = get_vec(&inp[0].0);
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
@@ -228,10 +282,11 @@ impl Operator for Exp2 {
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Sin;
 impl Operator for Sin {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 1);
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
         let inp_data = get_vec(&inp[0].0);
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
@@ -243,10 +298,11 @@ impl Operator for Sin {
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Recip;
 impl Operator for Recip {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 1);
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
         let inp_data = get_vec(&inp[0].0);
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
@@ -258,10 +314,11 @@ impl Operator for Recip {
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Sqrt;
 impl Operator for Sqrt {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 1);
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
         let inp_data = get_vec(&inp[0].0);
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
@@ -273,12 +330,47 @@ impl Operator for Sqrt {
     }
 }
 
+/// Replace NaN and infinities with finite values. Infinities without a replacement become the largest / smallest finite value.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct NanToNum {
+    pub nan: f32,
+    pub posinf: Option<f32>,
+    pub neginf: Option<f32>,
+}
+impl Operator for NanToNum {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 1);
+        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let inp_data = get_vec(&inp[0].0);
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut stack = vec![];
+        let (posinf, neginf) = (
+            self.posinf.unwrap_or(f32::MAX),
+            self.neginf.unwrap_or(f32::MIN),
+        );
+        for (i, out) in out_data.iter_mut().enumerate() {
+            let x = get_index(inp_data, &expr, &mut stack, i);
+            *out = if x.is_nan() {
+                self.nan
+            } else if x == f32::INFINITY {
+                posinf
+            } else if x == f32::NEG_INFINITY {
+                neginf
+            } else {
+                x
+            };
+        }
+        vec![Tensor::new(out_data)]
+    }
+}
+
 // Binary Ops (A x A -> A)
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Add;
 impl Operator for Add {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 2);
         let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
         let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
@@ -291,10 +383,11 @@ impl Operator for Add {
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Mul;
 impl Operator for Mul {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 2);
         let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
         let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
@@ -307,10 +400,11 @@ impl Operator for Mul {
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Mod;
 impl Operator for Mod {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 2);
         let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
         let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
@@ -323,10 +417,11 @@ impl Operator for Mod {
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct LessThan;
 impl Operator for LessThan {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 2);
         let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
         let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
@@ -342,10 +437,11 @@ impl Operator for LessThan {
 
 // Reduce Ops (A -> B (different shape))
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct SumReduce(pub usize);
 impl Operator for SumReduce {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 1);
         let sh = inp[0].1.shape_usize();
         let front_size = sh.iter().take(self.0).product::<usize>().max(1);
         let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
@@ -366,10 +462,11 @@ impl Operator for SumReduce {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct MaxReduce(pub usize);
 impl Operator for MaxReduce {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        check_arity::<Self>(&inp, 1);
         let sh = inp[0].1.shape_usize();
         let front_size = sh.iter().take(self.0).product::<usize>().max(1);
         let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
@@ -393,6 +490,18 @@ impl Operator for MaxReduce {
     }
 }
 
+/// Ensure an op received the number of inputs it expects, panicking with the op's name otherwise
+#[track_caller]
+pub fn check_arity<O: ?Sized>(inp: &[(InputTensor, ShapeTracker)], expected: usize) {
+    assert_eq!(
+        inp.len(),
+        expected,
+        "{} expected {expected} inputs, got {}",
+        std::any::type_name::<O>(),
+        inp.len()
+    );
+}
+
 fn get_vec<'a>(tensor: &'a InputTensor<'a>) -> &'a Vec<f32> {
     tensor.borrowed().downcast_ref::<Vec<f32>>().unwrap()
 }
@@ -0,0 +1,416 @@
+use std::{any::TypeId, fs, io, path::Path};
+
+use itertools::Itertools;
+use petgraph::{
+    stable_graph::NodeIndex,
+    visit::{EdgeRef, IntoEdgeReferences},
+};
+use rustc_hash::FxHashMap;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    op::{self, Constant, ConstantValue, Function, InputTensor, Operator, Tensor},
+    prelude::*,
+};
+
+type OpSerializer = fn(&dyn Operator) -> Value;
+type OpDeserializer = Box<dyn Fn(Value, &Graph) -> serde_json::Result<Box<dyn Operator>>>;
+type FunctionFactory =
+    Box<dyn Fn() -> Box<dyn Fn(Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor>>>;
+
+/// Maps ops to and from their serialized form. The default registry knows every primitive op.
+///
+/// Custom ops can be added with `register`, and `Function` ops (other than input loads) with `register_function`.
+pub struct OpRegistry {
+    serializers: FxHashMap<TypeId, (String, OpSerializer)>,
+    deserializers: FxHashMap<String, OpDeserializer>,
+    functions: FxHashMap<String, FunctionFactory>,
+}
+
+impl Default for OpRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            serializers: Default::default(),
+            deserializers: Default::default(),
+            functions: Default::default(),
+        };
+        registry
+            .register::<op::Contiguous>("Contiguous")
+            .register::<op::Log2>("Log2")
+            .register::<op::Exp2>("Exp2")
+            .register::<op::Sin>("Sin")
+            .register::<op::Recip>("Recip")
+            .register::<op::Sqrt>("Sqrt")
+            .register::<op::NanToNum>("NanToNum")
+            .register::<op::Add>("Add")
+            .register::<op::Mul>("Mul")
+            .register::<op::Mod>("Mod")
+            .register::<op::LessThan>("LessThan")
+            .register::<op::SumReduce>("SumReduce")
+            .register::<op::MaxReduce>("MaxReduce")
+            .register::<op::Barrier>("Barrier");
+        // Constants point at the dyn map of the graph they live in
+        registry.serializers.insert(
+            TypeId::of::<Constant>(),
+            ("Constant".to_string(), |op| {
+                serde_json::to_value(&op.as_any().downcast_ref::<Constant>().unwrap().0).unwrap()
+            }),
+        );
+        registry.deserializers.insert(
+            "Constant".to_string(),
+            Box::new(|data, graph| {
+                let value: ConstantValue = serde_json::from_value(data)?;
+                Ok(Box::new(Constant(value, &graph.dyn_map)))
+            }),
+        );
+        registry
+    }
+}
+
+impl OpRegistry {
+    /// Register an op type under a unique name
+    pub fn register<T: Operator + Serialize + DeserializeOwned + 'static>(
+        &mut self,
+        name: &str,
+    ) -> &mut Self {
+        self.serializers.insert(
+            TypeId::of::<T>(),
+            (name.to_string(), |op| {
+                serde_json::to_value(op.as_any().downcast_ref::<T>().unwrap()).unwrap()
+            }),
+        );
+        self.deserializers.insert(
+            name.to_string(),
+            Box::new(|data, _| Ok(Box::new(serde_json::from_value::<T>(data)?))),
+        );
+        self
+    }
+
+    /// Register a `Function` op by name, with a factory that recreates its closure when loading
+    pub fn register_function(
+        &mut self,
+        name: &str,
+        factory: impl Fn() -> Box<dyn Fn(Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor>> + 'static,
+    ) -> &mut Self {
+        self.functions.insert(name.to_string(), Box::new(factory));
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SerializedNode {
+    id: usize,
+    op: String,
+    data: Value,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SerializedEdge {
+    src: usize,
+    dst: usize,
+    /// (Input order, output order, shape), or none for a schedule dependency
+    data: Option<(u8, u8, ShapeTracker)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SerializedGraph {
+    nodes: Vec<SerializedNode>,
+    edges: Vec<SerializedEdge>,
+    no_delete: Vec<usize>,
+    to_retrieve: Vec<(usize, u8, ShapeTracker)>,
+    dyn_map: Vec<(char, usize)>,
+}
+
+/// Input loads are the only functions that can be saved without being registered, since their data gets set later
+fn is_input(graph: &Graph, node: NodeIndex) -> bool {
+    graph.input_kind(node) == Some(InputKind::Load)
+}
+
+impl Graph {
+    /// Save the op graph (ops, attributes, edges and shapes) to a JSON file. Tensor data isn't saved.
+    pub fn serialize(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.serialize_with(path, &OpRegistry::default())
+    }
+
+    /// Save the op graph, using `registry` to serialize custom ops
+    pub fn serialize_with(&self, path: impl AsRef<Path>, registry: &OpRegistry) -> io::Result<()> {
+        let nodes = self
+            .graph
+            .node_indices()
+            .map(|node| {
+                let op = self.graph.node_weight(node).unwrap();
+                let (op, data) = if let Some(Function(name, _)) =
+                    op.as_any().downcast_ref::<Function>()
+                {
+                    assert!(
+                        is_input(self, node) || registry.functions.contains_key(name),
+                        "Function op {name} can't be serialized, register it with OpRegistry::register_function"
+                    );
+                    ("Function".to_string(), Value::String(name.clone()))
+                } else {
+                    let (name, serializer) = registry
+                        .serializers
+                        .get(&op.as_ref().as_any().type_id())
+                        .unwrap_or_else(|| {
+                            panic!("Op {op:?} can't be serialized, register it with OpRegistry::register")
+                        });
+                    (name.clone(), serializer(op.as_ref()))
+                };
+                SerializedNode {
+                    id: node.index(),
+                    op,
+                    data,
+                }
+            })
+            .collect();
+        let edges = self
+            .graph
+            .edge_references()
+            .map(|e| SerializedEdge {
+                src: e.source().index(),
+                dst: e.target().index(),
+                data: e.weight().as_data(),
+            })
+            .collect();
+        let serialized = SerializedGraph {
+            nodes,
+            edges,
+            no_delete: self.no_delete.iter().map(|n| n.index()).sorted().collect(),
+            to_retrieve: self
+                .to_retrieve
+                .iter()
+                .map(|(n, (o, s))| (n.index(), *o, *s))
+                .sorted_by_key(|(n, _, _)| *n)
+                .collect(),
+            dyn_map: self
+                .dyn_map
+                .iter()
+                .map(|(c, s)| (*c, *s))
+                .sorted()
+                .collect(),
+        };
+        fs::write(path, serde_json::to_string(&serialized)?)
+    }
+
+    /// Load an op graph saved with `serialize` into this empty graph.
+    ///
+    /// Node ids are preserved, so ids saved alongside the graph still point at the same tensors. Inputs need to be set
+    /// again before executing.
+    pub fn deserialize(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.deserialize_with(path, &OpRegistry::default())
+    }
+
+    /// Load an op graph, using `registry` to deserialize custom ops.
+    ///
+    /// Malformed files, unregistered ops and edges to missing nodes are `InvalidData` errors, and leave the graph empty.
+    pub fn deserialize_with(
+        &mut self,
+        path: impl AsRef<Path>,
+        registry: &OpRegistry,
+    ) -> io::Result<()> {
+        assert_eq!(
+            self.graph.node_count(),
+            0,
+            "Graphs can only be deserialized into an empty graph"
+        );
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let serialized: SerializedGraph = serde_json::from_str(&fs::read_to_string(path)?)?;
+        let ops = serialized
+            .nodes
+            .into_iter()
+            .map(|SerializedNode { id, op, data }| {
+                let op: Box<dyn Operator> = if op == "Function" {
+                    let name = data
+                        .as_str()
+                        .ok_or_else(|| invalid(format!("Function {id} has no name")))?
+                        .to_string();
+                    if let Some(factory) = registry.functions.get(&name) {
+                        Box::new(Function(name, factory()))
+                    } else {
+                        let msg = format!("You must set a value for this tensor! ({name})");
+                        Box::new(Function(name, Box::new(move |_| panic!("{msg}"))))
+                    }
+                } else {
+                    let deserializer = registry.deserializers.get(&op).ok_or_else(|| {
+                        invalid(format!(
+                            "Op {op} isn't registered, register it with OpRegistry::register"
+                        ))
+                    })?;
+                    deserializer(data, self)
+                        .map_err(|e| invalid(format!("Malformed {op} op {id}: {e}")))?
+                };
+                Ok((id, op))
+            })
+            .collect::<io::Result<FxHashMap<_, _>>>()?;
+        // Check every reference to a node before building anything, so a bad file leaves the graph empty
+        if let Some(id) = serialized
+            .edges
+            .iter()
+            .flat_map(|e| [e.src, e.dst])
+            .chain(serialized.no_delete.iter().copied())
+            .chain(serialized.to_retrieve.iter().map(|(n, _, _)| *n))
+            .find(|id| !ops.contains_key(id))
+        {
+            return Err(invalid(format!("Graph refers to missing node {id}")));
+        }
+
+        // Fill the holes between ids with placeholders, so every node lands on its original id
+        let n_nodes = ops.keys().max().map(|i| i + 1).unwrap_or_default();
+        let mut ops = ops;
+        let mut holes = vec![];
+        for id in 0..n_nodes {
+            let op = ops.remove(&id).unwrap_or_else(|| {
+                holes.push(NodeIndex::new(id));
+                Box::new(Function("Removed".to_string(), Box::new(|_| vec![])))
+            });
+            self.graph.add_node(op);
+        }
+        for node in holes {
+            self.graph.remove_node(node);
+        }
+
+        for SerializedEdge { src, dst, data } in serialized.edges {
+            let dependency = if let Some((input_order, output_order, shape)) = data {
+                Dependency::Data {
+                    input_order,
+                    output_order,
+                    shape,
+                }
+            } else {
+                Dependency::Schedule
+            };
+            self.graph
+                .add_edge(NodeIndex::new(src), NodeIndex::new(dst), dependency);
+        }
+        self.no_delete
+            .extend(serialized.no_delete.into_iter().map(NodeIndex::new));
+        self.to_retrieve.extend(
+            serialized
+                .to_retrieve
+                .into_iter()
+                .map(|(n, o, s)| (NodeIndex::new(n), (o, s))),
+        );
+        self.dyn_map.extend(serialized.dyn_map);
+        self.toposort();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    crate::test_imports!();
+
+    #[test]
+    fn test_serialize_roundtrip() {
+        let path = std::env::temp_dir().join("luminal_test_serialize_roundtrip.json");
+        let (a_data, b_data) = (random_vec(6), random_vec(12));
+        let mut cx = Graph::new();
+        let a = cx
+            .named_tensor("A", (2, 's'))
+            .set_dyn(a_data.clone(), (2, 3));
+        let b = cx.tensor((3, 4)).set(b_data.clone());
+        let c = (a.matmul(b).softmax(1) * 's').retrieve();
+        let d = a.sum_reduce(1).barrier().exp().retrieve();
+        cx.execute();
+        cx.serialize(&path).unwrap();
+
+        let mut cx1 = Graph::new();
+        cx1.deserialize(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        GraphTensor::from_id(a.id, a.shape, &mut cx1).set_dyn(a_data, (2, 3));
+        GraphTensor::from_id(b.id, b.shape, &mut cx1).set(b_data);
+
+        assert_eq!(cx1.graph.node_count(), cx.graph.node_count());
+        cx1.execute();
+        assert_exact(
+            &GraphTensor::from_id(c.id, c.shape, &mut cx1).data(),
+            &c.data(),
+        );
+        assert_exact(
+            &GraphTensor::from_id(d.id, d.shape, &mut cx1).data(),
+            &d.data(),
+        );
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct AddScalar(f32);
+
+    impl Operator for AddScalar {
+        fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<crate::op::Tensor> {
+            let data = inp[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+            vec![crate::op::Tensor::new(
+                data.iter().map(|i| i + self.0).collect::<Vec<_>>(),
+            )]
+        }
+    }
+
+    #[test]
+    fn test_serialize_custom_op() {
+        let path = std::env::temp_dir().join("luminal_test_serialize_custom_op.json");
+        let mut registry = OpRegistry::default();
+        registry.register::<AddScalar>("AddScalar");
+        let mut cx = Graph::new();
+        let a = cx.tensor(3);
+        let id = cx.add_op(AddScalar(2.)).input(a.id, 0, a.shape).finish();
+        let b = GraphTensor::from_id(id, a.shape, &mut cx).retrieve();
+        cx.serialize_with(&path, &registry).unwrap();
+
+        let mut cx1 = Graph::new();
+        cx1.deserialize_with(&path, &registry).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        GraphTensor::from_id(a.id, a.shape, &mut cx1).set(vec![1., 2., 3.]);
+        cx1.execute();
+        assert_exact(
+            &GraphTensor::from_id(b.id, b.shape, &mut cx1).data(),
+            &[3., 4., 5.],
+        );
+    }
+
+    #[test]
+    fn test_deserialize_errors() {
+        let path = std::env::temp_dir().join("luminal_test_deserialize_errors.json");
+        let mut cx = Graph::new();
+        let err = cx.deserialize(&path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+
+        std::fs::write(&path, "not a graph").unwrap();
+        let err = cx.deserialize(&path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // Well-formed JSON describing a bad graph is invalid data too, rather than a panic
+        let node = |op: &str, data: &str| {
+            format!(
+                r#"{{"nodes":[{{"id":0,"op":"{op}","data":{data}}}],"edges":[],"no_delete":[],"to_retrieve":[],"dyn_map":[]}}"#
+            )
+        };
+        for graph in [
+            node("Function", "3"),
+            node("Unknown", "null"),
+            node("Constant", r#""not a constant""#),
+            node("Add", "[1, 2]"),
+            node("Add", "null").replace(
+                r#""edges":[]"#,
+                r#""edges":[{"src":0,"dst":5,"data":null}]"#,
+            ),
+        ] {
+            std::fs::write(&path, graph).unwrap();
+            let err = cx.deserialize(&path).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert_eq!(cx.graph.node_count(), 0);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "register it with OpRegistry::register")]
+    fn test_serialize_unregistered_op() {
+        let mut cx = Graph::new();
+        let a = cx.tensor(3);
+        cx.add_op(AddScalar(2.)).input(a.id, 0, a.shape).finish();
+        cx.serialize(std::env::temp_dir().join("luminal_test_serialize_unregistered.json"))
+            .unwrap();
+    }
+}
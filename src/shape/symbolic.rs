@@ -39,6 +39,18 @@ impl Expression {
     }
 }
 
+impl serde::Serialize for Expression {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.terms.read().serialize(serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Expression {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Expression::new(Vec::deserialize(deserializer)?))
+    }
+}
+
 impl Hash for Expression {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.terms.read().hash(state);
@@ -3,13 +3,14 @@ use tinyvec::ArrayVec;
 
 use crate::prelude::*;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
 pub struct ShapeTracker {
     pub dims: ArrayVec<[Expression; 6]>,
     pub indexes: ArrayVec<[usize; 6]>,
     pub fake: ArrayVec<[bool; 6]>,
     pub mask: ArrayVec<[(Expression, Expression); 6]>,
     pub padding: ArrayVec<[(Expression, Expression); 6]>,
+    pub flipped: ArrayVec<[bool; 6]>,
 }
 
 impl ShapeTracker {
@@ -21,6 +22,7 @@ impl ShapeTracker {
             fake: Default::default(),
             mask: Default::default(),
             padding: Default::default(),
+            flipped: Default::default(),
         };
         for (i, d) in dims.to_shape().into_iter().enumerate() {
             s.dims.push(d);
@@ -28,6 +30,7 @@ impl ShapeTracker {
             s.fake.push(false);
             s.mask.push((0.into(), i32::MAX.into())); // Unset upper bound mask are i32::MAX
             s.padding.push((0.into(), 0.into()));
+            s.flipped.push(false);
         }
         s
     }
@@ -46,6 +49,7 @@ impl ShapeTracker {
         self.fake.push(false);
         self.mask.push((0.into(), i32::MAX.into()));
         self.padding.push((0.into(), 0.into()));
+        self.flipped.push(false);
     }
 
     /// Add fake dim along a certian axis
@@ -65,6 +69,7 @@ impl ShapeTracker {
         }
         self.mask.remove(index);
         self.padding.remove(index);
+        self.flipped.remove(index);
         self.dims.remove(index)
     }
 
@@ -80,6 +85,12 @@ impl ShapeTracker {
         self.indexes.copy_from_slice(&new_indexes);
     }
 
+    /// Reverse the order of elements along an axis
+    pub fn flip(&mut self, axis: usize) {
+        let index = self.indexes[axis];
+        self.flipped[index] = !self.flipped[index];
+    }
+
     /// Strides without permute applied
     fn unordered_strides(&self) -> Vec<Expression> {
         let mut strides = (0..self.len())
@@ -122,6 +133,10 @@ impl ShapeTracker {
                 dim_ind /= current_elem_size;
                 // Get position in current dim
                 dim_ind %= current_size;
+                // Count from the other end if flipped
+                if self.flipped[i] {
+                    dim_ind = current_size - 1 - dim_ind;
+                }
                 // Add offset
                 dim_ind += self.mask[i].0 - self.padding[i].0;
                 // Multiply by stride
@@ -152,7 +167,10 @@ impl ShapeTracker {
             let (bottom_slice, top_slice) = self.mask[i];
             let logical_sh = pad_mask_dim(self.dims[i], self.padding[i], self.mask[i]);
             if !self.fake[i] {
-                let dim_ind = (logical / acc) % logical_sh;
+                let mut dim_ind = (logical / acc) % logical_sh;
+                if self.flipped[i] {
+                    dim_ind = logical_sh - 1 - dim_ind;
+                }
                 let greater_than = self.padding[i].0 - bottom_slice;
                 if greater_than != 0 {
                     ret &= dim_ind.gte(greater_than);
@@ -225,9 +243,11 @@ impl ShapeTracker {
         )
     }
 
-    /// Check if contiguous (no permutes or fake dimensions)
+    /// Check if contiguous (no permutes, flips or fake dimensions)
     pub fn is_contiguous(&self) -> bool {
-        self.indexes.iter().enumerate().all(|(a, b)| a == *b) && self.fake.iter().all(|i| !*i)
+        self.indexes.iter().enumerate().all(|(a, b)| a == *b)
+            && self.fake.iter().all(|i| !*i)
+            && self.flipped.iter().all(|i| !*i)
     }
 
     /// Check if this shape has been modified at all (permuted, sliced, or padded)
@@ -316,6 +336,10 @@ impl ShapeTracker {
         })
     }
 
+    pub fn is_flipped(&self) -> bool {
+        self.flipped.iter().any(|f| *f)
+    }
+
     pub fn is_padded(&self) -> bool {
         self.padding.iter().any(|(b, e)| {
             b.to_usize().map(|i| i != 0).unwrap_or(true)
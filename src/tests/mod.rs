@@ -67,6 +67,620 @@ fn test_shapes() {
     assert_exact(&b.data(), &[1., 3., 2., 4.]);
 }
 
+#[test]
+fn test_op_counts() {
+    let mut cx = Graph::new();
+    let a = cx.tensor(3).set(vec![1.0, 2.0, 3.0]);
+    let mut b = (a.exp2() + a.exp2()).sum_reduce(0).retrieve();
+
+    let counts = cx.op_counts();
+    assert_eq!(counts["Exp2"], 2);
+    assert_eq!(counts["Add"], 1);
+    assert_eq!(counts["SumReduce"], 1);
+
+    cx.compile(GenericCompiler::default(), &mut b);
+    assert_eq!(cx.op_counts()["Exp2"], 1);
+}
+
+#[test]
+fn test_graph_merge() {
+    let mut cx = Graph::new();
+    let a = cx.tensor(3).set(vec![1.0, 2.0, 3.0]);
+    let b = (a + a).retrieve();
+
+    let mut other = Graph::new();
+    let c = other.tensor('s');
+    let d = (c * 2.0 + 's').retrieve();
+    c.set_dyn(vec![1.0, 2.0], 2);
+    let (c_id, d_id, d_shape) = (c.id, d.id, d.shape);
+    let other_seed = other.seed.clone();
+
+    let map = cx.merge(other);
+    // The merged graph is freed, besides the seed its random ops now share with ours
+    assert_eq!(std::rc::Rc::strong_count(&other_seed), 2);
+    let c = GraphTensor::from_id(map[&c_id], ShapeTracker::new('s'), &mut cx);
+    let d = GraphTensor::from_id(map[&d_id], d_shape, &mut cx);
+    assert!(cx.to_retrieve.contains_key(&d.id));
+    cx.execute();
+
+    assert_exact(&b.data(), &[2.0, 4.0, 6.0]);
+    assert_exact(&d.data(), &[4.0, 6.0]);
+
+    c.set_dyn(vec![1.0, 2.0, 3.0], 3);
+    d.drop();
+    cx.execute();
+    assert_exact(&d.data(), &[5.0, 7.0, 9.0]);
+}
+
+#[test]
+fn test_graph_merge_host_functions() {
+    let mut cx = Graph::new();
+    let a = cx.tensor(2).set(vec![1.0, 2.0]);
+    let b = (a * 3.0).retrieve();
+
+    // Host functions reading dynamic dims must follow them into the graph they're merged into
+    let mut other = Graph::new();
+    let src = other.tensor(('s', 2));
+    let coords = other.tensor((2, 1)).set(vec![2.0, 0.0]);
+    let rows = src.gather_nd(coords).retrieve();
+    let dropped = src.drop_path(0.).retrieve();
+    let (src_id, rows_id, dropped_id) = (src.id, rows.id, dropped.id);
+    let (rows_shape, dropped_shape) = (rows.shape, dropped.shape);
+
+    let map = cx.merge(other);
+    let src = GraphTensor::from_id(map[&src_id], ShapeTracker::new(('s', 2)), &mut cx);
+    let rows = GraphTensor::from_id(map[&rows_id], rows_shape, &mut cx);
+    let dropped = GraphTensor::from_id(map[&dropped_id], dropped_shape, &mut cx);
+    for seq in [3, 4] {
+        let data = (0..seq * 2).map(|i| i as f32).collect::<Vec<_>>();
+        src.set_dyn(data.clone(), (seq, 2));
+        cx.execute();
+
+        assert_exact(&b.data(), &[3.0, 6.0]);
+        assert_exact(&rows.data(), &[4.0, 5.0, 0.0, 1.0]);
+        assert_exact(&dropped.data(), &data);
+        rows.drop();
+        dropped.drop();
+    }
+}
+
+#[test]
+fn test_stateful_modules() {
+    // Adds its state to the input and hands back a doubled state
+    struct Accumulate;
+    impl Module<(GraphTensor, GraphTensor)> for Accumulate {
+        type Output = (GraphTensor, GraphTensor);
+        fn forward(&self, (x, state): (GraphTensor, GraphTensor)) -> Self::Output {
+            (x + state, state * 2.0)
+        }
+    }
+    struct Negate;
+    impl Module<GraphTensor> for Negate {
+        type Output = GraphTensor;
+        fn forward(&self, x: GraphTensor) -> Self::Output {
+            -x
+        }
+    }
+
+    let mut cx = Graph::new();
+    let a = cx.tensor(2).set(vec![1.0, 2.0]);
+    let states = [
+        cx.tensor(2).set(vec![10.0, 20.0]),
+        cx.tensor(2).set(vec![100.0, 200.0]),
+    ];
+    let (b, new_states) = forward_with_states(&[Accumulate, Accumulate], a, states);
+    let b = b.retrieve();
+    let new_states = new_states
+        .into_iter()
+        .map(|s| s.retrieve())
+        .collect::<Vec<_>>();
+    let (c, d) = (MapFirst(Negate), Accumulate).forward((a, states[0]));
+    let (c, d) = (c.retrieve(), d.retrieve());
+    cx.execute();
+
+    assert_exact(&b.data(), &[111.0, 222.0]);
+    assert_exact(&new_states[0].data(), &[20.0, 40.0]);
+    assert_exact(&new_states[1].data(), &[200.0, 400.0]);
+    assert_exact(&c.data(), &[9.0, 18.0]);
+    assert_exact(&d.data(), &[20.0, 40.0]);
+}
+
+#[test]
+#[should_panic(expected = "Got 1 states for 2 layers")]
+fn test_forward_with_states_length_mismatch() {
+    struct Accumulate;
+    impl Module<(GraphTensor, GraphTensor)> for Accumulate {
+        type Output = (GraphTensor, GraphTensor);
+        fn forward(&self, (x, state): (GraphTensor, GraphTensor)) -> Self::Output {
+            (x + state, state)
+        }
+    }
+
+    let mut cx = Graph::new();
+    let a = cx.tensor(2);
+    let states = [cx.tensor(2)];
+    forward_with_states(&[Accumulate, Accumulate], a, states);
+}
+
+#[test]
+fn test_residual() {
+    struct Double;
+    impl Module<GraphTensor> for Double {
+        type Output = GraphTensor;
+        fn forward(&self, x: GraphTensor) -> Self::Output {
+            x * 2.0
+        }
+    }
+    struct Normalize;
+    impl Module<GraphTensor> for Normalize {
+        type Output = GraphTensor;
+        fn forward(&self, x: GraphTensor) -> Self::Output {
+            x / x.sum_reduce(0).expand(0, 2)
+        }
+    }
+
+    let mut cx = Graph::new();
+    let a = cx.tensor(2).set(vec![1.0, 3.0]);
+    let b = Residual(Double).forward(a).retrieve();
+    let pre = Residual((Normalize, Double)).forward(a).retrieve();
+    let post = (Residual(Double), Normalize).forward(a).retrieve();
+    cx.execute();
+
+    assert_exact(&b.data(), &[3.0, 9.0]);
+    assert_close(&pre.data(), &[1.5, 4.5]);
+    assert_close(&post.data(), &[0.25, 0.75]);
+}
+
+#[test]
+#[should_panic(expected = "Graph contains a cycle between nodes: 1 (Exp2), 2 (Log2)")]
+fn test_cycle_detection() {
+    let mut cx = Graph::new();
+    let a = cx.tensor(3).set(vec![1.0, 2.0, 3.0]);
+    let b = a.exp2();
+    let c = b.log2().retrieve();
+    cx.assert_no_cycles();
+    // A compiler wiring an output back into its own input
+    cx.add_edge(
+        c.id,
+        b.id,
+        Dependency::Data {
+            input_order: 1,
+            output_order: 0,
+            shape: c.shape,
+        },
+    );
+    cx.assert_no_cycles();
+}
+
+#[test]
+fn test_execution_order() {
+    let mut cx = Graph::new();
+    let a = cx.tensor(3).set(vec![1.0, 2.0, 3.0]);
+    let b = cx.tensor(3).set(vec![4.0, 5.0, 6.0]);
+    let c = (a.exp() + b).retrieve();
+    let d = (c * a).sum_reduce(0).retrieve();
+
+    let order = cx.execution_order();
+    assert_eq!(order.len(), cx.graph.node_count());
+    let position = |n| order.iter().position(|i| *i == n).unwrap();
+    for edge in cx.graph.edge_indices() {
+        let (src, dest) = cx.graph.edge_endpoints(edge).unwrap();
+        assert!(position(src) < position(dest));
+    }
+    assert!(position(c.id) < position(d.id));
+
+    // Adding ops refreshes the order
+    let e = (d + 1.0).retrieve();
+    assert!(cx.execution_order().contains(&e.id));
+}
+
+#[test]
+fn test_input_output_tensors() {
+    let mut cx = Graph::new();
+    let a = cx.named_tensor("A", 3).set(vec![1.0, 2.0, 3.0]);
+    let b = cx.tensor(3);
+    let noise = cx.rand(3);
+    let c = ((a + b) * noise + 1.0).retrieve();
+    let d = c.sum_reduce(0).retrieve();
+
+    assert_eq!(cx.input_tensors(), vec![a.id, b.id]);
+    assert_eq!(cx.output_tensors(), vec![c.id, d.id]);
+    assert_eq!(cx.input_kind(a.id), Some(InputKind::Load));
+    assert_eq!(cx.input_kind(noise.id), Some(InputKind::Generated));
+    assert_eq!(cx.input_kind(c.id), None);
+    let constants = cx
+        .graph
+        .node_indices()
+        .filter(|n| cx.input_kind(*n) == Some(InputKind::Op))
+        .count();
+    assert_eq!(constants, 1);
+}
+
+#[test]
+fn test_execute_with_inputs() {
+    let mut cx = Graph::new();
+    let mut a = cx.tensor(3).set(vec![0., 0., 0.]);
+    let mut b = cx.tensor(3).set(vec![1., 1., 1.]);
+    let mut c = (a * b + 1.0).retrieve();
+    let mut d = (a + b).sum_reduce(0).retrieve();
+    cx.compile(GenericCompiler::default(), (&mut a, &mut b, &mut c, &mut d));
+
+    let out = cx.execute_with_inputs([(a.id, vec![1., 2., 3.]), (b.id, vec![4., 5., 6.])]);
+    assert_exact(&out[&c.id], &[5., 11., 19.]);
+    assert_exact(&out[&d.id], &[21.]);
+    // Inputs left out use the data they were set with
+    let out = cx.execute_with_inputs([(a.id, vec![-1., 0., 1.])]);
+    assert_exact(&out[&c.id], &[0., 1., 2.]);
+    assert_exact(&out[&d.id], &[3.]);
+    // The input ops aren't rebound, so a later set still takes effect
+    b.set(vec![2., 2., 2.]);
+    let out = cx.execute_with_inputs([(a.id, vec![1., 1., 1.])]);
+    assert_exact(&out[&c.id], &[3., 3., 3.]);
+    assert_exact(&out[&d.id], &[9.]);
+}
+
+#[test]
+fn test_execute_subset() {
+    let mut cx = Graph::new();
+    let a = cx.tensor(3).set(vec![1., 2., 3.]);
+    let b = cx.tensor(3).set(vec![4., 5., 6.]);
+    let c = (a * b).retrieve();
+    let d = (a + b).sum_reduce(0).retrieve();
+
+    cx.execute_subset(&[c.id]);
+    assert_exact(&c.data(), &[4., 10., 18.]);
+    assert!(cx.get_tensor_ref(d.id, 0).is_none());
+    // Inputs and intermediates are still cleaned up after the run
+    assert_eq!(cx.tensors.len(), 1);
+
+    cx.execute_subset(&[d.id]);
+    assert_exact(&d.data(), &[21.]);
+}
+
+#[test]
+fn test_replace_op() {
+    let mut cx = Graph::new();
+    let a = cx.tensor(3).set(vec![1., 2., 3.]);
+    let b = cx.tensor(3).set(vec![4., 5., 6.]);
+    let mut c = (a + b).retrieve();
+    let d = (c * 2.).retrieve();
+
+    let srcs = cx.get_sources(c.id);
+    let old = c.id;
+    let new = cx.replace_op(old, crate::op::Mul, &srcs, &mut c);
+    assert_eq!(c.id, new);
+    assert!(cx.graph.node_weight(old).is_none());
+    assert!(cx.to_retrieve.contains_key(&new) && cx.no_delete.contains(&new));
+    cx.execute();
+
+    assert_exact(&c.data(), &[4., 10., 18.]);
+    // Consumers of the old op now read the new one
+    assert_eq!(cx.get_sources(d.id)[0].0, new);
+    assert_exact(&d.data(), &[8., 20., 36.]);
+}
+
+#[test]
+fn test_verify_against_cpu() {
+    let errors = Graph::verify_against_cpu(GenericCompiler::default(), |cx| {
+        let a = cx.tensor((2, 3)).set(vec![1., 2., 3., 4., 5., 6.]);
+        let b = cx.tensor(3).set(vec![0.5, -1., 2.]);
+        vec![
+            (a.exp2() * 2.0).sum_reduce(1),
+            a.matmul(b.expand(1, 1)),
+            (a / 0.).ln(),
+        ]
+    });
+    assert_eq!(errors.len(), 3);
+    for err in errors {
+        assert!(err.max_abs < 1e-5 && err.max_rel < 1e-5, "{err:?}");
+    }
+}
+
+#[test]
+fn test_graph_survives_verify_against_cpu() {
+    let mut cx = Graph::new();
+    let a = cx.tensor('s');
+    let b = (a * 2.0 + 's').retrieve();
+
+    let errors = Graph::verify_against_cpu(GenericCompiler::default(), |cx| {
+        vec![cx.tensor(2).set(vec![1., 2.]).exp2()]
+    });
+    assert!(errors[0].max_abs < 1e-5);
+    // Our shapes still hold expressions, which the verification graphs must not have freed
+    a.set_dyn(vec![1., 2., 3.], 3);
+    cx.execute();
+    assert_exact(&b.data(), &[5., 7., 9.]);
+}
+
+#[test]
+fn test_execute_timed() {
+    let mut cx = Graph::new();
+    let a = cx.tensor(3).set(vec![1., 2., 3.]);
+    let b = (a.exp2() * 2.0).retrieve();
+    let total = cx.execute_timed();
+    assert_exact(&b.data(), &[4., 8., 16.]);
+    b.drop();
+
+    let (op_total, op_times) = cx.execute_timed_ops();
+    assert_exact(&b.data(), &[4., 8., 16.]);
+    // Every op runs once, in execution order
+    assert_eq!(
+        op_times.iter().map(|(n, _)| *n).collect::<Vec<_>>(),
+        cx.execution_order()
+    );
+    assert!(
+        op_times
+            .iter()
+            .map(|(_, t)| *t)
+            .sum::<std::time::Duration>()
+            <= op_total
+    );
+    assert!(total > std::time::Duration::ZERO);
+}
+
+#[test]
+fn test_tap() {
+    struct AddOne;
+    impl Module<GraphTensor> for AddOne {
+        type Output = GraphTensor;
+        fn forward(&self, x: GraphTensor) -> Self::Output {
+            x + 1.0
+        }
+    }
+
+    let mut cx = Graph::new();
+    let a = cx.tensor((2, 3)).set(vec![0., 1., 2., 3., 4., 5.]);
+    let b = a.exp2();
+    let mut c = (b.permute((1, 0)) * 2.0).sum_reduce(0).retrieve();
+    let (d, mut taps) = forward_with_taps(&[AddOne, AddOne], a);
+    let mut d = d.retrieve();
+    // Tap an intermediate after the model is built, through the permuted view its consumer reads
+    let mut tapped = cx.tap(b.id);
+    assert_eq!(tapped.shape.shape_usize(), vec![2, 3]);
+    cx.compile(
+        GenericCompiler::default(),
+        (&mut c, &mut d, &mut tapped, &mut taps),
+    );
+    cx.execute();
+
+    assert_exact(&tapped.data(), &[1., 2., 4., 8., 16., 32.]);
+    assert_exact(&c.data(), &[14., 112.]);
+    assert_exact(&taps[0].data(), &[1., 2., 3., 4., 5., 6.]);
+    assert_exact(&taps[1].data(), &[2., 3., 4., 5., 6., 7.]);
+    assert_exact(&d.data(), &taps[1].data());
+}
+
+#[test]
+#[should_panic(expected = "it has no consumers to take its shape from")]
+fn test_tap_without_consumers() {
+    let mut cx = Graph::new();
+    let a = cx.tensor(3).set(vec![1.0, 2.0, 3.0]);
+    cx.tap(a.exp2().id);
+}
+
+#[test]
+fn test_select_attr() {
+    let mut cx = Graph::new();
+    let a = cx.tensor((2, 3, 4));
+    let b = a.sum_reduce(1).retrieve();
+    let c = a.sum_reduce(2).retrieve();
+    let d = a.max_reduce(1).retrieve();
+
+    let mut reduce = op::<crate::op::SumReduce>();
+    reduce.attr(|o: &crate::op::SumReduce| o.0 == 1);
+    let mut s = reduce.clone().search(&mut cx);
+    let mut matches = vec![];
+    while s.next_match() {
+        matches.push(s.get(&reduce));
+    }
+    assert_eq!(matches, vec![b.id]);
+    assert!(!matches.contains(&c.id) && !matches.contains(&d.id));
+}
+
+#[test]
+#[should_panic(expected = "luminal::op::Add expected 2 inputs, got 1")]
+fn test_arity_check() {
+    let mut cx = Graph::new();
+    let a = cx.tensor(3).set(vec![1.0, 2.0, 3.0]);
+    let b = cx.add_op(crate::op::Add).input(a.id, 0, a.shape).finish();
+    GraphTensor::from_id(b, a.shape, &mut cx).retrieve();
+    cx.execute();
+}
+
+#[test]
+fn test_npy_tensor_io() {
+    let inp_path = std::env::temp_dir().join("luminal_test_npy_in.npy");
+    let out_path = std::env::temp_dir().join("luminal_test_npy_out.npy");
+    crate::npy::write_npy(&inp_path, &[2, 3], &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]).unwrap();
+
+    let mut cx = Graph::new();
+    let a = cx.tensor((2, 's')).set_from_npy(&inp_path);
+    let b = a.permute((1, 0)).retrieve();
+    cx.execute();
+    b.save_npy(&out_path).unwrap();
+
+    let (shape, data) = crate::npy::read_npy(&out_path).unwrap();
+    std::fs::remove_file(&inp_path).unwrap();
+    std::fs::remove_file(&out_path).unwrap();
+    assert_eq!(shape, vec![3, 2]);
+    assert_exact(&data, &[1.0, 4.0, 2.0, 5.0, 3.0, 6.0]);
+}
+
+#[test]
+fn test_to_cpu_vec() {
+    let mut cx = Graph::new();
+    let a = cx.tensor(('s', 2));
+    let w = cx.tensor((2, 3)).set(vec![1., 0., 1., 0., 1., 1.]);
+    let b = a.matmul(w).permute((1, 0)).retrieve();
+
+    // The seq dim is only resolved once the graph runs
+    for (seq, expected) in [(1, vec![1., 2., 3.]), (2, vec![1., 3., 2., 4., 3., 7.])] {
+        a.set_dyn(vec![1., 2., 3., 4.][..seq * 2].to_vec(), (seq, 2));
+        cx.execute();
+        let (data, shape) = b.to_cpu_vec();
+        assert_eq!(shape, vec![3, seq]);
+        assert_exact(&data, &expected);
+        b.drop();
+    }
+}
+
+#[test]
+fn test_checkpoint_restore() {
+    let mut cx = Graph::new();
+    // Prefill: the cache is computed once from the prompt
+    let prompt = cx.tensor('p').set_dyn(vec![1., 2., 3.], 3);
+    let cache = (prompt * 2.).retrieve();
+    // Decode: each continuation starts from the cache
+    let cache_in = cx.tensor('p');
+    let token = cx.tensor('p');
+    let out = (cache_in + token).retrieve();
+
+    cx.execute_subset(&[cache.id]);
+    let checkpoint = cx.checkpoint([("cache", cache.id)]);
+    // Later runs don't touch the checkpoint
+    cache.drop();
+    cx.set_dyn_dim('p', 1);
+
+    for (token_data, expected) in [([1., 1., 1.], [3., 5., 7.]), ([0., 10., 0.], [2., 14., 6.])] {
+        cx.restore(&checkpoint, [("cache", cache_in.id)]);
+        assert_eq!(cx.dyn_map[&'p'], 3);
+        token.set(token_data.to_vec());
+        cx.execute_subset(&[out.id]);
+        assert_exact(&out.data(), &expected);
+        out.drop();
+    }
+}
+
+#[test]
+fn test_flop_count() {
+    let mut cx = Graph::new();
+    let a = cx.tensor(('s', 3));
+    let b = cx.tensor((3, 4));
+    let _ = (a.matmul(b) + 1.).retrieve();
+    cx.set_dyn_dim('s', 5);
+    // 2 * M * N * K for the matmul, plus one add per output element. Loads and constants are free.
+    assert_eq!(cx.flop_count(), 2 * 5 * 4 * 3 + 5 * 4);
+    cx.set_dyn_dim('s', 10);
+    assert_eq!(cx.flop_count(), 2 * 10 * 4 * 3 + 10 * 4);
+}
+
+#[test]
+fn test_flop_count_after_dedup() {
+    let mut cx = Graph::new();
+    let a = cx.tensor(3).set([1., 2., 3.]);
+    let mut b = (a * 2.).retrieve();
+    cx.compile(GenericCompiler::default(), &mut b);
+    assert_eq!(cx.flop_count(), 3);
+
+    // Ops added after the compile are counted once the graph is edited again
+    let mut c = (a + 1.).retrieve();
+    cx.dedup_constants((&mut b, &mut c));
+    assert_eq!(cx.flop_count(), 6);
+}
+
+#[test]
+fn test_dedup_constants() {
+    let mut cx = Graph::new();
+    // Two forward passes each building their own positions
+    let mut a = ((cx.arange(5) * 2.) + (cx.arange(5) * 2.)).retrieve();
+    // Host tables set twice with the same data
+    let x = cx.tensor(3);
+    let y = cx.tensor(3);
+    cx.set_tensor(x.id, 0, Tensor::new(vec![1., 2., 3.]));
+    cx.set_tensor(y.id, 0, Tensor::new(vec![1., 2., 3.]));
+    let mut b = (x.exp2() + y.exp2()).retrieve();
+    // Weights with the same data stay separate
+    let w1 = cx.tensor(3).keep();
+    let w2 = cx.tensor(3).keep();
+    cx.set_tensor(w1.id, 0, Tensor::new(vec![1., 1., 1.]));
+    cx.set_tensor(w2.id, 0, Tensor::new(vec![1., 1., 1.]));
+    let mut c = (w1 * w2).retrieve();
+    // Host functions are opaque, so even identical masks stay separate
+    let pos = cx.arange(5);
+    let mask = |t: GraphTensor| {
+        let id = t
+            .graph()
+            .add_op(Function(
+                "Mask".to_string(),
+                Box::new(|inp| {
+                    let x = inp[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+                    vec![Tensor::new(
+                        x.iter()
+                            .map(|x| if *x > 1. { 1. } else { 0. })
+                            .collect::<Vec<_>>(),
+                    )]
+                }),
+            ))
+            .input(t.id, 0, t.shape)
+            .finish();
+        GraphTensor::from_id(id, t.shape.contiguous(), t.graph_ref)
+    };
+    let mut d = (mask(pos) + mask(pos)).retrieve();
+    assert_eq!(cx.op_counts().get("SumReduce"), Some(&3));
+    assert_eq!(cx.op_counts().get("Exp2"), Some(&2));
+    assert_eq!(cx.op_counts().get("Mask"), Some(&2));
+
+    cx.dedup_constants((&mut a, &mut b, &mut c, &mut d));
+    // The arange chains and the ops on the merged tables collapse to one each
+    assert_eq!(cx.op_counts().get("SumReduce"), Some(&1));
+    assert_eq!(cx.op_counts().get("Exp2"), Some(&1));
+    assert_eq!(cx.op_counts().get("Mask"), Some(&2));
+    assert_ne!(w1.id, w2.id);
+    assert!(cx.graph.contains_node(w1.id) && cx.graph.contains_node(w2.id));
+    cx.execute();
+
+    assert_exact(&a.data(), &[0., 4., 8., 12., 16.]);
+    assert_close(&b.data(), &[4., 8., 16.]);
+    assert_exact(&c.data(), &[1., 1., 1.]);
+    assert_exact(&d.data(), &[0., 0., 2., 2., 2.]);
+}
+
+#[test]
+fn test_derive_serialize_module() {
+    #[derive(SerializeModule)]
+    struct Block {
+        weight: GraphTensor,
+        #[serialize(rename = "norm/scale")]
+        scale: GraphTensor,
+        bias: Option<GraphTensor>,
+    }
+    #[derive(SerializeModule)]
+    struct Model {
+        #[serialize(rename = "blk")]
+        layers: Vec<Block>,
+        #[serialize(rename = "")]
+        head: (GraphTensor,),
+        #[serialize(skip)]
+        _cache: GraphTensor,
+    }
+
+    let mut cx = Graph::new();
+    let model = Model {
+        layers: (0..2)
+            .map(|i| Block {
+                weight: cx.tensor(2),
+                scale: cx.tensor(2),
+                bias: (i == 0).then(|| cx.tensor(2)),
+            })
+            .collect(),
+        head: (cx.tensor(2),),
+        _cache: cx.tensor(2),
+    };
+    let mut keys = param_dict(&model).into_keys().collect::<Vec<_>>();
+    keys.sort();
+    assert_eq!(
+        keys,
+        [
+            "0",
+            "blk/0/bias",
+            "blk/0/norm/scale",
+            "blk/0/weight",
+            "blk/1/norm/scale",
+            "blk/1/weight"
+        ]
+    );
+}
+
 /// Ensure two arrays are nearly equal
 pub fn assert_close(a_vec: &[f32], b_vec: &[f32]) {
     assert_close_precision(a_vec, b_vec, 1e-3);